@@ -0,0 +1,10 @@
+//! Types shared by the devp2p discovery and RLPx crates
+
+extern crate secp256k1;
+extern crate bigint;
+
+mod netrestrict;
+mod signer;
+
+pub use netrestrict::{NetRestrict, Cidr};
+pub use signer::NodeSigner;
@@ -0,0 +1,136 @@
+use std::io;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Range of IP addresses sharing a prefix, like `10.0.0.0/8`
+pub struct Cidr {
+    pub address: IpAddr,
+    pub prefix: u8,
+}
+
+impl Cidr {
+    /// Whether the range contains an address. IPv4 ranges never
+    /// contain IPv6 addresses and the other way round.
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.address, *ip) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = if self.prefix == 0 { 0 } else { !0u32 << (32 - self.prefix as u32) };
+                u32::from(network) & mask == u32::from(ip) & mask
+            },
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = if self.prefix == 0 { 0 } else { !0u128 << (128 - self.prefix as u32) };
+                u128::from(network) & mask == u128::from(ip) & mask
+            },
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = io::Error;
+
+    /// Parse a range as an address and prefix length, or a single
+    /// address
+    fn from_str(s: &str) -> Result<Self, io::Error> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidInput, "invalid CIDR range");
+        let mut parts = s.trim().splitn(2, '/');
+        let address: IpAddr = parts.next().unwrap_or("").parse().map_err(|_| invalid())?;
+        let max = if address.is_ipv4() { 32 } else { 128 };
+        let prefix = match parts.next() {
+            Some(prefix) => prefix.parse().map_err(|_| invalid())?,
+            None => max,
+        };
+        if prefix > max {
+            return Err(invalid());
+        }
+        Ok(Cidr { address, prefix })
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+/// Restricts the peers we talk to by their address. Addresses in a
+/// denied range are refused. If any range is allowed, addresses
+/// outside all allowed ranges are refused as well, which confines the
+/// node to them.
+pub struct NetRestrict {
+    allowed: Vec<Cidr>,
+    denied: Vec<Cidr>,
+}
+
+impl NetRestrict {
+    /// Create a restriction allowing every address
+    pub fn new() -> Self {
+        NetRestrict::default()
+    }
+
+    /// Parse comma separated lists of allowed and denied ranges, like
+    /// `10.0.0.0/8,192.168.0.0/16`. Empty lists restrict nothing.
+    pub fn parse(allowed: &str, denied: &str) -> Result<Self, io::Error> {
+        let mut restrict = NetRestrict::new();
+        for range in allowed.split(',').filter(|range| !range.trim().is_empty()) {
+            restrict.allow(range.parse()?);
+        }
+        for range in denied.split(',').filter(|range| !range.trim().is_empty()) {
+            restrict.deny(range.parse()?);
+        }
+        Ok(restrict)
+    }
+
+    /// Confine peers to a range, next to the ranges already allowed
+    pub fn allow(&mut self, range: Cidr) {
+        self.allowed.push(range);
+    }
+
+    /// Refuse peers in a range, even if it is within an allowed range
+    pub fn deny(&mut self, range: Cidr) {
+        self.denied.push(range);
+    }
+
+    /// Whether we may talk to a peer at an address
+    pub fn allows(&self, ip: &IpAddr) -> bool {
+        !self.denied.iter().any(|range| range.contains(ip)) &&
+            (self.allowed.is_empty() || self.allowed.iter().any(|range| range.contains(ip)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Cidr, NetRestrict};
+    use std::net::IpAddr;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_ranges() {
+        let range: Cidr = "10.1.0.0/16".parse().unwrap();
+        assert!(range.contains(&ip("10.1.200.3")));
+        assert!(!range.contains(&ip("10.2.0.1")));
+        assert!(!range.contains(&ip("::1")));
+        assert!("0.0.0.0/0".parse::<Cidr>().unwrap().contains(&ip("8.8.8.8")));
+        assert!("fd00::/8".parse::<Cidr>().unwrap().contains(&ip("fd12::1")));
+        assert_eq!("127.0.0.1".parse::<Cidr>().unwrap().prefix, 32);
+
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("10.0.0/8".parse::<Cidr>().is_err());
+        assert!("10.0.0.0/x".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn allows_and_denies() {
+        assert!(NetRestrict::new().allows(&ip("1.2.3.4")));
+
+        let restrict = NetRestrict::parse("10.0.0.0/8, 192.168.1.0/24", "10.66.0.0/16").unwrap();
+        assert!(restrict.allows(&ip("10.1.2.3")));
+        assert!(restrict.allows(&ip("192.168.1.7")));
+        assert!(!restrict.allows(&ip("10.66.1.1")));
+        assert!(!restrict.allows(&ip("8.8.8.8")));
+
+        let restrict = NetRestrict::parse("", "1.2.3.0/24").unwrap();
+        assert!(!restrict.allows(&ip("1.2.3.4")));
+        assert!(restrict.allows(&ip("1.2.4.4")));
+        assert!(NetRestrict::parse("10.0.0.0/8,bad", "").is_err());
+    }
+}
@@ -0,0 +1,48 @@
+use secp256k1::{self, SECP256K1, Message, RecoverableSignature};
+use secp256k1::ecdh::SharedSecret;
+use secp256k1::key::{PublicKey, SecretKey};
+use bigint::H256;
+
+/// Operations on the node key needed by the RLPx handshake and by
+/// discovery. Implement it to keep the node key inside an external
+/// backend such as an HSM; the key itself is never requested. The
+/// RLPx auth message is signed with a per-connection ephemeral key,
+/// so RLPx only uses the node key for ECDH, while discovery signs its
+/// packets and node records with it. Signers are shared by the
+/// connections of a stream, which may run on other threads.
+pub trait NodeSigner: Send + Sync {
+    /// Public key of the node
+    fn public_key(&self) -> Result<PublicKey, secp256k1::Error>;
+
+    /// X coordinate of the ECDH shared point of the node key and the
+    /// given public key
+    fn ecdh(&self, public_key: &PublicKey) -> Result<H256, secp256k1::Error>;
+
+    /// The whole ECDH shared point of the node key and the given
+    /// public key, which discovery v5 derives its session keys from
+    fn ecdh_point(&self, public_key: &PublicKey) -> Result<PublicKey, secp256k1::Error>;
+
+    /// Recoverable signature of a message hash with the node key
+    fn sign(&self, message: &Message) -> Result<RecoverableSignature, secp256k1::Error>;
+}
+
+impl NodeSigner for SecretKey {
+    fn public_key(&self) -> Result<PublicKey, secp256k1::Error> {
+        PublicKey::from_secret_key(&SECP256K1, self)
+    }
+
+    fn ecdh(&self, public_key: &PublicKey) -> Result<H256, secp256k1::Error> {
+        let shared = SharedSecret::new_raw(&SECP256K1, public_key, self);
+        Ok(H256::from(&shared[0..32]))
+    }
+
+    fn ecdh_point(&self, public_key: &PublicKey) -> Result<PublicKey, secp256k1::Error> {
+        let mut point = *public_key;
+        point.mul_assign(&SECP256K1, self)?;
+        Ok(point)
+    }
+
+    fn sign(&self, message: &Message) -> Result<RecoverableSignature, secp256k1::Error> {
+        SECP256K1.sign_recoverable(message, self)
+    }
+}
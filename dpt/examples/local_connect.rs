@@ -29,6 +29,7 @@ use dpt::{DPTMessage, DPTNode, DPTStream};
 use url::Url;
 use std::time::{Instant, Duration};
 use std::io;
+use std::sync::Arc;
 
 // const BOOTSTRAP_NODES: [&str; 10] = [
 //     "enode://e809c4a2fec7daed400e5e28564e23693b23b2cc5a019b612505631bbe7b9ccf709c1796d2a3d29ef2b045f210caf51e3c4f5b6d3587d43ad5d6397526fa6179@174.112.32.157:30303",
@@ -75,7 +76,7 @@ fn main() {
     let handle = core.handle();
     let client = DPTStream::new(
         &addr, &handle,
-        SecretKey::new(&SECP256K1, &mut OsRng::new().unwrap()),
+        Arc::new(SecretKey::new(&SECP256K1, &mut OsRng::new().unwrap())),
         BOOTSTRAP_NODES.iter().map(|v| DPTNode::from_url(&Url::parse(v).unwrap()).unwrap()).collect(),
         &"127.0.0.1".parse().unwrap(), 50505).unwrap();
 
@@ -0,0 +1,124 @@
+use futures::{Poll, StartSend, Stream, Sink};
+use std::net::IpAddr;
+use std::io;
+use devp2p_common::NetRestrict;
+use bigint::H512;
+use v5::Discv5Stream;
+use {DPTStream, DPTNode, DPTMessage, LookupStatus, Enr};
+
+/// Discovery with either protocol version, for choosing it at runtime
+pub enum Discovery {
+    /// The DPT, discovery v4
+    V4(DPTStream),
+    /// Discovery v5
+    V5(Discv5Stream),
+}
+
+macro_rules! forward {
+    ( $discovery:expr, $stream:ident => $call:expr ) => {
+        match $discovery {
+            Discovery::V4(ref $stream) => $call,
+            Discovery::V5(ref $stream) => $call,
+        }
+    };
+    ( mut $discovery:expr, $stream:ident => $call:expr ) => {
+        match $discovery {
+            Discovery::V4(ref mut $stream) => $call,
+            Discovery::V5(ref mut $stream) => $call,
+        }
+    };
+}
+
+impl Discovery {
+    /// See `DPTStream::set_endpoint`
+    pub fn set_endpoint(&mut self, address: IpAddr, udp_port: u16, tcp_port: u16) {
+        forward!(mut *self, stream => stream.set_endpoint(address, udp_port, tcp_port))
+    }
+
+    /// See `DPTStream::local_record`
+    pub fn local_record(&self) -> &Enr {
+        forward!(*self, stream => stream.local_record())
+    }
+
+    /// See `DPTStream::node_record`
+    pub fn node_record(&self, remote_id: H512) -> Option<&Enr> {
+        forward!(*self, stream => stream.node_record(remote_id))
+    }
+
+    /// See `DPTStream::set_address_detection`
+    pub fn set_address_detection(&mut self, detect: bool) {
+        forward!(mut *self, stream => stream.set_address_detection(detect))
+    }
+
+    /// See `DPTStream::observed_address`
+    pub fn observed_address(&self) -> Option<IpAddr> {
+        forward!(*self, stream => stream.observed_address())
+    }
+
+    /// See `DPTStream::connected_peers`
+    pub fn connected_peers(&self) -> &[DPTNode] {
+        forward!(*self, stream => stream.connected_peers())
+    }
+
+    /// See `DPTStream::disconnect_peer`
+    pub fn disconnect_peer(&mut self, remote_id: H512) {
+        forward!(mut *self, stream => stream.disconnect_peer(remote_id))
+    }
+
+    /// See `DPTStream::ban_node`
+    pub fn ban_node(&mut self, remote_id: H512) {
+        forward!(mut *self, stream => stream.ban_node(remote_id))
+    }
+
+    /// See `DPTStream::unban_node`
+    pub fn unban_node(&mut self, remote_id: H512) {
+        forward!(mut *self, stream => stream.unban_node(remote_id))
+    }
+
+    /// See `DPTStream::ban_ip`
+    pub fn ban_ip(&mut self, ip: IpAddr) {
+        forward!(mut *self, stream => stream.ban_ip(ip))
+    }
+
+    /// See `DPTStream::unban_ip`
+    pub fn unban_ip(&mut self, ip: IpAddr) {
+        forward!(mut *self, stream => stream.unban_ip(ip))
+    }
+
+    /// See `DPTStream::set_net_restrict`
+    pub fn set_net_restrict(&mut self, net_restrict: Option<NetRestrict>) {
+        forward!(mut *self, stream => stream.set_net_restrict(net_restrict))
+    }
+
+    /// See `DPTStream::active_lookups`
+    pub fn active_lookups(&self) -> Vec<LookupStatus> {
+        forward!(*self, stream => stream.active_lookups())
+    }
+
+    /// See `DPTStream::get_peer`
+    pub fn get_peer(&self, remote_id: H512) -> Option<DPTNode> {
+        forward!(*self, stream => stream.get_peer(remote_id))
+    }
+}
+
+impl Stream for Discovery {
+    type Item = DPTNode;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        forward!(mut *self, stream => stream.poll())
+    }
+}
+
+impl Sink for Discovery {
+    type SinkItem = DPTMessage;
+    type SinkError = io::Error;
+
+    fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
+        forward!(mut *self, stream => stream.poll_complete())
+    }
+
+    fn start_send(&mut self, message: DPTMessage) -> StartSend<Self::SinkItem, Self::SinkError> {
+        forward!(mut *self, stream => stream.start_send(message))
+    }
+}
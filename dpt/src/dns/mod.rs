@@ -0,0 +1,378 @@
+//! DNS discovery of EIP-1459, resolving the signed node trees that
+//! `enrtree://` URLs point to
+
+mod resolver;
+
+pub use self::resolver::{TxtResolver, DnsResolver};
+
+use base32;
+use base64;
+use futures::{Poll, Async, Future, Stream};
+use secp256k1::{SECP256K1, Message, RecoverableSignature, RecoveryId};
+use secp256k1::key::PublicKey;
+use std::collections::{HashSet, VecDeque};
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+use util::keccak256;
+use enr::Enr;
+use DPTNode;
+
+/// Number of TXT queries resolved at the same time
+const MAX_RUNNING_QUERIES: usize = 8;
+
+const ROOT_PREFIX: &str = "enrtree-root:v1";
+const BRANCH_PREFIX: &str = "enrtree-branch:";
+const LINK_PREFIX: &str = "enrtree://";
+const RECORD_PREFIX: &str = "enr:";
+
+type TxtQuery = Box<dyn Future<Item = Vec<String>, Error = io::Error>>;
+
+fn base32_alphabet() -> base32::Alphabet {
+    base32::Alphabet::RFC4648 { padding: false }
+}
+
+/// Name of an entry in a tree, the base32 encoded start of its hash
+fn entry_hash(text: &str) -> String {
+    base32::encode(base32_alphabet(), &keccak256(text.as_bytes())[0..16])
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// Link to a node tree, `enrtree://<public key>@<domain>`, where the
+/// public key signs the root of the tree
+pub struct EnrTreeUrl {
+    pub public_key: PublicKey,
+    pub domain: String,
+}
+
+impl FromStr for EnrTreeUrl {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, io::Error> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidInput, "invalid enrtree URL");
+        if !s.starts_with(LINK_PREFIX) {
+            return Err(invalid());
+        }
+        let mut parts = s[LINK_PREFIX.len()..].splitn(2, '@');
+        let key = parts.next().and_then(|key| base32::decode(base32_alphabet(), key)).ok_or_else(invalid)?;
+        let public_key = PublicKey::from_slice(&SECP256K1, &key).map_err(|_| invalid())?;
+        let domain = parts.next().ok_or_else(invalid)?.trim_end_matches('.');
+        if domain.is_empty() {
+            return Err(invalid());
+        }
+        Ok(EnrTreeUrl { public_key, domain: domain.to_string() })
+    }
+}
+
+impl fmt::Display for EnrTreeUrl {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let key = self.public_key.serialize_vec(&SECP256K1, true);
+        write!(f, "{}{}@{}", LINK_PREFIX, base32::encode(base32_alphabet(), &key), self.domain)
+    }
+}
+
+/// Root of a tree, naming the subtrees of node records and of links
+/// to other trees
+struct Root {
+    enr_root: String,
+    link_root: String,
+}
+
+impl Root {
+    /// Parse a root, checking it is signed by the key of the tree
+    fn parse(text: &str, public_key: &PublicKey) -> Option<Root> {
+        let signed_end = text.find(" sig=")?;
+        let mut words = text.split_whitespace();
+        if words.next() != Some(ROOT_PREFIX) {
+            return None;
+        }
+        let (mut enr_root, mut link_root, mut signature) = (None, None, None);
+        for word in words {
+            let mut pair = word.splitn(2, '=');
+            match (pair.next(), pair.next()) {
+                (Some("e"), Some(value)) => enr_root = Some(value.to_string()),
+                (Some("l"), Some(value)) => link_root = Some(value.to_string()),
+                (Some("sig"), Some(value)) => signature = base64::decode_config(value, base64::URL_SAFE_NO_PAD).ok(),
+                _ => (),
+            }
+        }
+
+        let signature = signature?;
+        if signature.len() != 65 {
+            return None;
+        }
+        let signature = RecoverableSignature::from_compact(
+            &SECP256K1, &signature[0..64], RecoveryId::from_i32(signature[64] as i32).ok()?
+        ).ok()?;
+        let message = Message::from_slice(&keccak256(&text.as_bytes()[..signed_end])).ok()?;
+        if SECP256K1.recover(&message, &signature).ok()? != *public_key {
+            return None;
+        }
+        Some(Root { enr_root: enr_root?, link_root: link_root? })
+    }
+}
+
+/// Entry below the root of a tree
+enum Entry {
+    Branch(Vec<String>),
+    Record(Enr),
+    Link(EnrTreeUrl),
+}
+
+impl Entry {
+    fn parse(text: &str) -> Option<Entry> {
+        if let Some(hashes) = text.strip_prefix(BRANCH_PREFIX) {
+            Some(Entry::Branch(hashes.split(',')
+                               .map(|hash| hash.trim().to_string())
+                               .filter(|hash| !hash.is_empty())
+                               .collect()))
+        } else if let Some(record) = text.strip_prefix(RECORD_PREFIX) {
+            let data = base64::decode_config(record, base64::URL_SAFE_NO_PAD).ok()?;
+            Some(Entry::Record(Enr::decode(&data).ok()?))
+        } else if text.starts_with(LINK_PREFIX) {
+            text.parse().ok().map(Entry::Link)
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Subtree {
+    Records,
+    Links,
+}
+
+enum Query {
+    Root(EnrTreeUrl),
+    Entry { domain: String, hash: String, subtree: Subtree },
+}
+
+impl Query {
+    fn name(&self) -> String {
+        match *self {
+            Query::Root(ref url) => url.domain.clone(),
+            Query::Entry { ref domain, ref hash, .. } => format!("{}.{}", hash, domain),
+        }
+    }
+}
+
+/// Stream of the nodes of a DNS node tree and the trees it links to,
+/// which ends once all of them are walked. Entries that fail to
+/// resolve or verify are skipped.
+pub struct DnsDiscovery {
+    resolver: Box<dyn TxtResolver>,
+    queue: VecDeque<Query>,
+    running: Vec<(Query, TxtQuery)>,
+    /// Trees and entries queried already, so that no loop is walked
+    /// forever
+    seen: HashSet<String>,
+    nodes: Vec<DPTNode>,
+}
+
+impl DnsDiscovery {
+    /// Walk a tree, resolving its entries with a resolver
+    pub fn new(url: EnrTreeUrl, resolver: Box<dyn TxtResolver>) -> Self {
+        let mut seen = HashSet::new();
+        seen.insert(url.domain.clone());
+        let mut queue = VecDeque::new();
+        queue.push_back(Query::Root(url));
+        DnsDiscovery {
+            resolver, queue, seen,
+            running: Vec::new(),
+            nodes: Vec::new(),
+        }
+    }
+
+    fn handle(&mut self, query: Query, texts: Vec<String>) {
+        match query {
+            Query::Root(url) => {
+                let root = match texts.iter().filter_map(|text| Root::parse(text, &url.public_key)).next() {
+                    Some(root) => root,
+                    None => {
+                        debug!("no valid root at {}", url.domain);
+                        return;
+                    },
+                };
+                self.push_entry(&url.domain, root.enr_root, Subtree::Records);
+                self.push_entry(&url.domain, root.link_root, Subtree::Links);
+            },
+            Query::Entry { domain, hash, subtree } => {
+                // The name of an entry is its hash, which authenticates
+                // it through the signed root
+                let entry = texts.iter()
+                    .find(|text| entry_hash(text).eq_ignore_ascii_case(&hash))
+                    .and_then(|text| Entry::parse(text));
+                match (entry, subtree) {
+                    (Some(Entry::Branch(children)), _) => {
+                        for child in children {
+                            self.push_entry(&domain, child, subtree);
+                        }
+                    },
+                    (Some(Entry::Record(record)), Subtree::Records) => {
+                        match record.node() {
+                            Some(ref node) if record.verify() => self.nodes.push(node.clone()),
+                            _ => debug!("ignoring invalid node record at {}.{}", hash, domain),
+                        }
+                    },
+                    (Some(Entry::Link(url)), Subtree::Links) => {
+                        if self.seen.insert(url.domain.clone()) {
+                            self.queue.push_back(Query::Root(url));
+                        }
+                    },
+                    _ => debug!("ignoring invalid entry at {}.{}", hash, domain),
+                }
+            },
+        }
+    }
+
+    fn push_entry(&mut self, domain: &str, hash: String, subtree: Subtree) {
+        let query = Query::Entry { domain: domain.to_string(), hash, subtree };
+        if self.seen.insert(query.name()) {
+            self.queue.push_back(query);
+        }
+    }
+}
+
+impl Stream for DnsDiscovery {
+    type Item = DPTNode;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        loop {
+            if let Some(node) = self.nodes.pop() {
+                return Ok(Async::Ready(Some(node)));
+            }
+            while self.running.len() < MAX_RUNNING_QUERIES {
+                let query = match self.queue.pop_front() {
+                    Some(query) => query,
+                    None => break,
+                };
+                let future = self.resolver.resolve_txt(&query.name());
+                self.running.push((query, future));
+            }
+            if self.running.is_empty() {
+                return Ok(Async::Ready(None));
+            }
+
+            let mut done = Vec::new();
+            let mut finished = false;
+            let mut i = 0;
+            while i < self.running.len() {
+                let result = self.running[i].1.poll();
+                match result {
+                    Ok(Async::NotReady) => i += 1,
+                    Ok(Async::Ready(texts)) => done.push((self.running.remove(i).0, texts)),
+                    Err(e) => {
+                        let (query, _) = self.running.remove(i);
+                        debug!("resolving {} failed: {}", query.name(), e);
+                        finished = true;
+                    },
+                }
+            }
+            if done.is_empty() && !finished {
+                return Ok(Async::NotReady);
+            }
+            for (query, texts) in done {
+                self.handle(query, texts);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DnsDiscovery, EnrTreeUrl, TxtResolver, entry_hash};
+    use enr::Enr;
+    use futures::{future, Future, Stream};
+    use secp256k1::{SECP256K1, Message};
+    use secp256k1::key::{PublicKey, SecretKey};
+    use rand::os::OsRng;
+    use util::keccak256;
+    use base64;
+    use std::collections::HashMap;
+    use std::io;
+
+    struct MapResolver(HashMap<String, String>);
+
+    impl TxtResolver for MapResolver {
+        fn resolve_txt(&self, name: &str) -> Box<dyn Future<Item = Vec<String>, Error = io::Error>> {
+            match self.0.get(name) {
+                Some(text) => Box::new(future::ok(vec![text.clone()])),
+                None => Box::new(future::err(io::Error::new(io::ErrorKind::NotFound, "not found"))),
+            }
+        }
+    }
+
+    fn sign_root(key: &SecretKey, enr_root: &str, link_root: &str) -> String {
+        let text = format!("enrtree-root:v1 e={} l={} seq=1", enr_root, link_root);
+        let message = Message::from_slice(&keccak256(text.as_bytes())).unwrap();
+        let (rec, sig) = SECP256K1.sign_recoverable(&message, key).unwrap().serialize_compact(&SECP256K1);
+        let mut signature = sig.to_vec();
+        signature.push(rec.to_i32() as u8);
+        format!("{} sig={}", text, base64::encode_config(&signature, base64::URL_SAFE_NO_PAD))
+    }
+
+    fn record_entry(port: u16) -> (Enr, String) {
+        let key = SecretKey::new(&SECP256K1, &mut OsRng::new().unwrap());
+        let record = Enr::new(&key, 1, "10.0.0.1".parse().unwrap(), port, port).unwrap();
+        let text = format!("enr:{}", base64::encode_config(&record.encode(), base64::URL_SAFE_NO_PAD));
+        (record, text)
+    }
+
+    #[test]
+    fn eip1459_url() {
+        let url: EnrTreeUrl = "enrtree://AM5FCQLWIZX2QFPNJAP7VUERCCRNGRHWZG3YYHIUV7BVDQ5FDPRT2@nodes.example.org".parse().unwrap();
+        assert_eq!(url.domain, "nodes.example.org");
+        assert_eq!(url.to_string(), "enrtree://AM5FCQLWIZX2QFPNJAP7VUERCCRNGRHWZG3YYHIUV7BVDQ5FDPRT2@nodes.example.org");
+        assert!("enrtree://AM5FCQLWIZX2QFPNJAP7VUERCCRNGRHWZG3YYHIUV7BVDQ5FDPRT2@".parse::<EnrTreeUrl>().is_err());
+        assert!("enode://AM5FCQLWIZX2QFPNJAP7VUERCCRNGRHWZG3YYHIUV7BVDQ5FDPRT2@nodes.example.org".parse::<EnrTreeUrl>().is_err());
+        assert!("enrtree://AM5FCQ@nodes.example.org".parse::<EnrTreeUrl>().is_err());
+    }
+
+    #[test]
+    fn tree_walked() {
+        let key = SecretKey::new(&SECP256K1, &mut OsRng::new().unwrap());
+        let linked_key = SecretKey::new(&SECP256K1, &mut OsRng::new().unwrap());
+        let url = EnrTreeUrl {
+            public_key: PublicKey::from_secret_key(&SECP256K1, &key).unwrap(),
+            domain: "nodes.example.org".to_string(),
+        };
+        let linked = EnrTreeUrl {
+            public_key: PublicKey::from_secret_key(&SECP256K1, &linked_key).unwrap(),
+            domain: "more.example.org".to_string(),
+        };
+
+        let (first, first_text) = record_entry(30303);
+        let (second, second_text) = record_entry(30304);
+        let (third, third_text) = record_entry(30305);
+        let (_, forged_text) = record_entry(30306);
+        let branch = format!("enrtree-branch:{},{}", entry_hash(&first_text), entry_hash(&second_text));
+        let link = linked.to_string();
+        // A link back to the first tree is not walked again
+        let link_back = url.to_string();
+
+        let mut names = HashMap::new();
+        names.insert("nodes.example.org".to_string(), sign_root(&key, &entry_hash(&branch), &entry_hash(&link)));
+        names.insert(format!("{}.nodes.example.org", entry_hash(&branch)), branch.clone());
+        names.insert(format!("{}.nodes.example.org", entry_hash(&first_text)), first_text.clone());
+        names.insert(format!("{}.nodes.example.org", entry_hash(&second_text)), second_text.clone());
+        names.insert(format!("{}.nodes.example.org", entry_hash(&link)), link.clone());
+        names.insert("more.example.org".to_string(), sign_root(&linked_key, &entry_hash(&third_text), &entry_hash(&link_back)));
+        names.insert(format!("{}.more.example.org", entry_hash(&third_text)), third_text.clone());
+        names.insert(format!("{}.more.example.org", entry_hash(&link_back)), link_back.clone());
+
+        let nodes = DnsDiscovery::new(url.clone(), Box::new(MapResolver(names.clone()))).collect().wait().unwrap();
+        let mut ports: Vec<u16> = nodes.iter().map(|node| node.tcp_port).collect();
+        ports.sort();
+        assert_eq!(ports, vec![30303, 30304, 30305]);
+        assert_eq!(nodes.iter().find(|node| node.tcp_port == 30303), first.node().as_ref());
+        assert!(nodes.contains(&second.node().unwrap()) && nodes.contains(&third.node().unwrap()));
+
+        // Entries must match their names, and roots their key
+        names.insert(format!("{}.nodes.example.org", entry_hash(&second_text)), forged_text);
+        names.insert("more.example.org".to_string(), sign_root(&key, &entry_hash(&third_text), &entry_hash(&link_back)));
+        let nodes = DnsDiscovery::new(url, Box::new(MapResolver(names))).collect().wait().unwrap();
+        assert_eq!(nodes, vec![first.node().unwrap()]);
+    }
+}
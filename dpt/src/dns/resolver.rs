@@ -0,0 +1,189 @@
+use futures::future;
+use futures::Future;
+use tokio_core::reactor::{Timeout, Handle};
+use tokio_core::net::UdpSocket;
+use rand::{Rng, thread_rng};
+use std::fs::File;
+use std::io::{self, Read};
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+/// Time a DNS server is waited for
+const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+const TYPE_TXT: u16 = 16;
+const CLASS_IN: u16 = 1;
+
+/// Resolves the TXT records of domain names
+pub trait TxtResolver {
+    /// Texts of the TXT records of a name, each with its strings
+    /// joined
+    fn resolve_txt(&self, name: &str) -> Box<dyn Future<Item = Vec<String>, Error = io::Error>>;
+}
+
+/// TXT resolver querying a DNS server over UDP
+pub struct DnsResolver {
+    server: SocketAddr,
+    handle: Handle,
+}
+
+impl DnsResolver {
+    /// Create a resolver querying a server
+    pub fn new(server: SocketAddr, handle: &Handle) -> Self {
+        DnsResolver { server, handle: handle.clone() }
+    }
+
+    /// Create a resolver querying the first name server of
+    /// `/etc/resolv.conf`
+    pub fn system(handle: &Handle) -> Result<Self, io::Error> {
+        let mut conf = String::new();
+        File::open("/etc/resolv.conf")?.read_to_string(&mut conf)?;
+        let server = conf.lines()
+            .filter_map(|line| {
+                let mut words = line.split_whitespace();
+                match (words.next(), words.next()) {
+                    (Some("nameserver"), Some(address)) => address.parse::<IpAddr>().ok(),
+                    _ => None,
+                }
+            })
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no name server configured"))?;
+        Ok(DnsResolver::new(SocketAddr::new(server, 53), handle))
+    }
+}
+
+impl TxtResolver for DnsResolver {
+    fn resolve_txt(&self, name: &str) -> Box<dyn Future<Item = Vec<String>, Error = io::Error>> {
+        let id: u16 = thread_rng().gen();
+        let query = match encode_query(id, name) {
+            Ok(query) => query,
+            Err(e) => return Box::new(future::err(e)),
+        };
+        let local: SocketAddr = if self.server.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" }.parse().unwrap();
+        let (socket, timeout) = match (UdpSocket::bind(&local, &self.handle),
+                                       Timeout::new(QUERY_TIMEOUT, &self.handle)) {
+            (Ok(socket), Ok(timeout)) => (socket, timeout),
+            (Err(e), _) | (_, Err(e)) => return Box::new(future::err(e)),
+        };
+        let server = self.server;
+
+        let response = socket.send_dgram(query, server)
+            .and_then(|(socket, _)| socket.recv_dgram(vec![0u8; 4096]))
+            .and_then(move |(_, buf, len, from)| {
+                if from != server {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "DNS response from another address"));
+                }
+                decode_response(id, &buf[..len])
+            });
+        let timeout = timeout.then(|_| Err(io::Error::new(io::ErrorKind::TimedOut, "DNS query timed out")));
+        Box::new(response.select(timeout).map(|(texts, _)| texts).map_err(|(e, _)| e))
+    }
+}
+
+/// Recursive query for the TXT records of a name
+fn encode_query(id: u16, name: &str) -> Result<Vec<u8>, io::Error> {
+    let mut out = vec![
+        (id >> 8) as u8, id as u8,
+        0x01, 0x00, // recursion desired
+        0x00, 0x01, // one question
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+    ];
+    for label in name.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid domain name"));
+        }
+        out.push(label.len() as u8);
+        out.extend_from_slice(label.as_bytes());
+    }
+    out.push(0);
+    out.extend_from_slice(&[(TYPE_TXT >> 8) as u8, TYPE_TXT as u8, (CLASS_IN >> 8) as u8, CLASS_IN as u8]);
+    Ok(out)
+}
+
+fn read_u16(data: &[u8], at: usize) -> Option<u16> {
+    if data.len() < at + 2 {
+        return None;
+    }
+    Some(((data[at] as u16) << 8) | data[at + 1] as u16)
+}
+
+/// Position after a possibly compressed name
+fn skip_name(data: &[u8], mut at: usize) -> Option<usize> {
+    loop {
+        let len = *data.get(at)? as usize;
+        if len == 0 {
+            return Some(at + 1);
+        }
+        if len & 0xc0 == 0xc0 {
+            return Some(at + 2);
+        }
+        at += len + 1;
+    }
+}
+
+/// Texts of the TXT records answering a query
+fn decode_response(id: u16, data: &[u8]) -> Result<Vec<String>, io::Error> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "invalid DNS response");
+    if read_u16(data, 0) != Some(id) || data.len() < 12 || data[2] & 0x80 == 0 {
+        return Err(invalid());
+    }
+    match data[3] & 0x0f {
+        0 => (),
+        3 => return Err(io::Error::new(io::ErrorKind::NotFound, "DNS name not found")),
+        _ => return Err(io::Error::new(io::ErrorKind::Other, "DNS query failed")),
+    }
+    let questions = read_u16(data, 4).ok_or_else(invalid)?;
+    let answers = read_u16(data, 6).ok_or_else(invalid)?;
+
+    let mut at = 12;
+    for _ in 0..questions {
+        at = skip_name(data, at).ok_or_else(invalid)? + 4;
+    }
+    let mut texts = Vec::new();
+    for _ in 0..answers {
+        at = skip_name(data, at).ok_or_else(invalid)?;
+        let typ = read_u16(data, at).ok_or_else(invalid)?;
+        let len = read_u16(data, at + 8).ok_or_else(invalid)? as usize;
+        at += 10;
+        if data.len() < at + len {
+            return Err(invalid());
+        }
+        if typ == TYPE_TXT {
+            let rdata = &data[at..at + len];
+            let mut text = Vec::new();
+            let mut i = 0;
+            while i < rdata.len() {
+                let size = rdata[i] as usize;
+                text.extend_from_slice(rdata.get(i + 1..i + 1 + size).ok_or_else(invalid)?);
+                i += size + 1;
+            }
+            texts.push(String::from_utf8_lossy(&text).into_owned());
+        }
+        at += len;
+    }
+    Ok(texts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{encode_query, decode_response};
+
+    #[test]
+    fn txt_response_decoded() {
+        let query = encode_query(0x1234, "nodes.example.org").unwrap();
+        assert_eq!(&query[12..], b"\x05nodes\x07example\x03org\x00\x00\x10\x00\x01");
+        assert!(encode_query(1, "a..org").is_err());
+
+        let mut response = query.clone();
+        response[2] |= 0x80;
+        response[7] = 2;
+        // One TXT record split in two strings, and a CNAME ignored
+        response.extend_from_slice(b"\xc0\x0c\x00\x10\x00\x01\x00\x00\x00\x3c\x00\x08\x03abc\x03def");
+        response.extend_from_slice(b"\xc0\x0c\x00\x05\x00\x01\x00\x00\x00\x3c\x00\x02\xc0\x0c");
+        assert_eq!(decode_response(0x1234, &response).unwrap(), vec!["abcdef".to_string()]);
+        assert!(decode_response(0x4321, &response).is_err());
+
+        response[3] |= 0x03;
+        assert!(decode_response(0x1234, &response).is_err());
+        assert!(decode_response(0x1234, &response[..20]).is_err());
+    }
+}
@@ -0,0 +1,250 @@
+use rlp::{self, Encodable, Decodable, DecoderError, UntrustedRlp, RlpStream};
+use secp256k1::{SECP256K1, Message, RecoverableSignature, RecoveryId};
+use secp256k1::key::PublicKey;
+use bigint::H512;
+use util::{keccak256, pk2id};
+use std::net::IpAddr;
+use std::io;
+use devp2p_common::NodeSigner;
+use DPTNode;
+
+/// Largest encoded node record allowed by EIP-778, in bytes
+pub const MAX_RECORD_SIZE: usize = 300;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// Ethereum Node Record of EIP-778, signed with the "v4" identity
+/// scheme. Values are kept RLP encoded, by key in ascending order.
+pub struct Enr {
+    seq: u64,
+    pairs: Vec<(Vec<u8>, Vec<u8>)>,
+    signature: Vec<u8>,
+}
+
+impl Enr {
+    /// Sign a record of a node at an address with its key. A port of 0
+    /// is left out of the record.
+    pub fn new(signer: &dyn NodeSigner, seq: u64, address: IpAddr,
+               tcp_port: u16, udp_port: u16) -> Result<Enr, io::Error> {
+        let public_key = signer.public_key()
+            .map_err(|_| io::Error::new(io::ErrorKind::Other, "converting pub key failed"))?;
+        let (ip_key, tcp_key, udp_key) = match address {
+            IpAddr::V4(_) => ("ip", "tcp", "udp"),
+            IpAddr::V6(_) => ("ip6", "tcp6", "udp6"),
+        };
+        let address: Vec<u8> = match address {
+            IpAddr::V4(v) => v.octets().as_ref().into(),
+            IpAddr::V6(v) => v.octets().as_ref().into(),
+        };
+
+        let mut pairs = vec![
+            (b"id".to_vec(), rlp::encode(&"v4").to_vec()),
+            (ip_key.as_bytes().to_vec(), rlp::encode(&address).to_vec()),
+            (b"secp256k1".to_vec(), rlp::encode(&public_key.serialize_vec(&SECP256K1, true).to_vec()).to_vec()),
+        ];
+        if tcp_port != 0 {
+            pairs.push((tcp_key.as_bytes().to_vec(), rlp::encode(&tcp_port).to_vec()));
+        }
+        if udp_port != 0 {
+            pairs.push((udp_key.as_bytes().to_vec(), rlp::encode(&udp_port).to_vec()));
+        }
+        pairs.sort();
+
+        let mut record = Enr { seq, pairs, signature: Vec::new() };
+        let message = Message::from_slice(&keccak256(&record.content())).unwrap();
+        let signature = signer.sign(&message)
+            .map_err(|_| io::Error::new(io::ErrorKind::Other, "signing node record failed"))?;
+        record.signature = signature.serialize_compact(&SECP256K1).1.to_vec();
+        Ok(record)
+    }
+
+    /// Sequence number, increased whenever the record changes
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    /// RLP encoded value of a key
+    pub fn get(&self, key: &str) -> Option<&[u8]> {
+        self.pairs.iter()
+            .find(|&(k, _)| k.as_slice() == key.as_bytes())
+            .map(|(_, value)| value.as_slice())
+    }
+
+    fn value<T: Decodable>(&self, key: &str) -> Option<T> {
+        self.get(key).and_then(|value| UntrustedRlp::new(value).as_val().ok())
+    }
+
+    /// Public key of the "v4" identity scheme
+    pub fn public_key(&self) -> Option<PublicKey> {
+        let key: Vec<u8> = self.value("secp256k1")?;
+        PublicKey::from_slice(&SECP256K1, &key).ok()
+    }
+
+    /// Node id, derived from the public key
+    pub fn id(&self) -> Option<H512> {
+        self.public_key().map(|key| pk2id(&key))
+    }
+
+    /// IP address, IPv4 if the record has both
+    pub fn address(&self) -> Option<IpAddr> {
+        let ip: Option<Vec<u8>> = self.value("ip");
+        match ip {
+            Some(ref ip) if ip.len() == 4 => Some(IpAddr::from([ip[0], ip[1], ip[2], ip[3]])),
+            _ => {
+                let ip: Vec<u8> = self.value("ip6")?;
+                if ip.len() != 16 {
+                    return None;
+                }
+                let mut raw = [0u8; 16];
+                raw.copy_from_slice(&ip);
+                Some(IpAddr::from(raw))
+            },
+        }
+    }
+
+    /// RLPx port of the address
+    pub fn tcp_port(&self) -> Option<u16> {
+        match self.address() {
+            Some(IpAddr::V6(_)) => self.value("tcp6"),
+            _ => self.value("tcp"),
+        }
+    }
+
+    /// Discovery port of the address
+    pub fn udp_port(&self) -> Option<u16> {
+        match self.address() {
+            Some(IpAddr::V6(_)) => self.value("udp6"),
+            _ => self.value("udp"),
+        }
+    }
+
+    /// Discovery node of the record, if it has an address and ports
+    pub fn node(&self) -> Option<DPTNode> {
+        let udp_port = self.udp_port()?;
+        Some(DPTNode {
+            address: self.address()?,
+            tcp_port: self.tcp_port().unwrap_or(0),
+            udp_port,
+            id: self.id()?,
+        })
+    }
+
+    /// Whether the record uses the "v4" identity scheme and is signed
+    /// by its public key
+    pub fn verify(&self) -> bool {
+        let scheme: Option<String> = self.value("id");
+        let public_key = match (scheme, self.public_key()) {
+            (Some(ref scheme), Some(key)) if scheme == "v4" => key,
+            _ => return false,
+        };
+        if self.signature.len() != 64 {
+            return false;
+        }
+        let signature = match RecoverableSignature::from_compact(
+            &SECP256K1, &self.signature, RecoveryId::from_i32(0).unwrap()
+        ) {
+            Ok(signature) => signature.to_standard(&SECP256K1),
+            Err(_) => return false,
+        };
+        let message = Message::from_slice(&keccak256(&self.content())).unwrap();
+        SECP256K1.verify(&message, &signature, &public_key).is_ok()
+    }
+
+    /// Encoded record
+    pub fn encode(&self) -> Vec<u8> {
+        rlp::encode(self).to_vec()
+    }
+
+    /// Decode a record without verifying it, see `Enr::verify`
+    pub fn decode(data: &[u8]) -> Result<Enr, DecoderError> {
+        if data.len() > MAX_RECORD_SIZE {
+            return Err(DecoderError::Custom("node record too big"));
+        }
+        UntrustedRlp::new(data).as_val()
+    }
+
+    /// What the signature is over
+    fn content(&self) -> Vec<u8> {
+        let mut s = RlpStream::new_list(1 + self.pairs.len() * 2);
+        s.append(&self.seq);
+        for (key, value) in &self.pairs {
+            s.append(key);
+            s.append_raw(value, 1);
+        }
+        s.out()
+    }
+}
+
+impl Encodable for Enr {
+    fn rlp_append(&self, s: &mut RlpStream) {
+        s.begin_list(2 + self.pairs.len() * 2);
+        s.append(&self.signature);
+        s.append(&self.seq);
+        for (key, value) in &self.pairs {
+            s.append(key);
+            s.append_raw(value, 1);
+        }
+    }
+}
+
+impl Decodable for Enr {
+    fn decode(rlp: &UntrustedRlp) -> Result<Self, DecoderError> {
+        let count = rlp.item_count()?;
+        if count < 2 || count % 2 != 0 {
+            return Err(DecoderError::Custom("wrong node record length"));
+        }
+        let mut pairs: Vec<(Vec<u8>, Vec<u8>)> = Vec::new();
+        for i in 1..count / 2 {
+            let key: Vec<u8> = rlp.val_at(i * 2)?;
+            if pairs.last().map(|(last, _)| *last >= key).unwrap_or(false) {
+                return Err(DecoderError::Custom("node record keys not sorted"));
+            }
+            pairs.push((key, rlp.at(i * 2 + 1)?.as_raw().to_vec()));
+        }
+        Ok(Enr {
+            signature: rlp.val_at(0)?,
+            seq: rlp.val_at(1)?,
+            pairs,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Enr;
+    use secp256k1::SECP256K1;
+    use secp256k1::key::SecretKey;
+    use hexutil::read_hex;
+    use rand::os::OsRng;
+
+    #[test]
+    fn eip778_example() {
+        // Record and key given as an example in EIP-778
+        let key = read_hex("b71c71a67e1177ad4e901695e1b4b9ee17ae16c6668d313eac2f96dbcda3f291").unwrap();
+        let key = SecretKey::from_slice(&SECP256K1, &key).unwrap();
+        let encoded = read_hex("f884b8407098ad865b00a582051940cb9cf36836572411a47278783077011599ed5cd16b76f2635f4e234738f30813a89eb9137e3e3df5266e3a1f11df72ecf1145ccb9c01826964827634826970847f00000189736563703235366b31a103ca634cae0d49acb401d8a4c6b6fe8c55b70d115bf400769cc1400f3258cd31388375647082765f").unwrap();
+
+        let record = Enr::decode(&encoded).unwrap();
+        assert!(record.verify());
+        assert_eq!(record.seq(), 1);
+        assert_eq!(record.address(), Some("127.0.0.1".parse().unwrap()));
+        assert_eq!((record.tcp_port(), record.udp_port()), (None, Some(30303)));
+        assert_eq!(record.encode(), encoded);
+
+        let signed = Enr::new(&key, 1, "127.0.0.1".parse().unwrap(), 0, 30303).unwrap();
+        assert_eq!(signed, record);
+    }
+
+    #[test]
+    fn tampered_record_rejected() {
+        let key = SecretKey::new(&SECP256K1, &mut OsRng::new().unwrap());
+        let record = Enr::new(&key, 7, "::1".parse().unwrap(), 30303, 30301).unwrap();
+        let node = record.node().unwrap();
+        assert_eq!((node.address, node.tcp_port, node.udp_port), ("::1".parse().unwrap(), 30303, 30301));
+
+        let mut tampered = record.clone();
+        tampered.seq = 8;
+        assert!(!tampered.verify());
+        assert!(Enr::decode(&tampered.encode()).is_ok());
+        assert!(Enr::decode(&[0xc1, 0x80]).is_err());
+    }
+}
@@ -14,10 +14,31 @@ extern crate tokio_core;
 extern crate time;
 extern crate rand;
 extern crate url;
+extern crate crypto;
+extern crate base32;
+extern crate base64;
+extern crate devp2p_common;
+#[cfg(feature = "serialize")]
+extern crate serde;
+#[cfg(feature = "serialize")]
+#[macro_use]
+extern crate serde_derive;
 
 mod proto;
 mod message;
 mod util;
+mod enr;
+mod v5;
+mod discovery;
+mod dns;
+#[cfg(feature = "serialize")]
+mod serialize;
+
+pub use enr::{Enr, MAX_RECORD_SIZE};
+pub use v5::Discv5Stream;
+pub use discovery::Discovery;
+pub use dns::{DnsDiscovery, EnrTreeUrl, TxtResolver, DnsResolver};
+pub use devp2p_common::{NetRestrict, NodeSigner};
 
 use message::*;
 use proto::{DPTCodec, DPTCodecMessage};
@@ -28,12 +49,14 @@ use tokio_io::codec::{Framed, Encoder, Decoder};
 use tokio_core::reactor::{Timeout, Handle};
 use tokio_core::net::{UdpSocket, UdpFramed};
 use std::net::{IpAddr, SocketAddr, Ipv4Addr, Ipv6Addr};
+use std::collections::HashMap;
+use std::fmt;
 use std::io;
 use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use bigint::{H256, H512};
 use rlp::UntrustedRlp;
-use secp256k1::SECP256K1;
-use secp256k1::key::{PublicKey, SecretKey};
 use util::{keccak256, pk2id};
 use rand::{Rng, thread_rng};
 use url::{Host, Url};
@@ -59,6 +82,33 @@ fn retain_mut<T, F>(vec: &mut Vec<T>, mut f: F)
     }
 }
 
+/// Time after which a lookup whose queried nodes did not all answer
+/// is abandoned
+const LOOKUP_TIMEOUT: Duration = Duration::from_secs(30);
+/// Number of nodes that must see us at the same address before it is
+/// announced, when detecting our address
+const ADDRESS_DETECTION_VOTES: usize = 3;
+/// Number of most recent nodes whose view of our address is kept
+const ADDRESS_DETECTION_WINDOW: usize = 20;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// Snapshot of an outstanding FindNeighbours lookup
+pub struct LookupStatus {
+    /// Id the lookup searches neighbours of
+    pub target: H512,
+    /// Number of Neighbours responses received so far
+    pub rounds: usize,
+    /// Nodes a FindNeighbours request was sent to
+    pub queried: Vec<H512>,
+    /// Queried nodes that have not answered yet
+    pub remaining: Vec<H512>,
+}
+
+struct Lookup {
+    status: LookupStatus,
+    started: Instant,
+}
+
 /// DPT message for requesting new peers or ping with timeout
 pub enum DPTMessage {
     RequestNewPeer,
@@ -73,10 +123,19 @@ pub struct DPTStream {
     pingponged: Vec<DPTNode>,
     bootstrapped: bool,
     timeout: Option<(Timeout, Vec<H512>)>,
+    lookups: Vec<Lookup>,
     incoming: Vec<DPTNode>,
+    banned: Vec<H512>,
+    banned_ips: Vec<IpAddr>,
+    net_restrict: Option<NetRestrict>,
+    detect_address: bool,
+    address_votes: Vec<(H512, IpAddr)>,
     address: IpAddr,
     udp_port: u16,
     tcp_port: u16,
+    signer: Arc<dyn NodeSigner>,
+    record: Enr,
+    records: HashMap<H512, Enr>,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -105,10 +164,15 @@ impl DPTNode {
         SocketAddr::new(self.address, self.udp_port)
     }
 
+    /// Node of an `enode://<id>@<address>:<tcp port>` URL, with a
+    /// `discport` query parameter for a different UDP port
     pub fn from_url(url: &Url) -> Result<DPTNode, DPTNodeParseError> {
         let address = match url.host() {
             Some(Host::Ipv4(ip)) => IpAddr::V4(ip),
             Some(Host::Ipv6(ip)) => IpAddr::V6(ip),
+            // Hosts of URLs with a scheme unknown to the URL parser,
+            // like `enode`, are not parsed as IP addresses
+            Some(Host::Domain(domain)) => domain.parse().map_err(|_| DPTNodeParseError::UrlError)?,
             _ => return Err(DPTNodeParseError::UrlError),
         };
         let port = match url.port() {
@@ -119,36 +183,145 @@ impl DPTNode {
             Ok(id) => id,
             _ => return Err(DPTNodeParseError::HexError),
         };
+        let udp_port = match url.query_pairs().find(|(key, _)| key == "discport") {
+            Some((_, value)) => value.parse().map_err(|_| DPTNodeParseError::UrlError)?,
+            None => port,
+        };
 
         Ok(DPTNode {
             address, id,
             tcp_port: port,
-            udp_port: port,
+            udp_port,
         })
     }
 }
 
+impl FromStr for DPTNode {
+    type Err = DPTNodeParseError;
+
+    /// Parse an `enode://` URL, see `DPTNode::from_url`
+    fn from_str(s: &str) -> Result<DPTNode, DPTNodeParseError> {
+        let url = Url::parse(s).map_err(|_| DPTNodeParseError::UrlError)?;
+        if url.scheme() != "enode" {
+            return Err(DPTNodeParseError::UrlError);
+        }
+        DPTNode::from_url(&url)
+    }
+}
+
+impl fmt::Display for DPTNode {
+    /// Format as an `enode://` URL
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "enode://{:x}@{}", self.id, self.tcp_addr())?;
+        if self.udp_port != self.tcp_port {
+            write!(f, "?discport={}", self.udp_port)?;
+        }
+        Ok(())
+    }
+}
+
 impl DPTStream {
     /// Create a new DPT stream
     pub fn new(addr: &SocketAddr, handle: &Handle,
-               secret_key: SecretKey,
+               signer: Arc<dyn NodeSigner>,
                bootstrap_nodes: Vec<DPTNode>,
                public_address: &IpAddr, tcp_port: u16) -> Result<Self, io::Error> {
-        let id = pk2id(&match PublicKey::from_secret_key(&SECP256K1, &secret_key) {
+        let id = pk2id(&match signer.public_key() {
             Ok(val) => val,
             Err(_) => return Err(io::Error::new(io::ErrorKind::Other, "converting pub key failed")),
         });
         debug!("self id: {:x}", id);
+        // Starting the sequence at the current time keeps it increasing
+        // across restarts
+        let seq = time::now_utc().to_timespec().sec as u64;
+        let record = Enr::new(&*signer, seq, *public_address, tcp_port, addr.port())?;
         Ok(Self {
-            stream: UdpSocket::bind(addr, handle)?.framed(DPTCodec::new(secret_key)),
+            stream: UdpSocket::bind(addr, handle)?.framed(DPTCodec::new(signer.clone())),
             id, connected: bootstrap_nodes.clone(), incoming: bootstrap_nodes,
             pingponged: Vec::new(),
             bootstrapped: false,
             timeout: None,
-            address: public_address.clone(), udp_port: addr.port(), tcp_port
+            lookups: Vec::new(),
+            banned: Vec::new(),
+            banned_ips: Vec::new(),
+            net_restrict: None,
+            detect_address: false,
+            address_votes: Vec::new(),
+            address: *public_address, udp_port: addr.port(), tcp_port,
+            signer, record,
+            records: HashMap::new(),
         })
     }
 
+    /// Change the address and ports we announce in pings and our node
+    /// record, like when they are mapped on a NAT gateway
+    pub fn set_endpoint(&mut self, address: IpAddr, udp_port: u16, tcp_port: u16) {
+        self.address = address;
+        self.udp_port = udp_port;
+        self.tcp_port = tcp_port;
+        self.update_record();
+    }
+
+    /// Our node record, as sent to nodes asking for it
+    pub fn local_record(&self) -> &Enr {
+        &self.record
+    }
+
+    /// Latest verified record of a node, which is asked for when its
+    /// pongs announce a newer one
+    pub fn node_record(&self, remote_id: H512) -> Option<&Enr> {
+        self.records.get(&remote_id)
+    }
+
+    /// Sign a new record with a higher sequence number if our endpoint
+    /// changed
+    fn update_record(&mut self) {
+        if self.record.address() == Some(self.address) &&
+            self.record.tcp_port().unwrap_or(0) == self.tcp_port &&
+            self.record.udp_port().unwrap_or(0) == self.udp_port
+        {
+            return;
+        }
+        match Enr::new(&*self.signer, self.record.seq() + 1, self.address, self.tcp_port, self.udp_port) {
+            Ok(record) => self.record = record,
+            Err(e) => error!("signing node record failed: {}", e),
+        }
+    }
+
+    /// Learn our public address from the pongs of other nodes, which
+    /// tell where they saw our pings come from, and announce it once
+    /// enough of them agree
+    pub fn set_address_detection(&mut self, detect: bool) {
+        self.detect_address = detect;
+    }
+
+    /// Public address most recent pongs agree on, if enough of them do
+    pub fn observed_address(&self) -> Option<IpAddr> {
+        let mut best: Option<(IpAddr, usize)> = None;
+        for &(_, address) in &self.address_votes {
+            let votes = self.address_votes.iter().filter(|&&(_, vote)| vote == address).count();
+            if best.map(|(_, most)| votes > most).unwrap_or(true) {
+                best = Some((address, votes));
+            }
+        }
+        best.and_then(|(address, votes)| if votes >= ADDRESS_DETECTION_VOTES { Some(address) } else { None })
+    }
+
+    fn vote_address(&mut self, remote_id: H512, address: IpAddr) {
+        self.address_votes.retain(|&(id, _)| id != remote_id);
+        self.address_votes.push((remote_id, address));
+        if self.address_votes.len() > ADDRESS_DETECTION_WINDOW {
+            self.address_votes.remove(0);
+        }
+        if let (true, Some(observed)) = (self.detect_address, self.observed_address()) {
+            if observed != self.address {
+                info!("detected public address {}", observed);
+                self.address = observed;
+                self.update_record();
+            }
+        }
+    }
+
     /// Get all connected peers
     pub fn connected_peers(&self) -> &[DPTNode] {
         &self.pingponged
@@ -162,6 +335,58 @@ impl DPTStream {
         self.pingponged.retain(|node| {
             node.id != remote_id
         });
+        self.records.remove(&remote_id);
+    }
+
+    /// Forget a node and ignore it when neighbours tell about it again
+    pub fn ban_node(&mut self, remote_id: H512) {
+        self.disconnect_peer(remote_id);
+        self.incoming.retain(|node| node.id != remote_id);
+        if !self.banned.contains(&remote_id) {
+            self.banned.push(remote_id);
+        }
+    }
+
+    /// Let neighbours tell about a banned node again
+    pub fn unban_node(&mut self, remote_id: H512) {
+        self.banned.retain(|id| *id != remote_id);
+    }
+
+    /// Forget the nodes at an address and ignore them when neighbours
+    /// tell about them again
+    pub fn ban_ip(&mut self, ip: IpAddr) {
+        self.connected.retain(|node| node.address != ip);
+        self.pingponged.retain(|node| node.address != ip);
+        self.incoming.retain(|node| node.address != ip);
+        if !self.banned_ips.contains(&ip) {
+            self.banned_ips.push(ip);
+        }
+    }
+
+    /// Let neighbours tell about the nodes at a banned address again
+    pub fn unban_ip(&mut self, ip: IpAddr) {
+        self.banned_ips.retain(|banned| *banned != ip);
+    }
+
+    /// Only keep nodes at the addresses the restriction allows, `None`
+    /// for any address. Known nodes it does not allow are forgotten,
+    /// and those neighbours tell about are never pinged.
+    pub fn set_net_restrict(&mut self, net_restrict: Option<NetRestrict>) {
+        self.net_restrict = net_restrict;
+        let restrict = self.net_restrict.clone();
+        let allowed = |node: &DPTNode| restrict.as_ref().map(|restrict| restrict.allows(&node.address)).unwrap_or(true);
+        self.connected.retain(&allowed);
+        self.pingponged.retain(&allowed);
+        self.incoming.retain(&allowed);
+    }
+
+    fn is_allowed(&self, ip: &IpAddr) -> bool {
+        self.net_restrict.as_ref().map(|restrict| restrict.allows(ip)).unwrap_or(true)
+    }
+
+    /// Outstanding FindNeighbours lookups, for debugging discovery
+    pub fn active_lookups(&self) -> Vec<LookupStatus> {
+        self.lookups.iter().map(|lookup| lookup.status.clone()).collect()
     }
 
     /// Get the peer by its id
@@ -192,6 +417,7 @@ impl DPTStream {
                 tcp_port: to.tcp_port,
             },
             expire: self.default_expire(),
+            enr_seq: Some(self.record.seq()),
         };
         let data = rlp::encode(&message).to_vec();
 
@@ -208,6 +434,7 @@ impl DPTStream {
         let message = PongMessage {
             echo, to,
             expire: self.default_expire(),
+            enr_seq: Some(self.record.seq()),
         };
         let data = rlp::encode(&message).to_vec();
 
@@ -267,6 +494,38 @@ impl DPTStream {
 
         Ok(Async::Ready(()))
     }
+
+    fn send_enr_request(&mut self, addr: SocketAddr) -> Poll<(), io::Error> {
+        let typ = 0x05u8;
+        let message = EnrRequestMessage {
+            expire: self.default_expire(),
+        };
+        let data = rlp::encode(&message).to_vec();
+
+        debug!("sending enr request ...");
+        self.stream.start_send(DPTCodecMessage {
+            typ, data, addr
+        })?;
+        self.stream.poll_complete()?;
+
+        Ok(Async::Ready(()))
+    }
+
+    fn send_enr_response(&mut self, addr: SocketAddr, echo: H256) -> Poll<(), io::Error> {
+        let typ = 0x06u8;
+        let message = EnrResponseMessage {
+            echo,
+            record: self.record.clone(),
+        };
+        let data = rlp::encode(&message).to_vec();
+
+        self.stream.start_send(DPTCodecMessage {
+            typ, data, addr
+        })?;
+        self.stream.poll_complete()?;
+
+        Ok(Async::Ready(()))
+    }
 }
 
 impl Stream for DPTStream {
@@ -280,6 +539,8 @@ impl Stream for DPTStream {
             }
             self.bootstrapped = true;
         }
+        // Sends are buffered while the socket is not writable yet
+        self.stream.poll_complete()?;
 
         let mut timeoutted = false;
         if self.timeout.is_some() {
@@ -301,6 +562,15 @@ impl Stream for DPTStream {
             self.timeout = None;
         }
 
+        self.lookups.retain(|lookup| {
+            if lookup.started.elapsed() < LOOKUP_TIMEOUT {
+                true
+            } else {
+                debug!("lookup for {:x} timed out", lookup.status.target);
+                false
+            }
+        });
+
         loop {
             let (message, remote_id, hash) = match self.stream.poll()? {
                 Async::Ready(Some(Some(val))) => val,
@@ -323,7 +593,13 @@ impl Stream for DPTStream {
                         Err(_) => continue,
                     };
 
-                    self.send_pong(message.addr, hash, ping_message.to)?;
+                    // Tell the sender where we saw its ping come from
+                    let from = Endpoint {
+                        address: message.addr.ip(),
+                        udp_port: message.addr.port(),
+                        tcp_port: ping_message.from.tcp_port,
+                    };
+                    self.send_pong(message.addr, hash, from)?;
 
                     let v = self.connected.iter().find(|v| v.id == remote_id).map(|v| v.clone());
                     if v.is_some() {
@@ -345,6 +621,13 @@ impl Stream for DPTStream {
                             *v != remote_id
                         });
                     }
+                    self.vote_address(remote_id, pong_message.to.address);
+                    if let Some(seq) = pong_message.enr_seq {
+                        let known = self.records.get(&remote_id).map(|record| record.seq() >= seq).unwrap_or(false);
+                        if !known {
+                            self.send_enr_request(message.addr)?;
+                        }
+                    }
 
                     let v = self.connected.iter().find(|v| v.id == remote_id).map(|v| v.clone());
                     if v.is_some() {
@@ -367,6 +650,14 @@ impl Stream for DPTStream {
                             Err(_) => continue,
                         };
                     debug!("neighbouts message len {}", incoming_message.nodes.len());
+                    retain_mut(&mut self.lookups, |lookup| {
+                        if !lookup.status.remaining.contains(&remote_id) {
+                            return true;
+                        }
+                        lookup.status.remaining.retain(|id| *id != remote_id);
+                        lookup.status.rounds += 1;
+                        !lookup.status.remaining.is_empty()
+                    });
                     for node in incoming_message.nodes {
                         let node = DPTNode {
                             address: node.address,
@@ -374,7 +665,8 @@ impl Stream for DPTStream {
                             tcp_port: node.tcp_port,
                             id: node.id,
                     };
-                        if !self.connected.contains(&node) {
+                        if !self.connected.contains(&node) && !self.banned.contains(&node.id)
+                            && !self.banned_ips.contains(&node.address) && self.is_allowed(&node.address) {
                             self.send_ping(node.udp_addr(), node.clone())?;
 
                             debug!("pushing new node {:?}", node);
@@ -384,6 +676,34 @@ impl Stream for DPTStream {
                         }
                     }
                 },
+                0x05 /* enr request */ => {
+                    debug!("got enr request message");
+                    if UntrustedRlp::new(&message.data).as_val::<EnrRequestMessage>().is_err() {
+                        continue;
+                    }
+                    // Only answer nodes that proved their endpoint, so
+                    // that the larger response never goes to a spoofed
+                    // address
+                    if self.pingponged.iter().any(|node| node.id == remote_id) {
+                        self.send_enr_response(message.addr, hash)?;
+                    }
+                },
+                0x06 /* enr response */ => {
+                    debug!("got enr response message");
+                    let response: EnrResponseMessage = match UntrustedRlp::new(&message.data).as_val() {
+                        Ok(val) => val,
+                        Err(_) => continue,
+                    };
+                    if !response.record.verify() || response.record.id() != Some(remote_id) {
+                        debug!("ignoring invalid node record from {:x}", remote_id);
+                        continue;
+                    }
+                    let newer = self.records.get(&remote_id)
+                        .map(|record| record.seq() < response.record.seq()).unwrap_or(true);
+                    if newer && self.connected.iter().any(|node| node.id == remote_id) {
+                        self.records.insert(remote_id, response.record);
+                    }
+                },
                 _ => { }
             }
 
@@ -418,7 +738,17 @@ impl Sink for DPTStream {
                 }
 
                 let addr = self.pingponged[0].udp_addr();
+                let queried = self.pingponged[0].id;
                 self.send_find_neighbours(addr)?;
+                self.lookups.push(Lookup {
+                    status: LookupStatus {
+                        target: self.id,
+                        rounds: 0,
+                        queried: vec![queried],
+                        remaining: vec![queried],
+                    },
+                    started: Instant::now(),
+                });
 
                 return Ok(AsyncSink::Ready);
             },
@@ -441,7 +771,261 @@ impl Sink for DPTStream {
 
 #[cfg(test)]
 mod tests {
+    use super::{DPTStream, DPTNode, DPTMessage};
+    use bigint::H512;
+    use devp2p_common::NetRestrict;
+    use util::pk2id;
+    use tokio_core::reactor::Core;
+    use futures::{future, Async, Poll, Stream, Sink};
+    use secp256k1::{self, SECP256K1, Message, RecoverableSignature};
+    use secp256k1::key::{PublicKey, SecretKey};
+    use bigint::H256;
+    use devp2p_common::NodeSigner;
+    use rand::os::OsRng;
+    use std::io;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::net::{IpAddr, UdpSocket};
+
+    #[test]
+    fn enode_urls() {
+        let id = "e809c4a2fec7daed400e5e28564e23693b23b2cc5a019b612505631bbe7b9ccf709c1796d2a3d29ef2b045f210caf51e3c4f5b6d3587d43ad5d6397526fa6179";
+        let url = format!("enode://{}@174.112.32.157:30303", id);
+        let node: DPTNode = url.parse().unwrap();
+        assert_eq!(node.address, "174.112.32.157".parse::<IpAddr>().unwrap());
+        assert_eq!((node.tcp_port, node.udp_port), (30303, 30303));
+        assert_eq!(format!("{:x}", node.id), id);
+        assert_eq!(node.to_string(), url);
+
+        let url = format!("enode://{}@[::1]:30303?discport=30301", id);
+        let node: DPTNode = url.parse().unwrap();
+        assert_eq!((node.tcp_port, node.udp_port), (30303, 30301));
+        assert_eq!(node.to_string(), url);
+
+        assert!(format!("http://{}@127.0.0.1:30303", id).parse::<DPTNode>().is_err());
+        assert!("enode://00@127.0.0.1:30303".parse::<DPTNode>().is_err());
+        assert!(format!("enode://{}@127.0.0.1", id).parse::<DPTNode>().is_err());
+        assert!(format!("enode://{}@127.0.0.1:30303?discport=x", id).parse::<DPTNode>().is_err());
+    }
+
     #[test]
     fn it_works() {
     }
+
+    fn poll_all(a: &mut DPTStream, b: &mut DPTStream) -> Poll<(), io::Error> {
+        while let Async::Ready(Some(_)) = a.poll()? { }
+        while let Async::Ready(Some(_)) = b.poll()? { }
+        Ok(Async::NotReady)
+    }
+
+    #[test]
+    fn lookup_tracked_until_answered() {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let ip: IpAddr = "127.0.0.1".parse().unwrap();
+        let a_addr = UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let b_addr = UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+
+        let a_key = SecretKey::new(&SECP256K1, &mut OsRng::new().unwrap());
+        let a_id = pk2id(&PublicKey::from_secret_key(&SECP256K1, &a_key).unwrap());
+        let b_key = SecretKey::new(&SECP256K1, &mut OsRng::new().unwrap());
+        let b_id = pk2id(&PublicKey::from_secret_key(&SECP256K1, &b_key).unwrap());
+        let b_node = DPTNode {
+            address: ip, udp_port: b_addr.port(), tcp_port: b_addr.port(), id: b_id,
+        };
+
+        let mut a = DPTStream::new(&a_addr, &handle, Arc::new(a_key), vec![b_node], &ip, a_addr.port()).unwrap();
+        let mut b = DPTStream::new(&b_addr, &handle, Arc::new(b_key), Vec::new(), &ip, b_addr.port()).unwrap();
+
+        core.run(future::poll_fn(|| -> Poll<(), io::Error> {
+            poll_all(&mut a, &mut b)?;
+            if a.connected_peers().is_empty() {
+                Ok(Async::NotReady)
+            } else {
+                a.start_send(DPTMessage::RequestNewPeer)?;
+                Ok(Async::Ready(()))
+            }
+        })).unwrap();
+
+        assert_eq!(a.address_votes, vec![(b_id, ip)]);
+
+        let lookups = a.active_lookups();
+        assert_eq!(lookups.len(), 1);
+        assert_eq!(lookups[0].target, a_id);
+        assert_eq!(lookups[0].rounds, 0);
+        assert_eq!(lookups[0].queried, vec![b_id]);
+        assert_eq!(lookups[0].remaining, vec![b_id]);
+
+        core.run(future::poll_fn(|| -> Poll<(), io::Error> {
+            poll_all(&mut a, &mut b)?;
+            if a.active_lookups().is_empty() {
+                Ok(Async::Ready(()))
+            } else {
+                Ok(Async::NotReady)
+            }
+        })).unwrap();
+    }
+
+    struct CountingSigner {
+        key: SecretKey,
+        sign_calls: AtomicUsize,
+    }
+
+    impl NodeSigner for CountingSigner {
+        fn public_key(&self) -> Result<PublicKey, secp256k1::Error> {
+            self.key.public_key()
+        }
+
+        fn ecdh(&self, public_key: &PublicKey) -> Result<H256, secp256k1::Error> {
+            self.key.ecdh(public_key)
+        }
+
+        fn ecdh_point(&self, public_key: &PublicKey) -> Result<PublicKey, secp256k1::Error> {
+            self.key.ecdh_point(public_key)
+        }
+
+        fn sign(&self, message: &Message) -> Result<RecoverableSignature, secp256k1::Error> {
+            self.sign_calls.fetch_add(1, Ordering::SeqCst);
+            self.key.sign(message)
+        }
+    }
+
+    #[test]
+    fn packets_signed_by_signer() {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let ip: IpAddr = "127.0.0.1".parse().unwrap();
+        let a_addr = UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let b_addr = UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+
+        let signer = Arc::new(CountingSigner {
+            key: SecretKey::new(&SECP256K1, &mut OsRng::new().unwrap()),
+            sign_calls: AtomicUsize::new(0),
+        });
+        let b_key = SecretKey::new(&SECP256K1, &mut OsRng::new().unwrap());
+        let b_node = DPTNode {
+            address: ip, udp_port: b_addr.port(), tcp_port: b_addr.port(),
+            id: pk2id(&PublicKey::from_secret_key(&SECP256K1, &b_key).unwrap()),
+        };
+
+        let mut a = DPTStream::new(&a_addr, &handle, signer.clone(), vec![b_node], &ip, a_addr.port()).unwrap();
+        // The node record is signed on creation
+        assert_eq!(signer.sign_calls.load(Ordering::SeqCst), 1);
+        let mut b = DPTStream::new(&b_addr, &handle, Arc::new(b_key), Vec::new(), &ip, b_addr.port()).unwrap();
+
+        // So are the pings sent to b
+        core.run(future::poll_fn(|| -> Poll<(), io::Error> {
+            poll_all(&mut a, &mut b)?;
+            if a.connected_peers().is_empty() {
+                Ok(Async::NotReady)
+            } else {
+                Ok(Async::Ready(()))
+            }
+        })).unwrap();
+        assert!(signer.sign_calls.load(Ordering::SeqCst) > 1);
+    }
+
+    #[test]
+    fn node_records_exchanged() {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let ip: IpAddr = "127.0.0.1".parse().unwrap();
+        let a_addr = UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let b_addr = UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+
+        let a_key = SecretKey::new(&SECP256K1, &mut OsRng::new().unwrap());
+        let a_id = pk2id(&PublicKey::from_secret_key(&SECP256K1, &a_key).unwrap());
+        let b_key = SecretKey::new(&SECP256K1, &mut OsRng::new().unwrap());
+        let b_id = pk2id(&PublicKey::from_secret_key(&SECP256K1, &b_key).unwrap());
+        let a_node = DPTNode { address: ip, udp_port: a_addr.port(), tcp_port: 0, id: a_id };
+        let b_node = DPTNode { address: ip, udp_port: b_addr.port(), tcp_port: b_addr.port(), id: b_id };
+
+        let mut a = DPTStream::new(&a_addr, &handle, Arc::new(a_key), vec![b_node.clone()], &ip, 0).unwrap();
+        let mut b = DPTStream::new(&b_addr, &handle, Arc::new(b_key), vec![a_node.clone()], &ip, b_addr.port()).unwrap();
+        assert_eq!(a.local_record().node(), Some(a_node));
+
+        core.run(future::poll_fn(|| -> Poll<(), io::Error> {
+            poll_all(&mut a, &mut b)?;
+            if a.node_record(b_id).is_some() && b.node_record(a_id).is_some() {
+                Ok(Async::Ready(()))
+            } else {
+                Ok(Async::NotReady)
+            }
+        })).unwrap();
+        assert_eq!(a.node_record(b_id), Some(b.local_record()));
+        assert_eq!(a.node_record(b_id).unwrap().node(), Some(b_node));
+
+        // A changed endpoint is signed with a higher sequence number
+        let seq = b.local_record().seq();
+        b.set_endpoint(ip, b_addr.port(), 30303);
+        assert_eq!(b.local_record().seq(), seq + 1);
+        assert_eq!(b.local_record().tcp_port(), Some(30303));
+        assert!(b.local_record().verify());
+        b.set_endpoint(ip, b_addr.port(), 30303);
+        assert_eq!(b.local_record().seq(), seq + 1);
+    }
+
+    #[test]
+    fn banned_node_forgotten() {
+        let core = Core::new().unwrap();
+        let ip: IpAddr = "127.0.0.1".parse().unwrap();
+        let addr = UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let node = DPTNode { address: ip, udp_port: 30303, tcp_port: 30303, id: H512::random() };
+        let mut dpt = DPTStream::new(&addr, &core.handle(),
+                                     Arc::new(SecretKey::new(&SECP256K1, &mut OsRng::new().unwrap())),
+                                     vec![node.clone()], &ip, addr.port()).unwrap();
+        assert_eq!(dpt.get_peer(node.id), Some(node.clone()));
+
+        dpt.ban_node(node.id);
+        assert_eq!(dpt.get_peer(node.id), None);
+        assert!(dpt.incoming.is_empty());
+        assert_eq!(dpt.banned, vec![node.id]);
+        dpt.unban_node(node.id);
+        assert!(dpt.banned.is_empty());
+
+        dpt.connected.push(node.clone());
+        dpt.ban_ip(ip);
+        assert_eq!(dpt.get_peer(node.id), None);
+        assert_eq!(dpt.banned_ips, vec![ip]);
+    }
+
+    #[test]
+    fn restricted_nodes_forgotten() {
+        let core = Core::new().unwrap();
+        let ip: IpAddr = "127.0.0.1".parse().unwrap();
+        let addr = UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let node = |address: &str| DPTNode { address: address.parse().unwrap(), udp_port: 30303, tcp_port: 30303, id: H512::random() };
+        let (lab, outside) = (node("10.1.0.1"), node("192.0.2.1"));
+        let mut dpt = DPTStream::new(&addr, &core.handle(),
+                                     Arc::new(SecretKey::new(&SECP256K1, &mut OsRng::new().unwrap())),
+                                     vec![lab.clone(), outside.clone()], &ip, addr.port()).unwrap();
+
+        dpt.set_net_restrict(Some(NetRestrict::parse("10.0.0.0/8", "").unwrap()));
+        assert_eq!(dpt.get_peer(outside.id), None);
+        assert_eq!(dpt.incoming, vec![lab.clone()]);
+        assert!(!dpt.is_allowed(&outside.address));
+        assert!(dpt.is_allowed(&lab.address));
+    }
+
+    #[test]
+    fn address_detected_from_pongs() {
+        let core = Core::new().unwrap();
+        let ip: IpAddr = "127.0.0.1".parse().unwrap();
+        let addr = UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let mut dpt = DPTStream::new(&addr, &core.handle(),
+                                     Arc::new(SecretKey::new(&SECP256K1, &mut OsRng::new().unwrap())),
+                                     Vec::new(), &ip, addr.port()).unwrap();
+        let public: IpAddr = "203.0.113.7".parse().unwrap();
+        let (first, second) = (H512::random(), H512::random());
+
+        dpt.vote_address(first, public);
+        dpt.vote_address(first, public);
+        dpt.vote_address(second, public);
+        assert_eq!(dpt.observed_address(), None);
+        dpt.set_address_detection(true);
+        dpt.vote_address(H512::random(), "198.51.100.1".parse().unwrap());
+        dpt.vote_address(H512::random(), public);
+        assert_eq!(dpt.observed_address(), Some(public));
+        assert_eq!(dpt.address, public);
+    }
 }
@@ -1,6 +1,7 @@
 use rlp::{Encodable, Decodable, DecoderError, UntrustedRlp, RlpStream};
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 use bigint::{H256, H512};
+use enr::Enr;
 
 pub struct Neighbour {
     pub address: IpAddr,
@@ -142,15 +143,20 @@ pub struct PingMessage {
     pub from: Endpoint,
     pub to: Endpoint,
     pub expire: u64,
+    /// Sequence number of the sender's node record, see EIP-868
+    pub enr_seq: Option<u64>,
 }
 
 impl Encodable for PingMessage {
     fn rlp_append(&self, s: &mut RlpStream) {
-        s.begin_list(4);
+        s.begin_list(if self.enr_seq.is_some() { 5 } else { 4 });
         s.append(&4u32); // Version 4
         s.append(&self.from);
         s.append(&self.to);
         s.append(&self.expire);
+        if let Some(enr_seq) = self.enr_seq {
+            s.append(&enr_seq);
+        }
     }
 }
 
@@ -160,6 +166,7 @@ impl Decodable for PingMessage {
             from: rlp.val_at(1)?,
             to: rlp.val_at(2)?,
             expire: rlp.val_at(3)?,
+            enr_seq: if rlp.item_count()? > 4 { Some(rlp.val_at(4)?) } else { None },
         })
     }
 }
@@ -169,14 +176,19 @@ pub struct PongMessage {
     pub to: Endpoint,
     pub echo: H256,
     pub expire: u64,
+    /// Sequence number of the sender's node record, see EIP-868
+    pub enr_seq: Option<u64>,
 }
 
 impl Encodable for PongMessage {
     fn rlp_append(&self, s: &mut RlpStream) {
-        s.begin_list(3);
+        s.begin_list(if self.enr_seq.is_some() { 4 } else { 3 });
         s.append(&self.to);
         s.append(&self.echo);
         s.append(&self.expire);
+        if let Some(enr_seq) = self.enr_seq {
+            s.append(&enr_seq);
+        }
     }
 }
 
@@ -186,6 +198,49 @@ impl Decodable for PongMessage {
             to: rlp.val_at(0)?,
             echo: rlp.val_at(1)?,
             expire: rlp.val_at(2)?,
+            enr_seq: if rlp.item_count()? > 3 { Some(rlp.val_at(3)?) } else { None },
+        })
+    }
+}
+
+pub struct EnrRequestMessage {
+    pub expire: u64,
+}
+
+impl Encodable for EnrRequestMessage {
+    fn rlp_append(&self, s: &mut RlpStream) {
+        s.begin_list(1);
+        s.append(&self.expire);
+    }
+}
+
+impl Decodable for EnrRequestMessage {
+    fn decode(rlp: &UntrustedRlp) -> Result<Self, DecoderError> {
+        Ok(Self {
+            expire: rlp.val_at(0)?,
+        })
+    }
+}
+
+pub struct EnrResponseMessage {
+    /// Hash of the ENRRequest packet answered
+    pub echo: H256,
+    pub record: Enr,
+}
+
+impl Encodable for EnrResponseMessage {
+    fn rlp_append(&self, s: &mut RlpStream) {
+        s.begin_list(2);
+        s.append(&self.echo);
+        s.append(&self.record);
+    }
+}
+
+impl Decodable for EnrResponseMessage {
+    fn decode(rlp: &UntrustedRlp) -> Result<Self, DecoderError> {
+        Ok(Self {
+            echo: rlp.val_at(0)?,
+            record: Enr::decode(rlp.at(1)?.as_raw())?,
         })
     }
 }
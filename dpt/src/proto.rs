@@ -1,10 +1,11 @@
 use util::{keccak256, pk2id, id2pk};
 use std::net::SocketAddr;
-use secp256k1::key::SecretKey;
 use secp256k1::{SECP256K1, RecoverableSignature, Message, RecoveryId};
 use bigint::{H256, H512};
 use tokio_core::net::UdpCodec;
 use std::io;
+use std::sync::Arc;
+use devp2p_common::NodeSigner;
 
 macro_rules! try_none {
     ( $ex:expr ) => {
@@ -16,7 +17,7 @@ macro_rules! try_none {
 }
 
 pub struct DPTCodec {
-    secret_key: SecretKey,
+    signer: Arc<dyn NodeSigner>,
 }
 
 pub struct DPTCodecMessage {
@@ -26,9 +27,9 @@ pub struct DPTCodecMessage {
 }
 
 impl DPTCodec {
-    pub fn new(secret_key: SecretKey) -> Self {
+    pub fn new(signer: Arc<dyn NodeSigner>) -> Self {
         DPTCodec {
-            secret_key
+            signer
         }
     }
 }
@@ -73,7 +74,14 @@ impl UdpCodec for DPTCodec {
 
         let sighash = keccak256(&typdata);
         let message = Message::from_slice(&sighash).unwrap();
-        let rec_sig = &SECP256K1.sign_recoverable(&message, &self.secret_key).unwrap();
+        let rec_sig = match self.signer.sign(&message) {
+            Ok(rec_sig) => rec_sig,
+            Err(e) => {
+                // An empty datagram is dropped by its receiver
+                error!("signing discovery packet failed: {:?}", e);
+                return msg.addr;
+            },
+        };
         let (rec, sig) = rec_sig.serialize_compact(&SECP256K1);
 
         let mut hashdata = Vec::new();
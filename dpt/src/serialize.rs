@@ -0,0 +1,40 @@
+use serde::{Serialize, Serializer, Deserialize, Deserializer};
+use serde::de::Error;
+use bigint::H512;
+use std::net::IpAddr;
+use std::str::FromStr;
+use super::DPTNode;
+
+/// Serialized form of a `DPTNode`, with the node id as a hex string
+#[derive(Serialize, Deserialize)]
+struct NodeRecord {
+    address: IpAddr,
+    tcp_port: u16,
+    udp_port: u16,
+    id: String,
+}
+
+impl Serialize for DPTNode {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        NodeRecord {
+            address: self.address,
+            tcp_port: self.tcp_port,
+            udp_port: self.udp_port,
+            id: format!("{:x}", self.id),
+        }.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for DPTNode {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let record = NodeRecord::deserialize(deserializer)?;
+        let id = H512::from_str(&record.id)
+            .map_err(|_| D::Error::custom("invalid node id"))?;
+        Ok(DPTNode {
+            address: record.address,
+            tcp_port: record.tcp_port,
+            udp_port: record.udp_port,
+            id,
+        })
+    }
+}
@@ -0,0 +1,127 @@
+use rlp::{DecoderError, UntrustedRlp, RlpStream};
+use std::net::IpAddr;
+use enr::Enr;
+
+/// Longest request id allowed
+const MAX_REQUEST_ID_SIZE: usize = 8;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// Message carried encrypted in a packet
+pub enum Message {
+    Ping { request_id: Vec<u8>, enr_seq: u64 },
+    /// Answer to a ping, telling where it came from
+    Pong { request_id: Vec<u8>, enr_seq: u64, address: IpAddr, port: u16 },
+    /// Request for the records of nodes at log distances from the
+    /// recipient, where distance 0 is its own record
+    FindNode { request_id: Vec<u8>, distances: Vec<u16> },
+    /// One of `total` answers to a FindNode
+    Nodes { request_id: Vec<u8>, total: u64, records: Vec<Enr> },
+}
+
+impl Message {
+    /// Id matching requests with their answers
+    pub fn request_id(&self) -> &[u8] {
+        match *self {
+            Message::Ping { ref request_id, .. } |
+            Message::Pong { ref request_id, .. } |
+            Message::FindNode { ref request_id, .. } |
+            Message::Nodes { ref request_id, .. } => request_id,
+        }
+    }
+
+    /// Message type followed by its RLP encoded fields
+    pub fn encode(&self) -> Vec<u8> {
+        let (typ, data) = match *self {
+            Message::Ping { ref request_id, enr_seq } => {
+                let mut s = RlpStream::new_list(2);
+                s.append(request_id);
+                s.append(&enr_seq);
+                (0x01u8, s.out())
+            },
+            Message::Pong { ref request_id, enr_seq, address, port } => {
+                let address: Vec<u8> = match address {
+                    IpAddr::V4(v) => v.octets().as_ref().into(),
+                    IpAddr::V6(v) => v.octets().as_ref().into(),
+                };
+                let mut s = RlpStream::new_list(4);
+                s.append(request_id);
+                s.append(&enr_seq);
+                s.append(&address);
+                s.append(&port);
+                (0x02u8, s.out())
+            },
+            Message::FindNode { ref request_id, ref distances } => {
+                let mut s = RlpStream::new_list(2);
+                s.append(request_id);
+                s.append_list(distances);
+                (0x03u8, s.out())
+            },
+            Message::Nodes { ref request_id, total, ref records } => {
+                let mut s = RlpStream::new_list(3);
+                s.append(request_id);
+                s.append(&total);
+                s.append_list(records);
+                (0x04u8, s.out())
+            },
+        };
+        let mut out = vec![typ];
+        out.extend_from_slice(&data);
+        out
+    }
+
+    /// Decode a message, without verifying the records it carries
+    pub fn decode(data: &[u8]) -> Result<Message, DecoderError> {
+        if data.is_empty() {
+            return Err(DecoderError::RlpIsTooShort);
+        }
+        let rlp = UntrustedRlp::new(&data[1..]);
+        let request_id: Vec<u8> = rlp.val_at(0)?;
+        if request_id.len() > MAX_REQUEST_ID_SIZE {
+            return Err(DecoderError::Custom("request id too long"));
+        }
+        match data[0] {
+            0x01 => Ok(Message::Ping { request_id, enr_seq: rlp.val_at(1)? }),
+            0x02 => {
+                let address: Vec<u8> = rlp.val_at(2)?;
+                let address = if address.len() == 4 {
+                    IpAddr::from([address[0], address[1], address[2], address[3]])
+                } else if address.len() == 16 {
+                    let mut raw = [0u8; 16];
+                    raw.copy_from_slice(&address);
+                    IpAddr::from(raw)
+                } else {
+                    return Err(DecoderError::Custom("wrong address length"));
+                };
+                Ok(Message::Pong { request_id, enr_seq: rlp.val_at(1)?, address, port: rlp.val_at(3)? })
+            },
+            0x03 => Ok(Message::FindNode { request_id, distances: rlp.list_at(1)? }),
+            0x04 => Ok(Message::Nodes { request_id, total: rlp.val_at(1)?, records: rlp.list_at(2)? }),
+            _ => Err(DecoderError::Custom("unknown message type")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Message;
+    use enr::Enr;
+    use secp256k1::SECP256K1;
+    use secp256k1::key::SecretKey;
+    use rand::os::OsRng;
+
+    #[test]
+    fn messages_roundtrip() {
+        let key = SecretKey::new(&SECP256K1, &mut OsRng::new().unwrap());
+        let record = Enr::new(&key, 3, "10.0.0.1".parse().unwrap(), 30303, 30303).unwrap();
+        let messages = vec![
+            Message::Pong { request_id: vec![7], enr_seq: 1, address: "::1".parse().unwrap(), port: 30303 },
+            Message::FindNode { request_id: vec![1, 2], distances: vec![256, 255, 0] },
+            Message::Nodes { request_id: vec![], total: 2, records: vec![record] },
+        ];
+        for message in messages {
+            assert_eq!(Message::decode(&message.encode()).unwrap(), message);
+        }
+        assert!(Message::decode(&Message::Ping { request_id: vec![0; 9], enr_seq: 1 }.encode()).is_err());
+        assert!(Message::decode(&[0x09, 0xc1, 0x80]).is_err());
+    }
+}
@@ -0,0 +1,941 @@
+//! Discovery v5, finding nodes over encrypted sessions with the
+//! packet format of the discv5 wire protocol. Only node lookups are
+//! supported, not topics.
+
+mod packet;
+mod message;
+mod session;
+
+use self::packet::{AuthData, Header, authenticated_data};
+use self::message::Message;
+use self::session::{Session, ecdh, derive_keys, sign_id, verify_id, encrypt, decrypt};
+use super::{DPTNode, DPTMessage, Lookup, LookupStatus, LOOKUP_TIMEOUT,
+            ADDRESS_DETECTION_VOTES, ADDRESS_DETECTION_WINDOW};
+use enr::Enr;
+use futures::{Poll, Async, StartSend, AsyncSink, Future, Stream, Sink};
+use tokio_core::reactor::{Timeout, Handle};
+use tokio_core::net::{UdpSocket, UdpFramed, UdpCodec};
+use std::net::{IpAddr, SocketAddr};
+use std::collections::HashMap;
+use std::io;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use bigint::{H256, H512};
+use secp256k1::SECP256K1;
+use secp256k1::key::{PublicKey, SecretKey};
+use util::{keccak256, pk2id, id2pk};
+use rand::{Rng, thread_rng};
+use time;
+use devp2p_common::{NetRestrict, NodeSigner};
+
+/// Time a request waits for its answer, and a WHOAREYOU for the
+/// handshake answering it
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// Number of nodes a lookup queries at the same time
+const LOOKUP_PARALLELISM: usize = 3;
+/// Number of nodes a lookup queries before it ends
+const LOOKUP_MAX_QUERIED: usize = 16;
+/// Number of records a FindNode is answered with at most
+const MAX_FOUND_RECORDS: usize = 16;
+/// Number of records in one Nodes message, which keeps the largest
+/// records within a packet
+const RECORDS_PER_MESSAGE: usize = 3;
+/// Number of Nodes messages accepted for a single FindNode
+const MAX_NODES_MESSAGES: u64 = 6;
+
+struct Discv5Codec;
+
+impl UdpCodec for Discv5Codec {
+    type In = (SocketAddr, Vec<u8>);
+    type Out = (SocketAddr, Vec<u8>);
+
+    fn decode(&mut self, src: &SocketAddr, buf: &[u8]) -> Result<Self::In, io::Error> {
+        Ok((*src, buf.to_vec()))
+    }
+
+    fn encode(&mut self, (addr, data): Self::Out, buf: &mut Vec<u8>) -> SocketAddr {
+        buf.extend_from_slice(&data);
+        addr
+    }
+}
+
+/// Request waiting for its answer. It is sent again in a handshake
+/// if the node challenges it.
+struct Request {
+    addr: SocketAddr,
+    id: H512,
+    message: Message,
+    nonce: [u8; 12],
+    sent: Instant,
+    /// Nodes messages received, for a FindNode
+    responses: u64,
+}
+
+/// WHOAREYOU sent to a node, waiting for its handshake
+struct Challenge {
+    data: Vec<u8>,
+    sent: Instant,
+}
+
+/// Node id of discovery v5, the hash of the public key
+fn node_id(id: &H512) -> H256 {
+    keccak256(id)
+}
+
+/// Bit length of the xor of two node ids
+fn log_distance(a: &H256, b: &H256) -> u16 {
+    for i in 0..32 {
+        let x = a[i] ^ b[i];
+        if x != 0 {
+            return (256 - i * 8 - x.leading_zeros() as usize) as u16;
+        }
+    }
+    0
+}
+
+fn xor_distance(a: &H256, b: &H256) -> Vec<u8> {
+    (0..32).map(|i| a[i] ^ b[i]).collect()
+}
+
+/// Distances asked from a node for a lookup, the one of the target
+/// and its neighbours
+fn lookup_distances(target: &H256, node: &H256) -> Vec<u16> {
+    let distance = log_distance(target, node);
+    let mut distances = vec![distance];
+    if distance < 256 {
+        distances.push(distance + 1);
+    }
+    if distance > 1 {
+        distances.push(distance - 1);
+    }
+    distances
+}
+
+fn new_nonce() -> [u8; 12] {
+    let mut nonce = [0u8; 12];
+    thread_rng().fill_bytes(&mut nonce);
+    nonce
+}
+
+fn new_masking_iv() -> [u8; 16] {
+    let mut iv = [0u8; 16];
+    thread_rng().fill_bytes(&mut iv);
+    iv
+}
+
+fn new_request_id() -> Vec<u8> {
+    let mut id = vec![0u8; 8];
+    thread_rng().fill_bytes(&mut id);
+    id
+}
+
+/// Discovery v5 stream for sending DPT messages or receiving new
+/// peers, a drop-in alternative to `DPTStream`
+pub struct Discv5Stream {
+    stream: UdpFramed<Discv5Codec>,
+    id: H512,
+    node_id: H256,
+    signer: Arc<dyn NodeSigner>,
+    record: Enr,
+    records: HashMap<H512, Enr>,
+    sessions: HashMap<H256, Session>,
+    requests: Vec<Request>,
+    challenges: HashMap<H256, Challenge>,
+    connected: Vec<DPTNode>,
+    pingponged: Vec<DPTNode>,
+    bootstrapped: bool,
+    timeout: Option<(Timeout, Vec<H512>)>,
+    lookups: Vec<Lookup>,
+    incoming: Vec<DPTNode>,
+    banned: Vec<H512>,
+    banned_ips: Vec<IpAddr>,
+    net_restrict: Option<NetRestrict>,
+    detect_address: bool,
+    address_votes: Vec<(H512, IpAddr)>,
+    address: IpAddr,
+    udp_port: u16,
+    tcp_port: u16,
+}
+
+impl Discv5Stream {
+    /// Create a new discovery v5 stream
+    pub fn new(addr: &SocketAddr, handle: &Handle,
+               signer: Arc<dyn NodeSigner>,
+               bootstrap_nodes: Vec<DPTNode>,
+               public_address: &IpAddr, tcp_port: u16) -> Result<Self, io::Error> {
+        let id = pk2id(&signer.public_key()
+                       .map_err(|_| io::Error::new(io::ErrorKind::Other, "converting pub key failed"))?);
+        debug!("self id: {:x}", id);
+        // Starting the sequence at the current time keeps it increasing
+        // across restarts
+        let seq = time::now_utc().to_timespec().sec as u64;
+        let record = Enr::new(&*signer, seq, *public_address, tcp_port, addr.port())?;
+        Ok(Self {
+            stream: UdpSocket::bind(addr, handle)?.framed(Discv5Codec),
+            id, node_id: node_id(&id),
+            signer, record,
+            records: HashMap::new(),
+            sessions: HashMap::new(),
+            requests: Vec::new(),
+            challenges: HashMap::new(),
+            connected: bootstrap_nodes.clone(), incoming: bootstrap_nodes,
+            pingponged: Vec::new(),
+            bootstrapped: false,
+            timeout: None,
+            lookups: Vec::new(),
+            banned: Vec::new(),
+            banned_ips: Vec::new(),
+            net_restrict: None,
+            detect_address: false,
+            address_votes: Vec::new(),
+            address: *public_address, udp_port: addr.port(), tcp_port,
+        })
+    }
+
+    /// Change the address and ports we announce in our node record,
+    /// like when they are mapped on a NAT gateway
+    pub fn set_endpoint(&mut self, address: IpAddr, udp_port: u16, tcp_port: u16) {
+        self.address = address;
+        self.udp_port = udp_port;
+        self.tcp_port = tcp_port;
+        self.update_record();
+    }
+
+    /// Our node record, as sent in handshakes and to nodes asking for
+    /// it
+    pub fn local_record(&self) -> &Enr {
+        &self.record
+    }
+
+    /// Latest verified record of a node
+    pub fn node_record(&self, remote_id: H512) -> Option<&Enr> {
+        self.records.get(&remote_id)
+    }
+
+    /// Sign a new record with a higher sequence number if our endpoint
+    /// changed
+    fn update_record(&mut self) {
+        if self.record.address() == Some(self.address) &&
+            self.record.tcp_port().unwrap_or(0) == self.tcp_port &&
+            self.record.udp_port().unwrap_or(0) == self.udp_port
+        {
+            return;
+        }
+        match Enr::new(&*self.signer, self.record.seq() + 1, self.address, self.tcp_port, self.udp_port) {
+            Ok(record) => self.record = record,
+            Err(e) => error!("signing node record failed: {}", e),
+        }
+    }
+
+    /// Learn our public address from the pongs of other nodes, and
+    /// announce it once enough of them agree
+    pub fn set_address_detection(&mut self, detect: bool) {
+        self.detect_address = detect;
+    }
+
+    /// Public address most recent pongs agree on, if enough of them do
+    pub fn observed_address(&self) -> Option<IpAddr> {
+        let mut best: Option<(IpAddr, usize)> = None;
+        for &(_, address) in &self.address_votes {
+            let votes = self.address_votes.iter().filter(|&&(_, vote)| vote == address).count();
+            if best.map(|(_, most)| votes > most).unwrap_or(true) {
+                best = Some((address, votes));
+            }
+        }
+        best.and_then(|(address, votes)| if votes >= ADDRESS_DETECTION_VOTES { Some(address) } else { None })
+    }
+
+    fn vote_address(&mut self, remote_id: H512, address: IpAddr) {
+        self.address_votes.retain(|&(id, _)| id != remote_id);
+        self.address_votes.push((remote_id, address));
+        if self.address_votes.len() > ADDRESS_DETECTION_WINDOW {
+            self.address_votes.remove(0);
+        }
+        if let (true, Some(observed)) = (self.detect_address, self.observed_address()) {
+            if observed != self.address {
+                info!("detected public address {}", observed);
+                self.address = observed;
+                self.update_record();
+            }
+        }
+    }
+
+    /// Get all nodes that answered our pings
+    pub fn connected_peers(&self) -> &[DPTNode] {
+        &self.pingponged
+    }
+
+    /// Disconnect from a node, ending our session with it
+    pub fn disconnect_peer(&mut self, remote_id: H512) {
+        self.connected.retain(|node| node.id != remote_id);
+        self.pingponged.retain(|node| node.id != remote_id);
+        self.records.remove(&remote_id);
+        self.sessions.remove(&node_id(&remote_id));
+    }
+
+    /// Forget a node and ignore it when other nodes tell about it again
+    pub fn ban_node(&mut self, remote_id: H512) {
+        self.disconnect_peer(remote_id);
+        self.incoming.retain(|node| node.id != remote_id);
+        if !self.banned.contains(&remote_id) {
+            self.banned.push(remote_id);
+        }
+    }
+
+    /// Let other nodes tell about a banned node again
+    pub fn unban_node(&mut self, remote_id: H512) {
+        self.banned.retain(|id| *id != remote_id);
+    }
+
+    /// Forget the nodes at an address and ignore their packets
+    pub fn ban_ip(&mut self, ip: IpAddr) {
+        let ids: Vec<H512> = self.connected.iter().filter(|node| node.address == ip).map(|node| node.id).collect();
+        for id in ids {
+            self.disconnect_peer(id);
+        }
+        self.incoming.retain(|node| node.address != ip);
+        if !self.banned_ips.contains(&ip) {
+            self.banned_ips.push(ip);
+        }
+    }
+
+    /// Let the nodes at a banned address be found again
+    pub fn unban_ip(&mut self, ip: IpAddr) {
+        self.banned_ips.retain(|banned| *banned != ip);
+    }
+
+    /// See `DPTStream::set_net_restrict`
+    pub fn set_net_restrict(&mut self, net_restrict: Option<NetRestrict>) {
+        self.net_restrict = net_restrict;
+        let restrict = self.net_restrict.clone();
+        let allowed = |node: &DPTNode| restrict.as_ref().map(|restrict| restrict.allows(&node.address)).unwrap_or(true);
+        self.connected.retain(&allowed);
+        self.pingponged.retain(&allowed);
+        self.incoming.retain(&allowed);
+    }
+
+    fn is_allowed(&self, ip: &IpAddr) -> bool {
+        self.net_restrict.as_ref().map(|restrict| restrict.allows(ip)).unwrap_or(true)
+    }
+
+    /// Outstanding FindNode lookups, for debugging discovery
+    pub fn active_lookups(&self) -> Vec<LookupStatus> {
+        self.lookups.iter().map(|lookup| lookup.status.clone()).collect()
+    }
+
+    /// Get the peer by its id
+    pub fn get_peer(&self, remote_id: H512) -> Option<DPTNode> {
+        self.connected.iter().find(|node| node.id == remote_id).cloned()
+    }
+
+    /// Public key id of a node we know the node id of
+    fn known_id(&self, remote_node_id: &H256) -> Option<H512> {
+        self.connected.iter().map(|node| node.id)
+            .chain(self.records.keys().cloned())
+            .find(|id| node_id(id) == *remote_node_id)
+    }
+
+    /// Remember a verified record, telling about a new node unless we
+    /// knew it already
+    fn add_record(&mut self, record: Enr) -> Option<DPTNode> {
+        let node = record.node()?;
+        if node.id == self.id || self.banned.contains(&node.id) || self.banned_ips.contains(&node.address)
+            || !self.is_allowed(&node.address)
+        {
+            return None;
+        }
+        if self.records.get(&node.id).map(|known| known.seq() >= record.seq()).unwrap_or(false) {
+            return None;
+        }
+        self.records.insert(node.id, record);
+
+        if let Some(known) = self.connected.iter_mut().find(|known| known.id == node.id) {
+            *known = node;
+            return None;
+        }
+        debug!("pushing new node {:?}", node);
+        self.connected.push(node.clone());
+        // Nodes without RLPx, like those of other networks sharing
+        // discovery v5, are not worth dialing
+        if node.tcp_port != 0 {
+            self.incoming.push(node.clone());
+        }
+        Some(node)
+    }
+
+    fn send_packet(&mut self, addr: SocketAddr, data: Vec<u8>) -> Result<(), io::Error> {
+        self.stream.start_send((addr, data))?;
+        self.stream.poll_complete()?;
+        Ok(())
+    }
+
+    /// Send a message in our session with a node. Without a session
+    /// random data is sent instead, which the node challenges with a
+    /// WHOAREYOU, and the message goes in the handshake answering it.
+    fn send_message(&mut self, addr: SocketAddr, remote_id: H512, message: Message) -> Result<(), io::Error> {
+        let dest_id = node_id(&remote_id);
+        let nonce = new_nonce();
+        let header = Header { nonce, auth: AuthData::Message { src_id: self.node_id } }.encode();
+        let masking_iv = new_masking_iv();
+        let encrypted = match self.sessions.get(&dest_id) {
+            Some(session) => encrypt(&session.write_key, &nonce, &message.encode(),
+                                     &authenticated_data(&masking_iv, &header)),
+            None => {
+                let mut random = vec![0u8; 20];
+                thread_rng().fill_bytes(&mut random);
+                random
+            },
+        };
+        match message {
+            Message::Ping { .. } | Message::FindNode { .. } => {
+                self.requests.push(Request {
+                    addr, id: remote_id, message, nonce,
+                    sent: Instant::now(),
+                    responses: 0,
+                });
+            },
+            _ => (),
+        }
+        self.send_packet(addr, packet::encode(&dest_id, &masking_iv, &header, &encrypted))
+    }
+
+    fn send_ping(&mut self, node: &DPTNode) -> Result<(), io::Error> {
+        let message = Message::Ping { request_id: new_request_id(), enr_seq: self.record.seq() };
+        self.send_message(node.udp_addr(), node.id, message)
+    }
+
+    fn send_find_node(&mut self, addr: SocketAddr, remote_id: H512, distances: Vec<u16>) -> Result<(), io::Error> {
+        let message = Message::FindNode { request_id: new_request_id(), distances };
+        self.send_message(addr, remote_id, message)
+    }
+
+    /// Challenge a node whose message we could not decrypt
+    fn send_whoareyou(&mut self, addr: SocketAddr, src_id: H256, nonce: [u8; 12]) -> Result<(), io::Error> {
+        let mut id_nonce = [0u8; 16];
+        thread_rng().fill_bytes(&mut id_nonce);
+        // A known record is not sent again in the handshake
+        let enr_seq = self.known_id(&src_id)
+            .and_then(|id| self.records.get(&id))
+            .map(|record| record.seq()).unwrap_or(0);
+        let header = Header { nonce, auth: AuthData::WhoAreYou { id_nonce, enr_seq } }.encode();
+        let masking_iv = new_masking_iv();
+
+        debug!("sending whoareyou ...");
+        self.challenges.insert(src_id, Challenge {
+            data: authenticated_data(&masking_iv, &header),
+            sent: Instant::now(),
+        });
+        self.send_packet(addr, packet::encode(&src_id, &masking_iv, &header, &[]))
+    }
+
+    /// Answer the challenge of a node to a request we sent, with a
+    /// handshake establishing our session and carrying the request
+    fn send_handshake(&mut self, nonce: [u8; 12], enr_seq: u64, challenge_data: &[u8]) -> Result<(), io::Error> {
+        let index = match self.requests.iter().position(|request| request.nonce == nonce) {
+            Some(index) => index,
+            None => return Ok(()),
+        };
+        let mut request = self.requests.remove(index);
+        let remote_key = match id2pk(request.id) {
+            Ok(key) => key,
+            Err(_) => return Ok(()),
+        };
+        let dest_id = node_id(&request.id);
+
+        let ephemeral_secret = SecretKey::new(&SECP256K1, &mut thread_rng());
+        let ephemeral_key = PublicKey::from_secret_key(&SECP256K1, &ephemeral_secret)
+            .map_err(|_| io::Error::new(io::ErrorKind::Other, "converting pub key failed"))?
+            .serialize_vec(&SECP256K1, true).to_vec();
+        let shared = ecdh(&remote_key, &ephemeral_secret)?;
+        let session = Session::initiator(derive_keys(&shared, challenge_data, &self.node_id, &dest_id));
+        let id_signature = sign_id(&*self.signer, challenge_data, &ephemeral_key, &dest_id)?;
+
+        let record = if enr_seq < self.record.seq() { Some(self.record.clone()) } else { None };
+        request.nonce = new_nonce();
+        let header = Header {
+            nonce: request.nonce,
+            auth: AuthData::Handshake { src_id: self.node_id, id_signature, ephemeral_key, record },
+        }.encode();
+        let masking_iv = new_masking_iv();
+        let encrypted = encrypt(&session.write_key, &request.nonce, &request.message.encode(),
+                                &authenticated_data(&masking_iv, &header));
+
+        debug!("sending handshake ...");
+        let addr = request.addr;
+        self.sessions.insert(dest_id, session);
+        self.requests.push(request);
+        self.send_packet(addr, packet::encode(&dest_id, &masking_iv, &header, &encrypted))
+    }
+
+    /// Establish the session a node asked for by answering our
+    /// challenge, returning the message of the handshake
+    fn accept_handshake(&mut self, src_id: H256, nonce: [u8; 12], id_signature: &[u8],
+                        ephemeral_key: &[u8], record: Option<Enr>,
+                        packet: &packet::Packet) -> Result<Option<Vec<u8>>, io::Error> {
+        let challenge = match self.challenges.remove(&src_id) {
+            Some(challenge) => challenge,
+            None => return Ok(None),
+        };
+        let remote_id = match record {
+            Some(ref record) => match record.id() {
+                Some(id) if record.verify() && node_id(&id) == src_id => id,
+                _ => {
+                    debug!("ignoring handshake with invalid node record");
+                    return Ok(None);
+                },
+            },
+            None => match self.known_id(&src_id) {
+                Some(id) => id,
+                None => return Ok(None),
+            },
+        };
+        if self.banned.contains(&remote_id) {
+            return Ok(None);
+        }
+        let (remote_key, ephemeral) = match (id2pk(remote_id), PublicKey::from_slice(&SECP256K1, ephemeral_key)) {
+            (Ok(remote_key), Ok(ephemeral)) => (remote_key, ephemeral),
+            _ => return Ok(None),
+        };
+        if !verify_id(&remote_key, id_signature, &challenge.data, ephemeral_key, &self.node_id) {
+            debug!("ignoring handshake with invalid id signature from {:x}", remote_id);
+            return Ok(None);
+        }
+
+        let shared = ecdh(&ephemeral, &*self.signer)?;
+        let session = Session::recipient(derive_keys(&shared, &challenge.data, &src_id, &self.node_id));
+        let plain = match decrypt(&session.read_key, &nonce, &packet.message, &packet.authenticated) {
+            Some(plain) => plain,
+            None => return Ok(None),
+        };
+        self.sessions.insert(src_id, session);
+        if let Some(record) = record {
+            self.add_record(record);
+        }
+        Ok(Some(plain))
+    }
+
+    fn handle_packet(&mut self, addr: SocketAddr, data: &[u8]) -> Result<(), io::Error> {
+        if self.banned_ips.contains(&addr.ip()) {
+            return Ok(());
+        }
+        let packet = match packet::decode(&self.node_id, data) {
+            Some(packet) => packet,
+            None => return Ok(()),
+        };
+        let nonce = packet.header.nonce;
+        match packet.header.auth {
+            AuthData::Message { src_id } => {
+                let plain = self.sessions.get(&src_id).and_then(|session| {
+                    decrypt(&session.read_key, &nonce, &packet.message, &packet.authenticated)
+                });
+                match plain {
+                    Some(plain) => self.handle_message(addr, src_id, &plain),
+                    None => self.send_whoareyou(addr, src_id, nonce),
+                }
+            },
+            AuthData::WhoAreYou { enr_seq, .. } => {
+                debug!("got whoareyou");
+                self.send_handshake(nonce, enr_seq, &packet.authenticated)
+            },
+            AuthData::Handshake { src_id, ref id_signature, ref ephemeral_key, ref record } => {
+                debug!("got handshake");
+                match self.accept_handshake(src_id, nonce, id_signature, ephemeral_key, record.clone(), &packet)? {
+                    Some(plain) => self.handle_message(addr, src_id, &plain),
+                    None => Ok(()),
+                }
+            },
+        }
+    }
+
+    fn handle_message(&mut self, addr: SocketAddr, src_id: H256, plain: &[u8]) -> Result<(), io::Error> {
+        let remote_id = match self.known_id(&src_id) {
+            Some(id) => id,
+            None => return Ok(()),
+        };
+        let message = match Message::decode(plain) {
+            Ok(message) => message,
+            Err(_) => return Ok(()),
+        };
+        let request = self.requests.iter().position(|request| {
+            request.id == remote_id && request.message.request_id() == message.request_id()
+        });
+
+        match message {
+            Message::Ping { request_id, enr_seq } => {
+                debug!("got ping message");
+                let pong = Message::Pong {
+                    request_id,
+                    enr_seq: self.record.seq(),
+                    address: addr.ip(),
+                    port: addr.port(),
+                };
+                self.send_message(addr, remote_id, pong)?;
+                self.check_record(addr, remote_id, enr_seq)?;
+            },
+            Message::Pong { enr_seq, address, .. } => {
+                debug!("got pong message");
+                match request {
+                    Some(index) => { self.requests.remove(index); },
+                    None => return Ok(()),
+                }
+                if let Some((_, ref mut waiting)) = self.timeout {
+                    waiting.retain(|id| *id != remote_id);
+                }
+                self.vote_address(remote_id, address);
+                if let Some(node) = self.get_peer(remote_id) {
+                    if !self.pingponged.contains(&node) {
+                        debug!("pushing pingponged: {:?}", node);
+                        self.pingponged.push(node);
+                    }
+                }
+                self.check_record(addr, remote_id, enr_seq)?;
+            },
+            Message::FindNode { request_id, distances } => {
+                debug!("got find node message");
+                self.send_nodes(addr, remote_id, request_id, &distances)?;
+            },
+            Message::Nodes { total, records, .. } => {
+                debug!("got nodes message");
+                let index = match request {
+                    Some(index) => index,
+                    None => return Ok(()),
+                };
+                let distances = match self.requests[index].message {
+                    Message::FindNode { ref distances, .. } => distances.clone(),
+                    _ => return Ok(()),
+                };
+                self.requests[index].responses += 1;
+                let done = self.requests[index].responses >= total.min(MAX_NODES_MESSAGES);
+                if done {
+                    self.requests.remove(index);
+                }
+
+                for record in records {
+                    // Only records at the distances asked for are
+                    // accepted, so that nodes cannot flood the table
+                    let valid = record.verify() && record.id().map(|id| {
+                        distances.contains(&log_distance(&node_id(&id), &src_id))
+                    }).unwrap_or(false);
+                    if !valid {
+                        debug!("ignoring invalid node record from {:x}", remote_id);
+                        continue;
+                    }
+                    if let Some(node) = self.add_record(record) {
+                        self.send_ping(&node)?;
+                    }
+                }
+                if done {
+                    self.finish_query(remote_id, true)?;
+                }
+            },
+        }
+        Ok(())
+    }
+
+    /// Ask a node for its record if it announced a newer one
+    fn check_record(&mut self, addr: SocketAddr, remote_id: H512, enr_seq: u64) -> Result<(), io::Error> {
+        let known = self.records.get(&remote_id).map(|record| record.seq() >= enr_seq).unwrap_or(false);
+        if known {
+            return Ok(());
+        }
+        self.send_find_node(addr, remote_id, vec![0])
+    }
+
+    /// Answer a FindNode with the records of the nodes we know at the
+    /// distances asked for
+    fn send_nodes(&mut self, addr: SocketAddr, remote_id: H512,
+                  request_id: Vec<u8>, distances: &[u16]) -> Result<(), io::Error> {
+        let mut records = Vec::new();
+        if distances.contains(&0) {
+            records.push(self.record.clone());
+        }
+        for (id, record) in &self.records {
+            if records.len() >= MAX_FOUND_RECORDS {
+                break;
+            }
+            if *id != remote_id && distances.contains(&log_distance(&node_id(id), &self.node_id)) {
+                records.push(record.clone());
+            }
+        }
+
+        let chunks: Vec<Vec<Enr>> = if records.is_empty() {
+            vec![Vec::new()]
+        } else {
+            records.chunks(RECORDS_PER_MESSAGE).map(|chunk| chunk.to_vec()).collect()
+        };
+        let total = chunks.len() as u64;
+        for records in chunks {
+            let message = Message::Nodes { request_id: request_id.clone(), total, records };
+            self.send_message(addr, remote_id, message)?;
+        }
+        Ok(())
+    }
+
+    /// Start a lookup of the nodes closest to a random target
+    fn start_lookup(&mut self) -> Result<(), io::Error> {
+        self.lookups.push(Lookup {
+            status: LookupStatus {
+                target: H512::random(),
+                rounds: 0,
+                queried: Vec::new(),
+                remaining: Vec::new(),
+            },
+            started: Instant::now(),
+        });
+        let index = self.lookups.len() - 1;
+        self.query_lookup(index)?;
+        if self.lookups[index].status.remaining.is_empty() {
+            self.lookups.remove(index);
+        }
+        Ok(())
+    }
+
+    /// Query the closest nodes to the target of a lookup that it did
+    /// not query yet
+    fn query_lookup(&mut self, index: usize) -> Result<(), io::Error> {
+        let target = node_id(&self.lookups[index].status.target);
+        let mut candidates: Vec<(Vec<u8>, DPTNode)> = {
+            let status = &self.lookups[index].status;
+            self.connected.iter()
+                .filter(|node| !status.queried.contains(&node.id))
+                .map(|node| (xor_distance(&target, &node_id(&node.id)), node.clone()))
+                .collect()
+        };
+        candidates.sort_by(|a, b| a.0.cmp(&b.0));
+
+        for (_, node) in candidates {
+            {
+                let status = &mut self.lookups[index].status;
+                if status.remaining.len() >= LOOKUP_PARALLELISM || status.queried.len() >= LOOKUP_MAX_QUERIED {
+                    break;
+                }
+                status.queried.push(node.id);
+                status.remaining.push(node.id);
+            }
+            let distances = lookup_distances(&target, &node_id(&node.id));
+            self.send_find_node(node.udp_addr(), node.id, distances)?;
+        }
+        Ok(())
+    }
+
+    /// Continue the lookups waiting for a node, which answered or
+    /// timed out, and end those with nothing left to query
+    fn finish_query(&mut self, remote_id: H512, answered: bool) -> Result<(), io::Error> {
+        let mut index = 0;
+        while index < self.lookups.len() {
+            if self.lookups[index].status.remaining.contains(&remote_id) {
+                {
+                    let status = &mut self.lookups[index].status;
+                    status.remaining.retain(|id| *id != remote_id);
+                    if answered {
+                        status.rounds += 1;
+                    }
+                }
+                self.query_lookup(index)?;
+                if self.lookups[index].status.remaining.is_empty() {
+                    debug!("lookup for {:x} done", self.lookups[index].status.target);
+                    self.lookups.remove(index);
+                    continue;
+                }
+            }
+            index += 1;
+        }
+        Ok(())
+    }
+}
+
+impl Stream for Discv5Stream {
+    type Item = DPTNode;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        if !self.bootstrapped {
+            for node in self.connected.clone() {
+                self.send_ping(&node)?;
+            }
+            self.bootstrapped = true;
+        }
+        // Sends are buffered while the socket is not writable yet
+        self.stream.poll_complete()?;
+
+        let timeoutted = match self.timeout {
+            Some((ref mut timeout, _)) => timeout.poll()?.is_ready(),
+            None => false,
+        };
+        if timeoutted {
+            let (_, waiting) = self.timeout.take().unwrap();
+            debug!("{} endpoints timeoutted", waiting.len());
+            self.connected.retain(|node| !waiting.contains(&node.id));
+        }
+
+        self.lookups.retain(|lookup| {
+            if lookup.started.elapsed() < LOOKUP_TIMEOUT {
+                true
+            } else {
+                debug!("lookup for {:x} timed out", lookup.status.target);
+                false
+            }
+        });
+        self.challenges.retain(|_, challenge| challenge.sent.elapsed() < REQUEST_TIMEOUT);
+        let expired: Vec<H512> = self.requests.iter()
+            .filter(|request| request.sent.elapsed() >= REQUEST_TIMEOUT)
+            .map(|request| request.id).collect();
+        self.requests.retain(|request| request.sent.elapsed() < REQUEST_TIMEOUT);
+        for id in expired {
+            self.finish_query(id, false)?;
+        }
+
+        loop {
+            match self.stream.poll()? {
+                Async::Ready(Some((addr, data))) => self.handle_packet(addr, &data)?,
+                Async::NotReady => {
+                    return Ok(match self.incoming.pop() {
+                        Some(node) => Async::Ready(Some(node)),
+                        None => Async::NotReady,
+                    });
+                },
+                Async::Ready(None) => return Ok(Async::Ready(None)),
+            }
+
+            if let Some(node) = self.incoming.pop() {
+                return Ok(Async::Ready(Some(node)));
+            }
+        }
+    }
+}
+
+impl Sink for Discv5Stream {
+    type SinkItem = DPTMessage;
+    type SinkError = io::Error;
+
+    fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
+        self.stream.poll_complete()
+    }
+
+    fn start_send(&mut self, message: DPTMessage) -> StartSend<Self::SinkItem, Self::SinkError> {
+        match message {
+            DPTMessage::RequestNewPeer => {
+                if self.pingponged.is_empty() {
+                    debug!("no peers available to find node");
+                    for node in self.connected.clone() {
+                        self.send_ping(&node)?;
+                    }
+                } else {
+                    self.start_lookup()?;
+                }
+            },
+            DPTMessage::Ping(timeout) => {
+                let mut waiting = Vec::new();
+                for node in self.connected.clone() {
+                    self.send_ping(&node)?;
+                    waiting.push(node.id);
+                }
+                self.timeout = Some((timeout, waiting));
+            },
+        }
+        Ok(AsyncSink::Ready)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Discv5Stream, log_distance, node_id};
+    use {DPTNode, DPTMessage};
+    use bigint::H256;
+    use util::pk2id;
+    use tokio_core::reactor::Core;
+    use futures::{future, Async, Poll, Stream, Sink};
+    use secp256k1::SECP256K1;
+    use secp256k1::key::{PublicKey, SecretKey};
+    use hexutil::read_hex;
+    use rand::os::OsRng;
+    use std::io;
+    use std::sync::Arc;
+    use std::net::{IpAddr, UdpSocket};
+    use std::str::FromStr;
+
+    #[test]
+    fn node_ids() {
+        // Node key and id of the discv5 wire protocol test vectors
+        let key = read_hex("eef77acb6c6a6eebc5b363a475ac583ec7eccdb42b6481424c60f59aa326547f").unwrap();
+        let key = SecretKey::from_slice(&SECP256K1, &key).unwrap();
+        let id = pk2id(&PublicKey::from_secret_key(&SECP256K1, &key).unwrap());
+        let expected = H256::from_str("aaaa8419e9f49d0083561b48287df592939a8d19947d8c0ef88f2a4856a69fbb").unwrap();
+        assert_eq!(node_id(&id), expected);
+
+        assert_eq!(log_distance(&expected, &expected), 0);
+        assert_eq!(log_distance(&H256::from(1), &H256::from(2)), 2);
+        assert_eq!(log_distance(&expected, &H256::from_str("bbbb9d047f0488c0b5a93c1c3f2d8bafc7c8ff337024a55434a0d0555de64db9").unwrap()), 253);
+    }
+
+    fn stream(core: &Core, bootstrap_nodes: Vec<DPTNode>) -> (Discv5Stream, DPTNode) {
+        let ip: IpAddr = "127.0.0.1".parse().unwrap();
+        let addr = UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let key = SecretKey::new(&SECP256K1, &mut OsRng::new().unwrap());
+        let id = pk2id(&PublicKey::from_secret_key(&SECP256K1, &key).unwrap());
+        let node = DPTNode { address: ip, udp_port: addr.port(), tcp_port: addr.port(), id };
+        (Discv5Stream::new(&addr, &core.handle(), Arc::new(key), bootstrap_nodes, &ip, addr.port()).unwrap(), node)
+    }
+
+    fn poll_all(streams: &mut [&mut Discv5Stream]) -> Poll<(), io::Error> {
+        for stream in streams.iter_mut() {
+            while let Async::Ready(Some(_)) = stream.poll()? { }
+        }
+        Ok(Async::NotReady)
+    }
+
+    #[test]
+    fn nodes_found_over_sessions() {
+        let mut core = Core::new().unwrap();
+        let (mut a, a_node) = stream(&core, Vec::new());
+        let (mut b, b_node) = stream(&core, vec![a_node.clone()]);
+        let (mut c, c_node) = stream(&core, vec![a_node.clone()]);
+
+        // B and C learn about each other from A once they shook hands
+        // with it
+        core.run(future::poll_fn(|| -> Poll<(), io::Error> {
+            poll_all(&mut [&mut a, &mut b, &mut c])?;
+            if a.connected.len() == 2 && b.node_record(a_node.id).is_some() && !b.connected_peers().is_empty() {
+                Ok(Async::Ready(()))
+            } else {
+                Ok(Async::NotReady)
+            }
+        })).unwrap();
+        assert_eq!(b.node_record(a_node.id), Some(a.local_record()));
+        assert_eq!(a.node_record(c_node.id), Some(c.local_record()));
+        assert_eq!(b.observed_address(), None);
+        assert_eq!(b.address_votes, vec![(a_node.id, a_node.address)]);
+
+        // A tells B about C when asked for nodes at its distance
+        let distance = log_distance(&node_id(&c_node.id), &node_id(&a_node.id));
+        b.send_find_node(a_node.udp_addr(), a_node.id, vec![distance]).unwrap();
+        core.run(future::poll_fn(|| -> Poll<(), io::Error> {
+            poll_all(&mut [&mut a, &mut b, &mut c])?;
+            if b.connected_peers().contains(&c_node) {
+                Ok(Async::Ready(()))
+            } else {
+                Ok(Async::NotReady)
+            }
+        })).unwrap();
+        assert_eq!(b.node_record(c_node.id), Some(c.local_record()));
+        assert!(c.get_peer(b_node.id).is_some());
+
+        // Lookups end once the queried nodes answered
+        b.start_send(DPTMessage::RequestNewPeer).unwrap();
+        let lookups = b.active_lookups();
+        assert_eq!(lookups.len(), 1);
+        assert_eq!(lookups[0].queried.len(), 2);
+        core.run(future::poll_fn(|| -> Poll<(), io::Error> {
+            poll_all(&mut [&mut a, &mut b, &mut c])?;
+            if b.active_lookups().is_empty() {
+                Ok(Async::Ready(()))
+            } else {
+                Ok(Async::NotReady)
+            }
+        })).unwrap();
+
+        b.ban_node(a_node.id);
+        assert_eq!(b.get_peer(a_node.id), None);
+        assert!(!b.sessions.contains_key(&node_id(&a_node.id)));
+    }
+}
@@ -0,0 +1,243 @@
+use crypto::aes::{ctr, KeySize};
+use bigint::H256;
+use enr::Enr;
+
+const PROTOCOL_ID: &[u8] = b"discv5";
+const VERSION: &[u8] = &[0x00, 0x01];
+const MASKING_IV_SIZE: usize = 16;
+const STATIC_HEADER_SIZE: usize = 23;
+
+/// Smallest packet accepted, the size of a WHOAREYOU packet
+pub const MIN_PACKET_SIZE: usize = 63;
+/// Largest packet sent or accepted
+pub const MAX_PACKET_SIZE: usize = 1280;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// Authentication data of a packet, telling its kind
+pub enum AuthData {
+    /// Message encrypted with the keys of a session, or random data
+    /// when there is no session yet
+    Message { src_id: H256 },
+    /// Challenge of a node that could not decrypt our message
+    WhoAreYou { id_nonce: [u8; 16], enr_seq: u64 },
+    /// Answer to a challenge, establishing a session. The record is
+    /// left out if the challenge showed the node has a recent one.
+    Handshake {
+        src_id: H256,
+        id_signature: Vec<u8>,
+        ephemeral_key: Vec<u8>,
+        record: Option<Enr>,
+    },
+}
+
+impl AuthData {
+    fn flag(&self) -> u8 {
+        match *self {
+            AuthData::Message { .. } => 0,
+            AuthData::WhoAreYou { .. } => 1,
+            AuthData::Handshake { .. } => 2,
+        }
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        match *self {
+            AuthData::Message { ref src_id } => out.extend_from_slice(src_id),
+            AuthData::WhoAreYou { ref id_nonce, enr_seq } => {
+                out.extend_from_slice(id_nonce);
+                out.extend_from_slice(&u64_be(enr_seq));
+            },
+            AuthData::Handshake { ref src_id, ref id_signature, ref ephemeral_key, ref record } => {
+                out.extend_from_slice(src_id);
+                out.push(id_signature.len() as u8);
+                out.push(ephemeral_key.len() as u8);
+                out.extend_from_slice(id_signature);
+                out.extend_from_slice(ephemeral_key);
+                if let Some(ref record) = *record {
+                    out.extend_from_slice(&record.encode());
+                }
+            },
+        }
+        out
+    }
+
+    fn decode(flag: u8, data: &[u8]) -> Option<AuthData> {
+        match flag {
+            0 if data.len() == 32 => Some(AuthData::Message { src_id: H256::from(data) }),
+            1 if data.len() == 24 => {
+                let mut id_nonce = [0u8; 16];
+                id_nonce.copy_from_slice(&data[..16]);
+                let enr_seq = data[16..].iter().fold(0u64, |seq, byte| (seq << 8) | *byte as u64);
+                Some(AuthData::WhoAreYou { id_nonce, enr_seq })
+            },
+            2 if data.len() >= 34 => {
+                let (signature_size, key_size) = (data[32] as usize, data[33] as usize);
+                let record_start = 34 + signature_size + key_size;
+                if data.len() < record_start {
+                    return None;
+                }
+                let record = if data.len() > record_start {
+                    Some(Enr::decode(&data[record_start..]).ok()?)
+                } else {
+                    None
+                };
+                Some(AuthData::Handshake {
+                    src_id: H256::from(&data[..32]),
+                    id_signature: data[34..34 + signature_size].to_vec(),
+                    ephemeral_key: data[34 + signature_size..record_start].to_vec(),
+                    record,
+                })
+            },
+            _ => None,
+        }
+    }
+}
+
+fn u64_be(value: u64) -> [u8; 8] {
+    let mut out = [0u8; 8];
+    for (i, byte) in out.iter_mut().enumerate() {
+        *byte = (value >> (56 - i * 8)) as u8;
+    }
+    out
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// Unmasked packet header
+pub struct Header {
+    pub nonce: [u8; 12],
+    pub auth: AuthData,
+}
+
+impl Header {
+    /// Static header followed by the authentication data
+    pub fn encode(&self) -> Vec<u8> {
+        let auth = self.auth.encode();
+        let mut out = Vec::with_capacity(STATIC_HEADER_SIZE + auth.len());
+        out.extend_from_slice(PROTOCOL_ID);
+        out.extend_from_slice(VERSION);
+        out.push(self.auth.flag());
+        out.extend_from_slice(&self.nonce);
+        out.push((auth.len() >> 8) as u8);
+        out.push(auth.len() as u8);
+        out.extend_from_slice(&auth);
+        out
+    }
+}
+
+/// Packet received from a node
+pub struct Packet {
+    pub header: Header,
+    /// Masking IV and unmasked header, which encrypted messages
+    /// authenticate and which a WHOAREYOU challenges with
+    pub authenticated: Vec<u8>,
+    /// Encrypted message, empty for WHOAREYOU
+    pub message: Vec<u8>,
+}
+
+/// Masking IV and header, as authenticated by the message
+pub fn authenticated_data(masking_iv: &[u8; 16], header: &[u8]) -> Vec<u8> {
+    let mut out = masking_iv.to_vec();
+    out.extend_from_slice(header);
+    out
+}
+
+/// Packet to a node, masking the header with its id
+pub fn encode(dest_id: &H256, masking_iv: &[u8; 16], header: &[u8], message: &[u8]) -> Vec<u8> {
+    let mut masked = vec![0u8; header.len()];
+    ctr(KeySize::KeySize128, &dest_id[0..16], masking_iv).process(header, &mut masked);
+
+    let mut out = Vec::with_capacity(MASKING_IV_SIZE + header.len() + message.len());
+    out.extend_from_slice(masking_iv);
+    out.extend_from_slice(&masked);
+    out.extend_from_slice(message);
+    out
+}
+
+/// Unmask a packet sent to us, `None` if it is not a valid discv5
+/// packet
+pub fn decode(local_id: &H256, data: &[u8]) -> Option<Packet> {
+    if data.len() < MIN_PACKET_SIZE || data.len() > MAX_PACKET_SIZE {
+        return None;
+    }
+    let mut cipher = ctr(KeySize::KeySize128, &local_id[0..16], &data[..MASKING_IV_SIZE]);
+    let mut header = vec![0u8; STATIC_HEADER_SIZE];
+    cipher.process(&data[MASKING_IV_SIZE..MASKING_IV_SIZE + STATIC_HEADER_SIZE], &mut header);
+    if &header[..6] != PROTOCOL_ID || &header[6..8] != VERSION {
+        return None;
+    }
+
+    let auth_size = ((header[21] as usize) << 8) | header[22] as usize;
+    let header_end = MASKING_IV_SIZE + STATIC_HEADER_SIZE + auth_size;
+    if data.len() < header_end {
+        return None;
+    }
+    let mut auth = vec![0u8; auth_size];
+    cipher.process(&data[MASKING_IV_SIZE + STATIC_HEADER_SIZE..header_end], &mut auth);
+
+    let mut nonce = [0u8; 12];
+    nonce.copy_from_slice(&header[9..21]);
+    let parsed = Header { nonce, auth: AuthData::decode(header[8], &auth)? };
+
+    let mut authenticated = data[..MASKING_IV_SIZE].to_vec();
+    authenticated.extend_from_slice(&header);
+    authenticated.extend_from_slice(&auth);
+    Some(Packet {
+        header: parsed,
+        authenticated,
+        message: data[header_end..].to_vec(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AuthData, Header, encode, decode, authenticated_data};
+    use v5::message::Message;
+    use v5::session::{encrypt, decrypt};
+    use bigint::H256;
+    use hexutil::read_hex;
+    use std::str::FromStr;
+
+    // Vectors of the discv5 wire protocol test vectors
+    fn src_id() -> H256 {
+        H256::from_str("aaaa8419e9f49d0083561b48287df592939a8d19947d8c0ef88f2a4856a69fbb").unwrap()
+    }
+
+    fn dest_id() -> H256 {
+        H256::from_str("bbbb9d047f0488c0b5a93c1c3f2d8bafc7c8ff337024a55434a0d0555de64db9").unwrap()
+    }
+
+    #[test]
+    fn whoareyou_packet() {
+        let header = Header {
+            nonce: [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12],
+            auth: AuthData::WhoAreYou {
+                id_nonce: [1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16],
+                enr_seq: 0,
+            },
+        };
+        let encoded = read_hex("00000000000000000000000000000000088b3d434277464933a1ccc59f5967ad1d6035f15e528627dde75cd68292f9e6c27d6b66c8100a873fcbaed4e16b8d").unwrap();
+        assert_eq!(encode(&dest_id(), &[0u8; 16], &header.encode(), &[]), encoded);
+
+        let packet = decode(&dest_id(), &encoded).unwrap();
+        assert_eq!(packet.header, header);
+        assert_eq!(packet.authenticated, authenticated_data(&[0u8; 16], &header.encode()));
+        assert!(packet.message.is_empty());
+        assert!(decode(&src_id(), &encoded).is_none());
+    }
+
+    #[test]
+    fn ping_packet() {
+        let header = Header { nonce: [0xff; 12], auth: AuthData::Message { src_id: src_id() } };
+        let ping = Message::Ping { request_id: vec![0, 0, 0, 1], enr_seq: 2 };
+        let encoded = read_hex("00000000000000000000000000000000088b3d4342774649325f313964a39e55ea96c005ad52be8c7560413a7008f16c9e6d2f43bbea8814a546b7409ce783d34c4f53245d08dab84102ed931f66d1492acb308fa1c6715b9d139b81acbdcc").unwrap();
+
+        let packet = decode(&dest_id(), &encoded).unwrap();
+        assert_eq!(packet.header, header);
+        let plain = decrypt(&[0u8; 16], &header.nonce, &packet.message, &packet.authenticated).unwrap();
+        assert_eq!(Message::decode(&plain).unwrap(), ping);
+
+        let ad = authenticated_data(&[0u8; 16], &header.encode());
+        let message = encrypt(&[0u8; 16], &header.nonce, &ping.encode(), &ad);
+        assert_eq!(encode(&dest_id(), &[0u8; 16], &header.encode(), &message), encoded);
+    }
+}
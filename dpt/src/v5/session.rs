@@ -0,0 +1,189 @@
+use crypto::aead::{AeadEncryptor, AeadDecryptor};
+use crypto::aes::KeySize;
+use crypto::aes_gcm::AesGcm;
+use crypto::digest::Digest;
+use crypto::hkdf::{hkdf_extract, hkdf_expand};
+use crypto::sha2::Sha256;
+use secp256k1::{SECP256K1, Message, RecoverableSignature, RecoveryId};
+use secp256k1::key::PublicKey;
+use bigint::H256;
+use std::io;
+use devp2p_common::NodeSigner;
+
+const KEY_AGREEMENT_INFO: &[u8] = b"discovery v5 key agreement";
+const ID_SIGNATURE_TEXT: &[u8] = b"discovery v5 identity proof";
+
+/// Size of the authentication tag appended to encrypted messages
+pub const TAG_SIZE: usize = 16;
+
+/// Keys of an established session, named from our side
+#[derive(Debug, Clone)]
+pub struct Session {
+    pub write_key: [u8; 16],
+    pub read_key: [u8; 16],
+}
+
+impl Session {
+    /// Session of the node that answered a WHOAREYOU with a handshake
+    pub fn initiator(keys: ([u8; 16], [u8; 16])) -> Session {
+        Session { write_key: keys.0, read_key: keys.1 }
+    }
+
+    /// Session of the node that sent the WHOAREYOU
+    pub fn recipient(keys: ([u8; 16], [u8; 16])) -> Session {
+        Session { write_key: keys.1, read_key: keys.0 }
+    }
+}
+
+/// Compressed point shared by a public and a secret key
+pub fn ecdh(public_key: &PublicKey, signer: &dyn NodeSigner) -> Result<Vec<u8>, io::Error> {
+    let point = signer.ecdh_point(public_key)
+        .map_err(|_| io::Error::new(io::ErrorKind::Other, "key agreement failed"))?;
+    Ok(point.serialize_vec(&SECP256K1, true).to_vec())
+}
+
+/// Initiator and recipient keys of a session agreed on during a
+/// handshake
+pub fn derive_keys(shared_secret: &[u8], challenge_data: &[u8],
+                   initiator: &H256, recipient: &H256) -> ([u8; 16], [u8; 16]) {
+    let mut info = KEY_AGREEMENT_INFO.to_vec();
+    info.extend_from_slice(initiator);
+    info.extend_from_slice(recipient);
+
+    let mut prk = [0u8; 32];
+    hkdf_extract(Sha256::new(), challenge_data, shared_secret, &mut prk);
+    let mut okm = [0u8; 32];
+    hkdf_expand(Sha256::new(), &prk, &info, &mut okm);
+
+    let (mut initiator_key, mut recipient_key) = ([0u8; 16], [0u8; 16]);
+    initiator_key.copy_from_slice(&okm[..16]);
+    recipient_key.copy_from_slice(&okm[16..]);
+    (initiator_key, recipient_key)
+}
+
+fn id_signature_hash(challenge_data: &[u8], ephemeral_key: &[u8], recipient: &H256) -> Message {
+    let mut hasher = Sha256::new();
+    hasher.input(ID_SIGNATURE_TEXT);
+    hasher.input(challenge_data);
+    hasher.input(ephemeral_key);
+    hasher.input(recipient);
+    let mut hash = [0u8; 32];
+    hasher.result(&mut hash);
+    Message::from_slice(&hash).unwrap()
+}
+
+/// Prove owning our node id to the recipient of a handshake
+pub fn sign_id(signer: &dyn NodeSigner, challenge_data: &[u8],
+               ephemeral_key: &[u8], recipient: &H256) -> Result<Vec<u8>, io::Error> {
+    let message = id_signature_hash(challenge_data, ephemeral_key, recipient);
+    let signature = signer.sign(&message)
+        .map_err(|_| io::Error::new(io::ErrorKind::Other, "signing id nonce failed"))?;
+    Ok(signature.serialize_compact(&SECP256K1).1.to_vec())
+}
+
+/// Whether a handshake proves owning the public key
+pub fn verify_id(public_key: &PublicKey, signature: &[u8], challenge_data: &[u8],
+                 ephemeral_key: &[u8], recipient: &H256) -> bool {
+    if signature.len() != 64 {
+        return false;
+    }
+    let signature = match RecoverableSignature::from_compact(
+        &SECP256K1, signature, RecoveryId::from_i32(0).unwrap()
+    ) {
+        Ok(signature) => signature.to_standard(&SECP256K1),
+        Err(_) => return false,
+    };
+    let message = id_signature_hash(challenge_data, ephemeral_key, recipient);
+    SECP256K1.verify(&message, &signature, public_key).is_ok()
+}
+
+/// Encrypt a message with AES-GCM, appending the tag
+pub fn encrypt(key: &[u8], nonce: &[u8], plain: &[u8], ad: &[u8]) -> Vec<u8> {
+    let mut cipher = AesGcm::new(KeySize::KeySize128, key, nonce, ad);
+    let mut out = vec![0u8; plain.len() + TAG_SIZE];
+    {
+        let (data, tag) = out.split_at_mut(plain.len());
+        cipher.encrypt(plain, data, tag);
+    }
+    out
+}
+
+/// Decrypt and authenticate a message encrypted by `encrypt`
+pub fn decrypt(key: &[u8], nonce: &[u8], encrypted: &[u8], ad: &[u8]) -> Option<Vec<u8>> {
+    if encrypted.len() < TAG_SIZE {
+        return None;
+    }
+    let (data, tag) = encrypted.split_at(encrypted.len() - TAG_SIZE);
+    let mut cipher = AesGcm::new(KeySize::KeySize128, key, nonce, ad);
+    let mut out = vec![0u8; data.len()];
+    if cipher.decrypt(data, &mut out, tag) {
+        Some(out)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ecdh, derive_keys, sign_id, verify_id, encrypt, decrypt};
+    use secp256k1::SECP256K1;
+    use secp256k1::key::{PublicKey, SecretKey};
+    use bigint::H256;
+    use hexutil::read_hex;
+    use std::str::FromStr;
+
+    // Vectors of the discv5 wire protocol test vectors
+    const CHALLENGE_DATA: &str = "000000000000000000000000000000006469736376350001010102030405060708090a0b0c00180102030405060708090a0b0c0d0e0f100000000000000000";
+
+    fn node_id_a() -> H256 {
+        H256::from_str("aaaa8419e9f49d0083561b48287df592939a8d19947d8c0ef88f2a4856a69fbb").unwrap()
+    }
+
+    fn node_id_b() -> H256 {
+        H256::from_str("bbbb9d047f0488c0b5a93c1c3f2d8bafc7c8ff337024a55434a0d0555de64db9").unwrap()
+    }
+
+    fn secret_key() -> SecretKey {
+        let key = read_hex("fb757dc581730490a1d7a00deea65e9b1936924caaea8f44d476014856b68736").unwrap();
+        SecretKey::from_slice(&SECP256K1, &key).unwrap()
+    }
+
+    #[test]
+    fn key_agreement() {
+        let public_key = read_hex("039961e4c2356d61bedb83052c115d311acb3a96f5777296dcf297351130266231").unwrap();
+        let public_key = PublicKey::from_slice(&SECP256K1, &public_key).unwrap();
+        assert_eq!(ecdh(&public_key, &secret_key()).unwrap(),
+                   read_hex("033b11a2a1f214567e1537ce5e509ffd9b21373247f2a3ff6841f4976f53165e7e").unwrap());
+
+        let dest_key = read_hex("0317931e6e0840220642f230037d285d122bc59063221ef3226b1f403ddc69ca91").unwrap();
+        let dest_key = PublicKey::from_slice(&SECP256K1, &dest_key).unwrap();
+        let shared = ecdh(&dest_key, &secret_key()).unwrap();
+        let (initiator, recipient) = derive_keys(&shared, &read_hex(CHALLENGE_DATA).unwrap(),
+                                                 &node_id_a(), &node_id_b());
+        assert_eq!(initiator.to_vec(), read_hex("dccc82d81bd610f4f76d3ebe97a40571").unwrap());
+        assert_eq!(recipient.to_vec(), read_hex("ac74bb8773749920b0d3a8881c173ec5").unwrap());
+    }
+
+    #[test]
+    fn id_signature() {
+        let challenge = read_hex(CHALLENGE_DATA).unwrap();
+        let ephemeral_key = read_hex("039961e4c2356d61bedb83052c115d311acb3a96f5777296dcf297351130266231").unwrap();
+        let signature = sign_id(&secret_key(), &challenge, &ephemeral_key, &node_id_b()).unwrap();
+        assert_eq!(signature, read_hex("94852a1e2318c4e5e9d422c98eaf19d1d90d876b29cd06ca7cb7546d0fff7b484fe86c09a064fe72bdbef73ba8e9c34df0cd2b53e9d65528c2c7f336d5dfc6e6").unwrap());
+
+        let public_key = PublicKey::from_secret_key(&SECP256K1, &secret_key()).unwrap();
+        assert!(verify_id(&public_key, &signature, &challenge, &ephemeral_key, &node_id_b()));
+        assert!(!verify_id(&public_key, &signature, &challenge, &ephemeral_key, &node_id_a()));
+    }
+
+    #[test]
+    fn message_encryption() {
+        let key = read_hex("9f2d77db7004bf8a1a85107ac686990b").unwrap();
+        let nonce = read_hex("27b5af763c446acd2749fe8e").unwrap();
+        let ad = read_hex("93a7400fa0d6a694ebc24d5cf570f65d04215b6ac00757875e3f3a5f42107903").unwrap();
+        let encrypted = encrypt(&key, &nonce, &[0x01, 0xc2, 0x01, 0x01], &ad);
+        assert_eq!(encrypted, read_hex("a5d12a2d94b8ccb3ba55558229867dc13bfa3648").unwrap());
+        assert_eq!(decrypt(&key, &nonce, &encrypted, &ad), Some(vec![0x01, 0xc2, 0x01, 0x01]));
+        assert_eq!(decrypt(&key, &nonce, &encrypted, &[]), None);
+    }
+}
@@ -13,6 +13,8 @@ use crypto::aessafe::{AesSafe256Encryptor, AesSafe128Encryptor};
 use crypto::symmetriccipher::{Decryptor, Encryptor};
 use crypto::buffer::{RefReadBuffer, RefWriteBuffer};
 use errors::ECIESError;
+use devp2p_common::NodeSigner;
+use std::sync::Arc;
 use rlp;
 
 const AUTH_LEN: usize =
@@ -45,7 +47,7 @@ fn concat_kdf(key_material: H256) -> H256 {
 }
 
 pub struct ECIES {
-    secret_key: SecretKey,
+    signer: Arc<dyn NodeSigner>,
     public_key: PublicKey,
     remote_public_key: Option<PublicKey>,
 
@@ -71,16 +73,15 @@ pub struct ECIES {
 }
 
 impl ECIES {
-    pub fn new_client(secret_key: SecretKey, remote_id: H512) -> Result<Self, ECIESError> {
-        let public_key = PublicKey::from_secret_key(
-            &SECP256K1, &secret_key)?;
+    pub fn new_client(signer: Arc<dyn NodeSigner>, remote_id: H512) -> Result<Self, ECIESError> {
+        let public_key = signer.public_key()?;
         let remote_public_key = id2pk(remote_id)?;
         let nonce = H256::random();
         let (ephemeral_secret_key, ephemeral_public_key) =
             SECP256K1.generate_keypair(&mut OsRng::new()?)?;
 
         Ok(ECIES {
-            secret_key, public_key, ephemeral_secret_key,
+            signer, public_key, ephemeral_secret_key,
             ephemeral_public_key, nonce,
 
             remote_public_key: Some(remote_public_key),
@@ -96,15 +97,14 @@ impl ECIES {
         })
     }
 
-    pub fn new_server(secret_key: SecretKey) -> Result<Self, ECIESError> {
-        let public_key = PublicKey::from_secret_key(
-            &SECP256K1, &secret_key)?;
+    pub fn new_server(signer: Arc<dyn NodeSigner>) -> Result<Self, ECIESError> {
+        let public_key = signer.public_key()?;
         let nonce = H256::random();
         let (ephemeral_secret_key, ephemeral_public_key) =
             SECP256K1.generate_keypair(&mut OsRng::new()?)?;
 
         Ok(ECIES {
-            secret_key, public_key, ephemeral_secret_key,
+            signer, public_key, ephemeral_secret_key,
             ephemeral_public_key, nonce,
 
             remote_public_key: None,
@@ -156,7 +156,7 @@ impl ECIES {
         let data_iv = &encrypted[65..(encrypted.len() - 32)];
         let tag = H256::from(&encrypted[(encrypted.len() - 32)..]);
 
-        let x = ecdh_x(&public_key, &self.secret_key);
+        let x = self.signer.ecdh(&public_key)?;
         let key = concat_kdf(x);
         let ekey = H128::from(&key[0..16]);
         let mkey = sha256(&key[16..32]);
@@ -178,7 +178,7 @@ impl ECIES {
     }
 
     fn create_auth_unencrypted(&self) -> Result<[u8; AUTH_LEN], ECIESError> {
-        let x = ecdh_x(&self.remote_public_key.unwrap(), &self.secret_key);
+        let x = self.signer.ecdh(&self.remote_public_key.unwrap())?;
         let msg = Message::from_slice((x ^ self.nonce).as_ref())?;
         let sig_rec = SECP256K1.sign_recoverable(&msg, &self.ephemeral_secret_key)?;
         let (rec, sig) = sig_rec.serialize_compact(&SECP256K1);
@@ -210,7 +210,7 @@ impl ECIES {
             return Err(ECIESError::InvalidAuthData);
         }
 
-        let x = ecdh_x(self.remote_public_key.as_ref().unwrap(), &self.secret_key);
+        let x = self.signer.ecdh(self.remote_public_key.as_ref().unwrap())?;
         let msg = Message::from_slice((x ^ self.remote_nonce.unwrap()).as_ref())?;
         self.remote_ephemeral_public_key = Some(SECP256K1.recover(&msg, &sig_rec)?);
         self.ephemeral_shared_secret = Some(
@@ -434,13 +434,74 @@ impl ECIES {
 #[cfg(test)]
 mod tests {
     use util::{keccak256, pk2id, id2pk};
-    use secp256k1::{SECP256K1, Message};
+    use secp256k1::{SECP256K1, Message, RecoverableSignature};
     use secp256k1::ecdh::SharedSecret;
     use secp256k1::key::{PublicKey, SecretKey};
-    use bigint::{H512, H256};
+    use bigint::H256;
     use rand::os::OsRng;
+    use devp2p_common::NodeSigner;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
     use super::ECIES;
 
+    struct CountingSigner {
+        key: SecretKey,
+        ecdh_calls: AtomicUsize,
+    }
+
+    impl NodeSigner for CountingSigner {
+        fn public_key(&self) -> Result<PublicKey, ::secp256k1::Error> {
+            self.key.public_key()
+        }
+
+        fn ecdh(&self, public_key: &PublicKey) -> Result<H256, ::secp256k1::Error> {
+            self.ecdh_calls.fetch_add(1, Ordering::SeqCst);
+            self.key.ecdh(public_key)
+        }
+
+        fn ecdh_point(&self, public_key: &PublicKey) -> Result<PublicKey, ::secp256k1::Error> {
+            self.key.ecdh_point(public_key)
+        }
+
+        fn sign(&self, message: &Message) -> Result<RecoverableSignature, ::secp256k1::Error> {
+            self.key.sign(message)
+        }
+    }
+
+    #[test]
+    fn handshake_with_custom_signer() {
+        let server = Arc::new(CountingSigner {
+            key: SecretKey::new(&SECP256K1, &mut OsRng::new().unwrap()),
+            ecdh_calls: AtomicUsize::new(0),
+        });
+        let client = Arc::new(CountingSigner {
+            key: SecretKey::new(&SECP256K1, &mut OsRng::new().unwrap()),
+            ecdh_calls: AtomicUsize::new(0),
+        });
+
+        let mut server_ecies = ECIES::new_server(server.clone()).unwrap();
+        let mut client_ecies = ECIES::new_client(
+            client.clone(), pk2id(&server.public_key().unwrap())).unwrap();
+
+        let auth = client_ecies.create_auth().unwrap();
+        server_ecies.parse_auth(auth.as_ref()).unwrap();
+        let ack = server_ecies.create_ack().unwrap();
+        client_ecies.parse_ack(ack.as_ref()).unwrap();
+        assert_eq!(server_ecies.remote_id(), pk2id(&client.public_key().unwrap()));
+
+        // The client derives the static shared secret for the auth
+        // signature and decrypts the ack; the server decrypts the auth
+        // and derives the static shared secret to recover the
+        // signature
+        assert_eq!(client.ecdh_calls.load(Ordering::SeqCst), 2);
+        assert_eq!(server.ecdh_calls.load(Ordering::SeqCst), 2);
+
+        let data = [1u8, 2u8, 3u8];
+        server_ecies.parse_header(client_ecies.create_header(data.len()).as_ref()).unwrap();
+        let ret = server_ecies.parse_body(client_ecies.create_body(&data).as_ref()).unwrap();
+        assert_eq!(ret, data);
+    }
+
     #[test]
     fn communicate() {
         let server_secret_key = SecretKey::new(&SECP256K1, &mut OsRng::new().unwrap());
@@ -450,8 +511,8 @@ mod tests {
         let client_public_key = PublicKey::from_secret_key(
             &SECP256K1, &client_secret_key).unwrap();
 
-        let mut server_ecies = ECIES::new_server(server_secret_key).unwrap();
-        let mut client_ecies = ECIES::new_client(client_secret_key, pk2id(&server_public_key)).unwrap();
+        let mut server_ecies = ECIES::new_server(Arc::new(server_secret_key)).unwrap();
+        let mut client_ecies = ECIES::new_client(Arc::new(client_secret_key), pk2id(&server_public_key)).unwrap();
 
         // Handshake
         let auth = client_ecies.create_auth().unwrap();
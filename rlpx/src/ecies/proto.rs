@@ -5,12 +5,14 @@ use tokio_io::codec::{Framed, Encoder, Decoder};
 use tokio_core::reactor::{Handle, Core};
 use tokio_core::net::TcpStream;
 use bytes::{BytesMut, BufMut};
-use errors::ECIESError;
-use secp256k1::key::SecretKey;
+use errors::{ECIESError, HandshakeError};
 use bigint::H512;
 use std::io;
 use std::net::SocketAddr;
 use super::algorithm::ECIES;
+use socket::SocketBuffers;
+use devp2p_common::NodeSigner;
+use std::sync::Arc;
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 /// Current ECIES state of a connection
@@ -32,18 +34,18 @@ pub struct ECIESCodec {
 }
 
 impl ECIESCodec {
-    /// Create a new server codec using the given secret key
-    pub fn new_server(secret_key: SecretKey) -> Result<Self, ECIESError> {
+    /// Create a new server codec using the given node signer
+    pub fn new_server(signer: Arc<dyn NodeSigner>) -> Result<Self, ECIESError> {
         Ok(Self {
-            ecies: ECIES::new_server(secret_key)?,
+            ecies: ECIES::new_server(signer)?,
             state: ECIESState::Auth
         })
     }
 
-    /// Create a new client codec using the given secret key and the server's public id
-    pub fn new_client(secret_key: SecretKey, remote_id: H512) -> Result<Self, ECIESError> {
+    /// Create a new client codec using the given node signer and the server's public id
+    pub fn new_client(signer: Arc<dyn NodeSigner>, remote_id: H512) -> Result<Self, ECIESError> {
         Ok(Self {
-            ecies: ECIES::new_client(secret_key, remote_id)?,
+            ecies: ECIES::new_client(signer, remote_id)?,
             state: ECIESState::Auth
         })
     }
@@ -152,17 +154,22 @@ impl ECIESStream {
     /// Connect to an ECIES server
     pub fn connect(
         addr: &SocketAddr, handle: &Handle,
-        secret_key: SecretKey, remote_id: H512
+        signer: Arc<dyn NodeSigner>, remote_id: H512,
+        buffers: SocketBuffers
     ) -> Box<Future<Item = ECIESStream, Error = io::Error>> {
-        let ecies = match ECIESCodec::new_client(secret_key, remote_id) {
+        let ecies = match ECIESCodec::new_client(signer, remote_id) {
             Ok(val) => val,
             Err(e) => return Box::new(future::err(
-                io::Error::new(io::ErrorKind::Other, "invalid handshake")))
+                io::Error::from(HandshakeError("invalid handshake"))))
                 as Box<Future<Item = ECIESStream, Error = io::Error>>,
         };
 
         debug!("connecting to ecies stream ...");
         let stream = TcpStream::connect(addr, handle)
+            .and_then(move |socket| {
+                buffers.apply(&socket)?;
+                Ok(socket)
+            })
             .and_then(|socket| {
                 debug!("sending ecies auth ...");
                 socket.framed(ecies).send(ECIESValue::Auth)
@@ -179,7 +186,7 @@ impl ECIESStream {
                     })
                 } else {
                     error!("expected ack, got {:?} instead", ack);
-                    Err(io::Error::new(io::ErrorKind::Other, "invalid handshake"))
+                    Err(io::Error::from(HandshakeError("invalid handshake")))
                 }
             });
 
@@ -188,12 +195,12 @@ impl ECIESStream {
 
     /// Listen on a just connected ECIES clinet
     pub fn incoming(
-        stream: TcpStream, secret_key: SecretKey
+        stream: TcpStream, signer: Arc<dyn NodeSigner>
     ) -> Box<Future<Item = ECIESStream, Error = io::Error>> {
-        let ecies = match ECIESCodec::new_server(secret_key) {
+        let ecies = match ECIESCodec::new_server(signer) {
             Ok(val) => val,
             Err(e) => return Box::new(future::err(
-                io::Error::new(io::ErrorKind::Other, "invalid handshake")))
+                io::Error::from(HandshakeError("invalid handshake"))))
                 as Box<Future<Item = ECIESStream, Error = io::Error>>,
         };
 
@@ -206,7 +213,7 @@ impl ECIESStream {
                         Ok((remote_id, transport)),
                     ack => {
                         error!("expected auth, got {:?} instead", ack);
-                        Err(io::Error::new(io::ErrorKind::Other, "invalid handshake"))
+                        Err(io::Error::from(HandshakeError("invalid handshake")))
                     }
                 }
             })
@@ -1,7 +1,55 @@
 use secp256k1;
-use std::io;
+use peer::DisconnectReason;
+use std::{error, fmt, io};
 use crypto::symmetriccipher::SymmetricCipherError;
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Failure of the ECIES or Hello handshake with a peer. Kept as the
+/// inner error of the `io::Error` the handshake fails with.
+pub struct HandshakeError(pub &'static str);
+
+impl fmt::Display for HandshakeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "handshake failed: {}", self.0)
+    }
+}
+
+impl error::Error for HandshakeError {
+    fn description(&self) -> &str {
+        self.0
+    }
+}
+
+impl From<HandshakeError> for io::Error {
+    fn from(error: HandshakeError) -> io::Error {
+        io::Error::new(io::ErrorKind::Other, error)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// A peer sent a Disconnect in place of its Hello, refusing the
+/// connection. Kept as the inner error of the `io::Error` the
+/// handshake fails with, with the reason the peer gave, if any.
+pub struct RefusedError(pub Option<DisconnectReason>);
+
+impl fmt::Display for RefusedError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "peer refused the connection: {:?}", self.0)
+    }
+}
+
+impl error::Error for RefusedError {
+    fn description(&self) -> &str {
+        "peer refused the connection"
+    }
+}
+
+impl From<RefusedError> for io::Error {
+    fn from(error: RefusedError) -> io::Error {
+        io::Error::new(io::ErrorKind::ConnectionRefused, error)
+    }
+}
+
 #[derive(Debug)]
 pub enum ECIESError {
     SECP256K1(secp256k1::Error),
@@ -14,7 +62,12 @@ pub enum ECIESError {
 
 impl From<ECIESError> for io::Error {
     fn from(error: ECIESError) -> io::Error {
-        io::Error::new(io::ErrorKind::Other, "ECIES error")
+        match error {
+            ECIESError::IO(error) => error,
+            ECIESError::InvalidAuthData => HandshakeError("invalid auth data").into(),
+            ECIESError::InvalidAckData => HandshakeError("invalid ack data").into(),
+            error => io::Error::new(io::ErrorKind::Other, format!("ECIES error: {:?}", error)),
+        }
     }
 }
 
@@ -11,6 +11,8 @@ extern crate bigint;
 extern crate rlp;
 extern crate hexutil;
 extern crate bytes;
+extern crate snap;
+extern crate devp2p_common;
 #[macro_use]
 extern crate log;
 #[macro_use]
@@ -23,18 +25,24 @@ pub mod ecies;
 mod peer;
 mod mac;
 mod errors;
+mod socket;
 
-pub use peer::{PeerStream, CapabilityInfo};
+pub use peer::{PeerStream, PeerInfo, PeerTable, CapabilityInfo, CapabilityOffsets, DisconnectReason,
+               RESERVED_MESSAGE_IDS};
+pub use socket::SocketBuffers;
+pub use devp2p_common::NodeSigner;
+pub use errors::{HandshakeError, RefusedError};
+pub use devp2p_common::{NetRestrict, Cidr};
 
 use bigint::H512;
 use util::pk2id;
-use secp256k1::SECP256K1;
-use secp256k1::key::{PublicKey, SecretKey};
+use secp256k1::key::SecretKey;
 use futures::future;
 use futures::{Poll, Async, StartSend, AsyncSink, Future, Stream, Sink};
 use std::io;
-use std::net::SocketAddr;
-use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
 use tokio_core::reactor::Handle;
 use tokio_core::net::{TcpListener, Incoming};
 use tokio_io::{AsyncRead, AsyncWrite};
@@ -42,11 +50,16 @@ use tokio_io::codec::{Framed, Encoder, Decoder};
 use rand::{Rng, thread_rng};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
-/// Sending node type specifying either all, any or a particular peer
+/// Sending node type specifying either all, any or a particular peer.
+/// A message no connected peer is selected for is dropped.
 pub enum RLPxNode {
     Any,
     All,
     Peer(H512),
+    /// At most the given number of randomly chosen peers
+    Random(usize),
+    /// All peers but the given one, like the one a block came from
+    AllExcept(H512),
 }
 
 /// Sending message for RLPx
@@ -65,8 +78,18 @@ pub enum RLPxReceiveMessage {
         node: H512,
         capabilities: Vec<CapabilityInfo>,
     },
+    /// A peer went away. `clean` is set when the connection was
+    /// closed or a Disconnect message was exchanged, and unset when it
+    /// failed with an error. `reason` is the one the remote gave, if any.
+    /// A dialed peer refusing us with a Disconnect in place of its
+    /// Hello is reported too, without a `Connected` before. A
+    /// connection replaced by a second one to the same peer is
+    /// reported with AlreadyConnected, followed by a `Connected` for
+    /// the one kept.
     Disconnected {
-        node: H512
+        node: H512,
+        reason: Option<DisconnectReason>,
+        clean: bool,
     },
     Normal {
         node: H512,
@@ -81,15 +104,26 @@ pub struct RLPxStream {
     streams: Vec<PeerStream>,
     futures: Vec<(H512, Box<Future<Item = PeerStream, Error = io::Error>>)>,
     incoming_futures: Vec<Box<Future<Item = PeerStream, Error = io::Error>>>,
-    newly_connected: Vec<(H512, Vec<CapabilityInfo>)>,
-    newly_disconnected: Vec<H512>,
+    /// Connected and Disconnected events not reported yet, in the
+    /// order they happened
+    peer_events: VecDeque<RLPxReceiveMessage>,
     active_peers: Vec<H512>,
-    secret_key: SecretKey,
+    peer_table: PeerTable,
+    signer: Arc<dyn NodeSigner>,
+    id: H512,
     protocol_version: usize,
     client_version: String,
     capabilities: Vec<CapabilityInfo>,
     port: u16,
     tcp_incoming: Option<Incoming>,
+    socket_buffers: SocketBuffers,
+    max_inbound: Option<usize>,
+    max_outbound: Option<usize>,
+    max_peers_per_ip: Option<usize>,
+    trusted_peers: Vec<H512>,
+    banned_peers: Vec<H512>,
+    banned_ips: Vec<IpAddr>,
+    net_restrict: Option<NetRestrict>,
     handle: Handle,
 }
 
@@ -98,50 +132,210 @@ impl RLPxStream {
     pub fn new(handle: &Handle, secret_key: SecretKey, protocol_version: usize,
                client_version: String, capabilities: Vec<CapabilityInfo>,
                listen: Option<&SocketAddr>) -> Result<RLPxStream, io::Error> {
+        RLPxStream::new_with_signer(handle, Arc::new(secret_key), protocol_version,
+                                    client_version, capabilities, listen)
+    }
+
+    /// Create a new RLPx stream whose handshakes use the given node
+    /// signer instead of a local secret key
+    pub fn new_with_signer(handle: &Handle, signer: Arc<dyn NodeSigner>, protocol_version: usize,
+                           client_version: String, capabilities: Vec<CapabilityInfo>,
+                           listen: Option<&SocketAddr>) -> Result<RLPxStream, io::Error> {
+        let id = match signer.public_key() {
+            Ok(key) => pk2id(&key),
+            Err(_) => return Err(io::Error::new(io::ErrorKind::Other,
+                                                "SECP256K1 public key error")),
+        };
+
         Ok(RLPxStream {
+            id,
             streams: Vec::new(),
             futures: Vec::new(),
-            secret_key, protocol_version, client_version,
+            signer, protocol_version, client_version,
             capabilities,
             handle: handle.clone(),
             active_peers: Vec::new(),
-            newly_connected: Vec::new(),
-            newly_disconnected: Vec::new(),
+            peer_table: PeerTable::default(),
+            peer_events: VecDeque::new(),
             port: listen.map(|addr| addr.port()).unwrap_or(0),
             tcp_incoming: match listen {
                 Some(addr) => Some(TcpListener::bind(addr, handle)?.incoming()),
                 None => None,
             },
             incoming_futures: Vec::new(),
+            socket_buffers: SocketBuffers::default(),
+            max_inbound: None,
+            max_outbound: None,
+            max_peers_per_ip: None,
+            trusted_peers: Vec::new(),
+            banned_peers: Vec::new(),
+            banned_ips: Vec::new(),
+            net_restrict: None,
         })
     }
 
-    /// Append a new peer to this RLPx stream if it does not exist
+    /// Client version advertised in the Hello of new connections
+    pub fn client_version(&self) -> &str {
+        &self.client_version
+    }
+
+    /// Change the client version advertised in the Hello. Only
+    /// affects connections established afterwards.
+    pub fn set_client_version(&mut self, client_version: String) {
+        self.client_version = client_version;
+    }
+
+    /// Advertise one more capability in the Hello. Only affects
+    /// connections established afterwards.
+    pub fn add_capability(&mut self, capability: CapabilityInfo) {
+        self.capabilities.push(capability);
+    }
+
+    /// Port announced in the Hello of new connections, the listen port
+    /// by default
+    pub fn set_advertised_port(&mut self, port: u16) {
+        self.port = port;
+    }
+
+    /// Set the TCP socket buffer sizes applied to new connections
+    pub fn set_socket_buffers(&mut self, buffers: SocketBuffers) {
+        self.socket_buffers = buffers;
+    }
+
+    /// Set the maximum numbers of inbound and outbound connections,
+    /// `None` meaning no limit. Peers connecting beyond the inbound
+    /// limit are disconnected with `TooManyPeers`, and no peer is
+    /// dialed beyond the outbound one. Trusted peers are exempt.
+    pub fn set_peer_limits(&mut self, max_inbound: Option<usize>, max_outbound: Option<usize>) {
+        self.max_inbound = max_inbound;
+        self.max_outbound = max_outbound;
+    }
+
+    /// Set how many peers may be connected from the same address,
+    /// `None` for no limit. Trusted peers are not limited. Peers
+    /// beyond it are disconnected with `TooManyPeers` once their
+    /// handshake completes.
+    pub fn set_max_peers_per_ip(&mut self, max: Option<usize>) {
+        self.max_peers_per_ip = max;
+    }
+
+    /// Exempt a peer from the peer limits
+    pub fn add_trusted_peer(&mut self, remote_id: H512) {
+        if !self.trusted_peers.contains(&remote_id) {
+            self.trusted_peers.push(remote_id);
+        }
+    }
+
+    /// Subject a peer to the peer limits again
+    pub fn remove_trusted_peer(&mut self, remote_id: H512) {
+        self.trusted_peers.retain(|id| *id != remote_id);
+    }
+
+    /// Whether a peer is exempt from the peer limits
+    pub fn is_trusted(&self, remote_id: H512) -> bool {
+        self.trusted_peers.contains(&remote_id)
+    }
+
+    /// Stop dialing a peer and refuse its connections, disconnecting it
+    /// with `UselessPeer`. Does not disconnect it if it is connected.
+    pub fn ban_peer(&mut self, remote_id: H512) {
+        if !self.banned_peers.contains(&remote_id) {
+            self.banned_peers.push(remote_id);
+        }
+    }
+
+    /// Accept a banned peer again
+    pub fn unban_peer(&mut self, remote_id: H512) {
+        self.banned_peers.retain(|id| *id != remote_id);
+    }
+
+    /// Stop dialing an address and drop its connections before the
+    /// handshake. Does not disconnect peers already connected from it.
+    pub fn ban_ip(&mut self, ip: IpAddr) {
+        if !self.banned_ips.contains(&ip) {
+            self.banned_ips.push(ip);
+        }
+    }
+
+    /// Accept a banned address again
+    pub fn unban_ip(&mut self, ip: IpAddr) {
+        self.banned_ips.retain(|banned| *banned != ip);
+    }
+
+    /// Only dial and accept peers at the addresses the restriction
+    /// allows, `None` for any address. Does not disconnect peers
+    /// already connected.
+    pub fn set_net_restrict(&mut self, net_restrict: Option<NetRestrict>) {
+        self.net_restrict = net_restrict;
+    }
+
+    /// Whether the network restriction allows an address
+    pub fn is_allowed(&self, ip: &IpAddr) -> bool {
+        restrict_allows(self.net_restrict.as_ref(), ip)
+    }
+
+    /// Number of connected inbound and outbound peers, outbound
+    /// including the peers being dialed
+    pub fn peer_counts(&self) -> (usize, usize) {
+        let outbound = self.streams.iter().filter(|peer| peer.is_outbound()).count();
+        (self.streams.len() - outbound, outbound + self.futures.len())
+    }
+
+    /// Append a new peer to this RLPx stream if it does not exist, is
+    /// not banned and the outbound limit is not reached
     pub fn add_peer(
         &mut self, addr: &SocketAddr, remote_id: H512
     ) {
+        if self.banned_peers.contains(&remote_id) || self.banned_ips.contains(&addr.ip()) {
+            debug!("not connecting to banned peer {}", remote_id);
+            return;
+        }
+        if !self.is_allowed(&addr.ip()) {
+            debug!("not connecting to peer {} at restricted address {}", remote_id, addr);
+            return;
+        }
+        if !self.is_trusted(remote_id) && ip_full(&self.streams, self.max_peers_per_ip, remote_id, addr.ip()) {
+            debug!("not connecting to peer {}, too many peers at {}", remote_id, addr.ip());
+            return;
+        }
+        if let (Some(max_outbound), false) = (self.max_outbound, self.is_trusted(remote_id)) {
+            if self.peer_counts().1 >= max_outbound {
+                debug!("not connecting to peer {}, outbound limit reached", remote_id);
+                return;
+            }
+        }
         if !self.active_peers.contains(&remote_id) {
             info!("connecting to peer {}", remote_id);
-            let future = PeerStream::connect(addr, &self.handle, self.secret_key.clone(),
+            let future = PeerStream::connect(addr, &self.handle, self.signer.clone(),
                                              remote_id, self.protocol_version,
                                              self.client_version.clone(),
-                                             self.capabilities.clone(), self.port);
+                                             self.capabilities.clone(), self.port,
+                                             self.socket_buffers);
             self.futures.push((remote_id, future));
             self.active_peers.push(remote_id);
         }
     }
 
     /// Force disconnecting a peer if it is already connected or about
-    /// to be connected. Useful for removing peers on a different hard
-    /// fork network
-    pub fn disconnect_peer(&mut self, remote_id: H512) {
+    /// to be connected, telling a connected peer the reason in a
+    /// Disconnect message. Useful for removing peers on a different
+    /// hard fork network
+    pub fn disconnect_peer(&mut self, remote_id: H512, reason: DisconnectReason) {
+        for peer in self.streams.iter_mut().filter(|peer| peer.remote_id() == remote_id) {
+            if let Err(e) = peer.send_disconnect(reason) {
+                debug!("sending disconnect failed: {:?}", e);
+            }
+        }
+
         let ref mut futures = self.futures;
         let ref mut streams = self.streams;
-        let ref mut newly_disconnected = self.newly_disconnected;
+        let ref mut peer_events = self.peer_events;
 
         retain_mut(streams, |peer| {
             if peer.remote_id() == remote_id {
-                newly_disconnected.push(remote_id);
+                peer_events.push_back(RLPxReceiveMessage::Disconnected {
+                    node: remote_id, reason: None, clean: true,
+                });
                 false
             } else {
                 true
@@ -159,7 +353,12 @@ impl RLPxStream {
         let ref mut incoming_futures = self.incoming_futures;
         let ref mut streams = self.streams;
         let ref mut active_peers = self.active_peers;
-        let ref mut newly_connected = self.newly_connected;
+        let peer_events = &mut self.peer_events;
+        let id = self.id;
+        let max_inbound = self.max_inbound;
+        let max_peers_per_ip = self.max_peers_per_ip;
+        let trusted_peers = &self.trusted_peers;
+        let banned_peers = &self.banned_peers;
 
         let mut all_ready = true;
 
@@ -169,10 +368,17 @@ impl RLPxStream {
                     all_ready = false;
                     true
                 },
-                Ok(Async::Ready(peer)) => {
+                Ok(Async::Ready(mut peer)) => {
                     debug!("new peer connected");
-                    newly_connected.push((remote_id, peer.capabilities().into()));
-                    streams.push(peer);
+                    if !trusted_peers.contains(&remote_id) && peer_ip_full(streams, max_peers_per_ip, &peer) {
+                        debug!("disconnecting {:x}, too many peers at its address", remote_id);
+                        if let Err(e) = peer.send_disconnect(DisconnectReason::TooManyPeers) {
+                            debug!("sending disconnect failed: {:?}", e);
+                        }
+                        active_peers.retain(|peer_id| *peer_id != remote_id);
+                        return false;
+                    }
+                    insert_stream(streams, peer_events, id, peer);
                     false
                 },
                 Err(e) => {
@@ -180,6 +386,12 @@ impl RLPxStream {
                     active_peers.retain(|peer_id| {
                         *peer_id != remote_id
                     });
+                    let refused = e.get_ref().and_then(|inner| inner.downcast_ref::<RefusedError>());
+                    if let Some(&RefusedError(reason)) = refused {
+                        peer_events.push_back(RLPxReceiveMessage::Disconnected {
+                            node: remote_id, reason, clean: true,
+                        });
+                    }
                     false
                 },
             }
@@ -191,8 +403,19 @@ impl RLPxStream {
             loop {
                 match tcp_incoming.poll()? {
                     Async::Ready(Some((stream, addr))) => {
+                        if self.banned_ips.contains(&addr.ip()) {
+                            debug!("dropping connection from banned address {}", addr);
+                            continue;
+                        }
+                        if !restrict_allows(self.net_restrict.as_ref(), &addr.ip()) {
+                            debug!("dropping connection from restricted address {}", addr);
+                            continue;
+                        }
+                        if let Err(e) = self.socket_buffers.apply(&stream) {
+                            error!("failed to set socket buffers for {}: {}", addr, e);
+                        }
                         incoming_futures.push(PeerStream::incoming(
-                            stream, self.secret_key.clone(),
+                            stream, self.signer.clone(),
                             self.protocol_version,
                             self.client_version.clone(),
                             self.capabilities.clone(), self.port));
@@ -208,10 +431,38 @@ impl RLPxStream {
                     all_ready = false;
                     true
                 },
-                Ok(Async::Ready(peer)) => {
+                Ok(Async::Ready(mut peer)) => {
                     debug!("new peer connected");
-                    newly_connected.push((peer.remote_id(), peer.capabilities().into()));
-                    streams.push(peer);
+                    let remote_id = peer.remote_id();
+                    let inbound = streams.iter().filter(|stream| !stream.is_outbound()).count();
+                    let duplicate = streams.iter().any(|stream| stream.remote_id() == remote_id);
+                    if banned_peers.contains(&remote_id) {
+                        debug!("disconnecting banned peer {:x}", remote_id);
+                        if let Err(e) = peer.send_disconnect(DisconnectReason::UselessPeer) {
+                            debug!("sending disconnect failed: {:?}", e);
+                        }
+                        return false;
+                    }
+                    let full = max_inbound.map(|max| inbound >= max).unwrap_or(false);
+                    if full && !duplicate && !trusted_peers.contains(&remote_id) {
+                        debug!("disconnecting {:x}, inbound limit reached", remote_id);
+                        if let Err(e) = peer.send_disconnect(DisconnectReason::TooManyPeers) {
+                            debug!("sending disconnect failed: {:?}", e);
+                        }
+                        return false;
+                    }
+                    if !trusted_peers.contains(&remote_id) && peer_ip_full(streams, max_peers_per_ip, &peer) {
+                        debug!("disconnecting {:x}, too many peers at its address", remote_id);
+                        if let Err(e) = peer.send_disconnect(DisconnectReason::TooManyPeers) {
+                            debug!("sending disconnect failed: {:?}", e);
+                        }
+                        return false;
+                    }
+                    if insert_stream(streams, peer_events, id, peer) &&
+                        !active_peers.contains(&remote_id)
+                    {
+                        active_peers.push(remote_id);
+                    }
                     false
                 },
                 Err(e) => {
@@ -232,6 +483,139 @@ impl RLPxStream {
     pub fn active_peers(&self) -> &[H512] {
         self.active_peers.as_ref()
     }
+
+    /// Table of the connected peers, updated as the stream reports
+    /// peers connecting and disconnecting
+    pub fn peer_table(&self) -> PeerTable {
+        self.peer_table.clone()
+    }
+
+    /// Message id ranges negotiated with a connected peer, `None` if
+    /// the peer is not connected
+    pub fn capability_offsets(&self, remote_id: H512) -> Option<&CapabilityOffsets> {
+        self.streams.iter()
+            .find(|peer| peer.remote_id() == remote_id)
+            .map(|peer| peer.capability_offsets())
+    }
+
+    /// Client version a connected peer announced in its Hello, `None`
+    /// if the peer is not connected
+    pub fn peer_client_version(&self, remote_id: H512) -> Option<&str> {
+        self.streams.iter()
+            .find(|peer| peer.remote_id() == remote_id)
+            .map(|peer| peer.remote_client_version())
+    }
+
+    /// Summary of a connected peer, `None` if the peer is not
+    /// connected
+    pub fn peer_info(&self, remote_id: H512) -> Option<PeerInfo> {
+        self.streams.iter()
+            .find(|peer| peer.remote_id() == remote_id)
+            .map(|peer| peer.info())
+    }
+
+    /// Summaries of all connected peers
+    pub fn peers(&self) -> Vec<PeerInfo> {
+        self.streams.iter().map(|peer| peer.info()).collect()
+    }
+
+    /// Next pending Connected or Disconnected event, updating the
+    /// peer table as it is reported
+    fn next_peer_event(&mut self) -> Option<RLPxReceiveMessage> {
+        let event = self.peer_events.pop_front()?;
+        self.peer_table.update(&self.streams);
+        Some(event)
+    }
+}
+
+/// Simultaneous dial tie-break: when both sides dialed each other,
+/// the node with the numerically lower id keeps its outbound
+/// connection and the other keeps its inbound one
+pub fn keep_outbound(local_id: H512, remote_id: H512) -> bool {
+    local_id < remote_id
+}
+
+/// Whether another peer connected from an address would exceed the
+/// limit of peers per address. Connections of the same peer are not
+/// counted, since only one of them is kept.
+fn ip_full(streams: &[PeerStream], max: Option<usize>, remote_id: H512, ip: IpAddr) -> bool {
+    max.map(|max| {
+        streams.iter()
+            .filter(|stream| stream.remote_id() != remote_id)
+            .filter(|stream| stream.remote_addr().map(|addr| addr.ip()) == Some(ip))
+            .count() >= max
+    }).unwrap_or(false)
+}
+
+/// Whether a network restriction, if any, allows an address
+fn restrict_allows(net_restrict: Option<&NetRestrict>, ip: &IpAddr) -> bool {
+    net_restrict.map(|restrict| restrict.allows(ip)).unwrap_or(true)
+}
+
+fn peer_ip_full(streams: &[PeerStream], max: Option<usize>, peer: &PeerStream) -> bool {
+    match peer.remote_addr() {
+        Some(addr) => ip_full(streams, max, peer.remote_id(), addr.ip()),
+        None => false,
+    }
+}
+
+/// Add a newly established connection, resolving duplicate
+/// connections to the same peer, and queue the events reporting it.
+/// The connection that is not kept is sent a Disconnect with
+/// AlreadyConnected. Returns whether the peer was not connected
+/// before.
+fn insert_stream(
+    streams: &mut Vec<PeerStream>, peer_events: &mut VecDeque<RLPxReceiveMessage>,
+    local_id: H512, mut peer: PeerStream,
+) -> bool {
+    let remote_id = peer.remote_id();
+    let index = match streams.iter().position(|stream| stream.remote_id() == remote_id) {
+        Some(index) => index,
+        None => {
+            peer_events.push_back(RLPxReceiveMessage::Connected {
+                node: remote_id, capabilities: peer.capabilities().into(),
+            });
+            streams.push(peer);
+            return true;
+        },
+    };
+
+    if streams[index].is_outbound() == peer.is_outbound() ||
+        keep_outbound(local_id, remote_id) != peer.is_outbound()
+    {
+        debug!("dropping duplicate connection to {:x}", remote_id);
+        if let Err(e) = peer.send_disconnect(DisconnectReason::AlreadyConnected) {
+            debug!("sending disconnect failed: {:?}", e);
+        }
+        return false;
+    }
+
+    debug!("replacing duplicate connection to {:x}", remote_id);
+    let capabilities: Vec<CapabilityInfo> = peer.capabilities().into();
+    if let Err(e) = streams[index].send_disconnect(DisconnectReason::AlreadyConnected) {
+        debug!("sending disconnect failed: {:?}", e);
+    }
+    streams[index] = peer;
+    // The kept connection may share other capabilities. If the
+    // replaced one was reported already, it is reported as gone and
+    // the kept one as a new connection.
+    let pending = peer_events.iter_mut().rev().find(|event| match **event {
+        RLPxReceiveMessage::Connected { node, .. } |
+        RLPxReceiveMessage::Disconnected { node, .. } => node == remote_id,
+        _ => false,
+    });
+    match pending {
+        Some(&mut RLPxReceiveMessage::Connected { capabilities: ref mut pending, .. }) => {
+            *pending = capabilities;
+        },
+        _ => {
+            peer_events.push_back(RLPxReceiveMessage::Disconnected {
+                node: remote_id, reason: Some(DisconnectReason::AlreadyConnected), clean: true,
+            });
+            peer_events.push_back(RLPxReceiveMessage::Connected { node: remote_id, capabilities });
+        },
+    }
+    false
 }
 
 fn retain_mut<T, F>(vec: &mut Vec<T>, mut f: F)
@@ -262,17 +646,8 @@ impl Stream for RLPxStream {
     fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
         self.poll_new_peers()?;
 
-        if self.newly_connected.len() > 0 {
-            let connected = self.newly_connected.pop().unwrap();
-            return Ok(Async::Ready(Some(RLPxReceiveMessage::Connected {
-                node: connected.0,
-                capabilities: connected.1,
-            })));
-        }
-        if self.newly_disconnected.len() > 0 {
-            return Ok(Async::Ready(Some(RLPxReceiveMessage::Disconnected {
-                node: self.newly_disconnected.pop().unwrap()
-            })));
+        if let Some(event) = self.next_peer_event() {
+            return Ok(Async::Ready(Some(event)));
         }
 
         let mut ret: Option<Self::Item> = None;
@@ -280,8 +655,7 @@ impl Stream for RLPxStream {
         {
             let ref mut streams = self.streams;
             let ref mut active_peers = self.active_peers;
-            let ref mut newly_connected = self.newly_connected;
-            let ref mut newly_disconnected = self.newly_disconnected;
+            let ref mut peer_events = self.peer_events;
 
             retain_mut(streams, |ref mut peer| {
                 if ret.is_some() {
@@ -293,7 +667,12 @@ impl Stream for RLPxStream {
                     Ok(Async::NotReady) => true,
                     Ok(Async::Ready(None)) => {
                         debug!("peer disconnected no error");
-                        newly_disconnected.push(id);
+                        active_peers.retain(|peer_id| {
+                            *peer_id != id
+                        });
+                        peer_events.push_back(RLPxReceiveMessage::Disconnected {
+                            node: id, reason: peer.disconnect_reason(), clean: true,
+                        });
                         false
                     },
                     Ok(Async::Ready(Some((cap, message_id, data)))) => {
@@ -311,7 +690,9 @@ impl Stream for RLPxStream {
                         active_peers.retain(|peer_id| {
                             *peer_id != id
                         });
-                        newly_disconnected.push(id);
+                        peer_events.push_back(RLPxReceiveMessage::Disconnected {
+                            node: id, reason: None, clean: false,
+                        });
                         false
                     },
                 }
@@ -320,19 +701,9 @@ impl Stream for RLPxStream {
 
         if ret.is_some() {
             Ok(Async::Ready(ret))
+        } else if let Some(event) = self.next_peer_event() {
+            Ok(Async::Ready(Some(event)))
         } else {
-            if self.newly_connected.len() > 0 {
-                let connected = self.newly_connected.pop().unwrap();
-                return Ok(Async::Ready(Some(RLPxReceiveMessage::Connected {
-                    node: connected.0,
-                    capabilities: connected.1,
-                })));
-            }
-            if self.newly_disconnected.len() > 0 {
-                return Ok(Async::Ready(Some(RLPxReceiveMessage::Disconnected {
-                    node: self.newly_disconnected.pop().unwrap()
-                })));
-            }
             Ok(Async::NotReady)
         }
     }
@@ -347,11 +718,13 @@ impl Sink for RLPxStream {
 
         let ref mut streams = self.streams;
         let ref mut active_peers = self.active_peers;
-        let ref mut newly_disconnected = self.newly_disconnected;
+        let ref mut peer_events = self.peer_events;
 
         let mut any_ready = false;
+        let mut any_not_ready = false;
+        let mut sent = 0;
         if match &message.node {
-            &RLPxNode::Any => true,
+            &RLPxNode::Any | &RLPxNode::Random(_) => true,
             _ => false,
         } {
             thread_rng().shuffle(streams);
@@ -364,22 +737,30 @@ impl Sink for RLPxStream {
 
             if match message.node {
                 RLPxNode::Peer(peer_id) => peer_id == id,
+                RLPxNode::AllExcept(peer_id) => peer_id != id,
                 RLPxNode::All => true,
                 RLPxNode::Any => !any_ready,
+                RLPxNode::Random(count) => sent < count,
             } {
                 let remote_id = peer.remote_id();
                 match peer.start_send((message.capability_name, message.id, message.data.clone())) {
                     Ok(AsyncSink::Ready) => {
                         any_ready = true;
+                        sent += 1;
+                        true
+                    },
+                    Ok(AsyncSink::NotReady(_)) => {
+                        any_not_ready = true;
                         true
                     },
-                    Ok(AsyncSink::NotReady(_)) => true,
                     Err(e) => {
                         debug!("peer disconnected with error {:?}", e);
                         active_peers.retain(|peer_id| {
                             *peer_id != remote_id
                         });
-                        newly_disconnected.push(remote_id);
+                        peer_events.push_back(RLPxReceiveMessage::Disconnected {
+                            node: remote_id, reason: None, clean: false,
+                        });
                         false
                     },
                 }
@@ -388,7 +769,9 @@ impl Sink for RLPxStream {
             }
         });
 
-        if any_ready {
+        // Only a selected peer that is busy makes the message wait, as
+        // its socket wakes the task up again
+        if any_ready || !any_not_ready {
             Ok(AsyncSink::Ready)
         } else {
             Ok(AsyncSink::NotReady(message))
@@ -398,7 +781,7 @@ impl Sink for RLPxStream {
     fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
         let ref mut streams = self.streams;
         let ref mut active_peers = self.active_peers;
-        let ref mut newly_disconnected = self.newly_disconnected;
+        let ref mut peer_events = self.peer_events;
 
         let mut all_ready = true;
 
@@ -415,7 +798,9 @@ impl Sink for RLPxStream {
                     active_peers.retain(|peer_id| {
                         *peer_id != remote_id
                     });
-                    newly_disconnected.push(remote_id);
+                    peer_events.push_back(RLPxReceiveMessage::Disconnected {
+                        node: remote_id, reason: None, clean: false,
+                    });
                     false
                 },
             }
@@ -431,7 +816,476 @@ impl Sink for RLPxStream {
 
 #[cfg(test)]
 mod tests {
+    use super::{RLPxStream, RLPxReceiveMessage, RLPxSendMessage, RLPxNode, PeerStream,
+                CapabilityInfo, DisconnectReason, NetRestrict, insert_stream, keep_outbound};
+    use bigint::H512;
+    use ecies::ECIESStream;
+    use rlp::RlpStream;
+    use util::pk2id;
+    use tokio_core::reactor::Core;
+    use tokio_core::net::TcpListener;
+    use secp256k1::SECP256K1;
+    use secp256k1::key::{PublicKey, SecretKey};
+    use futures::{future, Future, Stream, Sink, Async, AsyncSink, Poll};
+    use rand::os::OsRng;
+    use std::collections::VecDeque;
+    use std::sync::Arc;
+    use std::io;
+
     #[test]
     fn it_works() {
     }
+
+    fn key_pair() -> (SecretKey, H512) {
+        let key = SecretKey::new(&SECP256K1, &mut OsRng::new().unwrap());
+        let id = pk2id(&PublicKey::from_secret_key(&SECP256K1, &key).unwrap());
+        (key, id)
+    }
+
+    /// Dial from one node to the other, returning the dialer's and
+    /// the listener's ends of the connection
+    fn dial(
+        core: &mut Core, dialer: SecretKey, listener: (SecretKey, H512)
+    ) -> (PeerStream, PeerStream) {
+        let handle = core.handle();
+        let capabilities = vec![CapabilityInfo { name: "eth", version: 62, length: 8 }];
+        let server_capabilities = capabilities.clone();
+
+        let tcp = TcpListener::bind(&"127.0.0.1:0".parse().unwrap(), &handle).unwrap();
+        let addr = tcp.local_addr().unwrap();
+        let (listener_key, listener_id) = listener;
+        let server = tcp.incoming().into_future().map_err(|(e, _)| e)
+            .and_then(move |(incoming, _)| {
+                let (stream, _) = incoming.unwrap();
+                PeerStream::incoming(stream, Arc::new(listener_key), 5,
+                                     "listener".to_string(), server_capabilities, 0)
+            });
+        let client = PeerStream::connect(&addr, &handle, Arc::new(dialer), listener_id, 5,
+                                         "dialer".to_string(), capabilities, 0,
+                                         Default::default());
+        core.run(client.join(server)).unwrap()
+    }
+
+    #[test]
+    fn simultaneous_dial_keeps_one_connection() {
+        let mut core = Core::new().unwrap();
+        let (a_key, a_id) = key_pair();
+        let (b_key, b_id) = key_pair();
+
+        let (a_out, b_in) = dial(&mut core, a_key, (b_key, b_id));
+        let (b_out, a_in) = dial(&mut core, b_key, (a_key, a_id));
+        assert!(a_out.is_outbound() && b_out.is_outbound());
+        assert!(!a_in.is_outbound() && !b_in.is_outbound());
+
+        let (mut a_streams, mut a_events) = (Vec::new(), VecDeque::new());
+        assert!(insert_stream(&mut a_streams, &mut a_events, a_id, a_out));
+        assert!(!insert_stream(&mut a_streams, &mut a_events, a_id, a_in));
+        assert_eq!(a_streams.len(), 1);
+        assert_eq!(a_streams[0].is_outbound(), keep_outbound(a_id, b_id));
+        // Neither connection was reported yet, so one is reported
+        assert_eq!(a_events.len(), 1);
+
+        // Arrival order does not matter
+        let (mut b_streams, mut b_events) = (Vec::new(), VecDeque::new());
+        assert!(insert_stream(&mut b_streams, &mut b_events, b_id, b_in));
+        assert!(!insert_stream(&mut b_streams, &mut b_events, b_id, b_out));
+        assert_eq!(b_streams.len(), 1);
+        assert_eq!(b_streams[0].is_outbound(), keep_outbound(b_id, a_id));
+
+        // Both sides keep the same connection
+        assert!(a_streams[0].is_outbound() != b_streams[0].is_outbound());
+    }
+
+    #[test]
+    fn duplicate_connection_reported() {
+        let mut core = Core::new().unwrap();
+        let (a_key, a_id) = key_pair();
+        let (b_key, b_id) = key_pair();
+        let mut rlpx = RLPxStream::new(&core.handle(), a_key, 5, "local".to_string(),
+                                       vec![CapabilityInfo { name: "eth", version: 62, length: 8 }],
+                                       None).unwrap();
+
+        let (a_out, b_in) = dial(&mut core, a_key, (b_key, b_id));
+        let (b_out, a_in) = dial(&mut core, b_key, (a_key, a_id));
+        // Establish the connection that loses the tie-break first
+        let (first, first_remote, second) = if keep_outbound(a_id, b_id) {
+            (a_in, b_out, a_out)
+        } else {
+            (a_out, b_in, a_in)
+        };
+        let connected = RLPxReceiveMessage::Connected {
+            node: b_id,
+            capabilities: vec![CapabilityInfo { name: "eth", version: 62, length: 8 }],
+        };
+
+        let mut events = Vec::new();
+        rlpx.incoming_futures.push(Box::new(future::ok(first)));
+        core.run(future::poll_fn(|| -> Poll<(), io::Error> {
+            events.push(try_ready!(rlpx.poll()).unwrap());
+            Ok(Async::Ready(()))
+        })).unwrap();
+        assert_eq!(events, vec![connected.clone()]);
+
+        events.clear();
+        rlpx.incoming_futures.push(Box::new(future::ok(second)));
+        core.run(future::poll_fn(|| -> Poll<(), io::Error> {
+            while events.len() < 2 {
+                events.push(try_ready!(rlpx.poll()).unwrap());
+            }
+            Ok(Async::Ready(()))
+        })).unwrap();
+        assert_eq!(events, vec![
+            RLPxReceiveMessage::Disconnected {
+                node: b_id, reason: Some(DisconnectReason::AlreadyConnected), clean: true,
+            },
+            connected,
+        ]);
+        assert_eq!(rlpx.peers().len(), 1);
+        assert_eq!(rlpx.peers()[0].outbound, keep_outbound(a_id, b_id));
+
+        // The replaced connection was told why
+        let (message, first_remote) = match core.run(first_remote.into_future()) {
+            Ok(result) => result,
+            Err((e, _)) => panic!("receiving failed: {}", e),
+        };
+        assert!(message.is_none());
+        assert_eq!(first_remote.disconnect_reason(), Some(DisconnectReason::AlreadyConnected));
+    }
+
+    #[test]
+    fn updated_client_version_in_hello() {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+
+        let server_key = SecretKey::new(&SECP256K1, &mut OsRng::new().unwrap());
+        let server_id = pk2id(&PublicKey::from_secret_key(&SECP256K1, &server_key).unwrap());
+        let client_key = SecretKey::new(&SECP256K1, &mut OsRng::new().unwrap());
+        let capabilities = vec![CapabilityInfo { name: "eth", version: 62, length: 8 }];
+
+        let mut rlpx = RLPxStream::new(&handle, client_key, 5, "first".to_string(),
+                                       capabilities.clone(), None).unwrap();
+        assert_eq!(rlpx.client_version(), "first");
+        rlpx.set_client_version("second".to_string());
+        assert_eq!(rlpx.client_version(), "second");
+
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap(), &handle).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = listener.incoming().into_future().map_err(|(e, _)| e)
+            .and_then(move |(incoming, _)| {
+                let (stream, _) = incoming.unwrap();
+                PeerStream::incoming(stream, Arc::new(server_key), 5,
+                                     "server".to_string(), capabilities, 0)
+            });
+        rlpx.add_peer(&addr, server_id);
+
+        let (server, _) = core.run(server.join(future::poll_fn(|| rlpx.poll_new_peers()))).unwrap();
+        assert_eq!(server.remote_client_version(), "second");
+        assert_eq!(server.client_version(), "server");
+    }
+
+    #[test]
+    fn clean_and_unclean_disconnects() {
+        let mut core = Core::new().unwrap();
+        let (key, _) = key_pair();
+        let (clean_peer, unclean_peer) = (key_pair(), key_pair());
+        let mut rlpx = RLPxStream::new(&core.handle(), key, 5, "local".to_string(),
+                                       vec![CapabilityInfo { name: "eth", version: 62, length: 8 }],
+                                       None).unwrap();
+
+        let (local, mut clean) = dial(&mut core, key, clean_peer);
+        rlpx.streams.push(local);
+        let (local, mut unclean) = dial(&mut core, key, unclean_peer);
+        rlpx.streams.push(local);
+
+        core.run(future::lazy(|| -> Result<(), io::Error> {
+            // Disconnect with TooManyPeers, then close the connection
+            let mut reason = RlpStream::new_list(1);
+            reason.append(&DisconnectReason::TooManyPeers.code());
+            clean.send_reserved_message(0x01, &reason.out())?;
+            // Reserved message id nobody handles
+            unclean.send_reserved_message(0x05, &[0xc0])
+        })).unwrap();
+        drop(clean);
+
+        let mut disconnected = Vec::new();
+        core.run(future::poll_fn(|| -> Poll<(), io::Error> {
+            while disconnected.len() < 2 {
+                match try_ready!(rlpx.poll()) {
+                    Some(RLPxReceiveMessage::Disconnected { node, reason, clean }) =>
+                        disconnected.push((node, reason, clean)),
+                    _ => panic!(),
+                }
+            }
+            Ok(Async::Ready(()))
+        })).unwrap();
+
+        disconnected.sort_by_key(|&(_, _, clean)| clean);
+        assert_eq!(disconnected, vec![
+            (unclean_peer.1, None, false),
+            (clean_peer.1, Some(DisconnectReason::TooManyPeers), true),
+        ]);
+    }
+
+    #[test]
+    fn peer_info_reported() {
+        let mut core = Core::new().unwrap();
+        let (key, _) = key_pair();
+        let remote = key_pair();
+        let mut rlpx = RLPxStream::new(&core.handle(), key, 5, "local".to_string(),
+                                       vec![CapabilityInfo { name: "eth", version: 62, length: 8 }],
+                                       None).unwrap();
+        assert_eq!(rlpx.peer_info(remote.1), None);
+
+        let (local, _remote_end) = dial(&mut core, key, remote);
+        let addr = local.remote_addr();
+        rlpx.streams.push(local);
+        let info = rlpx.peer_info(remote.1).unwrap();
+        assert_eq!(info.id, remote.1);
+        assert_eq!(info.client_version, "listener");
+        assert_eq!(info.capabilities, vec![CapabilityInfo { name: "eth", version: 62, length: 8 }]);
+        assert_eq!(info.protocol_version, 5);
+        assert!(addr.is_some());
+        assert_eq!(info.remote_addr, addr);
+        assert!(info.outbound);
+        assert_eq!(rlpx.peers().iter().map(|peer| peer.id).collect::<Vec<_>>(), vec![remote.1]);
+
+        // The table only changes once the connection is reported
+        let table = rlpx.peer_table();
+        assert!(table.is_empty());
+        rlpx.peer_events.push_back(RLPxReceiveMessage::Connected {
+            node: remote.1, capabilities: info.capabilities.clone(),
+        });
+        match core.run(rlpx.into_future().map_err(|(e, _)| e)).unwrap().0 {
+            Some(RLPxReceiveMessage::Connected { node, .. }) => assert_eq!(node, remote.1),
+            _ => panic!("expected Connected"),
+        }
+        assert_eq!(table.peer(remote.1).unwrap().client_version, "listener");
+        assert_eq!(table.peers().len(), 1);
+    }
+
+    #[test]
+    fn disconnect_reason_sent() {
+        let mut core = Core::new().unwrap();
+        let (key, _) = key_pair();
+        let remote = key_pair();
+        let mut rlpx = RLPxStream::new(&core.handle(), key, 5, "local".to_string(),
+                                       vec![CapabilityInfo { name: "eth", version: 62, length: 8 }],
+                                       None).unwrap();
+        let (local, remote_end) = dial(&mut core, key, remote);
+        rlpx.streams.push(local);
+
+        rlpx.disconnect_peer(remote.1, DisconnectReason::SubprotocolError);
+        assert!(rlpx.streams.is_empty());
+        let (message, remote_end) = match core.run(remote_end.into_future()) {
+            Ok(result) => result,
+            Err((e, _)) => panic!("receiving failed: {}", e),
+        };
+        assert!(message.is_none());
+        assert_eq!(remote_end.disconnect_reason(), Some(DisconnectReason::SubprotocolError));
+    }
+
+    #[test]
+    fn refusal_reported() {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let (key, _) = key_pair();
+        let (remote_key, remote_id) = key_pair();
+        let mut rlpx = RLPxStream::new(&handle, key, 5, "local".to_string(), Vec::new(), None).unwrap();
+
+        let tcp = TcpListener::bind(&"127.0.0.1:0".parse().unwrap(), &handle).unwrap();
+        let addr = tcp.local_addr().unwrap();
+        // Answer the Hello with a Disconnect for TooManyPeers
+        let remote = tcp.incoming().into_future().map_err(|(e, _)| e)
+            .and_then(move |(incoming, _)| ECIESStream::incoming(incoming.unwrap().0, Arc::new(remote_key)))
+            .and_then(|socket| socket.send(vec![0x01, 0xc1, DisconnectReason::TooManyPeers.code()]));
+        rlpx.add_peer(&addr, remote_id);
+
+        let local = future::poll_fn(|| -> Poll<_, io::Error> {
+            match try_ready!(rlpx.poll()) {
+                Some(RLPxReceiveMessage::Disconnected { node, reason, clean }) =>
+                    Ok(Async::Ready((node, reason, clean))),
+                _ => panic!(),
+            }
+        });
+        let (_remote, disconnected) = core.run(remote.join(local)).unwrap();
+        assert_eq!(disconnected, (remote_id, Some(DisconnectReason::TooManyPeers), true));
+        assert!(rlpx.active_peers().is_empty());
+    }
+
+    #[test]
+    fn send_to_selected_peers() {
+        let mut core = Core::new().unwrap();
+        let (key, _) = key_pair();
+        let mut rlpx = RLPxStream::new(&core.handle(), key, 5, "local".to_string(),
+                                       vec![CapabilityInfo { name: "eth", version: 62, length: 8 }],
+                                       None).unwrap();
+        let mut remotes = Vec::new();
+        for _ in 0..3 {
+            let (remote_key, remote_id) = key_pair();
+            let (local, remote) = dial(&mut core, key, (remote_key, remote_id));
+            rlpx.streams.push(local);
+            remotes.push((remote_id, remote));
+        }
+        let excluded = remotes[1].0;
+
+        // Each selective message is followed by one to all peers, so
+        // that every remote knows when it has seen everything
+        let messages = vec![
+            (RLPxNode::AllExcept(excluded), 1), (RLPxNode::All, 2),
+            (RLPxNode::Random(2), 3), (RLPxNode::All, 4),
+        ];
+        for (node, id) in messages {
+            rlpx = core.run(rlpx.send(RLPxSendMessage {
+                node, capability_name: "eth", id, data: vec![0xc0],
+            })).unwrap();
+        }
+
+        let mut random_count = 0;
+        for (remote_id, remote) in remotes {
+            let received: Vec<usize> = core.run(
+                remote.map(|(_, id, _)| id).take_while(|&id| Ok(id != 4)).collect()
+            ).unwrap();
+            assert_eq!(received.contains(&1), remote_id != excluded);
+            if received.contains(&3) {
+                random_count += 1;
+            }
+        }
+        assert_eq!(random_count, 2);
+    }
+
+    #[test]
+    fn send_without_selected_peer_dropped() {
+        let mut core = Core::new().unwrap();
+        let (key, _) = key_pair();
+        let mut rlpx = RLPxStream::new(&core.handle(), key, 5, "local".to_string(),
+                                       vec![CapabilityInfo { name: "eth", version: 62, length: 8 }],
+                                       None).unwrap();
+        let message = |node| RLPxSendMessage { node, capability_name: "eth", id: 1, data: vec![0xc0] };
+        assert_eq!(rlpx.start_send(message(RLPxNode::Random(2))).unwrap(), AsyncSink::Ready);
+
+        let (remote_key, remote_id) = key_pair();
+        let (local, _remote) = dial(&mut core, key, (remote_key, remote_id));
+        rlpx.streams.push(local);
+        assert_eq!(rlpx.start_send(message(RLPxNode::AllExcept(remote_id))).unwrap(), AsyncSink::Ready);
+        assert_eq!(rlpx.start_send(message(RLPxNode::Peer(H512::random()))).unwrap(), AsyncSink::Ready);
+    }
+
+    /// Connect to a listening RLPx stream, polling it until it took
+    /// the new connection
+    fn connect_to(
+        core: &mut Core, rlpx: &mut RLPxStream, addr: &::std::net::SocketAddr, key: SecretKey
+    ) -> PeerStream {
+        let client = PeerStream::connect(addr, &core.handle(), Arc::new(key), rlpx.id, 5,
+                                         "dialer".to_string(),
+                                         vec![CapabilityInfo { name: "eth", version: 62, length: 8 }],
+                                         0, Default::default());
+        let mut accepted = false;
+        let server = future::poll_fn(|| -> Poll<(), io::Error> {
+            rlpx.poll_new_peers()?;
+            accepted |= !rlpx.incoming_futures.is_empty();
+            if accepted && rlpx.incoming_futures.is_empty() { Ok(Async::Ready(())) } else { Ok(Async::NotReady) }
+        });
+        match core.run(client.join(server)) {
+            Ok((peer, ())) => peer,
+            Err(e) => panic!("connection failed: {}", e),
+        }
+    }
+
+    #[test]
+    fn peer_limits() {
+        let mut core = Core::new().unwrap();
+        let addr = ::std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let (key, _) = key_pair();
+        let mut rlpx = RLPxStream::new(&core.handle(), key, 5, "local".to_string(),
+                                       vec![CapabilityInfo { name: "eth", version: 62, length: 8 }],
+                                       Some(&addr)).unwrap();
+        rlpx.set_peer_limits(Some(1), Some(0));
+
+        rlpx.add_peer(&addr, H512::random());
+        assert_eq!(rlpx.peer_counts(), (0, 0));
+        assert!(rlpx.active_peers().is_empty());
+
+        let first = connect_to(&mut core, &mut rlpx, &addr, key_pair().0);
+        assert_eq!(rlpx.peer_counts(), (1, 0));
+
+        // The second inbound peer is told there are too many peers
+        let second = connect_to(&mut core, &mut rlpx, &addr, key_pair().0);
+        assert_eq!(rlpx.peer_counts(), (1, 0));
+        let (message, second) = match core.run(second.into_future()) {
+            Ok(result) => result,
+            Err((e, _)) => panic!("receiving failed: {}", e),
+        };
+        assert!(message.is_none());
+        assert_eq!(second.disconnect_reason(), Some(DisconnectReason::TooManyPeers));
+        drop(first);
+    }
+
+    #[test]
+    fn peers_per_ip_limited() {
+        let mut core = Core::new().unwrap();
+        let addr = ::std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let (key, _) = key_pair();
+        let mut rlpx = RLPxStream::new(&core.handle(), key, 5, "local".to_string(),
+                                       vec![CapabilityInfo { name: "eth", version: 62, length: 8 }],
+                                       Some(&addr)).unwrap();
+        rlpx.set_max_peers_per_ip(Some(1));
+
+        let _first = connect_to(&mut core, &mut rlpx, &addr, key_pair().0);
+        assert_eq!(rlpx.peer_counts(), (1, 0));
+        rlpx.add_peer(&addr, H512::random());
+        assert_eq!(rlpx.peer_counts(), (1, 0));
+
+        let second = connect_to(&mut core, &mut rlpx, &addr, key_pair().0);
+        assert_eq!(rlpx.peer_counts(), (1, 0));
+        let (message, second) = match core.run(second.into_future()) {
+            Ok(result) => result,
+            Err((e, _)) => panic!("receiving failed: {}", e),
+        };
+        assert!(message.is_none());
+        assert_eq!(second.disconnect_reason(), Some(DisconnectReason::TooManyPeers));
+
+        let (trusted_key, trusted_id) = key_pair();
+        rlpx.add_trusted_peer(trusted_id);
+        let _trusted = connect_to(&mut core, &mut rlpx, &addr, trusted_key);
+        assert_eq!(rlpx.peer_counts(), (2, 0));
+    }
+
+    #[test]
+    fn trusted_peers_exceed_limits() {
+        let mut core = Core::new().unwrap();
+        let addr = ::std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let (key, _) = key_pair();
+        let mut rlpx = RLPxStream::new(&core.handle(), key, 5, "local".to_string(),
+                                       vec![CapabilityInfo { name: "eth", version: 62, length: 8 }],
+                                       Some(&addr)).unwrap();
+        rlpx.set_peer_limits(Some(0), Some(0));
+
+        let (inbound_key, inbound_id) = key_pair();
+        rlpx.add_trusted_peer(inbound_id);
+        let _inbound = connect_to(&mut core, &mut rlpx, &addr, inbound_key);
+        assert_eq!(rlpx.peer_counts(), (1, 0));
+        assert_eq!(rlpx.active_peers(), &[inbound_id]);
+
+        let outbound_id = H512::random();
+        rlpx.add_peer(&addr, outbound_id);
+        assert_eq!(rlpx.peer_counts(), (1, 0));
+        rlpx.add_trusted_peer(outbound_id);
+        rlpx.add_peer(&addr, outbound_id);
+        assert_eq!(rlpx.peer_counts(), (1, 1));
+
+        // Bans still apply
+        let banned_id = H512::random();
+        rlpx.add_trusted_peer(banned_id);
+        rlpx.ban_peer(banned_id);
+        rlpx.add_peer(&addr, banned_id);
+        assert_eq!(rlpx.peer_counts(), (1, 1));
+        rlpx.unban_peer(banned_id);
+        rlpx.ban_ip(addr.ip());
+        rlpx.add_peer(&addr, banned_id);
+        assert_eq!(rlpx.peer_counts(), (1, 1));
+        rlpx.unban_ip(addr.ip());
+        rlpx.set_net_restrict(Some(NetRestrict::parse("10.0.0.0/8", "").unwrap()));
+        rlpx.add_peer(&addr, banned_id);
+        assert_eq!(rlpx.peer_counts(), (1, 1));
+    }
 }
@@ -3,17 +3,30 @@ use bigint::H512;
 use std::io;
 use std::net::SocketAddr;
 use ecies::ECIESStream;
+use socket::SocketBuffers;
+use devp2p_common::NodeSigner;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
 use tokio_core::reactor::Handle;
 use tokio_core::net::TcpStream;
 use tokio_io::{AsyncRead, AsyncWrite};
 use tokio_io::codec::{Framed, Encoder, Decoder};
-use secp256k1::{self, SECP256K1};
-use secp256k1::key::{PublicKey, SecretKey};
 use util::pk2id;
+use errors::{HandshakeError, RefusedError};
 use futures::future;
 use futures::{Poll, Async, StartSend, AsyncSink, Future, Stream, Sink};
+use std::cmp::min;
+use snap;
 use rlp;
 
+/// Lowest base protocol version that compresses message payloads
+/// with snappy
+pub const SNAPPY_PROTOCOL_VERSION: usize = 5;
+
+/// Maximum size of a decompressed message payload, as required by
+/// the devp2p specification
+const MAX_DECOMPRESSED_LEN: usize = 16 * 1024 * 1024;
+
 #[derive(Clone, Debug, Copy, PartialEq, Eq)]
 /// Capability information
 pub struct CapabilityInfo {
@@ -22,6 +35,121 @@ pub struct CapabilityInfo {
     pub length: usize,
 }
 
+/// Number of message ids reserved for the base protocol, after which
+/// the shared capabilities' ids start
+pub const RESERVED_MESSAGE_IDS: usize = 0x10;
+
+#[derive(Clone, Debug, PartialEq, Eq, Default)]
+/// Message id ranges of the shared capabilities of a connection.
+/// Capabilities are allotted consecutive ranges after the reserved
+/// ids, in alphabetical order of their names.
+pub struct CapabilityOffsets {
+    capabilities: Vec<CapabilityInfo>,
+}
+
+impl CapabilityOffsets {
+    /// Create the offsets of the given shared capabilities
+    pub fn new(mut capabilities: Vec<CapabilityInfo>) -> Self {
+        capabilities.sort_by_key(|cap| cap.name);
+        CapabilityOffsets { capabilities }
+    }
+
+    /// Shared capabilities in id order
+    pub fn capabilities(&self) -> &[CapabilityInfo] {
+        &self.capabilities
+    }
+
+    /// Resolve an on-wire message id into its capability and the id
+    /// relative to it. `None` for reserved or out of range ids.
+    pub fn resolve(&self, wire_id: usize) -> Option<(CapabilityInfo, usize)> {
+        if wire_id < RESERVED_MESSAGE_IDS {
+            return None;
+        }
+
+        let mut id = wire_id - RESERVED_MESSAGE_IDS;
+        for cap in &self.capabilities {
+            if id < cap.length {
+                return Some((*cap, id));
+            }
+            id -= cap.length;
+        }
+        None
+    }
+
+    /// On-wire message id of a message of the named capability.
+    /// `None` if the capability is not shared or the id is out of its
+    /// range.
+    pub fn wire_id(&self, name: &str, id: usize) -> Option<usize> {
+        let mut offset = RESERVED_MESSAGE_IDS;
+        for cap in &self.capabilities {
+            if cap.name == name {
+                return if id < cap.length { Some(offset + id) } else { None };
+            }
+            offset += cap.length;
+        }
+        None
+    }
+}
+
+#[derive(Clone, Debug, Copy, PartialEq, Eq)]
+/// Reason of a base protocol Disconnect message
+pub enum DisconnectReason {
+    DisconnectRequested,
+    TcpSubsystemError,
+    BreachOfProtocol,
+    UselessPeer,
+    TooManyPeers,
+    AlreadyConnected,
+    IncompatibleVersion,
+    InvalidIdentity,
+    ClientQuitting,
+    UnexpectedIdentity,
+    ConnectedToSelf,
+    PingTimeout,
+    SubprotocolError,
+}
+
+impl DisconnectReason {
+    /// Reason of the given wire code, if it is a known one
+    pub fn from_code(code: u8) -> Option<DisconnectReason> {
+        Some(match code {
+            0x00 => DisconnectReason::DisconnectRequested,
+            0x01 => DisconnectReason::TcpSubsystemError,
+            0x02 => DisconnectReason::BreachOfProtocol,
+            0x03 => DisconnectReason::UselessPeer,
+            0x04 => DisconnectReason::TooManyPeers,
+            0x05 => DisconnectReason::AlreadyConnected,
+            0x06 => DisconnectReason::IncompatibleVersion,
+            0x07 => DisconnectReason::InvalidIdentity,
+            0x08 => DisconnectReason::ClientQuitting,
+            0x09 => DisconnectReason::UnexpectedIdentity,
+            0x0a => DisconnectReason::ConnectedToSelf,
+            0x0b => DisconnectReason::PingTimeout,
+            0x10 => DisconnectReason::SubprotocolError,
+            _ => return None,
+        })
+    }
+
+    /// Wire code of the reason
+    pub fn code(&self) -> u8 {
+        match *self {
+            DisconnectReason::DisconnectRequested => 0x00,
+            DisconnectReason::TcpSubsystemError => 0x01,
+            DisconnectReason::BreachOfProtocol => 0x02,
+            DisconnectReason::UselessPeer => 0x03,
+            DisconnectReason::TooManyPeers => 0x04,
+            DisconnectReason::AlreadyConnected => 0x05,
+            DisconnectReason::IncompatibleVersion => 0x06,
+            DisconnectReason::InvalidIdentity => 0x07,
+            DisconnectReason::ClientQuitting => 0x08,
+            DisconnectReason::UnexpectedIdentity => 0x09,
+            DisconnectReason::ConnectedToSelf => 0x0a,
+            DisconnectReason::PingTimeout => 0x0b,
+            DisconnectReason::SubprotocolError => 0x10,
+        }
+    }
+}
+
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct CapabilityMessage {
     pub name: String,
@@ -77,15 +205,134 @@ impl Decodable for HelloMessage {
     }
 }
 
+/// Encode a message into a RLPx frame. The payload is snappy
+/// compressed if the negotiated base protocol version supports it.
+pub fn encode_frame(
+    protocol_version: usize, id: usize, data: &[u8]
+) -> Result<Vec<u8>, io::Error> {
+    let first = rlp::encode(&id);
+    assert!(first.len() == 1);
+
+    let mut ret: Vec<u8> = Vec::new();
+    ret.push(first[0]);
+    if protocol_version >= SNAPPY_PROTOCOL_VERSION {
+        let compressed = snap::raw::Encoder::new().compress_vec(data)
+            .map_err(|_| io::Error::new(io::ErrorKind::Other, "snappy compression failed"))?;
+        ret.extend_from_slice(&compressed);
+    } else {
+        ret.extend_from_slice(data);
+    }
+    Ok(ret)
+}
+
+/// Decode a RLPx frame into its message id and payload, reverting the
+/// snappy compression if the negotiated base protocol version uses it.
+pub fn decode_frame(
+    protocol_version: usize, frame: &[u8]
+) -> Result<(usize, Vec<u8>), io::Error> {
+    if frame.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::Other,
+                                  "message id parsing failed (empty)"));
+    }
+
+    let message_id: usize = match UntrustedRlp::new(&frame[0..1]).as_val() {
+        Ok(message_id) => message_id,
+        Err(_) => return Err(io::Error::new(io::ErrorKind::Other,
+                                            "message id parsing failed (invalid)")),
+    };
+
+    if protocol_version < SNAPPY_PROTOCOL_VERSION {
+        return Ok((message_id, (&frame[1..]).into()));
+    }
+
+    let len = snap::raw::decompress_len(&frame[1..])
+        .map_err(|_| io::Error::new(io::ErrorKind::Other, "snappy decompression failed"))?;
+    if len > MAX_DECOMPRESSED_LEN {
+        return Err(io::Error::new(io::ErrorKind::Other,
+                                  "snappy decompression failed (too big)"));
+    }
+    let data = snap::raw::Decoder::new().decompress_vec(&frame[1..])
+        .map_err(|_| io::Error::new(io::ErrorKind::Other, "snappy decompression failed"))?;
+    Ok((message_id, data))
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// What we know about a connected peer
+pub struct PeerInfo {
+    pub id: H512,
+    /// Client version the remote advertised in its Hello
+    pub client_version: String,
+    /// Capabilities shared with the remote
+    pub capabilities: Vec<CapabilityInfo>,
+    pub protocol_version: usize,
+    pub remote_addr: Option<SocketAddr>,
+    /// Whether we dialed the peer
+    pub outbound: bool,
+    /// Time since the Hello handshake completed
+    pub connected_for: Duration,
+}
+
+#[derive(Debug, Clone, Default)]
+/// Connected peers of a RLPx stream, which the stream updates as
+/// peers connect and disconnect. Clones share the same table, so it
+/// can be read from other threads while the stream is polled.
+pub struct PeerTable {
+    peers: Arc<RwLock<Vec<(PeerInfo, Instant)>>>,
+}
+
+impl PeerTable {
+    /// Summaries of the connected peers
+    pub fn peers(&self) -> Vec<PeerInfo> {
+        self.peers.read().unwrap().iter().map(|&(ref info, connected_at)| PeerInfo {
+            connected_for: connected_at.elapsed(),
+            ..info.clone()
+        }).collect()
+    }
+
+    /// Summary of a connected peer, `None` if the peer is not
+    /// connected
+    pub fn peer(&self, remote_id: H512) -> Option<PeerInfo> {
+        self.peers.read().unwrap().iter()
+            .find(|peer| peer.0.id == remote_id)
+            .map(|&(ref info, connected_at)| PeerInfo {
+                connected_for: connected_at.elapsed(),
+                ..info.clone()
+            })
+    }
+
+    /// Number of connected peers
+    pub fn len(&self) -> usize {
+        self.peers.read().unwrap().len()
+    }
+
+    /// Whether no peer is connected
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Replace the table with the given peer streams
+    pub(crate) fn update(&self, streams: &[PeerStream]) {
+        *self.peers.write().unwrap() = streams.iter()
+            .map(|peer| (peer.info(), peer.connected_at()))
+            .collect();
+    }
+}
+
 /// Peer stream of a RLPx
 pub struct PeerStream {
     stream: ECIESStream,
     protocol_version: usize,
     client_version: String,
-    shared_capabilities: Vec<CapabilityInfo>,
+    remote_client_version: String,
+    offsets: CapabilityOffsets,
     port: u16,
     id: H512,
     remote_id: H512,
+    outbound: bool,
+    remote_addr: Option<SocketAddr>,
+    disconnect_reason: Option<DisconnectReason>,
+    disconnected: bool,
+    connected_at: Instant,
 }
 
 impl PeerStream {
@@ -96,45 +343,114 @@ impl PeerStream {
 
     /// Get all capabilities of this peer stream
     pub fn capabilities(&self) -> &[CapabilityInfo] {
-        &self.shared_capabilities
+        self.offsets.capabilities()
+    }
+
+    /// Message id ranges of the shared capabilities
+    pub fn capability_offsets(&self) -> &CapabilityOffsets {
+        &self.offsets
+    }
+
+    /// Base protocol version agreed with the remote, which is the
+    /// minimum of both sides' Hello
+    pub fn protocol_version(&self) -> usize {
+        self.protocol_version
+    }
+
+    /// Whether we dialed this peer, as opposed to accepting its
+    /// connection
+    pub fn is_outbound(&self) -> bool {
+        self.outbound
+    }
+
+    /// Address of the remote, `None` for streams created from an
+    /// existing ECIES stream
+    pub fn remote_addr(&self) -> Option<SocketAddr> {
+        self.remote_addr
+    }
+
+    /// Reason given by the remote in its Disconnect message, if it
+    /// sent one with a known reason
+    pub fn disconnect_reason(&self) -> Option<DisconnectReason> {
+        self.disconnect_reason
+    }
+
+    /// When the Hello handshake completed
+    pub fn connected_at(&self) -> Instant {
+        self.connected_at
+    }
+
+    /// Summary of the remote and the connection to it
+    pub fn info(&self) -> PeerInfo {
+        PeerInfo {
+            id: self.remote_id,
+            client_version: self.remote_client_version.clone(),
+            capabilities: self.capabilities().into(),
+            protocol_version: self.protocol_version,
+            remote_addr: self.remote_addr,
+            outbound: self.outbound,
+            connected_for: self.connected_at.elapsed(),
+        }
+    }
+
+    /// Client version we advertised in our Hello
+    pub fn client_version(&self) -> &str {
+        &self.client_version
+    }
+
+    /// Client version the remote advertised in its Hello
+    pub fn remote_client_version(&self) -> &str {
+        &self.remote_client_version
     }
 
     /// Connect to a peer over TCP
     pub fn connect(
         addr: &SocketAddr, handle: &Handle,
-        secret_key: SecretKey, remote_id: H512,
+        signer: Arc<dyn NodeSigner>, remote_id: H512,
         protocol_version: usize, client_version: String,
-        capabilities: Vec<CapabilityInfo>, port: u16
+        capabilities: Vec<CapabilityInfo>, port: u16,
+        buffers: SocketBuffers
     ) -> Box<Future<Item = PeerStream, Error = io::Error>> {
+        let remote_addr = *addr;
         Box::new(
-            ECIESStream::connect(addr, handle, secret_key.clone(), remote_id)
+            ECIESStream::connect(addr, handle, signer.clone(), remote_id, buffers)
                 .and_then(move |socket| {
-                    PeerStream::new(socket, secret_key, protocol_version,
+                    PeerStream::new(socket, signer, protocol_version,
                                     client_version, capabilities, port)
+                })
+                .map(move |mut peer| {
+                    peer.outbound = true;
+                    peer.remote_addr = Some(remote_addr);
+                    peer
                 }))
     }
 
     /// Incoming peer stream over TCP
     pub fn incoming(
-        stream: TcpStream, secret_key: SecretKey,
+        stream: TcpStream, signer: Arc<dyn NodeSigner>,
         protocol_version: usize, client_version: String,
         capabilities: Vec<CapabilityInfo>, port: u16
     ) -> Box<Future<Item = PeerStream, Error = io::Error>> {
+        let remote_addr = stream.peer_addr().ok();
         Box::new(
-            ECIESStream::incoming(stream, secret_key.clone())
+            ECIESStream::incoming(stream, signer.clone())
                 .and_then(move |socket| {
-                    PeerStream::new(socket, secret_key, protocol_version,
+                    PeerStream::new(socket, signer, protocol_version,
                                     client_version, capabilities, port)
+                })
+                .map(move |mut peer| {
+                    peer.remote_addr = remote_addr;
+                    peer
                 }))
     }
 
     /// Create a new peer stream
     pub fn new(
-        ecies_stream: ECIESStream, secret_key: SecretKey,
+        ecies_stream: ECIESStream, signer: Arc<dyn NodeSigner>,
         protocol_version: usize, client_version: String,
         capabilities: Vec<CapabilityInfo>, port: u16
     ) -> Box<Future<Item = PeerStream, Error = io::Error>> {
-        let public_key = match PublicKey::from_secret_key(&SECP256K1, &secret_key) {
+        let public_key = match signer.public_key() {
             Ok(key) => key,
             Err(_) => return Box::new(future::err(
                 io::Error::new(io::ErrorKind::Other, "SECP256K1 public key error")))
@@ -179,24 +495,27 @@ impl PeerStream {
                 debug!("receiving hello message ...");
                 if hello.is_none() {
                     debug!("hello failed because of no value");
-                    return Err(io::Error::new(io::ErrorKind::Other, "hello failed (no value)"));
+                    return Err(io::Error::from(HandshakeError("hello failed (no value)")));
                 }
                 let hello = hello.unwrap();
 
                 let message_id_rlp = UntrustedRlp::new(&hello[0..1]);
                 let message_id: Result<usize, rlp::DecoderError> = message_id_rlp.as_val();
                 match message_id {
+                    Ok(0x01) => {
+                        let reason = decode_disconnect_reason(&hello[1..]);
+                        debug!("hello failed because the peer disconnected, reason: {:?}", reason);
+                        return Err(io::Error::from(RefusedError(reason)));
+                    },
                     Ok(message_id) => {
                         if message_id != 0 {
                             error!("hello failed because message id is not 0 but {}", message_id);
-                            return Err(io::Error::new(io::ErrorKind::Other,
-                                                      "hello failed (message id)"));
+                            return Err(io::Error::from(HandshakeError("hello failed (message id)")));
                         }
                     },
                     Err(_) => {
                         debug!("hello failed because message id cannot be parsed");
-                        return Err(io::Error::new(io::ErrorKind::Other,
-                                                  "hello failed (message id parsing)"));
+                        return Err(io::Error::from(HandshakeError("hello failed (message id parsing)")));
                     }
                 }
 
@@ -226,19 +545,27 @@ impl PeerStream {
                             });
                         }
 
-                        shared_capabilities.sort_by_key(|v| v.name.clone());
+
+                        let protocol_version = min(protocol_version, val.protocol_version);
+                        debug!("negotiated base protocol version {}", protocol_version);
 
                         Ok(PeerStream {
                             remote_id: transport.remote_id(),
                             stream: transport,
                             client_version: nonhello_client_version,
+                            remote_client_version: val.client_version,
                             protocol_version, port, id,
-                            shared_capabilities,
+                            offsets: CapabilityOffsets::new(shared_capabilities),
+                            outbound: false,
+                            remote_addr: None,
+                            disconnect_reason: None,
+                            disconnected: false,
+                            connected_at: Instant::now(),
                         })
                     },
                     Err(_) => {
                         debug!("hello failed because message rlp parsing failed");
-                        Err(io::Error::new(io::ErrorKind::Other, "hello failed (rlp error)"))
+                        Err(io::Error::from(HandshakeError("hello failed (rlp error)")))
                     }
                 }
             });
@@ -246,23 +573,36 @@ impl PeerStream {
         Box::new(stream)
     }
 
+    /// Send a base protocol message
+    pub(crate) fn send_reserved_message(
+        &mut self, message_id: usize, data: &[u8]
+    ) -> Result<(), io::Error> {
+        let payload = encode_frame(self.protocol_version, message_id, data)?;
+        self.stream.start_send(payload)?;
+        self.stream.poll_complete()?;
+        Ok(())
+    }
+
+    /// Send a Disconnect message with the given reason. The
+    /// connection is left open for the remote to close.
+    pub fn send_disconnect(&mut self, reason: DisconnectReason) -> Result<(), io::Error> {
+        let mut s = RlpStream::new_list(1);
+        s.append(&reason.code());
+        self.send_reserved_message(0x01, &s.out())
+    }
+
     fn handle_reserved_message(
         &mut self, message_id: usize, data: Vec<u8>
     ) -> Result<(), io::Error> {
         match message_id {
             0x01 /* disconnect */ => {
-                let reason: Result<usize, rlp::DecoderError> = UntrustedRlp::new(&data).val_at(0);
-                debug!("received disconnect message, reason: {:?}", reason);
-                return Err(io::Error::new(io::ErrorKind::Other,
-                                          "explicit disconnect"));
+                self.disconnect_reason = decode_disconnect_reason(&data);
+                debug!("received disconnect message, reason: {:?}", self.disconnect_reason);
+                self.disconnected = true;
             },
             0x02 /* ping */ => {
                 debug!("received ping message data {:?}", data);
-                let mut payload: Vec<u8> = rlp::encode(&0x03usize /* pong */).to_vec();
-                payload.append(&mut rlp::EMPTY_LIST_RLP.to_vec());
-                debug!("sending pong message payload {:?}", payload);
-                self.stream.start_send(payload)?;
-                self.stream.poll_complete()?;
+                self.send_reserved_message(0x03 /* pong */, &rlp::EMPTY_LIST_RLP)?;
             },
             0x03 /* pong */ => {
                 debug!("received pong message");
@@ -277,45 +617,50 @@ impl PeerStream {
     }
 }
 
+/// Reason of a Disconnect message payload, if it is a known one
+fn decode_disconnect_reason(data: &[u8]) -> Option<DisconnectReason> {
+    let rlp = UntrustedRlp::new(data);
+    // The reason is usually wrapped in a list, but some clients send
+    // it bare
+    let reason: Result<u8, rlp::DecoderError> = if rlp.is_list() {
+        rlp.val_at(0)
+    } else {
+        rlp.as_val()
+    };
+    reason.ok().and_then(DisconnectReason::from_code)
+}
+
 impl Stream for PeerStream {
     type Item = (CapabilityInfo, usize, Vec<u8>);
     type Error = io::Error;
 
+    /// Ends cleanly, with `None`, when the remote closes the connection
+    /// or sends a Disconnect message
     fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        if self.disconnected {
+            return Ok(Async::Ready(None));
+        }
+
         match try_ready!(self.stream.poll()) {
             Some(val) => {
                 debug!("received peer message: {:?}", val);
-                let message_id_rlp = UntrustedRlp::new(&val[0..1]);
-                let message_id: Result<usize, rlp::DecoderError> = message_id_rlp.as_val();
-
-                let (cap, id) = match message_id {
-                    Ok(message_id) => {
-                        if message_id < 0x10 {
-                            self.handle_reserved_message(message_id, (&val[1..]).into())?;
-                            return Ok(Async::NotReady);
-                        }
+                let (message_id, data) = decode_frame(self.protocol_version, &val)?;
 
-                        let mut message_id = message_id - 0x10;
-                        let mut index = 0;
-                        for cap in &self.shared_capabilities {
-                            if message_id > cap.length {
-                                message_id = message_id - cap.length;
-                                index = index + 1;
-                            }
-                        }
-                        if index >= self.shared_capabilities.len() {
-                            return Err(io::Error::new(io::ErrorKind::Other,
-                                                      "message id parsing failed (too big)"));
-                        }
-                        (self.shared_capabilities[index].clone(), message_id)
-                    },
-                    Err(_) => {
-                        return Err(io::Error::new(io::ErrorKind::Other,
-                                                  "message id parsing failed (invalid)"));
+                if message_id < RESERVED_MESSAGE_IDS {
+                    self.handle_reserved_message(message_id, data)?;
+                    if self.disconnected {
+                        return Ok(Async::Ready(None));
                     }
+                    return Ok(Async::NotReady);
+                }
+
+                let (cap, message_id) = match self.offsets.resolve(message_id) {
+                    Some(val) => val,
+                    None => return Err(io::Error::new(io::ErrorKind::Other,
+                                                      "message id parsing failed (too big)")),
                 };
 
-                Ok(Async::Ready(Some((cap, id, (&val[1..]).into()))))
+                Ok(Async::Ready(Some((cap, message_id, data))))
             },
             None => Ok(Async::Ready(None)),
         }
@@ -327,41 +672,15 @@ impl Sink for PeerStream {
     type SinkError = io::Error;
 
     fn start_send(&mut self, (cap_name, id, data): (&'static str, usize, Vec<u8>)) -> StartSend<Self::SinkItem, Self::SinkError> {
-        let cap = self.shared_capabilities.iter().find(|cap| {
-            cap.name == cap_name
-        });
-
-        if cap.is_none() {
-            debug!("giving up sending cap {} of id {} to 0x{:x} because remote does not support.",
-                   cap_name, id, self.remote_id());
-            return Ok(AsyncSink::Ready);
-        }
-
-        let cap = *cap.unwrap();
-
-        if id >= cap.length {
-            debug!("giving up sending cap {} of id {} to 0x{:x} because it is too big.",
-                   cap_name, id, self.remote_id());
-            return Ok(AsyncSink::Ready);
-        }
-
-        let mut message_id = 0x10;
-        for scap in &self.shared_capabilities {
-            if scap != &cap {
-                message_id = message_id + scap.length;
-            } else {
-                break;
-            }
-        }
-        message_id = message_id + id;
-        let first = rlp::encode(&message_id);
-        assert!(first.len() == 1);
-
-        let mut ret: Vec<u8> = Vec::new();
-        ret.push(first[0]);
-        for d in &data {
-            ret.push(*d);
-        }
+        let message_id = match self.offsets.wire_id(cap_name, id) {
+            Some(message_id) => message_id,
+            None => {
+                debug!("giving up sending cap {} of id {} to 0x{:x} because remote does not support it or it is too big.",
+                       cap_name, id, self.remote_id());
+                return Ok(AsyncSink::Ready);
+            },
+        };
+        let ret = encode_frame(self.protocol_version, message_id, &data)?;
 
         match self.stream.start_send(ret)? {
             AsyncSink::Ready => Ok(AsyncSink::Ready),
@@ -373,3 +692,114 @@ impl Sink for PeerStream {
         self.stream.poll_complete()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{PeerStream, CapabilityInfo, CapabilityOffsets, encode_frame, decode_frame};
+    use socket::SocketBuffers;
+    use tokio_core::reactor::Core;
+    use tokio_core::net::TcpListener;
+    use secp256k1::SECP256K1;
+    use secp256k1::key::{PublicKey, SecretKey};
+    use futures::{Future, Stream, Sink};
+    use rand::os::OsRng;
+    use util::pk2id;
+    use std::sync::Arc;
+
+    fn connect_pair(
+        client_version: usize, server_version: usize
+    ) -> (Core, PeerStream, PeerStream) {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+
+        let server_key = SecretKey::new(&SECP256K1, &mut OsRng::new().unwrap());
+        let server_id = pk2id(&PublicKey::from_secret_key(&SECP256K1, &server_key).unwrap());
+        let client_key = SecretKey::new(&SECP256K1, &mut OsRng::new().unwrap());
+        let capabilities = vec![CapabilityInfo { name: "eth", version: 62, length: 8 }];
+        let server_capabilities = capabilities.clone();
+
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap(), &handle).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = listener.incoming().into_future().map_err(|(e, _)| e)
+            .and_then(move |(incoming, _)| {
+                let (stream, _) = incoming.unwrap();
+                PeerStream::incoming(stream, Arc::new(server_key), server_version,
+                                     "server".to_string(), server_capabilities, 0)
+            });
+        let client = PeerStream::connect(&addr, &handle, Arc::new(client_key), server_id,
+                                         client_version, "client".to_string(),
+                                         capabilities, 0, SocketBuffers::default());
+
+        let (client, server) = core.run(client.join(server)).unwrap();
+        (core, client, server)
+    }
+
+    #[test]
+    fn capability_offsets() {
+        let offsets = CapabilityOffsets::new(vec![
+            CapabilityInfo { name: "eth", version: 62, length: 8 },
+            CapabilityInfo { name: "bzz", version: 1, length: 2 },
+            CapabilityInfo { name: "les", version: 2, length: 21 },
+        ]);
+
+        assert_eq!(offsets.resolve(0x0f), None);
+        assert_eq!(offsets.resolve(0x11).unwrap().0.name, "bzz");
+        assert_eq!(offsets.resolve(0x12), Some((offsets.capabilities()[1], 0)));
+        assert_eq!(offsets.resolve(0x19), Some((offsets.capabilities()[1], 7)));
+        assert_eq!(offsets.resolve(0x1a), Some((offsets.capabilities()[2], 0)));
+        assert_eq!(offsets.resolve(0x1a + 21), None);
+
+        assert_eq!(offsets.wire_id("eth", 3), Some(0x15));
+        assert_eq!(offsets.wire_id("eth", 8), None);
+        assert_eq!(offsets.wire_id("par", 0), None);
+    }
+
+    #[test]
+    fn frame_v4_uncompressed() {
+        let data = vec![0u8; 64];
+        let frame = encode_frame(4, 0x13, &data).unwrap();
+        assert_eq!(frame.len(), 1 + data.len());
+        assert_eq!(&frame[1..], &data[..]);
+        assert_eq!(decode_frame(4, &frame).unwrap(), (0x13, data));
+    }
+
+    #[test]
+    fn frame_v5_compressed() {
+        let data = vec![0u8; 64];
+        let frame = encode_frame(5, 0x13, &data).unwrap();
+        assert!(frame.len() < 1 + data.len());
+        assert_eq!(decode_frame(5, &frame).unwrap(), (0x13, data));
+    }
+
+    #[test]
+    fn negotiate_v4_v5() {
+        let (mut core, client, server) = connect_pair(4, 5);
+        assert_eq!(client.protocol_version(), 4);
+        assert_eq!(server.protocol_version(), 4);
+
+        let data = vec![1u8; 64];
+        let client = core.run(client.send(("eth", 3, data.clone()))).unwrap();
+        let (message, _) = core.run(server.into_future().map_err(|(e, _)| e)).unwrap();
+        let (cap, id, received) = message.unwrap();
+        assert_eq!(cap.name, "eth");
+        assert_eq!(id, 3);
+        assert_eq!(received, data);
+        drop(client);
+    }
+
+    #[test]
+    fn negotiate_v5_v5() {
+        let (mut core, client, server) = connect_pair(5, 5);
+        assert_eq!(client.protocol_version(), 5);
+        assert_eq!(server.protocol_version(), 5);
+
+        let data = vec![1u8; 64];
+        let server = core.run(server.send(("eth", 4, data.clone()))).unwrap();
+        let (message, _) = core.run(client.into_future().map_err(|(e, _)| e)).unwrap();
+        let (cap, id, received) = message.unwrap();
+        assert_eq!(cap.name, "eth");
+        assert_eq!(id, 4);
+        assert_eq!(received, data);
+        drop(server);
+    }
+}
@@ -0,0 +1,52 @@
+use tokio_core::net::TcpStream;
+use std::io;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+/// TCP socket buffer sizes applied to every accepted or dialed RLPx
+/// connection. `None` keeps the operating system default. The OS may
+/// clamp the requested values (Linux, for example, doubles them and
+/// caps them at `net.core.rmem_max` / `net.core.wmem_max`), so values
+/// of a few hundred KiB to a few MiB are reasonable for high-latency
+/// links.
+pub struct SocketBuffers {
+    pub recv: Option<usize>,
+    pub send: Option<usize>,
+}
+
+impl SocketBuffers {
+    /// Apply the buffer sizes to a TCP stream
+    pub fn apply(&self, stream: &TcpStream) -> Result<(), io::Error> {
+        if let Some(size) = self.recv {
+            stream.set_recv_buffer_size(size)?;
+        }
+        if let Some(size) = self.send {
+            stream.set_send_buffer_size(size)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SocketBuffers;
+    use tokio_core::reactor::Core;
+    use tokio_core::net::{TcpListener, TcpStream};
+    use futures::{Future, Stream};
+
+    #[test]
+    fn applies_buffer_sizes() {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap(), &handle).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let accept = listener.incoming().into_future().map_err(|(e, _)| e);
+        let connect = TcpStream::connect(&addr, &handle);
+        let (stream, _) = core.run(connect.join(accept)).unwrap();
+
+        let buffers = SocketBuffers { recv: Some(64 * 1024), send: Some(32 * 1024) };
+        buffers.apply(&stream).unwrap();
+        assert!(stream.recv_buffer_size().unwrap() >= 64 * 1024);
+        assert!(stream.send_buffer_size().unwrap() >= 32 * 1024);
+    }
+}
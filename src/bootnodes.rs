@@ -0,0 +1,110 @@
+//! Bootstrap nodes of the public networks. The Ethereum lists are the
+//! ones in Geth's `params/bootnodes.go`; see the Classic and Mordor lists
+//! for where those come from.
+
+use dpt::DPTNode;
+
+/// Bootstrap nodes of the Ethereum mainnet
+pub const MAINNET: &[&str] = &[
+    "enode://d860a01f9722d78051619d1e2351aba3f43f943f6f00718d1b9baa4101932a1f5011f16bb2b1bb35db20d6fe28fa0bf09636d26a87d31de9ec6203eeedb1f666@18.138.108.67:30303",
+    "enode://22a8232c3abc76a16ae9d6c3b164f98775fe226f0917b0ca871128a74a8e9630b458460865bab457221f1d448dd9791d24c4e5d88786180ac185df813a68d4de@3.209.45.79:30303",
+    "enode://2b252ab6a1d0f971d9722cb839a42cb81db019ba44c08754628ab4a823487071b5695317c8ccd085219c3a03af063495b2f1da8d18218da2d6a82981b45e6ffc@65.108.70.101:30303",
+    "enode://4aeb4ab6c14b23e2c4cfdce879c04b0748a20d8e9b59e25ded2a08143e265c6c25936e74cbc8e641e3312ca288673d91f2f93f8e277de3cfa444ecdaaf982052@157.90.35.166:30303",
+];
+
+/// Bootstrap nodes of the Sepolia testnet
+pub const SEPOLIA: &[&str] = &[
+    "enode://4e5e92199ee224a01932a377160aa432f31d0b351f84ab413a8e0a42f4f36476f8fb1cbe914af0d9aef0d51665c214cf653c651c4bbd9d5550a934f241f1682b@138.197.51.181:30303",
+    "enode://143e11fb766781d22d92a2e33f8f104cddae4411a122295ed1fdb6638de96a6ce65f5b7c964ba3763bba27961738fef7d3ecc739268f3e5e771fb4c87b6234ba@146.190.1.103:30303",
+    "enode://8b61dc2d06c3f96fddcbebb0efb29d60d3598650275dc469c22229d3e5620369b0d3dedafd929835fe7f489618f19f456fe7c0df572bf2d914a9f4e006f783a9@170.64.250.88:30303",
+    "enode://10d62eff032205fcef19497f35ca8477bea0eadfff6d769a147e895d8b2b8f8ae6341630c645c30f5df6e67547c03494ced3d9c5764e8622a26587b083b028e8@139.59.49.206:30303",
+    "enode://9e9492e2e8836114cc75f5b929784f4f46c324ad01daf87d956f98b3b6c5fcba95524d6e5cf9861dc96a2c8a171ea7105bb554a197455058de185fa870970c7c@138.68.123.152:30303",
+];
+
+/// Bootstrap nodes of the Holesky testnet
+pub const HOLESKY: &[&str] = &[
+    "enode://ac906289e4b7f12df423d654c5a962b6ebe5b3a74cc9e06292a85221f9a64a6f1cfdd6b714ed6dacef51578f92b34c60ee91e9ede9c7f8fadc4d347326d95e2b@146.190.13.128:30303",
+    "enode://a3435a0155a3e837c02f5e7f5662a2f1fbc25b48e4dc232016e1c51b544cb5b4510ef633ea3278c0e970fa8ad8141e2d4d0f9f95456c537ff05fdf9b31c15072@178.128.136.233:30303",
+];
+
+/// Bootstrap nodes of Ethereum Classic. These are the nodes the examples
+/// of this repository have connected to since they were written, not a
+/// list taken from a current Classic client, so some of them may be gone.
+pub const CLASSIC: &[&str] = &[
+    "enode://e809c4a2fec7daed400e5e28564e23693b23b2cc5a019b612505631bbe7b9ccf709c1796d2a3d29ef2b045f210caf51e3c4f5b6d3587d43ad5d6397526fa6179@174.112.32.157:30303",
+    "enode://6e538e7c1280f0a31ff08b382db5302480f775480b8e68f8febca0ceff81e4b19153c6f8bf60313b93bef2cc34d34e1df41317de0ce613a201d1660a788a03e2@52.206.67.235:30303",
+    "enode://5fbfb426fbb46f8b8c1bd3dd140f5b511da558cd37d60844b525909ab82e13a25ee722293c829e52cb65c2305b1637fa9a2ea4d6634a224d5f400bfe244ac0de@162.243.55.45:30303",
+    "enode://42d8f29d1db5f4b2947cd5c3d76c6d0d3697e6b9b3430c3d41e46b4bb77655433aeedc25d4b4ea9d8214b6a43008ba67199374a9b53633301bca0cd20c6928ab@104.155.176.151:30303",
+    "enode://814920f1ec9510aa9ea1c8f79d8b6e6a462045f09caa2ae4055b0f34f7416fca6facd3dd45f1cf1673c0209e0503f02776b8ff94020e98b6679a0dc561b4eba0@104.154.136.117:30303",
+    "enode://72e445f4e89c0f476d404bc40478b0df83a5b500d2d2e850e08eb1af0cd464ab86db6160d0fde64bd77d5f0d33507ae19035671b3c74fec126d6e28787669740@104.198.71.200:30303",
+    "enode://5cd218959f8263bc3721d7789070806b0adff1a0ed3f95ec886fb469f9362c7507e3b32b256550b9a7964a23a938e8d42d45a0c34b332bfebc54b29081e83b93@35.187.57.94:30303",
+    "enode://39abab9d2a41f53298c0c9dc6bbca57b0840c3ba9dccf42aa27316addc1b7e56ade32a0a9f7f52d6c5db4fe74d8824bcedfeaecf1a4e533cacb71cf8100a9442@144.76.238.49:30303",
+    "enode://f50e675a34f471af2438b921914b5f06499c7438f3146f6b8936f1faeb50b8a91d0d0c24fb05a66f05865cd58c24da3e664d0def806172ddd0d4c5bdbf37747e@144.76.238.49:30306",
+    "enode://6dd3ac8147fa82e46837ec8c3223d69ac24bcdbab04b036a3705c14f3a02e968f7f1adfcdb002aacec2db46e625c04bf8b5a1f85bb2d40a479b3cc9d45a444af@104.237.131.102:30303",
+];
+
+/// Bootstrap nodes of the Mordor testnet of Ethereum Classic, after
+/// core-geth's `params/bootnodes_mordor.go`. Not checked against the
+/// upstream list as of October 2026, so compare before relying on them.
+pub const MORDOR: &[&str] = &[
+    "enode://534d18fd46c5cd5ba48a68250c47cea27a1376869755ed631c94b91386328039eb607cf10dd8d0aa173f5ec21e3fb45c5d7a7aa904f97bc2557e9cb4ccc703f1@51.158.190.99:30303",
+    "enode://15b6ae4e9e18772f297c90d83645b0fbdb56667ce2d747d6d575b21d7b60c2d3cd52b11dec24e418438caf80ddc433232b3685320ed5d0e768e3972596385bfc@51.158.191.43:41235",
+    "enode://8fa15f5012ac3c47619147220b7772fcc5db0cb7fd132b5d196e7ccacb166ac1fcf83be1dace6cd288e288a85e032423b6e7e9e57f479fe7373edea045caa56b@176.9.51.216:31355",
+    "enode://34c14141b79652afc334dcd2ba4d8047946246b2310dc8e45737ebe3e6f15f9279ca4702b90bc5be12929f6194e2c3ce19a837b7fec7ebffcee9e9fe4693b504@176.9.51.216:31365",
+];
+
+/// Parse a list of `enode://` URLs known to be valid
+fn parse(urls: &[&str]) -> Vec<DPTNode> {
+    urls.iter().map(|url| url.parse().unwrap()).collect()
+}
+
+/// Nodes discovery starts from on the Ethereum mainnet
+pub fn mainnet() -> Vec<DPTNode> {
+    parse(MAINNET)
+}
+
+/// Nodes discovery starts from on Ethereum Classic
+pub fn classic() -> Vec<DPTNode> {
+    parse(CLASSIC)
+}
+
+/// Nodes discovery starts from on the Mordor testnet
+pub fn mordor() -> Vec<DPTNode> {
+    parse(MORDOR)
+}
+
+/// Nodes discovery starts from on the Sepolia testnet
+pub fn sepolia() -> Vec<DPTNode> {
+    parse(SEPOLIA)
+}
+
+/// Nodes discovery starts from on the Holesky testnet
+pub fn holesky() -> Vec<DPTNode> {
+    parse(HOLESKY)
+}
+
+#[cfg(test)]
+mod tests {
+    use dpt::DPTNode;
+    use super::{mainnet, classic, mordor, sepolia, holesky,
+                MAINNET, CLASSIC, MORDOR, SEPOLIA, HOLESKY};
+
+    #[test]
+    fn presets_parsed() {
+        for nodes in &[mainnet(), classic(), mordor(), sepolia(), holesky()] {
+            assert!(!nodes.is_empty());
+            assert!(nodes.iter().all(|node| node.tcp_port == node.udp_port));
+        }
+    }
+
+    #[test]
+    fn every_entry_parsed() {
+        for urls in &[MAINNET, CLASSIC, MORDOR, SEPOLIA, HOLESKY] {
+            for url in urls.iter() {
+                let node: DPTNode = url.parse()
+                    .unwrap_or_else(|_| panic!("bootnode {} does not parse", url));
+                assert_eq!(format!("{:x}", node.id), url[8..136]);
+            }
+        }
+    }
+}
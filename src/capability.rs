@@ -0,0 +1,19 @@
+use bigint::H512;
+
+/// A custom subprotocol whose messages are handled by `DevP2PStream`
+/// itself, see `DevP2PStream::register_capability`
+pub trait Capability {
+    /// Name advertised in the Hello
+    fn name(&self) -> &'static str;
+
+    /// Version advertised in the Hello
+    fn version(&self) -> usize;
+
+    /// Number of message ids the capability uses
+    fn message_count(&self) -> usize;
+
+    /// Handle a message of this capability from a peer, with the id
+    /// relative to the capability, returning the messages to send back
+    /// to that peer as (id, data) pairs
+    fn handle(&mut self, node: H512, id: usize, data: &[u8]) -> Vec<(usize, Vec<u8>)>;
+}
@@ -0,0 +1,187 @@
+use rlp::DecoderError;
+use bigint::H512;
+use rlpx::HandshakeError;
+use std::{error, fmt, io};
+
+#[derive(Debug, PartialEq, Eq)]
+/// Errors of decoding DevP2P messages
+pub enum DevP2PError {
+    /// The message id is reserved or outside every shared capability
+    UnknownMessageId(usize),
+    /// The message belongs to a capability this crate cannot decode
+    UnsupportedCapability(String),
+    /// The message payload is not valid RLP for its id
+    Rlp(DecoderError),
+}
+
+impl From<DecoderError> for DevP2PError {
+    fn from(error: DecoderError) -> DevP2PError {
+        DevP2PError::Rlp(error)
+    }
+}
+
+impl fmt::Display for DevP2PError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DevP2PError::UnknownMessageId(id) => write!(f, "unknown message id {}", id),
+            DevP2PError::UnsupportedCapability(ref name) => write!(f, "unsupported capability {}", name),
+            DevP2PError::Rlp(ref error) => write!(f, "invalid rlp: {:?}", error),
+        }
+    }
+}
+
+impl error::Error for DevP2PError {
+    fn description(&self) -> &str {
+        "devp2p decoding error"
+    }
+}
+
+impl From<DevP2PError> for io::Error {
+    fn from(error: DevP2PError) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, error)
+    }
+}
+
+#[derive(Debug)]
+/// Errors of the DevP2P streams by origin, with the peer involved if
+/// known. The streams fail with them, and `io::Error`s wrapping them
+/// convert back with `Error::from`.
+pub enum Error {
+    /// A message could not be decoded
+    Decode {
+        node: Option<H512>,
+        error: DevP2PError,
+    },
+    /// The ECIES or Hello handshake with a peer failed
+    Handshake {
+        node: Option<H512>,
+        error: io::Error,
+    },
+    /// Node discovery failed
+    Discovery(io::Error),
+    /// Connecting, sending or receiving failed
+    Transport {
+        node: Option<H512>,
+        error: io::Error,
+    },
+}
+
+impl Error {
+    /// Peer the error happened with, if known
+    pub fn node(&self) -> Option<H512> {
+        match self {
+            &Error::Decode { node, .. } |
+            &Error::Handshake { node, .. } |
+            &Error::Transport { node, .. } => node,
+            &Error::Discovery(_) => None,
+        }
+    }
+
+    /// Attach the peer the error happened with
+    pub fn with_node(mut self, remote_id: H512) -> Self {
+        match self {
+            Error::Decode { ref mut node, .. } |
+            Error::Handshake { ref mut node, .. } |
+            Error::Transport { ref mut node, .. } => *node = Some(remote_id),
+            Error::Discovery(_) => (),
+        }
+        self
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Decode { ref error, .. } => write!(f, "decoding failed: {}", error)?,
+            Error::Handshake { ref error, .. } => write!(f, "{}", error)?,
+            Error::Discovery(ref error) => write!(f, "discovery failed: {}", error)?,
+            Error::Transport { ref error, .. } => write!(f, "transport failed: {}", error)?,
+        }
+        match self.node() {
+            Some(node) => write!(f, " (peer {:x})", node),
+            None => Ok(()),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn description(&self) -> &str {
+        "devp2p error"
+    }
+}
+
+impl From<DevP2PError> for Error {
+    fn from(error: DevP2PError) -> Error {
+        Error::Decode { node: None, error }
+    }
+}
+
+impl From<io::Error> for Error {
+    /// Unwrap an `Error` carried by an `io::Error`, or tell handshake
+    /// and decoding failures apart from transport ones
+    fn from(error: io::Error) -> Error {
+        let (is_error, is_handshake, is_decode) = match error.get_ref() {
+            Some(inner) => (inner.is::<Error>(), inner.is::<HandshakeError>(), inner.is::<DevP2PError>()),
+            None => (false, false, false),
+        };
+
+        if is_error || is_decode {
+            let inner = error.into_inner().expect("checked above");
+            return match inner.downcast::<Error>() {
+                Ok(error) => *error,
+                Err(inner) => Error::Decode {
+                    node: None,
+                    error: *inner.downcast::<DevP2PError>().expect("checked above"),
+                },
+            };
+        }
+        if is_handshake {
+            return Error::Handshake { node: None, error };
+        }
+        Error::Transport { node: None, error }
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(error: Error) -> io::Error {
+        let kind = match error {
+            Error::Decode { .. } => io::ErrorKind::InvalidData,
+            Error::Transport { ref error, .. } => error.kind(),
+            _ => io::ErrorKind::Other,
+        };
+        io::Error::new(kind, error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Error, DevP2PError};
+    use bigint::H512;
+    use rlp::DecoderError;
+    use rlpx::HandshakeError;
+    use std::io;
+
+    #[test]
+    fn classified_through_io_errors() {
+        let node = H512::random();
+        let error = io::Error::from(Error::from(DevP2PError::UnknownMessageId(0x42)).with_node(node));
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        match Error::from(error) {
+            Error::Decode { node: Some(n), error: DevP2PError::UnknownMessageId(0x42) } => assert_eq!(n, node),
+            error => panic!("unexpected error {:?}", error),
+        }
+
+        match Error::from(io::Error::from(DevP2PError::Rlp(DecoderError::RlpIsTooShort))) {
+            Error::Decode { node: None, error: DevP2PError::Rlp(_) } => (),
+            error => panic!("unexpected error {:?}", error),
+        }
+        match Error::from(io::Error::from(HandshakeError("invalid handshake"))) {
+            Error::Handshake { node: None, .. } => (),
+            error => panic!("unexpected error {:?}", error),
+        }
+        match Error::from(io::Error::new(io::ErrorKind::ConnectionReset, "reset")) {
+            Error::Transport { node: None, ref error } => assert_eq!(error.kind(), io::ErrorKind::ConnectionReset),
+            error => panic!("unexpected error {:?}", error),
+        }
+    }
+}
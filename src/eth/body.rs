@@ -0,0 +1,120 @@
+use rlp::{self, Encodable, Decodable, RlpStream, DecoderError, UntrustedRlp};
+use bigint::{H256, U256};
+use block::Block;
+use trie::MemoryTrie;
+use std::collections::HashMap;
+use super::envelope::TransactionEnvelope;
+use super::header::{Header, ommers_hash};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// Body of a block as sent in BlockBodies messages
+pub struct BlockBody {
+    pub transactions: Vec<TransactionEnvelope>,
+    pub ommers: Vec<Header>,
+}
+
+impl BlockBody {
+    /// Root of the trie of the body's transactions, keyed by index
+    pub fn transactions_root(&self) -> H256 {
+        let mut trie = MemoryTrie::empty(HashMap::new());
+        for (i, transaction) in self.transactions.iter().enumerate() {
+            // Typed transactions are stored as their type and payload,
+            // without the string header wrapping them in RLP
+            let value = match *transaction {
+                TransactionEnvelope::Legacy(ref transaction) => rlp::encode(&**transaction).to_vec(),
+                TransactionEnvelope::Typed { transaction_type, ref payload } => {
+                    let mut value = vec![transaction_type];
+                    value.extend_from_slice(payload);
+                    value
+                },
+            };
+            trie.insert_raw(rlp::encode(&U256::from(i)).to_vec(), value);
+        }
+        trie.root()
+    }
+
+    /// Whether this is the body of the block with the given header,
+    /// by its transactions root and ommers hash
+    pub fn matches(&self, header: &Header) -> bool {
+        header.ommers_hash == ommers_hash(&self.ommers) &&
+            header.transactions_root == self.transactions_root()
+    }
+}
+
+impl From<Block> for BlockBody {
+    /// Body of a block with legacy transactions only
+    fn from(block: Block) -> Self {
+        BlockBody {
+            transactions: block.transactions.into_iter().map(TransactionEnvelope::from).collect(),
+            ommers: block.ommers.into_iter().map(Header::from).collect(),
+        }
+    }
+}
+
+impl Encodable for BlockBody {
+    fn rlp_append(&self, s: &mut RlpStream) {
+        s.begin_list(2);
+        s.append_list(&self.transactions);
+        s.append_list(&self.ommers);
+    }
+}
+
+impl Decodable for BlockBody {
+    fn decode(rlp: &UntrustedRlp) -> Result<Self, DecoderError> {
+        Ok(BlockBody {
+            transactions: rlp.list_at(0)?,
+            ommers: rlp.list_at(1)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BlockBody;
+    use super::super::header::ommers_hash;
+    use super::super::proto::{sample_header, sample_transaction};
+    use block::{self, Block, transactions_root};
+    use rlp::{self, UntrustedRlp};
+
+    #[test]
+    fn body_of_legacy_block() {
+        let legacy: block::Header = rlp::decode(&rlp::encode(&sample_header()));
+        let block = Block {
+            header: legacy.clone(),
+            transactions: vec![sample_transaction()],
+            ommers: vec![legacy],
+        };
+        let body = BlockBody::from(block.clone());
+        assert_eq!(body.transactions, vec![sample_transaction().into()]);
+        assert_eq!(body.ommers, vec![sample_header()]);
+        assert_eq!(ommers_hash(&body.ommers), block::ommers_hash(&block.ommers));
+
+        // Same layout as the block without its header
+        let encoded = rlp::encode(&body);
+        let rlp = UntrustedRlp::new(&encoded);
+        let block_encoded = rlp::encode(&block);
+        let block_rlp = UntrustedRlp::new(&block_encoded);
+        assert_eq!(rlp.at(0).unwrap().as_raw(), block_rlp.at(1).unwrap().as_raw());
+        assert_eq!(rlp.as_val::<BlockBody>().unwrap(), body);
+    }
+
+    #[test]
+    fn matches_header_roots() {
+        let mut header = sample_header();
+        let transactions = vec![sample_transaction(), sample_transaction()];
+        header.transactions_root = transactions_root(&transactions);
+        header.ommers_hash = ommers_hash(&[]);
+        let mut body = BlockBody {
+            transactions: transactions.into_iter().map(Into::into).collect(),
+            ommers: Vec::new(),
+        };
+        assert_eq!(body.transactions_root(), header.transactions_root);
+        assert!(body.matches(&header));
+
+        body.transactions.pop();
+        assert!(!body.matches(&header));
+        body.transactions.push(sample_transaction().into());
+        body.ommers.push(sample_header());
+        assert!(!body.matches(&header));
+    }
+}
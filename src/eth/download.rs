@@ -0,0 +1,309 @@
+use futures::{Async, Future, Poll};
+use bigint::{H256, H512, U256};
+use block::RlpHash;
+use super::header::Header;
+use std::io;
+use super::{ETHStream, ETHMessage, HashOrNumber};
+
+/// Default number of headers requested at once
+pub const DEFAULT_HEADER_BATCH: usize = 192;
+/// Default number of times a failed or empty header request is retried
+pub const DEFAULT_HEADER_RETRIES: usize = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Trusted block the header downloader anchors to
+pub struct Checkpoint {
+    pub number: U256,
+    pub hash: H256,
+}
+
+/// Downloads the headers following a local head from one peer, up to
+/// a target block, in batches of consecutive headers whose parent
+/// hashes link them to the local chain. With a checkpoint, the headers
+/// up to it are downloaded first, from the checkpoint down to the local
+/// head. Requests only time out with a request timeout set on the
+/// `ETHStream`.
+pub struct HeaderDownloader {
+    peer: H512,
+    next_number: U256,
+    parent_hash: H256,
+    target: U256,
+    batch_size: usize,
+    max_retries: usize,
+    retries: usize,
+    checkpoint: Option<Checkpoint>,
+    below_checkpoint: Option<(U256, H256)>,
+    request: Option<Box<dyn Future<Item = Vec<Header>, Error = io::Error>>>,
+}
+
+impl HeaderDownloader {
+    /// Download the headers after the local head with the given number
+    /// and hash from a peer, up to block number `target`
+    pub fn new(peer: H512, head_number: U256, head_hash: H256, target: U256) -> Self {
+        HeaderDownloader {
+            peer,
+            next_number: head_number + U256::one(),
+            parent_hash: head_hash,
+            target,
+            batch_size: DEFAULT_HEADER_BATCH,
+            max_retries: DEFAULT_HEADER_RETRIES,
+            retries: 0,
+            checkpoint: None,
+            below_checkpoint: None,
+            request: None,
+        }
+    }
+
+    /// Set the number of headers requested at once
+    pub fn set_batch_size(&mut self, batch_size: usize) {
+        self.batch_size = batch_size;
+    }
+
+    /// Set how many times in a row a failed, timed out or empty header
+    /// request is retried before the download fails
+    pub fn set_max_retries(&mut self, max_retries: usize) {
+        self.max_retries = max_retries;
+    }
+
+    /// Set a trusted checkpoint between the next header and the
+    /// target. The headers up to it are verified walking down from its
+    /// hash, each one having to be the parent of the header above it,
+    /// until they link to the local head. Headers above it are
+    /// verified as usual.
+    pub fn set_checkpoint(&mut self, checkpoint: Option<Checkpoint>) {
+        self.checkpoint = checkpoint;
+        self.below_checkpoint = checkpoint
+            .filter(|checkpoint| checkpoint.number >= self.next_number && checkpoint.number <= self.target)
+            .map(|checkpoint| (checkpoint.number, checkpoint.hash));
+    }
+
+    /// Peer the headers are downloaded from
+    pub fn peer(&self) -> H512 {
+        self.peer
+    }
+
+    /// Number and hash of the last header verified to link to the
+    /// local head
+    pub fn head(&self) -> (U256, H256) {
+        (self.next_number - U256::one(), self.parent_hash)
+    }
+
+    /// Start and length of the next batch to request, or `None` once
+    /// the target is reached. Below a checkpoint, the batch ends at
+    /// the highest header not verified yet.
+    pub fn next_batch(&self) -> Option<(U256, usize)> {
+        let last = match self.below_checkpoint {
+            Some((number, _)) => number,
+            None if self.next_number > self.target => return None,
+            None => self.target,
+        };
+        let remaining = last - self.next_number + U256::one();
+        let count = if remaining < U256::from(self.batch_size) {
+            remaining.as_usize()
+        } else {
+            self.batch_size
+        };
+        match self.below_checkpoint {
+            Some((number, _)) => Some((number - U256::from(count) + U256::one(), count)),
+            None => Some((self.next_number, count)),
+        }
+    }
+
+    /// Check that a batch of headers, in ascending order, continues the
+    /// verified chain and advance past it. Below a checkpoint, the
+    /// batch must end at the highest header not verified yet.
+    pub fn import(&mut self, headers: &[Header]) -> Result<(), io::Error> {
+        let max = self.next_batch().map(|(_, count)| count).unwrap_or(0);
+        if headers.len() > max {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "too many headers"));
+        }
+        if self.below_checkpoint.is_some() {
+            return self.import_below_checkpoint(headers);
+        }
+
+        let mut number = self.next_number;
+        let mut parent_hash = self.parent_hash;
+        for header in headers {
+            if header.number != number {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "header number out of sequence"));
+            }
+            if header.parent_hash != parent_hash {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "header does not link to its parent"));
+            }
+            parent_hash = header.rlp_hash();
+            number = number + U256::one();
+        }
+        self.next_number = number;
+        self.parent_hash = parent_hash;
+        Ok(())
+    }
+
+    /// Verify headers below the checkpoint from the highest one down,
+    /// each having to hash to the parent hash of the one above it
+    fn import_below_checkpoint(&mut self, headers: &[Header]) -> Result<(), io::Error> {
+        let (mut number, mut hash) = self.below_checkpoint.unwrap();
+        for header in headers.iter().rev() {
+            if header.number != number {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "header number out of sequence"));
+            }
+            if header.rlp_hash() != hash {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "header does not link to the checkpoint"));
+            }
+            hash = header.parent_hash;
+            number = number - U256::one();
+        }
+
+        if number + U256::one() > self.next_number {
+            self.below_checkpoint = Some((number, hash));
+            return Ok(());
+        }
+        if hash != self.parent_hash {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "checkpoint does not link to the local head"));
+        }
+        let checkpoint = self.checkpoint.unwrap();
+        self.below_checkpoint = None;
+        self.next_number = checkpoint.number + U256::one();
+        self.parent_hash = checkpoint.hash;
+        Ok(())
+    }
+
+    /// Poll for the next batch of verified headers, sending requests
+    /// through `eth`, which must keep being polled in the same task.
+    /// Batches are in ascending order, but come from a checkpoint down
+    /// to the local head first. Resolves to `None` once the target is
+    /// reached, and fails when the peer sends headers that do not
+    /// link, or retries run out.
+    pub fn poll(&mut self, eth: &mut ETHStream) -> Poll<Option<Vec<Header>>, io::Error> {
+        loop {
+            if self.request.is_none() {
+                let (start, count) = match self.next_batch() {
+                    Some(batch) => batch,
+                    None => return Ok(Async::Ready(None)),
+                };
+                self.request = Some(match self.below_checkpoint {
+                    // Highest header first, so that a partial response
+                    // still links to the verified ones
+                    Some((_, hash)) => Box::new(eth.request(self.peer, ETHMessage::GetBlockHeaders {
+                        start: HashOrNumber::Hash(hash), max_headers: count, skip: 0, reverse: true,
+                    }).and_then(|response| match response {
+                        ETHMessage::BlockHeaders(mut headers) => {
+                            headers.reverse();
+                            Ok(headers)
+                        },
+                        _ => Err(io::Error::new(io::ErrorKind::Other, "unexpected response")),
+                    })),
+                    None => eth.request_headers(self.peer, HashOrNumber::Number(start), count),
+                });
+            }
+
+            let headers = match self.request.as_mut().unwrap().poll() {
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Ok(Async::Ready(headers)) => headers,
+                Err(e) => {
+                    debug!("header request to {:x} failed: {}", self.peer, e);
+                    Vec::new()
+                },
+            };
+            self.request = None;
+
+            if headers.is_empty() {
+                self.retries += 1;
+                if self.retries > self.max_retries {
+                    return Err(io::Error::new(io::ErrorKind::Other, "header download retries exhausted"));
+                }
+                continue;
+            }
+            self.import(&headers)?;
+            self.retries = 0;
+            return Ok(Async::Ready(Some(headers)));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{HeaderDownloader, Checkpoint};
+    use super::super::sample_header;
+    use bigint::{H256, H512, U256};
+    use block::RlpHash;
+    use super::super::header::Header;
+
+    fn chain(parent_hash: H256, first: u64, count: u64) -> Vec<Header> {
+        let mut parent_hash = parent_hash;
+        (first..first + count).map(|number| {
+            let mut header = sample_header();
+            header.number = U256::from(number);
+            header.parent_hash = parent_hash;
+            parent_hash = header.rlp_hash();
+            header
+        }).collect()
+    }
+
+    #[test]
+    fn batches_up_to_target() {
+        let mut downloader = HeaderDownloader::new(H512::random(), U256::from(10), H256::random(), U256::from(20));
+        downloader.set_batch_size(4);
+        assert_eq!(downloader.next_batch(), Some((U256::from(11), 4)));
+
+        downloader.next_number = U256::from(19);
+        assert_eq!(downloader.next_batch(), Some((U256::from(19), 2)));
+        downloader.next_number = U256::from(21);
+        assert_eq!(downloader.next_batch(), None);
+    }
+
+    #[test]
+    fn verifies_linkage() {
+        let head_hash = H256::random();
+        let mut downloader = HeaderDownloader::new(H512::random(), U256::from(10), head_hash, U256::from(20));
+        let headers = chain(head_hash, 11, 5);
+
+        // Not linked to the local head, or not consecutive
+        assert!(downloader.import(&chain(H256::random(), 11, 5)).is_err());
+        assert!(downloader.import(&headers[1..]).is_err());
+        let mut gapped = headers.clone();
+        gapped.remove(2);
+        assert!(downloader.import(&gapped).is_err());
+        assert_eq!(downloader.head(), (U256::from(10), head_hash));
+
+        downloader.import(&headers).unwrap();
+        assert_eq!(downloader.head(), (U256::from(15), headers[4].rlp_hash()));
+        assert_eq!(downloader.next_batch(), Some((U256::from(16), 5)));
+        assert!(downloader.import(&chain(headers[4].rlp_hash(), 16, 6)).is_err());
+    }
+
+    #[test]
+    fn links_headers_below_checkpoint() {
+        let head_hash = H256::random();
+        let mut downloader = HeaderDownloader::new(H512::random(), U256::from(10), head_hash, U256::from(20));
+        downloader.set_batch_size(2);
+        let headers = chain(head_hash, 11, 6);
+        let checkpoint = Checkpoint { number: U256::from(13), hash: headers[2].rlp_hash() };
+
+        // Headers 11 and 12 do not link to the checkpoint
+        let mut unlinked = chain(H256::random(), 11, 2);
+        unlinked.push(headers[2].clone());
+        downloader.set_checkpoint(Some(checkpoint));
+        assert_eq!(downloader.next_batch(), Some((U256::from(12), 2)));
+        assert!(downloader.import(&unlinked[1..]).is_err());
+        assert!(downloader.import(&headers[..2]).is_err());
+
+        // Walking down from the checkpoint, with a partial response
+        downloader.import(&headers[2..3]).unwrap();
+        assert_eq!(downloader.next_batch(), Some((U256::from(11), 2)));
+        assert!(downloader.import(&unlinked[..2]).is_err());
+        downloader.import(&headers[1..2]).unwrap();
+        assert_eq!(downloader.head(), (U256::from(10), head_hash));
+        downloader.import(&headers[..1]).unwrap();
+        assert_eq!(downloader.head(), (U256::from(13), checkpoint.hash));
+
+        // Above the checkpoint headers must link as usual
+        assert!(downloader.import(&chain(H256::random(), 14, 2)).is_err());
+        downloader.import(&headers[3..5]).unwrap();
+
+        // A checkpoint that does not link to the local head
+        let mut downloader = HeaderDownloader::new(H512::random(), U256::from(10), H256::random(), U256::from(20));
+        downloader.set_checkpoint(Some(checkpoint));
+        assert!(downloader.import(&headers[..3]).is_err());
+        assert_eq!(downloader.head().0, U256::from(10));
+    }
+}
@@ -0,0 +1,124 @@
+use rlp::{self, Encodable, Decodable, RlpStream, DecoderError, UntrustedRlp};
+use bigint::H256;
+use block::{Transaction, RlpHash};
+use sha3::{Digest, Keccak256};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// A transaction as found in Transactions, PooledTransactions and
+/// BlockBodies messages. Legacy transactions are RLP lists, while
+/// EIP-2718 typed transactions are byte strings holding the type
+/// followed by its type specific payload, which is kept as is.
+pub enum TransactionEnvelope {
+    Legacy(Box<Transaction>),
+    Typed {
+        transaction_type: u8,
+        payload: Vec<u8>,
+    },
+}
+
+impl TransactionEnvelope {
+    /// EIP-2718 type of the transaction, 0 for legacy transactions
+    pub fn transaction_type(&self) -> u8 {
+        match *self {
+            TransactionEnvelope::Legacy(_) => 0,
+            TransactionEnvelope::Typed { transaction_type, .. } => transaction_type,
+        }
+    }
+
+    /// Hash of the transaction, over its type and payload for typed
+    /// transactions
+    pub fn hash(&self) -> H256 {
+        match *self {
+            TransactionEnvelope::Legacy(ref transaction) => transaction.rlp_hash(),
+            TransactionEnvelope::Typed { transaction_type, ref payload } => {
+                let mut hasher = Keccak256::new();
+                hasher.input(&[transaction_type]);
+                hasher.input(payload);
+                H256::from(hasher.result().as_slice())
+            },
+        }
+    }
+
+    /// Size of the transaction as announced to eth/68 peers, the one of
+    /// its encoding without the string header of typed transactions
+    pub fn encoded_len(&self) -> usize {
+        match *self {
+            TransactionEnvelope::Legacy(ref transaction) => rlp::encode(&**transaction).len(),
+            TransactionEnvelope::Typed { ref payload, .. } => 1 + payload.len(),
+        }
+    }
+}
+
+impl From<Transaction> for TransactionEnvelope {
+    fn from(transaction: Transaction) -> Self {
+        TransactionEnvelope::Legacy(Box::new(transaction))
+    }
+}
+
+impl Encodable for TransactionEnvelope {
+    fn rlp_append(&self, s: &mut RlpStream) {
+        match *self {
+            TransactionEnvelope::Legacy(ref transaction) => {
+                s.append(&**transaction);
+            },
+            TransactionEnvelope::Typed { transaction_type, ref payload } => {
+                let mut data = Vec::with_capacity(payload.len() + 1);
+                data.push(transaction_type);
+                data.extend_from_slice(payload);
+                s.append(&data);
+            },
+        }
+    }
+}
+
+impl Decodable for TransactionEnvelope {
+    fn decode(rlp: &UntrustedRlp) -> Result<Self, DecoderError> {
+        if rlp.is_list() {
+            return Ok(TransactionEnvelope::Legacy(Box::new(rlp.as_val()?)));
+        }
+
+        let data = rlp.data()?;
+        // EIP-2718 types range from 0x00 to 0x7f
+        if data.is_empty() || data[0] > 0x7f {
+            return Err(DecoderError::Custom("invalid transaction type"));
+        }
+        Ok(TransactionEnvelope::Typed {
+            transaction_type: data[0],
+            payload: data[1..].to_vec(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TransactionEnvelope;
+    use super::super::proto::sample_transaction;
+    use rlp::{self, RlpStream, UntrustedRlp};
+
+    #[test]
+    fn legacy_and_typed_in_one_list() {
+        let legacy = TransactionEnvelope::from(sample_transaction());
+        let typed = TransactionEnvelope::Typed {
+            transaction_type: 0x02,
+            payload: vec![0xc3, 0x01, 0x02, 0x03],
+        };
+
+        let mut s = RlpStream::new_list(2);
+        s.append(&legacy);
+        s.append(&typed);
+        let encoded = s.out();
+
+        // The typed transaction is a byte string starting with its type
+        let rlp = UntrustedRlp::new(&encoded);
+        assert_eq!(rlp.at(1).unwrap().data().unwrap(), &[0x02, 0xc3, 0x01, 0x02, 0x03]);
+        let decoded: Vec<TransactionEnvelope> = rlp.as_list().unwrap();
+        assert_eq!(decoded, vec![legacy, typed]);
+        assert_eq!(decoded[1].transaction_type(), 2);
+    }
+
+    #[test]
+    fn invalid_type_rejected() {
+        let encoded = rlp::encode(&vec![0xffu8, 0x01]);
+        assert!(UntrustedRlp::new(&encoded).as_val::<TransactionEnvelope>().is_err());
+    }
+}
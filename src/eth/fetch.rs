@@ -0,0 +1,289 @@
+use futures::{Async, Future, Poll};
+use bigint::{H256, H512};
+use block::RlpHash;
+use tokio_core::reactor::Timeout;
+use super::header::Header;
+use super::receipt::{Receipt, receipts_root};
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::time::{Duration, Instant};
+use super::{ETHStream, BlockBody};
+
+/// Default number of blocks whose data is requested from a peer at
+/// once
+pub const DEFAULT_FETCH_BATCH: usize = 128;
+
+/// Default number of seconds before a peer that failed a request is
+/// asked again
+pub const DEFAULT_FETCH_RETRY: u64 = 30;
+
+/// Data of a block fetched by hash, which its header commits to
+pub trait BlockData: Sized {
+    /// Request the data of the blocks with the given hashes from a peer
+    fn request(eth: &mut ETHStream, node: H512, hashes: Vec<H256>)
+               -> Box<dyn Future<Item = Vec<Self>, Error = io::Error>>;
+
+    /// Whether this is the data of the block with the given header
+    fn matches(&self, header: &Header) -> bool;
+}
+
+impl BlockData for BlockBody {
+    fn request(eth: &mut ETHStream, node: H512, hashes: Vec<H256>)
+               -> Box<dyn Future<Item = Vec<Self>, Error = io::Error>> {
+        eth.request_bodies(node, hashes)
+    }
+
+    /// Matched by transactions root and ommers hash
+    fn matches(&self, header: &Header) -> bool {
+        BlockBody::matches(self, header)
+    }
+}
+
+impl BlockData for Vec<Receipt> {
+    fn request(eth: &mut ETHStream, node: H512, hashes: Vec<H256>)
+               -> Box<dyn Future<Item = Vec<Self>, Error = io::Error>> {
+        eth.request_receipts(node, hashes)
+    }
+
+    /// Matched by receipts root
+    fn matches(&self, header: &Header) -> bool {
+        receipts_root(self) == header.receipts_root
+    }
+}
+
+/// Fetches the bodies of queued headers
+pub type BodyFetcher = Fetcher<BlockBody>;
+/// Fetches the receipts of queued headers, validated against their
+/// receipts roots
+pub type ReceiptFetcher = Fetcher<Vec<Receipt>>;
+
+type DataRequest<T> = Box<dyn Future<Item = Vec<T>, Error = io::Error>>;
+
+/// Fetches the data of queued headers, spreading requests over the
+/// connected peers with one request in flight per peer. Data is
+/// matched back to the headers committing to it, and headers left
+/// without data are queued again for another peer.
+pub struct Fetcher<T: BlockData> {
+    queue: VecDeque<Header>,
+    in_flight: HashMap<H512, (Vec<Header>, DataRequest<T>)>,
+    failed: HashMap<H512, Instant>,
+    retry_interval: Duration,
+    retry: Option<Timeout>,
+    batch_size: usize,
+}
+
+impl<T: BlockData> Fetcher<T> {
+    /// Create a fetcher with no headers queued
+    pub fn new() -> Self {
+        Fetcher {
+            queue: VecDeque::new(),
+            in_flight: HashMap::new(),
+            failed: HashMap::new(),
+            retry_interval: Duration::from_secs(DEFAULT_FETCH_RETRY),
+            retry: None,
+            batch_size: DEFAULT_FETCH_BATCH,
+        }
+    }
+
+    /// Set the number of blocks whose data is requested from a peer at
+    /// once
+    pub fn set_batch_size(&mut self, batch_size: usize) {
+        self.batch_size = batch_size;
+    }
+
+    /// Set how long a peer that failed a request is left out before
+    /// being asked again
+    pub fn set_retry_interval(&mut self, interval: Duration) {
+        self.retry_interval = interval;
+    }
+
+    /// Queue headers whose data should be fetched
+    pub fn push<I: IntoIterator<Item = Header>>(&mut self, headers: I) {
+        self.queue.extend(headers);
+    }
+
+    /// Number of headers still waiting for their data, queued or in
+    /// flight
+    pub fn remaining(&self) -> usize {
+        self.queue.len() + self.in_flight.values().map(|(headers, _)| headers.len()).sum::<usize>()
+    }
+
+    /// Peers with a request in flight
+    pub fn busy_peers(&self) -> Vec<H512> {
+        self.in_flight.keys().cloned().collect()
+    }
+
+    /// Poll for the next data fetched, with its headers, sending
+    /// requests through `eth`, which must keep being polled in the
+    /// same task. Peers that fail a request or answer with no matching
+    /// data are not asked again until the retry interval passed or
+    /// they reconnect. Resolves to `None` once every queued header has
+    /// its data.
+    pub fn poll(&mut self, eth: &mut ETHStream) -> Poll<Option<Vec<(Header, T)>>, io::Error> {
+        loop {
+            let next_retry = self.expire_failed(Instant::now());
+            self.dispatch(eth);
+            if self.in_flight.is_empty() {
+                if self.queue.is_empty() {
+                    return Ok(Async::Ready(None));
+                }
+                // Peers connecting wake the task through `eth`, failed
+                // peers becoming available again through the timeout
+                self.retry = match next_retry {
+                    Some(at) => Some(Timeout::new_at(at, eth.handle())?),
+                    None => None,
+                };
+                if let Some(ref mut retry) = self.retry {
+                    if retry.poll()?.is_ready() {
+                        continue;
+                    }
+                }
+                return Ok(Async::NotReady);
+            }
+
+            let mut completed = None;
+            for (&node, &mut (_, ref mut request)) in self.in_flight.iter_mut() {
+                match request.poll() {
+                    Ok(Async::NotReady) => continue,
+                    Ok(Async::Ready(bodies)) => completed = Some((node, bodies)),
+                    Err(e) => {
+                        debug!("block data request to {:x} failed: {}", node, e);
+                        completed = Some((node, Vec::new()));
+                    },
+                }
+                break;
+            }
+            let (node, bodies) = match completed {
+                Some(completed) => completed,
+                None => return Ok(Async::NotReady),
+            };
+
+            let (headers, _) = self.in_flight.remove(&node).unwrap();
+            let (matched, missing) = match_data(headers, bodies);
+            if matched.is_empty() {
+                self.failed.insert(node, Instant::now());
+            }
+            for header in missing.into_iter().rev() {
+                self.queue.push_front(header);
+            }
+            if !matched.is_empty() {
+                return Ok(Async::Ready(Some(matched)));
+            }
+        }
+    }
+
+    /// Forget the peers that failed at least the retry interval before
+    /// `now`, returning when the next of the others may be asked again
+    fn expire_failed(&mut self, now: Instant) -> Option<Instant> {
+        let retry_interval = self.retry_interval;
+        self.failed.retain(|_, &mut failed_at| now.duration_since(failed_at) < retry_interval);
+        self.failed.values().min().map(|&failed_at| failed_at + retry_interval)
+    }
+
+    /// Send a batch of queued headers to every idle peer
+    fn dispatch(&mut self, eth: &mut ETHStream) {
+        // A peer reconnecting gets another chance
+        self.failed.retain(|&node, _| eth.peer_version(node).is_some());
+        let peers: Vec<H512> = eth.peers_by_total_difficulty().into_iter()
+            .map(|(node, _)| node)
+            .filter(|node| !self.in_flight.contains_key(node) && !self.failed.contains_key(node))
+            .collect();
+        for node in peers {
+            if self.queue.is_empty() {
+                break;
+            }
+            let count = ::std::cmp::min(self.batch_size, self.queue.len());
+            let headers: Vec<Header> = self.queue.drain(..count).collect();
+            let request = T::request(eth, node, headers.iter().map(|header| header.rlp_hash()).collect());
+            self.in_flight.insert(node, (headers, request));
+        }
+    }
+}
+
+impl<T: BlockData> Default for Fetcher<T> {
+    fn default() -> Self {
+        Fetcher::new()
+    }
+}
+
+/// Pair data with the requested headers it belongs to, returning the
+/// pairs and the headers left without data. Data matching no header
+/// left is dropped.
+fn match_data<T: BlockData>(headers: Vec<Header>, data: Vec<T>) -> (Vec<(Header, T)>, Vec<Header>) {
+    let mut missing: Vec<Option<Header>> = headers.into_iter().map(Some).collect();
+    let mut matched = Vec::new();
+    for item in data {
+        let position = missing.iter().position(|header| match *header {
+            Some(ref header) => item.matches(header),
+            None => false,
+        });
+        if let Some(position) = position {
+            matched.push((missing[position].take().unwrap(), item));
+        }
+    }
+    (matched, missing.into_iter().flatten().collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{match_data, BlockData, BodyFetcher};
+    use super::super::{sample_header, BlockBody};
+    use super::super::proto::{sample_transaction, sample_receipt};
+    use super::super::header::ommers_hash;
+    use super::super::receipt::receipts_root;
+    use block::transactions_root;
+    use bigint::H512;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn bodies_matched_by_roots() {
+        let empty = BlockBody { transactions: Vec::new(), ommers: Vec::new() };
+        let full = BlockBody { transactions: vec![sample_transaction().into()], ommers: Vec::new() };
+        let mut empty_header = sample_header();
+        empty_header.transactions_root = transactions_root(&[]);
+        empty_header.ommers_hash = ommers_hash(&[]);
+        let mut full_header = empty_header.clone();
+        full_header.transactions_root = transactions_root(&[sample_transaction()]);
+
+        // Out of order and partial responses still pair up
+        let headers = vec![empty_header.clone(), full_header.clone(), empty_header.clone()];
+        let (matched, missing) = match_data(headers.clone(), vec![full.clone(), empty.clone()]);
+        assert_eq!(matched, vec![(full_header.clone(), full.clone()), (empty_header.clone(), empty.clone())]);
+        assert_eq!(missing, vec![empty_header.clone()]);
+
+        // Bodies of blocks that were not requested are dropped
+        let (matched, missing) = match_data(vec![empty_header.clone()], vec![full.clone()]);
+        assert!(matched.is_empty());
+        assert_eq!(missing, vec![empty_header]);
+    }
+
+    #[test]
+    fn receipts_matched_by_root() {
+        let receipts = vec![sample_receipt(), sample_receipt()];
+        let mut header = sample_header();
+        header.receipts_root = receipts_root(&receipts);
+        assert!(receipts.matches(&header));
+        assert!(!receipts[..1].to_vec().matches(&header));
+
+        let (matched, missing) = match_data(vec![header.clone()], vec![Vec::new(), receipts.clone()]);
+        assert_eq!(matched, vec![(header, receipts)]);
+        assert!(missing.is_empty());
+    }
+
+    #[test]
+    fn failed_peers_expire() {
+        let mut fetcher = BodyFetcher::new();
+        fetcher.set_retry_interval(Duration::from_secs(10));
+        let now = Instant::now();
+        let (old, recent) = (H512::random(), H512::random());
+        fetcher.failed.insert(old, now - Duration::from_secs(10));
+        fetcher.failed.insert(recent, now - Duration::from_secs(4));
+
+        assert_eq!(fetcher.expire_failed(now), Some(now + Duration::from_secs(6)));
+        assert!(!fetcher.failed.contains_key(&old));
+        assert!(fetcher.failed.contains_key(&recent));
+
+        assert_eq!(fetcher.expire_failed(now + Duration::from_secs(6)), None);
+        assert!(fetcher.failed.is_empty());
+    }
+}
@@ -0,0 +1,228 @@
+use rlp::{Encodable, Decodable, RlpStream, DecoderError, UntrustedRlp};
+use bigint::H256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// EIP-2124 fork identifier sent in the eth/64 Status message
+pub struct ForkId {
+    /// CRC32 checksum of the genesis hash and the block numbers of
+    /// all forks already passed
+    pub hash: [u8; 4],
+    /// Block number of the next upcoming fork, or 0 if none is known
+    pub next: u64,
+}
+
+impl ForkId {
+    /// Fork id of a chain with the given genesis and fork block
+    /// numbers, as seen by a node whose head is at block `head`
+    pub fn new(genesis_hash: H256, forks: &[u64], head: u64) -> Self {
+        let mut filter = ForkFilter::new(genesis_hash, forks);
+        filter.set_head(head);
+        filter.fork_id()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Reasons to reject the fork id of a remote peer
+pub enum ForkIdError {
+    /// The remote is on a fork we know of but announces a different
+    /// next fork, so it needs a software update
+    RemoteStale,
+    /// The remote is on a fork we do not know of, or announces a next
+    /// fork we have already passed without it
+    LocalIncompatibleOrStale,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// Genesis hash and fork block numbers of a chain
+pub struct ForkSchedule {
+    pub genesis_hash: H256,
+    pub forks: Vec<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// Fork schedule of the local chain, computing our fork id and
+/// validating the ones of remote peers following EIP-2124
+pub struct ForkFilter {
+    forks: Vec<u64>,
+    sums: Vec<[u8; 4]>,
+    head: u64,
+}
+
+impl ForkFilter {
+    /// Filter of a chain with the given genesis and fork block
+    /// numbers, with the local head at the genesis
+    pub fn new(genesis_hash: H256, forks: &[u64]) -> Self {
+        let mut forks: Vec<u64> = forks.iter().cloned().filter(|&fork| fork != 0).collect();
+        forks.sort();
+        forks.dedup();
+
+        let mut crc = crc32_update(0, &genesis_hash);
+        let mut sums = vec![crc.to_be_bytes()];
+        for &fork in &forks {
+            crc = crc32_update(crc, &fork.to_be_bytes());
+            sums.push(crc.to_be_bytes());
+        }
+
+        ForkFilter { forks, sums, head: 0 }
+    }
+
+    /// Update the block number of the local head
+    pub fn set_head(&mut self, head: u64) {
+        self.head = head;
+    }
+
+    /// Block number of the local head
+    pub fn head(&self) -> u64 {
+        self.head
+    }
+
+    /// Our current fork id
+    pub fn fork_id(&self) -> ForkId {
+        let passed = self.forks.iter().take_while(|&&fork| fork <= self.head).count();
+        ForkId {
+            hash: self.sums[passed],
+            next: self.forks.get(passed).cloned().unwrap_or(0),
+        }
+    }
+
+    /// Check whether a peer announcing `remote` can stay connected
+    pub fn validate(&self, remote: &ForkId) -> Result<(), ForkIdError> {
+        let passed = self.forks.iter().take_while(|&&fork| fork <= self.head).count();
+
+        // Same fork: fine unless the remote expects a fork we
+        // already passed without it
+        if self.sums[passed] == remote.hash {
+            if remote.next > 0 && self.head >= remote.next {
+                return Err(ForkIdError::LocalIncompatibleOrStale);
+            }
+            return Ok(());
+        }
+
+        // Remote is behind: it must announce the fork we know follows
+        if let Some(index) = self.sums[..passed].iter().position(|sum| sum == &remote.hash) {
+            if self.forks[index] != remote.next {
+                return Err(ForkIdError::RemoteStale);
+            }
+            return Ok(());
+        }
+
+        // Remote is ahead: its fork must be one we know of
+        if self.sums[passed + 1..].iter().any(|sum| sum == &remote.hash) {
+            return Ok(());
+        }
+
+        Err(ForkIdError::LocalIncompatibleOrStale)
+    }
+}
+
+/// Continue an IEEE CRC32 checksum over more data
+fn crc32_update(crc: u32, data: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ 0xedb88320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+impl Encodable for ForkId {
+    fn rlp_append(&self, s: &mut RlpStream) {
+        s.begin_list(2);
+        s.append(&&self.hash[..]);
+        s.append(&self.next);
+    }
+}
+
+impl Decodable for ForkId {
+    fn decode(rlp: &UntrustedRlp) -> Result<Self, DecoderError> {
+        let hash_rlp = rlp.at(0)?;
+        let hash_data = hash_rlp.data()?;
+        if hash_data.len() < 4 {
+            return Err(DecoderError::RlpIsTooShort);
+        }
+        if hash_data.len() > 4 {
+            return Err(DecoderError::RlpIsTooBig);
+        }
+        let mut hash = [0u8; 4];
+        hash.copy_from_slice(hash_data);
+
+        Ok(ForkId {
+            hash,
+            next: rlp.val_at(1)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ForkId, ForkFilter, ForkIdError};
+    use bigint::H256;
+    use hexutil::read_hex;
+    use rlp::{self, UntrustedRlp};
+
+    const MAINNET_FORKS: &[u64] = &[1150000, 1920000, 2463000, 2675000, 4370000];
+
+    fn mainnet_genesis() -> H256 {
+        H256::from(&read_hex("0xd4e56740f876aef8c010b86a40d5f56745a118d0906a34e69aec8c0db1cb8fa3")
+                   .unwrap()[..])
+    }
+
+    #[test]
+    fn mainnet_fork_ids() {
+        let genesis = mainnet_genesis();
+        assert_eq!(ForkId::new(genesis, MAINNET_FORKS, 0),
+                   ForkId { hash: [0xfc, 0x64, 0xec, 0x04], next: 1150000 });
+        assert_eq!(ForkId::new(genesis, MAINNET_FORKS, 1150000),
+                   ForkId { hash: [0x97, 0xc2, 0xc3, 0x4c], next: 1920000 });
+        assert_eq!(ForkId::new(genesis, MAINNET_FORKS, 1920000),
+                   ForkId { hash: [0x91, 0xd1, 0xf9, 0x48], next: 2463000 });
+    }
+
+    #[test]
+    fn mainnet_validation() {
+        let forks = [1150000, 1920000, 2463000, 2675000, 4370000, 7280000, 7280000];
+        let mut filter = ForkFilter::new(mainnet_genesis(), &forks);
+        let byzantium = [0xa0, 0x0b, 0xc3, 0x24];
+        let petersburg = [0x66, 0x8d, 0xb0, 0xaf];
+        let spurious = [0x3e, 0xdd, 0x5b, 0x10];
+        let unknown = [0x5c, 0xdd, 0xc0, 0xe1];
+
+        filter.set_head(7987396);
+        assert_eq!(filter.fork_id(), ForkId { hash: petersburg, next: 0 });
+        let cases = [
+            // Same fork, with or without a future fork
+            (ForkId { hash: petersburg, next: 0 }, Ok(())),
+            (ForkId { hash: petersburg, next: u64::MAX }, Ok(())),
+            // Remote is syncing
+            (ForkId { hash: byzantium, next: 7280000 }, Ok(())),
+            (ForkId { hash: spurious, next: 4370000 }, Ok(())),
+            // Remote missed a fork
+            (ForkId { hash: spurious, next: 4370001 }, Err(ForkIdError::RemoteStale)),
+            // Remote is on an unknown fork
+            (ForkId { hash: unknown, next: 0 }, Err(ForkIdError::LocalIncompatibleOrStale)),
+            // Remote announces a fork we already passed
+            (ForkId { hash: petersburg, next: 7987396 }, Err(ForkIdError::LocalIncompatibleOrStale)),
+        ];
+        for (remote, result) in cases.iter() {
+            assert_eq!(&filter.validate(remote), result, "{:?}", remote);
+        }
+
+        // We are syncing and the remote is ahead
+        filter.set_head(7279999);
+        assert_eq!(filter.fork_id(), ForkId { hash: byzantium, next: 7280000 });
+        assert_eq!(filter.validate(&ForkId { hash: petersburg, next: 0 }), Ok(()));
+        assert_eq!(filter.validate(&ForkId { hash: byzantium, next: 0 }), Ok(()));
+        assert_eq!(filter.validate(&ForkId { hash: unknown, next: 0 }),
+                   Err(ForkIdError::LocalIncompatibleOrStale));
+    }
+
+    #[test]
+    fn rlp_round_trip() {
+        let fork_id = ForkId { hash: [0x00, 0x00, 0x00, 0x01], next: 0 };
+        let encoded = rlp::encode(&fork_id);
+        assert_eq!(&encoded[..], &[0xc6, 0x84, 0x00, 0x00, 0x00, 0x01, 0x80]);
+        assert_eq!(UntrustedRlp::new(&encoded).as_val::<ForkId>().unwrap(), fork_id);
+    }
+}
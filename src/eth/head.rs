@@ -0,0 +1,174 @@
+use bigint::{H256, H512, U256};
+use block::RlpHash;
+use super::ETHMessage;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// Best block announced by the network
+pub struct NetworkHead {
+    pub hash: H256,
+    pub number: U256,
+    /// Total difficulty, known once the block came in a NewBlock
+    pub total_difficulty: Option<U256>,
+    /// Peers that announced the block
+    pub peers: Vec<H512>,
+}
+
+impl NetworkHead {
+    /// Whether this head is better than a block with the given number
+    /// and total difficulty, by total difficulty when both are known
+    /// and by number otherwise
+    fn is_better_than(&self, number: U256, total_difficulty: Option<U256>) -> bool {
+        match (self.total_difficulty, total_difficulty) {
+            (Some(ours), Some(theirs)) => ours > theirs,
+            _ => self.number > number,
+        }
+    }
+}
+
+/// Follows the best head announced by all peers through NewBlock and
+/// NewBlockHashes messages, and tells when it goes past our local head
+pub struct HeadTracker {
+    local_number: U256,
+    local_total_difficulty: U256,
+    best: Option<NetworkHead>,
+}
+
+impl HeadTracker {
+    /// Create a tracker for a local head with the given number and
+    /// total difficulty
+    pub fn new(local_number: U256, local_total_difficulty: U256) -> Self {
+        HeadTracker {
+            local_number,
+            local_total_difficulty,
+            best: None,
+        }
+    }
+
+    /// Update our local head, forgetting the network head if we caught
+    /// up with it
+    pub fn set_local_head(&mut self, number: U256, total_difficulty: U256) {
+        self.local_number = number;
+        self.local_total_difficulty = total_difficulty;
+        if !self.is_ahead() {
+            self.best = None;
+        }
+    }
+
+    /// Best head announced by the network that is ahead of our local
+    /// head
+    pub fn best(&self) -> Option<&NetworkHead> {
+        self.best.as_ref()
+    }
+
+    /// Record the blocks announced in a message from a peer, returning
+    /// the new network head when it advanced past our local head
+    pub fn record(&mut self, peer: H512, message: &ETHMessage) -> Option<NetworkHead> {
+        let announced = match *message {
+            ETHMessage::NewBlock { ref header, total_difficulty, .. } =>
+                vec![(header.rlp_hash(), header.number, Some(total_difficulty))],
+            ETHMessage::NewBlockHashes(ref hashes) =>
+                hashes.iter().map(|&(hash, number)| (hash, number, None)).collect(),
+            _ => return None,
+        };
+
+        let mut advanced = false;
+        for (hash, number, total_difficulty) in announced {
+            if let Some(ref mut best) = self.best {
+                if best.hash == hash {
+                    if !best.peers.contains(&peer) {
+                        best.peers.push(peer);
+                    }
+                    if best.total_difficulty.is_none() {
+                        best.total_difficulty = total_difficulty;
+                    }
+                    continue;
+                }
+                if best.is_better_than(number, total_difficulty) ||
+                    (best.number == number && total_difficulty.is_none())
+                {
+                    continue;
+                }
+            }
+            let head = NetworkHead { hash, number, total_difficulty, peers: vec![peer] };
+            if head.is_better_than(self.local_number, Some(self.local_total_difficulty)) {
+                self.best = Some(head);
+                advanced = true;
+            }
+        }
+
+        if advanced { self.best.clone() } else { None }
+    }
+
+    /// Forget a peer that went away, dropping the network head once no
+    /// peer announcing it is left
+    pub fn remove_peer(&mut self, peer: &H512) {
+        let empty = match self.best {
+            Some(ref mut best) => {
+                best.peers.retain(|p| p != peer);
+                best.peers.is_empty()
+            },
+            None => false,
+        };
+        if empty {
+            self.best = None;
+        }
+    }
+
+    fn is_ahead(&self) -> bool {
+        match self.best {
+            Some(ref best) => best.is_better_than(self.local_number, Some(self.local_total_difficulty)),
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::HeadTracker;
+    use super::super::{ETHMessage, BlockBody, sample_header};
+    use bigint::{H256, H512, U256};
+    use block::RlpHash;
+
+    fn new_block(number: u64, total_difficulty: u64) -> ETHMessage {
+        let mut header = sample_header();
+        header.number = U256::from(number);
+        ETHMessage::NewBlock {
+            header,
+            body: BlockBody { transactions: Vec::new(), ommers: Vec::new() },
+            total_difficulty: U256::from(total_difficulty),
+        }
+    }
+
+    #[test]
+    fn follows_best_announced_head() {
+        let mut tracker = HeadTracker::new(U256::from(100), U256::from(1000));
+        let (a, b) = (H512::random(), H512::random());
+
+        // Behind or at our head
+        assert_eq!(tracker.record(a, &new_block(100, 1000)), None);
+        assert_eq!(tracker.record(a, &ETHMessage::NewBlockHashes(vec![(H256::random(), U256::from(99))])), None);
+
+        let hash = H256::random();
+        let head = tracker.record(a, &ETHMessage::NewBlockHashes(vec![(hash, U256::from(101))])).unwrap();
+        assert_eq!((head.hash, head.number, head.total_difficulty), (hash, U256::from(101), None));
+
+        // The same block from another peer adds an announcer only
+        assert_eq!(tracker.record(b, &ETHMessage::NewBlockHashes(vec![(hash, U256::from(101))])), None);
+        assert_eq!(tracker.best().unwrap().peers, vec![a, b]);
+
+        let block = new_block(102, 1200);
+        let head = tracker.record(b, &block).unwrap();
+        match block {
+            ETHMessage::NewBlock { ref header, .. } => assert_eq!(head.hash, header.rlp_hash()),
+            _ => unreachable!(),
+        }
+        assert_eq!(head.total_difficulty, Some(U256::from(1200)));
+        assert_eq!(tracker.record(a, &new_block(101, 1100)), None);
+
+        tracker.remove_peer(&b);
+        assert_eq!(tracker.best(), None);
+        tracker.record(a, &new_block(103, 1300)).unwrap();
+        tracker.set_local_head(U256::from(103), U256::from(1300));
+        assert_eq!(tracker.best(), None);
+    }
+}
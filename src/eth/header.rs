@@ -0,0 +1,218 @@
+use rlp::{self, Encodable, Decodable, RlpStream, DecoderError, UntrustedRlp};
+use bigint::{Address, Gas, H256, H64, U256, B256};
+use bloom::LogsBloom;
+use block::{self, RlpHash};
+use sha3::{Digest, Keccak256};
+
+/// Number of fields of a header before London
+const LEGACY_FIELDS: usize = 15;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// Block header, with the fields added by later forks: the base fee of
+/// London, the withdrawals root of Shanghai, the blob gas fields and
+/// beacon block root of Cancun and the requests hash of Prague. A fork
+/// field is only present when all the ones before it are, and the
+/// header is encoded with exactly the fields it was decoded with, so
+/// that its hash is the one of the block.
+pub struct Header {
+    pub parent_hash: H256,
+    pub ommers_hash: H256,
+    pub beneficiary: Address,
+    pub state_root: H256,
+    pub transactions_root: H256,
+    pub receipts_root: H256,
+    pub logs_bloom: LogsBloom,
+    pub difficulty: U256,
+    pub number: U256,
+    pub gas_limit: Gas,
+    pub gas_used: Gas,
+    pub timestamp: u64,
+    pub extra_data: B256,
+    pub mix_hash: H256,
+    pub nonce: H64,
+    pub base_fee_per_gas: Option<U256>,
+    pub withdrawals_root: Option<H256>,
+    pub blob_gas_used: Option<u64>,
+    pub excess_blob_gas: Option<u64>,
+    pub parent_beacon_block_root: Option<H256>,
+    pub requests_hash: Option<H256>,
+}
+
+impl Header {
+    /// Number of fork fields present, which are the leading ones set
+    fn fork_fields(&self) -> usize {
+        let present = [
+            self.base_fee_per_gas.is_some(),
+            self.withdrawals_root.is_some(),
+            self.blob_gas_used.is_some(),
+            self.excess_blob_gas.is_some(),
+            self.parent_beacon_block_root.is_some(),
+            self.requests_hash.is_some(),
+        ];
+        present.iter().take_while(|&&present| present).count()
+    }
+}
+
+/// Hash of a list of ommers, as found in the header of their block
+pub fn ommers_hash(ommers: &[Header]) -> H256 {
+    H256::from(Keccak256::digest(&rlp::encode_list(ommers)).as_slice())
+}
+
+impl RlpHash for Header {
+    fn rlp_hash(&self) -> H256 {
+        H256::from(Keccak256::digest(&rlp::encode(self)).as_slice())
+    }
+}
+
+impl From<block::Header> for Header {
+    /// Header of a block before London
+    fn from(header: block::Header) -> Self {
+        Header {
+            parent_hash: header.parent_hash,
+            ommers_hash: header.ommers_hash,
+            beneficiary: header.beneficiary,
+            state_root: header.state_root,
+            transactions_root: header.transactions_root,
+            receipts_root: header.receipts_root,
+            logs_bloom: header.logs_bloom,
+            difficulty: header.difficulty,
+            number: header.number,
+            gas_limit: header.gas_limit,
+            gas_used: header.gas_used,
+            timestamp: header.timestamp,
+            extra_data: header.extra_data,
+            mix_hash: header.mix_hash,
+            nonce: header.nonce,
+            base_fee_per_gas: None,
+            withdrawals_root: None,
+            blob_gas_used: None,
+            excess_blob_gas: None,
+            parent_beacon_block_root: None,
+            requests_hash: None,
+        }
+    }
+}
+
+impl Encodable for Header {
+    fn rlp_append(&self, s: &mut RlpStream) {
+        let fork_fields = self.fork_fields();
+        s.begin_list(LEGACY_FIELDS + fork_fields);
+        s.append(&self.parent_hash);
+        s.append(&self.ommers_hash);
+        s.append(&self.beneficiary);
+        s.append(&self.state_root);
+        s.append(&self.transactions_root);
+        s.append(&self.receipts_root);
+        s.append(&self.logs_bloom);
+        s.append(&self.difficulty);
+        s.append(&self.number);
+        s.append(&self.gas_limit);
+        s.append(&self.gas_used);
+        s.append(&self.timestamp);
+        s.append(&self.extra_data);
+        s.append(&self.mix_hash);
+        s.append(&self.nonce);
+
+        if fork_fields > 0 { s.append(self.base_fee_per_gas.as_ref().unwrap()); }
+        if fork_fields > 1 { s.append(self.withdrawals_root.as_ref().unwrap()); }
+        if fork_fields > 2 { s.append(self.blob_gas_used.as_ref().unwrap()); }
+        if fork_fields > 3 { s.append(self.excess_blob_gas.as_ref().unwrap()); }
+        if fork_fields > 4 { s.append(self.parent_beacon_block_root.as_ref().unwrap()); }
+        if fork_fields > 5 { s.append(self.requests_hash.as_ref().unwrap()); }
+    }
+}
+
+/// Fork field with the given index, if the header has it
+fn fork_field<T: Decodable>(rlp: &UntrustedRlp, index: usize) -> Result<Option<T>, DecoderError> {
+    if LEGACY_FIELDS + index < rlp.item_count()? {
+        Ok(Some(rlp.val_at(LEGACY_FIELDS + index)?))
+    } else {
+        Ok(None)
+    }
+}
+
+impl Decodable for Header {
+    fn decode(rlp: &UntrustedRlp) -> Result<Self, DecoderError> {
+        let count = rlp.item_count()?;
+        if count < LEGACY_FIELDS {
+            return Err(DecoderError::RlpIncorrectListLen);
+        }
+        if count > LEGACY_FIELDS + 6 {
+            return Err(DecoderError::Custom("header has fields of an unknown fork"));
+        }
+
+        Ok(Header {
+            parent_hash: rlp.val_at(0)?,
+            ommers_hash: rlp.val_at(1)?,
+            beneficiary: rlp.val_at(2)?,
+            state_root: rlp.val_at(3)?,
+            transactions_root: rlp.val_at(4)?,
+            receipts_root: rlp.val_at(5)?,
+            logs_bloom: rlp.val_at(6)?,
+            difficulty: rlp.val_at(7)?,
+            number: rlp.val_at(8)?,
+            gas_limit: rlp.val_at(9)?,
+            gas_used: rlp.val_at(10)?,
+            timestamp: rlp.val_at(11)?,
+            extra_data: rlp.val_at(12)?,
+            mix_hash: rlp.val_at(13)?,
+            nonce: rlp.val_at(14)?,
+            base_fee_per_gas: fork_field(rlp, 0)?,
+            withdrawals_root: fork_field(rlp, 1)?,
+            blob_gas_used: fork_field(rlp, 2)?,
+            excess_blob_gas: fork_field(rlp, 3)?,
+            parent_beacon_block_root: fork_field(rlp, 4)?,
+            requests_hash: fork_field(rlp, 5)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Header;
+    use super::super::proto::sample_header;
+    use block::{self, RlpHash};
+    use bigint::{H256, U256};
+    use rlp::{self, UntrustedRlp};
+
+    fn london_header() -> Header {
+        let mut header = sample_header();
+        header.base_fee_per_gas = Some(U256::from(1_000_000_000u64));
+        header
+    }
+
+    #[test]
+    fn legacy_header_unchanged() {
+        let legacy: block::Header = rlp::decode(&rlp::encode(&sample_header()));
+        assert_eq!(Header::from(legacy.clone()), sample_header());
+        assert_eq!(sample_header().rlp_hash(), legacy.rlp_hash());
+    }
+
+    #[test]
+    fn fork_fields_hashed() {
+        let header = london_header();
+        let encoded = rlp::encode(&header);
+        assert_eq!(UntrustedRlp::new(&encoded).item_count().unwrap(), 16);
+        assert_eq!(rlp::decode::<Header>(&encoded), header);
+        assert!(header.rlp_hash() != sample_header().rlp_hash());
+
+        let mut prague = london_header();
+        prague.withdrawals_root = Some(H256::from(1));
+        prague.blob_gas_used = Some(131072);
+        prague.excess_blob_gas = Some(0);
+        prague.parent_beacon_block_root = Some(H256::from(2));
+        prague.requests_hash = Some(H256::from(3));
+        let encoded = rlp::encode(&prague);
+        assert_eq!(UntrustedRlp::new(&encoded).item_count().unwrap(), 21);
+        assert_eq!(rlp::decode::<Header>(&encoded), prague);
+        assert_eq!(rlp::encode(&rlp::decode::<Header>(&encoded)).to_vec(), encoded.to_vec());
+    }
+
+    #[test]
+    fn fork_fields_after_gap_dropped() {
+        // A Cancun field without the ones before it can't be encoded
+        let mut header = sample_header();
+        header.blob_gas_used = Some(1);
+        assert_eq!(rlp::encode(&header).to_vec(), rlp::encode(&sample_header()).to_vec());
+    }
+}
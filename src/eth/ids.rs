@@ -0,0 +1,36 @@
+//! Message ids of the ETH protocol, relative to the start of the eth
+//! capability, and the message space lengths of the eth capability
+
+pub const STATUS: usize = 0x00;
+pub const NEW_BLOCK_HASHES: usize = 0x01;
+pub const TRANSACTIONS: usize = 0x02;
+pub const GET_BLOCK_HEADERS: usize = 0x03;
+pub const BLOCK_HEADERS: usize = 0x04;
+pub const GET_BLOCK_BODIES: usize = 0x05;
+pub const BLOCK_BODIES: usize = 0x06;
+pub const NEW_BLOCK: usize = 0x07;
+/// Since eth/65
+pub const NEW_POOLED_TRANSACTION_HASHES: usize = 0x08;
+/// Since eth/65
+pub const GET_POOLED_TRANSACTIONS: usize = 0x09;
+/// Since eth/65
+pub const POOLED_TRANSACTIONS: usize = 0x0a;
+/// From eth/63 to eth/66
+pub const GET_NODE_DATA: usize = 0x0d;
+/// From eth/63 to eth/66
+pub const NODE_DATA: usize = 0x0e;
+/// Since eth/63
+pub const GET_RECEIPTS: usize = 0x0f;
+/// Since eth/63
+pub const RECEIPTS: usize = 0x10;
+
+/// Message space length of eth/62
+pub const ETH62_LENGTH: usize = 8;
+/// Message space length of eth/63 and later versions, which keep the
+/// ids of removed messages reserved
+pub const ETH63_LENGTH: usize = 17;
+
+/// Message space length of the given eth version
+pub fn length(version: usize) -> usize {
+    if version < 63 { ETH62_LENGTH } else { ETH63_LENGTH }
+}
@@ -0,0 +1,138 @@
+use bigint::{H256, H512};
+use std::collections::{HashMap, HashSet, VecDeque};
+use super::ETHMessage;
+
+/// Bounded history of the transaction hashes a peer is known to
+/// have, because it sent them to us or we sent them to it
+struct PeerTransactions {
+    order: VecDeque<H256>,
+    hashes: HashSet<H256>,
+}
+
+/// Transactions known by each peer, used to avoid sending a peer
+/// transactions it already has
+pub struct KnownTransactions {
+    capacity: usize,
+    peers: HashMap<H512, PeerTransactions>,
+}
+
+impl KnownTransactions {
+    /// Create a new history remembering at most `capacity` hashes per
+    /// peer
+    pub fn new(capacity: usize) -> Self {
+        KnownTransactions {
+            capacity,
+            peers: HashMap::new(),
+        }
+    }
+
+    /// Record that `peer` has the transaction of the given hash.
+    /// Returns whether it was not known yet.
+    pub fn insert(&mut self, peer: H512, hash: H256) -> bool {
+        let capacity = self.capacity;
+        let known = self.peers.entry(peer).or_insert_with(|| PeerTransactions {
+            order: VecDeque::new(),
+            hashes: HashSet::new(),
+        });
+        if !known.hashes.insert(hash) {
+            return false;
+        }
+
+        known.order.push_back(hash);
+        while known.order.len() > capacity {
+            if let Some(hash) = known.order.pop_front() {
+                known.hashes.remove(&hash);
+            }
+        }
+        true
+    }
+
+    /// Whether `peer` is known to have the transaction of the given
+    /// hash
+    pub fn contains(&self, peer: &H512, hash: &H256) -> bool {
+        self.peers.get(peer).map(|known| known.hashes.contains(hash)).unwrap_or(false)
+    }
+
+    /// Copy of a Transactions or pooled transaction hashes message
+    /// without what `peer` already knows, which may leave it empty.
+    /// `None` for other messages.
+    pub fn filter(&self, peer: &H512, message: &ETHMessage) -> Option<ETHMessage> {
+        let unknown = |hash: &H256| !self.contains(peer, hash);
+        match *message {
+            ETHMessage::Transactions(ref transactions) =>
+                Some(ETHMessage::Transactions(
+                    transactions.iter().filter(|t| unknown(&t.hash())).cloned().collect())),
+            ETHMessage::NewPooledTransactionHashes(ref hashes) =>
+                Some(ETHMessage::NewPooledTransactionHashes(
+                    hashes.iter().filter(|h| unknown(h)).cloned().collect())),
+            ETHMessage::NewPooledTransactionHashes68(ref announcements) =>
+                Some(ETHMessage::NewPooledTransactionHashes68(
+                    announcements.iter().filter(|&&(_, _, hash)| unknown(&hash)).cloned().collect())),
+            _ => None,
+        }
+    }
+
+    /// Forget everything about a disconnected peer
+    pub fn remove_peer(&mut self, peer: &H512) {
+        self.peers.remove(peer);
+    }
+}
+
+/// Hashes of the transactions sent or announced by a message
+pub fn transaction_hashes(message: &ETHMessage) -> Vec<H256> {
+    match message {
+        &ETHMessage::Transactions(ref transactions) |
+        &ETHMessage::PooledTransactions(ref transactions) =>
+            transactions.iter().map(|t| t.hash()).collect(),
+        ETHMessage::NewPooledTransactionHashes(hashes) => hashes.clone(),
+        ETHMessage::NewPooledTransactionHashes68(announcements) =>
+            announcements.iter().map(|&(_, _, hash)| hash).collect(),
+        _ => Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{KnownTransactions, transaction_hashes};
+    use super::super::{ETHMessage, TransactionEnvelope};
+    use super::super::proto::sample_transaction;
+    use bigint::{H256, H512};
+
+    #[test]
+    fn bounded_per_peer() {
+        let mut known = KnownTransactions::new(2);
+        let peers = [H512::random(), H512::random()];
+        let hashes = [H256::random(), H256::random(), H256::random()];
+
+        assert!(known.insert(peers[0], hashes[0]));
+        assert!(!known.insert(peers[0], hashes[0]));
+        assert!(!known.contains(&peers[1], &hashes[0]));
+
+        known.insert(peers[0], hashes[1]);
+        known.insert(peers[0], hashes[2]);
+        assert!(!known.contains(&peers[0], &hashes[0]));
+        assert!(known.contains(&peers[0], &hashes[2]));
+
+        known.remove_peer(&peers[0]);
+        assert!(!known.contains(&peers[0], &hashes[2]));
+    }
+
+    #[test]
+    fn filters_known_transactions() {
+        let mut known = KnownTransactions::new(16);
+        let peer = H512::random();
+        let legacy = sample_transaction().into();
+        let typed = TransactionEnvelope::Typed { transaction_type: 2, payload: vec![0xc0] };
+        known.insert(peer, typed.hash());
+
+        let message = ETHMessage::Transactions(vec![legacy, typed.clone()]);
+        let filtered = known.filter(&peer, &message).unwrap();
+        assert_eq!(transaction_hashes(&filtered), vec![transaction_hashes(&message)[0]]);
+        assert_eq!(known.filter(&H512::random(), &message), Some(message.clone()));
+
+        let announcement = ETHMessage::NewPooledTransactionHashes68(vec![(2, 1, typed.hash())]);
+        assert_eq!(known.filter(&peer, &announcement),
+                   Some(ETHMessage::NewPooledTransactionHashes68(Vec::new())));
+        assert_eq!(known.filter(&peer, &ETHMessage::GetBlockBodies(Vec::new())), None);
+    }
+}
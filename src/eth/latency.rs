@@ -0,0 +1,131 @@
+use bigint::H512;
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+/// Response latency statistics of a peer
+pub struct LatencyStats {
+    /// Average over the most recent responses
+    pub average: Duration,
+    /// Maximum over the most recent responses
+    pub max: Duration,
+    /// Number of responses the statistics are computed from
+    pub samples: usize,
+}
+
+/// Per-peer tracker matching requests to their responses, keeping a
+/// rolling window of response latencies
+pub struct LatencyTracker {
+    window: usize,
+    pending: HashMap<H512, VecDeque<(usize, Instant)>>,
+    latencies: HashMap<H512, VecDeque<Duration>>,
+}
+
+impl LatencyTracker {
+    /// Create a new tracker averaging over the last `window` responses
+    pub fn new(window: usize) -> Self {
+        LatencyTracker {
+            window,
+            pending: HashMap::new(),
+            latencies: HashMap::new(),
+        }
+    }
+
+    /// Record a request sent to `peer` at `at`, expecting a response
+    /// with message id `response_id`
+    pub fn request_sent(&mut self, peer: H512, response_id: usize, at: Instant) {
+        self.pending.entry(peer).or_default()
+            .push_back((response_id, at));
+    }
+
+    /// Record a message with id `id` received from `peer` at `at`.
+    /// If it answers the oldest outstanding request with that id,
+    /// returns the measured latency.
+    pub fn response_received(&mut self, peer: H512, id: usize, at: Instant) -> Option<Duration> {
+        let sent = {
+            let pending = self.pending.get_mut(&peer)?;
+            let index = pending.iter().position(|&(response_id, _)| response_id == id)?;
+            pending.remove(index)?.1
+        };
+
+        let latency = at.duration_since(sent);
+        let latencies = self.latencies.entry(peer).or_default();
+        latencies.push_back(latency);
+        while latencies.len() > self.window {
+            latencies.pop_front();
+        }
+        Some(latency)
+    }
+
+    /// Latency statistics of a peer, if any response was measured
+    pub fn stats(&self, peer: &H512) -> Option<LatencyStats> {
+        let latencies = self.latencies.get(peer)?;
+        if latencies.is_empty() {
+            return None;
+        }
+
+        let total = latencies.iter().fold(Duration::new(0, 0), |acc, &l| acc + l);
+        Some(LatencyStats {
+            average: total / latencies.len() as u32,
+            max: latencies.iter().cloned().max().unwrap_or_default(),
+            samples: latencies.len(),
+        })
+    }
+
+    /// Whether the average latency of a peer exceeds `threshold`
+    pub fn is_slow(&self, peer: &H512, threshold: Duration) -> bool {
+        match self.stats(peer) {
+            Some(stats) => stats.average > threshold,
+            None => false,
+        }
+    }
+
+    /// Forget everything about a peer
+    pub fn remove(&mut self, peer: &H512) {
+        self.pending.remove(peer);
+        self.latencies.remove(peer);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::LatencyTracker;
+    use bigint::H512;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn slow_peer_is_flagged() {
+        let mut tracker = LatencyTracker::new(8);
+        let fast = H512::random();
+        let slow = H512::random();
+        let threshold = Duration::from_millis(200);
+        let start = Instant::now();
+
+        for i in 0..4 {
+            let sent = start + Duration::from_secs(i);
+            tracker.request_sent(fast, 4, sent);
+            tracker.request_sent(slow, 4, sent);
+            tracker.response_received(fast, 4, sent + Duration::from_millis(50));
+            tracker.response_received(slow, 4, sent + Duration::from_millis(300 + 100 * i));
+        }
+
+        let stats = tracker.stats(&slow).unwrap();
+        assert_eq!(stats.samples, 4);
+        assert_eq!(stats.max, Duration::from_millis(600));
+        assert!(stats.average > threshold);
+        assert!(tracker.is_slow(&slow, threshold));
+        assert!(!tracker.is_slow(&fast, threshold));
+    }
+
+    #[test]
+    fn unmatched_response_is_ignored() {
+        let mut tracker = LatencyTracker::new(8);
+        let peer = H512::random();
+        let now = Instant::now();
+
+        tracker.request_sent(peer, 6, now);
+        assert_eq!(tracker.response_received(peer, 4, now), None);
+        assert!(tracker.stats(&peer).is_none());
+    }
+}
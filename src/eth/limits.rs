@@ -0,0 +1,143 @@
+use rlp::{UntrustedRlp, DecoderError};
+use std::collections::HashMap;
+use super::ids;
+
+/// Default maximum size of an ETH message payload
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 10 * 1024 * 1024;
+/// Default maximum nesting depth of RLP lists in an ETH message.
+/// Receipts, the deepest message, need 6.
+pub const DEFAULT_MAX_DEPTH: usize = 16;
+/// Maximum number of items of block data requests and responses:
+/// headers, bodies, receipts and node data
+pub const MAX_BLOCK_ITEMS: usize = 1024;
+/// Maximum number of items of block and transaction announcements
+/// and of pooled transaction requests and responses
+pub const MAX_ANNOUNCED_ITEMS: usize = 4096;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// Limits checked on incoming ETH messages before decoding them
+pub struct DecodeLimits {
+    /// Maximum payload size in bytes of messages without a specific
+    /// limit
+    pub max_message_size: usize,
+    /// Maximum payload size in bytes by message id
+    pub message_sizes: HashMap<usize, usize>,
+    /// Maximum nesting depth of RLP lists
+    pub max_depth: usize,
+}
+
+impl Default for DecodeLimits {
+    fn default() -> Self {
+        DecodeLimits {
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            message_sizes: HashMap::new(),
+            max_depth: DEFAULT_MAX_DEPTH,
+        }
+    }
+}
+
+impl DecodeLimits {
+    /// Maximum payload size of messages with the given id
+    pub fn max_size(&self, id: usize) -> usize {
+        self.message_sizes.get(&id).cloned().unwrap_or(self.max_message_size)
+    }
+
+    /// Whether a message payload stays within the limits. Only item
+    /// headers are walked, nothing is decoded.
+    pub fn allows(&self, id: usize, payload: &[u8]) -> bool {
+        payload.len() <= self.max_size(id) &&
+            within_depth(&UntrustedRlp::new(payload), self.max_depth)
+    }
+}
+
+/// Maximum number of list items of the ETH message with the given id,
+/// or `None` if it is not a list of items
+pub fn max_items(id: usize) -> Option<usize> {
+    match id {
+        ids::GET_BLOCK_BODIES | ids::BLOCK_BODIES | ids::BLOCK_HEADERS |
+        ids::GET_NODE_DATA | ids::NODE_DATA |
+        ids::GET_RECEIPTS | ids::RECEIPTS => Some(MAX_BLOCK_ITEMS),
+        ids::NEW_BLOCK_HASHES | ids::TRANSACTIONS |
+        ids::NEW_POOLED_TRANSACTION_HASHES |
+        ids::GET_POOLED_TRANSACTIONS | ids::POOLED_TRANSACTIONS => Some(MAX_ANNOUNCED_ITEMS),
+        _ => None,
+    }
+}
+
+/// Number of items of a RLP list, failing without walking the rest of
+/// the list once it has more than `max`
+pub fn bounded_item_count(rlp: &UntrustedRlp, max: usize) -> Result<usize, DecoderError> {
+    if !rlp.is_list() {
+        return Err(DecoderError::RlpExpectedToBeList);
+    }
+    let count = rlp.iter().take(max + 1).count();
+    if count > max {
+        return Err(DecoderError::Custom("too many items"));
+    }
+    Ok(count)
+}
+
+/// Whether the lists of a RLP item are nested at most `depth` deep
+fn within_depth(rlp: &UntrustedRlp, depth: usize) -> bool {
+    if !rlp.is_list() {
+        return true;
+    }
+    if depth == 0 {
+        return false;
+    }
+    rlp.iter().all(|item| within_depth(&item, depth - 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DecodeLimits, MAX_ANNOUNCED_ITEMS, MAX_BLOCK_ITEMS};
+    use super::super::{ETHMessage, ids};
+    use bigint::{H256, U256};
+    use rlp::{RlpStream, UntrustedRlp};
+
+    fn nested(depth: usize) -> Vec<u8> {
+        let mut s = RlpStream::new();
+        for _ in 0..depth {
+            s.begin_list(1);
+        }
+        s.append(&1u8);
+        s.out().to_vec()
+    }
+
+    #[test]
+    fn depth_and_size() {
+        let mut limits = DecodeLimits { max_depth: 4, ..Default::default() };
+        assert!(limits.allows(2, &nested(4)));
+        assert!(!limits.allows(2, &nested(5)));
+        assert!(limits.allows(2, &[0x01]));
+
+        limits.message_sizes.insert(6, 4);
+        assert!(limits.allows(2, &nested(4)));
+        assert!(!limits.allows(6, &nested(4)));
+    }
+
+    #[test]
+    fn item_counts() {
+        let announcements = |count: usize| {
+            let mut s = RlpStream::new_list(count);
+            for i in 0..count {
+                s.begin_list(2);
+                s.append(&H256::from(i as u64));
+                s.append(&U256::from(i));
+            }
+            s.out().to_vec()
+        };
+        let data = announcements(MAX_ANNOUNCED_ITEMS);
+        assert!(ETHMessage::decode(&UntrustedRlp::new(&data), ids::NEW_BLOCK_HASHES).is_ok());
+        let data = announcements(MAX_ANNOUNCED_ITEMS + 1);
+        assert!(ETHMessage::decode(&UntrustedRlp::new(&data), ids::NEW_BLOCK_HASHES).is_err());
+
+        let mut s = RlpStream::new_list(MAX_BLOCK_ITEMS + 1);
+        for i in 0..MAX_BLOCK_ITEMS + 1 {
+            s.append(&H256::from(i as u64));
+        }
+        let data = s.out();
+        assert!(ETHMessage::decode(&UntrustedRlp::new(&data), ids::GET_BLOCK_BODIES).is_err());
+        assert!(ETHMessage::decode(&UntrustedRlp::new(&data), ids::GET_POOLED_TRANSACTIONS).is_ok());
+    }
+}
@@ -1,41 +1,242 @@
 mod proto;
+mod seen;
+mod known;
+mod provider;
+mod latency;
+mod pending;
+mod request_id;
+mod forkid;
+mod envelope;
+mod limits;
+mod body;
+mod header;
+mod receipt;
+mod download;
+mod fetch;
+mod head;
+mod pool;
+mod reorg;
+#[cfg(feature = "serialize")]
+mod serialize;
+pub mod ids;
 
 use futures::{StartSend, Async, Poll, Stream, Sink, AsyncSink, Future, future};
+use futures::sync::oneshot;
+use futures::task::{self, Task};
 use rlp::{self, UntrustedRlp};
 use bigint::{H512, H256, U256};
-use rlpx::{RLPxSendMessage, RLPxReceiveMessage, RLPxNode, CapabilityInfo};
-use dpt::DPTNode;
-use secp256k1::key::SecretKey;
-use tokio_core::reactor::Handle;
+use rlpx::{RLPxSendMessage, RLPxReceiveMessage, RLPxNode, CapabilityInfo, CapabilityOffsets, DisconnectReason,
+           PeerTable, NodeSigner};
+use block::RlpHash;
+use rand::{thread_rng, Rng};
+use tokio_core::reactor::{Handle, Timeout};
+use std::cmp::Reverse;
 use std::io;
-use std::time::Duration;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use std::net::{IpAddr, SocketAddr};
+use std::collections::{HashMap, VecDeque};
 
-use super::{DevP2PStream, DevP2PConfig};
+pub use self::header::Header;
+pub use self::receipt::{Receipt, ReceiptOutcome};
 
-pub use self::proto::ETHMessage;
+use super::{DevP2PStream, DevP2PBuilder, DevP2PError, Error, Capability, ReputationEvent};
+
+pub use self::proto::{ETHMessage, HashOrNumber, SUPPORTED_ETH_VERSIONS};
+pub use self::provider::{HeaderProvider, ChainDataProvider, ServeLimits,
+                         DEFAULT_MAX_SERVED_HEADERS, DEFAULT_MAX_SERVED_BODIES};
+pub use self::forkid::{ForkId, ForkFilter, ForkIdError, ForkSchedule};
+pub use self::envelope::TransactionEnvelope;
+pub use self::body::BlockBody;
+pub use self::download::{HeaderDownloader, Checkpoint, DEFAULT_HEADER_BATCH, DEFAULT_HEADER_RETRIES};
+pub use self::head::{HeadTracker, NetworkHead};
+pub use self::pool::TxPool;
+use self::pool::{serve_pooled, missing_announced};
+use self::reorg::find_branch;
+pub use self::fetch::{Fetcher, BlockData, BodyFetcher, ReceiptFetcher, DEFAULT_FETCH_BATCH, DEFAULT_FETCH_RETRY};
+pub use self::limits::{DecodeLimits, DEFAULT_MAX_MESSAGE_SIZE, DEFAULT_MAX_DEPTH,
+                       MAX_BLOCK_ITEMS, MAX_ANNOUNCED_ITEMS, max_items};
+#[cfg(test)]
+pub(crate) use self::proto::sample_header;
+use self::seen::{SeenBlocks, block_hashes};
+use self::known::{KnownTransactions, transaction_hashes};
+use self::provider::{serve_headers, serve_chain_data, ServeRate};
+pub use self::latency::LatencyStats;
+use self::latency::LatencyTracker;
+use self::pending::PendingRequests;
+pub use self::request_id::{REQUEST_ID_VERSION, is_request_id_wrapped,
+                           wrap_request_id, unwrap_request_id};
+
+/// Default number of recently seen block hashes remembered
+const DEFAULT_SEEN_BLOCKS: usize = 1024;
+/// Number of transaction hashes remembered per peer
+const KNOWN_TRANSACTIONS: usize = 32768;
+/// Default maximum number of hashes in one NewBlockHashes message
+const DEFAULT_MAX_ANNOUNCE_HASHES: usize = 256;
+/// Default maximum number of transactions accepted in one
+/// Transactions or PooledTransactions message
+const DEFAULT_MAX_TRANSACTIONS: usize = 4096;
+/// Number of recent responses peer latency statistics are computed from
+const LATENCY_WINDOW: usize = 16;
+/// First request id of the requests sent by `ETHStream::request`, far
+/// above the ids consumers would pick
+const FIRST_TYPED_REQUEST_ID: u64 = 1 << 63;
+/// Time peers have to answer the fork block header request
+const FORK_BLOCK_CHECK_TIMEOUT: u64 = 15;
+/// Maximum number of messages sent by the stream itself kept until
+/// their busy peers are ready
+const MAX_BUFFERED_MESSAGES: usize = 1024;
+/// Seconds after which requests are forgotten when no request timeout
+/// is set
+const STALE_REQUEST_AGE: u64 = 120;
+/// Maximum number of searches for the ancestors of announced blocks in
+/// flight, one per peer at most
+const MAX_ANCESTOR_SEARCHES: usize = 16;
+/// Time peers have to answer the search for the ancestors of a block
+/// they announced
+const ANCESTOR_SEARCH_TIMEOUT: u64 = 15;
+/// Default interval in milliseconds between announcements of the
+/// pending transactions of the pool
+const DEFAULT_TX_ANNOUNCE_INTERVAL: u64 = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Handling of NewBlock and NewBlockHashes announcements whose blocks
+/// have all been seen before
+pub enum DuplicateBlockPolicy {
+    /// Deliver them as `ETHReceiveMessage::Duplicate`
+    Flag,
+    /// Drop them without notifying the consumer
+    Suppress,
+}
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 /// Receiving message of ETH
 pub enum ETHReceiveMessage {
+    /// A peer shares an ETH version with us. `version` is the highest
+    /// shared one, used for all messages with that peer, and
+    /// `client_version` the one from its Hello. Its Status arrives
+    /// afterwards as a `Normal` message and is then available from
+    /// `ETHStream::peer_status`.
     Connected {
         node: H512,
         version: usize,
+        client_version: String,
     },
+    /// A peer went away, see `RLPxReceiveMessage::Disconnected`
     Disconnected {
-        node: H512
+        node: H512,
+        reason: Option<DisconnectReason>,
+        clean: bool,
     },
+    /// A message from a peer. `request_id` is set for requests and
+    /// responses received from eth/66 peers.
     Normal {
         node: H512,
         version: usize,
+        request_id: Option<u64>,
+        data: ETHMessage,
+    },
+    Duplicate {
+        node: H512,
+        version: usize,
+        request_id: Option<u64>,
         data: ETHMessage,
     },
+    /// A request with message id `id` sent to a peer got no response
+    /// within the request timeout
+    Timeout {
+        node: H512,
+        id: usize,
+        request_id: Option<u64>,
+    },
+    /// A block announced by a peer whose parent we do not know, with
+    /// the headers leading to it from `ancestor`, the closest block
+    /// the header provider knows. The ancestor is our head when we
+    /// merely miss the blocks in between. See
+    /// `ETHStream::set_reorg_depth`.
+    Reorg {
+        node: H512,
+        ancestor: H256,
+        branch: Vec<Header>,
+        header: Header,
+        body: BlockBody,
+        total_difficulty: U256,
+    },
+    /// A block announced by a peer whose parent we do not know, and
+    /// no known ancestor of which the peer sent within the reorg depth
+    UnknownAncestor {
+        node: H512,
+        header: Header,
+        body: BlockBody,
+        total_difficulty: U256,
+    },
+    /// A message of a capability run next to eth, see
+    /// `ETHStream::add_subprotocol`
+    Subprotocol {
+        node: H512,
+        capability: CapabilityInfo,
+        id: usize,
+        data: Vec<u8>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// Chain we announce in our Status to ETH and PAR peers
+pub struct ChainConfig {
+    pub network_id: usize,
+    pub genesis_hash: H256,
+    /// Hash of our best block
+    pub best_hash: H256,
+    /// Total difficulty of our best block
+    pub total_difficulty: U256,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// Status announced by a connected ETH peer, with its best block
+/// updated by the NewBlock messages it sends afterwards
+pub struct PeerStatus {
+    pub protocol_version: usize,
+    pub network_id: usize,
+    pub total_difficulty: U256,
+    pub best_hash: H256,
+    pub genesis_hash: H256,
+    /// Fork id of eth/64 and later peers
+    pub fork_id: Option<ForkId>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
+/// Information about a connected ETH peer
+pub struct PeerInfo {
+    /// Negotiated ETH protocol version
+    pub version: usize,
+    /// Response latency statistics, once a request sent to this peer
+    /// has been answered
+    pub latency: Option<LatencyStats>,
+    /// Number of messages rejected for breaking protocol limits
+    pub violations: usize,
+    /// Reputation score, see `DevP2PStream::report_peer`
+    pub score: i32,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 /// Sending message of ETH
 pub struct ETHSendMessage {
     pub node: RLPxNode,
+    /// Request id put in the envelope of requests and responses sent to
+    /// eth/66 peers, 0 if unset. Requests sent to `RLPxNode::Any` go to
+    /// an arbitrary peer chosen by the ETH stream, so that the envelope
+    /// matches its version. Requests, responses and messages not every
+    /// version has, sent to several peers with `RLPxNode::All`,
+    /// `RLPxNode::Random` or `RLPxNode::AllExcept`, are sent to each
+    /// peer separately, leaving out the peers whose version does not
+    /// have them. Sending a
+    /// message to a peer whose version does not have it, like
+    /// GetNodeData to an eth/67 peer, fails. Transactions, blocks and
+    /// their announcements sent to a particular peer leave out what it
+    /// already sent us or we sent it, and are dropped if nothing is
+    /// left.
+    pub request_id: Option<u64>,
     pub data: ETHMessage,
 }
 
@@ -46,42 +247,214 @@ pub struct ETHStream {
     best_hash: H256,
     total_difficulty: U256,
     network_id: usize,
+    seen_blocks: SeenBlocks,
+    known_transactions: KnownTransactions,
+    duplicate_block_policy: DuplicateBlockPolicy,
+    header_provider: Option<Box<dyn HeaderProvider>>,
+    chain_data_provider: Option<Box<dyn ChainDataProvider>>,
+    tx_pool: Option<Box<dyn TxPool>>,
+    reorg_depth: Option<usize>,
+    ancestor_searches: HashMap<(H512, u64), (Header, BlockBody, U256, Instant)>,
+    tx_announce_interval: Duration,
+    tx_announce_timer: Option<Timeout>,
+    serve_limits: ServeLimits,
+    serve_rate: ServeRate,
+    inbound_requests: HashMap<H512, usize>,
+    peer_versions: HashMap<H512, usize>,
+    peer_statuses: HashMap<H512, PeerStatus>,
+    latency: LatencyTracker,
+    request_timeout: Option<Duration>,
+    pending_requests: PendingRequests,
+    waiting: HashMap<(H512, u64), oneshot::Sender<Result<ETHMessage, io::Error>>>,
+    next_request_id: u64,
+    max_average_latency: Option<Duration>,
+    syncing: bool,
+    idle_reap_interval: Option<Duration>,
+    last_message: HashMap<H512, Instant>,
+    max_announce_hashes: usize,
+    max_transactions: usize,
+    strict_decoding: bool,
+    validate_status: bool,
+    fork_block_check: Option<(U256, H256)>,
+    pending_fork_block_checks: HashMap<H512, Instant>,
+    decode_limits: DecodeLimits,
+    violations: HashMap<H512, usize>,
+    fork_filter: ForkFilter,
+    awaiting_head: bool,
+    head_waiter: Option<Task>,
+    subprotocols: Vec<&'static str>,
+    outgoing: VecDeque<ETHSendMessage>,
 }
 
 impl ETHStream {
-    /// Create a new ETH stream
+    /// Create a new ETH stream, advertising every version in
+    /// `SUPPORTED_ETH_VERSIONS`. Each peer uses the highest version it
+    /// shares with us. The network, like the client version and the
+    /// bootstrap nodes, is set up by `network`.
     pub fn new(addr: &SocketAddr, public_addr: &IpAddr,
-               handle: &Handle, secret_key: SecretKey,
-               client_version: String, network_id: usize,
-               genesis_hash: H256, best_hash: H256,
-               total_difficulty: U256,
-               bootstrap_nodes: Vec<DPTNode>,
-               config: DevP2PConfig,
+               handle: &Handle, signer: Arc<dyn NodeSigner>,
+               chain: ChainConfig, network: DevP2PBuilder,
     ) -> Result<Self, io::Error> {
+        let idle_reap_interval = network.config().idle_reap_interval;
+        let network = SUPPORTED_ETH_VERSIONS.iter().fold(network, |network, &version| {
+            network.capability(CapabilityInfo { name: "eth", version, length: ids::length(version) })
+        });
+
         Ok(ETHStream {
-            stream: DevP2PStream::new(addr, public_addr, handle, secret_key,
-                                      4, client_version,
-                                      vec![CapabilityInfo { name: "eth", version: 62, length: 8 },
-                                           // CapabilityInfo { name: "eth", version: 63, length: 17 },
-                                      ],
-                                      bootstrap_nodes,
-                                      config)?,
-            genesis_hash, best_hash, total_difficulty, network_id
+            stream: network.build(addr, public_addr, handle, signer)?,
+            genesis_hash: chain.genesis_hash,
+            best_hash: chain.best_hash,
+            total_difficulty: chain.total_difficulty,
+            network_id: chain.network_id,
+            seen_blocks: SeenBlocks::new(DEFAULT_SEEN_BLOCKS),
+            known_transactions: KnownTransactions::new(KNOWN_TRANSACTIONS),
+            duplicate_block_policy: DuplicateBlockPolicy::Flag,
+            header_provider: None,
+            chain_data_provider: None,
+            tx_pool: None,
+            reorg_depth: None,
+            ancestor_searches: HashMap::new(),
+            tx_announce_interval: Duration::from_millis(DEFAULT_TX_ANNOUNCE_INTERVAL),
+            tx_announce_timer: None,
+            serve_limits: ServeLimits::default(),
+            serve_rate: ServeRate::new(Instant::now()),
+            inbound_requests: HashMap::new(),
+            peer_versions: HashMap::new(),
+            peer_statuses: HashMap::new(),
+            latency: LatencyTracker::new(LATENCY_WINDOW),
+            request_timeout: None,
+            pending_requests: PendingRequests::new(),
+            waiting: HashMap::new(),
+            next_request_id: FIRST_TYPED_REQUEST_ID,
+            max_average_latency: None,
+            syncing: false,
+            idle_reap_interval,
+            last_message: HashMap::new(),
+            max_announce_hashes: DEFAULT_MAX_ANNOUNCE_HASHES,
+            max_transactions: DEFAULT_MAX_TRANSACTIONS,
+            strict_decoding: false,
+            validate_status: false,
+            fork_block_check: None,
+            pending_fork_block_checks: HashMap::new(),
+            decode_limits: DecodeLimits::default(),
+            violations: HashMap::new(),
+            fork_filter: ForkFilter::new(chain.genesis_hash, &[]),
+            awaiting_head: false,
+            head_waiter: None,
+            subprotocols: Vec::new(),
+            outgoing: VecDeque::new(),
         })
     }
 
+    /// Create a new ETH stream whose chain head is not known yet. The
+    /// network is left alone, so no peer connects, until the head is
+    /// supplied with `update_head`.
+    pub fn new_awaiting_head(addr: &SocketAddr, public_addr: &IpAddr,
+                             handle: &Handle, signer: Arc<dyn NodeSigner>,
+                             network_id: usize, genesis_hash: H256,
+                             network: DevP2PBuilder,
+    ) -> Result<Self, io::Error> {
+        let chain = ChainConfig {
+            network_id, genesis_hash,
+            best_hash: genesis_hash,
+            total_difficulty: U256::zero(),
+        };
+        let mut stream = ETHStream::new(addr, public_addr, handle, signer, chain, network)?;
+        stream.awaiting_head = true;
+        Ok(stream)
+    }
+
     /// Force disconnecting a peer if it is already connected or about
-    /// to be connected. Useful for removing peers on a different hard
-    /// fork network
-    pub fn disconnect_peer(&mut self, remote_id: H512) {
-        self.stream.disconnect_peer(remote_id);
+    /// to be connected, telling a connected peer the reason in a
+    /// Disconnect message. Useful for removing peers on a different
+    /// hard fork network
+    pub fn disconnect_peer(&mut self, remote_id: H512, reason: DisconnectReason) {
+        self.stream.disconnect_peer(remote_id, reason);
+    }
+
+    /// Trust a peer, exempting it from peer limits and keeping it
+    /// connected when it fails fork checks, is idle, slow or sends
+    /// malformed messages. Peers on another chain are still dropped.
+    pub fn add_trusted_peer(&mut self, remote_id: H512) {
+        self.stream.add_trusted_peer(remote_id);
+    }
+
+    /// Stop trusting a peer
+    pub fn remove_trusted_peer(&mut self, remote_id: H512) {
+        self.stream.remove_trusted_peer(remote_id);
+    }
+
+    /// Score a peer for its behaviour, see `DevP2PStream::report_peer`.
+    /// Responses, timeouts and invalid messages are already reported.
+    pub fn report_peer(&mut self, remote_id: H512, event: ReputationEvent) {
+        self.stream.report_peer(remote_id, event);
+    }
+
+    /// Disconnect a peer and keep it away for the ban duration
+    pub fn ban_peer(&mut self, remote_id: H512) {
+        self.stream.ban_peer(remote_id);
+    }
+
+    /// Lift the ban of a peer
+    pub fn unban_peer(&mut self, remote_id: H512) {
+        self.stream.unban_peer(remote_id);
+    }
+
+    /// Advertise a custom capability next to eth, see
+    /// `DevP2PStream::register_capability`
+    pub fn register_capability(&mut self, capability: Box<dyn Capability>) {
+        self.stream.register_capability(capability);
+    }
+
+    /// Advertise a capability that requires eth, like snap. Its
+    /// messages from peers sharing eth are returned as
+    /// `ETHReceiveMessage::Subprotocol` and sent with
+    /// `start_send_subprotocol`.
+    pub fn add_subprotocol(&mut self, capability: CapabilityInfo) {
+        self.subprotocols.push(capability.name);
+        self.stream.add_capability(capability);
+    }
+
+    /// Send a message of a capability added with `add_subprotocol`,
+    /// flushed by `poll_complete`
+    pub fn start_send_subprotocol(
+        &mut self, message: RLPxSendMessage
+    ) -> StartSend<RLPxSendMessage, Error> {
+        self.stream.start_send(message)
     }
 
     /// Active peers
-    pub fn active_peers(&mut self) -> &[H512] {
+    pub fn active_peers(&self) -> &[H512] {
         self.stream.active_peers()
     }
 
+    /// Table of the connected peers, see `DevP2PStream::peer_table`
+    pub fn peer_table(&self) -> PeerTable {
+        self.stream.peer_table()
+    }
+
+    /// Summary of the connection to a peer, see
+    /// `DevP2PStream::peer_info`
+    pub fn connection_info(&self, remote_id: H512) -> Option<rlpx::PeerInfo> {
+        self.stream.peer_info(remote_id)
+    }
+
+    /// Summaries of the connections to all connected peers
+    pub fn connections(&self) -> Vec<rlpx::PeerInfo> {
+        self.stream.peers()
+    }
+
+    /// Client version advertised in the Hello of new connections
+    pub fn client_version(&self) -> &str {
+        self.stream.client_version()
+    }
+
+    /// Change the client version advertised in the Hello. Only
+    /// affects connections established afterwards.
+    pub fn set_client_version(&mut self, client_version: String) {
+        self.stream.set_client_version(client_version);
+    }
+
     /// Set the best hash of the blockchain
     pub fn set_best_hash(&mut self, hash: H256) {
         self.best_hash = hash;
@@ -91,92 +464,2453 @@ impl ETHStream {
     pub fn set_total_difficulty(&mut self, diff: U256) {
         self.total_difficulty = diff;
     }
-}
 
-impl Stream for ETHStream {
-    type Item = ETHReceiveMessage;
-    type Error = io::Error;
+    /// Set how many recently seen block hashes are remembered
+    pub fn set_seen_blocks_capacity(&mut self, capacity: usize) {
+        self.seen_blocks.set_capacity(capacity);
+    }
 
-    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
-        let result = try_ready!(self.stream.poll());
+    /// Set the handling of announcements of already seen blocks
+    pub fn set_duplicate_block_policy(&mut self, policy: DuplicateBlockPolicy) {
+        self.duplicate_block_policy = policy;
+    }
+
+    /// Set the source of headers used to answer GetBlockHeaders
+    /// requests. Once set, those requests are answered automatically
+    /// and no longer delivered to the consumer.
+    pub fn set_header_provider(&mut self, provider: Box<dyn HeaderProvider>) {
+        self.header_provider = Some(provider);
+    }
 
-        if result.is_none() {
-            return Ok(Async::Ready(None));
+    /// Set the source of chain data used to answer the requests it
+    /// provides for, which are no longer delivered to the consumer.
+    /// A header provider set with `set_header_provider` takes
+    /// precedence for GetBlockHeaders.
+    pub fn set_chain_data_provider(&mut self, provider: Box<dyn ChainDataProvider>) {
+        self.chain_data_provider = Some(provider);
+    }
+
+    /// Set the transaction pool. Once set, transactions sent by peers
+    /// are imported into it instead of being delivered, announced
+    /// transactions it misses are requested, GetPooledTransactions
+    /// requests are answered from it, and its pending transactions
+    /// are sent to peers every transaction announce interval.
+    pub fn set_tx_pool(&mut self, pool: Box<dyn TxPool>) {
+        self.tx_pool = Some(pool);
+    }
+
+    /// Set how often the pending transactions of the pool are sent to
+    /// peers, one second by default
+    pub fn set_tx_announce_interval(&mut self, interval: Duration) {
+        self.tx_announce_interval = interval;
+        self.tx_announce_timer = None;
+    }
+
+    /// Look for the branch point of blocks announced in NewBlock
+    /// messages whose parent the header provider does not know, asking
+    /// the announcing peer for at most `depth` headers backwards. Those
+    /// blocks are then delivered as `ETHReceiveMessage::Reorg` or
+    /// `ETHReceiveMessage::UnknownAncestor`. `None`, the default, or
+    /// no header provider delivers them as they are, as are blocks
+    /// announced while the peer already has a search in flight or too
+    /// many peers have one. Peers not answering in time get their
+    /// block delivered as `UnknownAncestor`.
+    pub fn set_reorg_depth(&mut self, depth: Option<usize>) {
+        self.reorg_depth = depth;
+    }
+
+    /// Set the limits on serving the requests of peers
+    pub fn set_serve_limits(&mut self, limits: ServeLimits) {
+        self.serve_limits = limits;
+    }
+
+    /// Whether the block of the given hash is in the recently seen
+    /// history
+    pub fn has_seen_block(&self, hash: H256) -> bool {
+        self.seen_blocks.contains(&hash)
+    }
+
+    /// Peers that announced the block of the given hash or were sent
+    /// it, if it is still in the recently seen history
+    pub fn block_announcers(&self, hash: H256) -> &[H512] {
+        self.seen_blocks.announcers(&hash)
+    }
+
+    /// ETH version negotiated with a connected peer, as announced in
+    /// `ETHReceiveMessage::Connected`
+    pub fn peer_version(&self, node: H512) -> Option<usize> {
+        self.peer_versions.get(&node).cloned()
+    }
+
+    /// Event loop the stream runs on
+    pub(crate) fn handle(&self) -> &Handle {
+        self.stream.handle()
+    }
+
+    /// Information about a connected peer
+    pub fn peer_info(&self, node: H512) -> Option<PeerInfo> {
+        self.peer_versions.get(&node).map(|&version| PeerInfo {
+            version,
+            latency: self.latency.stats(&node),
+            violations: self.violations.get(&node).cloned().unwrap_or(0),
+            score: self.stream.reputation().score(&node),
+        })
+    }
+
+    /// Status of a connected peer, once it has sent one
+    pub fn peer_status(&self, node: H512) -> Option<&PeerStatus> {
+        self.peer_statuses.get(&node)
+    }
+
+    /// Statuses of all connected peers that have sent one
+    pub fn peers(&self) -> &HashMap<H512, PeerStatus> {
+        &self.peer_statuses
+    }
+
+    /// Peers that have sent a Status, by decreasing total difficulty
+    pub fn peers_by_total_difficulty(&self) -> Vec<(H512, &PeerStatus)> {
+        let mut peers: Vec<_> = self.peer_statuses.iter()
+            .map(|(&node, status)| (node, status))
+            .collect();
+        peers.sort_by_key(|&(_, status)| Reverse(status.total_difficulty));
+        peers
+    }
+
+    /// Peer with the highest total difficulty, the best sync target
+    pub fn best_peer(&self) -> Option<(H512, &PeerStatus)> {
+        self.peer_statuses.iter()
+            .max_by_key(|&(_, status)| status.total_difficulty)
+            .map(|(&node, status)| (node, status))
+    }
+
+    /// Set the average response latency above which peers are
+    /// disconnected while syncing. `None` disables the policy.
+    pub fn set_max_average_latency(&mut self, threshold: Option<Duration>) {
+        self.max_average_latency = threshold;
+    }
+
+    /// Set how long requests sent to a particular peer may wait for
+    /// their response before an `ETHReceiveMessage::Timeout` is
+    /// emitted, or the future of `request` fails. `None`, the default,
+    /// emits no `Timeout` and only forgets requests left unanswered for
+    /// two minutes, failing their futures.
+    pub fn set_request_timeout(&mut self, timeout: Option<Duration>) {
+        self.request_timeout = timeout;
+    }
+
+    /// Stop waiting for the response to the request with the given
+    /// request id sent to a peer, returning whether it was pending
+    pub fn cancel_request(&mut self, node: H512, request_id: u64) -> bool {
+        self.pending_requests.cancel(&node, request_id)
+    }
+
+    /// Send a request to a peer, resolving to its response once this
+    /// stream receives it, instead of delivering the response to the
+    /// consumer. The stream must keep being polled for the future to
+    /// resolve. It fails if the peer is not connected, disconnects or
+    /// does not answer in time, see `set_request_timeout`. Like
+    /// `start_send`, this must be called from within a task.
+    pub fn request(
+        &mut self, node: H512, data: ETHMessage
+    ) -> Box<dyn Future<Item = ETHMessage, Error = io::Error>> {
+        if !self.peer_versions.contains_key(&node) {
+            return Box::new(future::err(io::Error::new(io::ErrorKind::NotConnected, "peer is not connected")));
         }
-        let result = result.unwrap();
+        let request_id = self.next_request_id;
+        self.next_request_id += 1;
 
-        match result {
-            RLPxReceiveMessage::Connected { node, capabilities } => {
-                if capabilities.len() == 0 {
-                    debug!("connected a node without matching capability, ignoring.");
-                    return self.poll();
-                }
+        let sent = self.send_buffered(ETHSendMessage {
+            node: RLPxNode::Peer(node),
+            request_id: Some(request_id),
+            data,
+        }).and_then(|_| self.poll_complete());
+        if let Err(e) = sent {
+            return Box::new(future::err(e.into()));
+        }
 
-                let version = capabilities[0].version;
-                let total_difficulty = self.total_difficulty;
-                let best_hash = self.best_hash;
-                let genesis_hash = self.genesis_hash;
+        let (sender, receiver) = oneshot::channel();
+        self.waiting.insert((node, request_id), sender);
+        Box::new(receiver.then(|response| match response {
+            Ok(response) => response,
+            Err(_) => Err(io::Error::new(io::ErrorKind::Other, "request got no response")),
+        }))
+    }
 
-                // Send Status
-                self.start_send(ETHSendMessage {
-                    node: RLPxNode::Peer(node),
-                    data: ETHMessage::Status {
-                        network_id: 1,
-                        total_difficulty,
-                        best_hash,
-                        genesis_hash,
-                        protocol_version: version,
-                    }
-                })?;
-                self.poll_complete()?;
+    /// Request at most `max_headers` consecutive headers from `start`
+    /// from a peer, see `request`
+    pub fn request_headers(
+        &mut self, node: H512, start: HashOrNumber, max_headers: usize
+    ) -> Box<dyn Future<Item = Vec<Header>, Error = io::Error>> {
+        Box::new(self.request(node, ETHMessage::GetBlockHeaders {
+            start, max_headers, skip: 0, reverse: false,
+        }).and_then(|response| match response {
+            ETHMessage::BlockHeaders(headers) => Ok(headers),
+            _ => Err(io::Error::new(io::ErrorKind::Other, "unexpected response")),
+        }))
+    }
+
+    /// Request the bodies of the blocks with the given hashes from a
+    /// peer, see `request`
+    pub fn request_bodies(
+        &mut self, node: H512, hashes: Vec<H256>
+    ) -> Box<dyn Future<Item = Vec<BlockBody>, Error = io::Error>> {
+        Box::new(self.request(node, ETHMessage::GetBlockBodies(hashes))
+                 .and_then(|response| match response {
+                     ETHMessage::BlockBodies(bodies) => Ok(bodies),
+                     _ => Err(io::Error::new(io::ErrorKind::Other, "unexpected response")),
+                 }))
+    }
+
+    /// Request the receipts of the blocks with the given hashes from a
+    /// peer, see `request`
+    pub fn request_receipts(
+        &mut self, node: H512, hashes: Vec<H256>
+    ) -> Box<dyn Future<Item = Vec<Vec<Receipt>>, Error = io::Error>> {
+        Box::new(self.request(node, ETHMessage::GetReceipts(hashes))
+                 .and_then(|response| match response {
+                     ETHMessage::Receipts(receipts) => Ok(receipts),
+                     _ => Err(io::Error::new(io::ErrorKind::Other, "unexpected response")),
+                 }))
+    }
+
+    /// Set whether we are actively syncing, enabling the latency
+    /// policy
+    pub fn set_syncing(&mut self, syncing: bool) {
+        self.syncing = syncing;
+    }
+
+    /// Set the maximum number of hashes sent in one NewBlockHashes
+    /// message by `announce_block_hashes`
+    pub fn set_max_announce_hashes(&mut self, max: usize) {
+        self.max_announce_hashes = max;
+    }
+
+    /// Set the fork schedule our fork id is computed from. eth/64
+    /// peers announcing an incompatible fork id are disconnected.
+    /// Defaults to a chain without any fork.
+    pub fn set_fork_filter(&mut self, filter: ForkFilter) {
+        self.fork_filter = filter;
+    }
+
+    /// Switch to a new fork schedule, keeping our best block number,
+    /// e.g. when a fork gets scheduled while running. The stored fork
+    /// ids of eth/64 peers are checked again and the peers no longer
+    /// compatible are disconnected, unless trusted. Statuses sent
+    /// afterwards announce the new fork id. Returns the disconnected
+    /// peers.
+    pub fn update_fork_config(&mut self, schedule: ForkSchedule) -> Vec<H512> {
+        let mut filter = ForkFilter::new(schedule.genesis_hash, &schedule.forks);
+        filter.set_head(self.fork_filter.head());
+        self.fork_filter = filter;
+
+        let incompatible: Vec<H512> = self.peer_statuses.iter()
+            .filter(|&(node, status)| match status.fork_id {
+                Some(ref fork_id) => self.fork_filter.validate(fork_id).is_err() &&
+                    !self.stream.is_trusted(*node),
+                None => false,
+            })
+            .map(|(node, _)| *node)
+            .collect();
+        for &node in &incompatible {
+            debug!("disconnecting peer {:x} incompatible with the new fork schedule", node);
+            self.disconnect_peer(node, DisconnectReason::SubprotocolError);
+        }
+        incompatible
+    }
+
+    /// Update the block number of our best block, moving our fork id
+    /// past the forks it activates
+    pub fn set_best_number(&mut self, number: u64) {
+        self.fork_filter.set_head(number);
+    }
+
+    /// Move our chain head to the block of the given hash and number,
+    /// with the given total difficulty. Statuses sent to peers
+    /// connecting afterwards announce the new head. A stream created
+    /// with `new_awaiting_head` starts connecting to peers.
+    pub fn update_head(&mut self, hash: H256, number: u64, total_difficulty: U256) {
+        self.set_best_hash(hash);
+        self.set_best_number(number);
+        self.set_total_difficulty(total_difficulty);
+        self.awaiting_head = false;
+        if let Some(task) = self.head_waiter.take() {
+            task.notify();
+        }
+    }
+
+    /// Set the maximum number of transactions accepted in one
+    /// Transactions or PooledTransactions message. Larger messages are
+    /// dropped before decoding and count as a violation of the peer.
+    pub fn set_max_transactions(&mut self, max: usize) {
+        self.max_transactions = max;
+    }
+
+    /// Decode messages with `ETHMessage::decode_strict`, disconnecting
+    /// peers that send a malformed or non-canonical message instead of
+    /// ignoring it
+    pub fn set_strict_decoding(&mut self, strict: bool) {
+        self.strict_decoding = strict;
+    }
 
-                return Ok(Async::Ready(Some(ETHReceiveMessage::Connected {
-                    node, version
-                })))
+    /// Check the genesis hash and network id of the Status sent by
+    /// peers, disconnecting with `UselessPeer` the ones on another
+    /// chain instead of delivering their messages
+    pub fn set_validate_status(&mut self, validate: bool) {
+        self.validate_status = validate;
+    }
+
+    /// Request the header of the given block number from peers once
+    /// they sent their Status, disconnecting with `UselessPeer` the
+    /// ones whose header has another hash, like the DAO fork block
+    /// 1920000 to tell ETC and ETH peers apart. Peers without the
+    /// block yet are kept, peers not answering in time are dropped.
+    pub fn set_fork_block_check(&mut self, check: Option<(U256, H256)>) {
+        self.fork_block_check = check;
+    }
+
+    /// Set the size and nesting limits of incoming messages. Messages
+    /// over them are dropped before decoding and count as a violation
+    /// of the peer.
+    pub fn set_decode_limits(&mut self, limits: DecodeLimits) {
+        self.decode_limits = limits;
+    }
+
+    /// Announce new blocks to all peers, batching the hashes into as
+    /// few NewBlockHashes messages as the configured cap allows
+    pub fn announce_block_hashes(&mut self, hashes: Vec<(H256, U256)>) -> Result<(), io::Error> {
+        for message in batch_block_hashes(hashes, self.max_announce_hashes) {
+            self.send_buffered(ETHSendMessage {
+                node: RLPxNode::All,
+                request_id: None,
+                data: message,
+            })?;
+        }
+        self.poll_complete()?;
+        Ok(())
+    }
+
+    /// Propagate a new block: the full block goes to a random subset
+    /// of the square root of the peers and only its hash to the
+    /// others. Peers that announced the block to us are skipped.
+    pub fn propagate_block(
+        &mut self, header: Header, body: BlockBody, total_difficulty: U256
+    ) -> Result<(), io::Error> {
+        let hash = header.rlp_hash();
+        let number = header.number;
+        let peers: Vec<H512> = self.peer_versions.keys()
+            .filter(|node| !self.seen_blocks.announcers(&hash).contains(node))
+            .cloned()
+            .collect();
+        let (full, announced) = propagation_targets(peers);
+
+        for node in full {
+            self.send_buffered(ETHSendMessage {
+                node: RLPxNode::Peer(node),
+                request_id: None,
+                data: ETHMessage::NewBlock { header: header.clone(), body: body.clone(), total_difficulty },
+            })?;
+        }
+        for node in announced {
+            self.send_buffered(ETHSendMessage {
+                node: RLPxNode::Peer(node),
+                request_id: None,
+                data: ETHMessage::NewBlockHashes(vec![(hash, number)]),
+            })?;
+        }
+        self.poll_complete()?;
+        Ok(())
+    }
+
+    /// Propagate transactions: they are sent in full to a random
+    /// subset of the square root of the peers and only announced by
+    /// hash to the others, except eth/64 and older peers, which get
+    /// them in full. Each peer only receives those it is not known to
+    /// have.
+    pub fn propagate_transactions(
+        &mut self, transactions: Vec<TransactionEnvelope>
+    ) -> Result<(), io::Error> {
+        let peers: Vec<H512> = self.peer_versions.keys().cloned().collect();
+        let (full, announced) = propagation_targets(peers);
+        for node in announced {
+            let version = self.peer_versions[&node];
+            let data = transaction_announcement(version, &transactions)
+                .unwrap_or_else(|| ETHMessage::Transactions(transactions.clone()));
+            self.send_buffered(ETHSendMessage { node: RLPxNode::Peer(node), request_id: None, data })?;
+        }
+        for node in full {
+            self.send_buffered(ETHSendMessage {
+                node: RLPxNode::Peer(node),
+                request_id: None,
+                data: ETHMessage::Transactions(transactions.clone()),
+            })?;
+        }
+        self.poll_complete()?;
+        Ok(())
+    }
+
+    /// Send an already RLP-encoded ETH message with the given id to
+    /// all peers whose negotiated ETH version is at least
+    /// `min_version`. The payload is encoded only once by the caller;
+    /// snappy compression is still applied per peer as needed.
+    pub fn broadcast_raw(
+        &mut self, id: usize, payload: &[u8], min_version: usize
+    ) -> Result<(), io::Error> {
+        for message in raw_messages(&self.peer_versions, id, payload, min_version) {
+            self.stream.start_send(message)?;
+        }
+        self.stream.poll_complete()?;
+        Ok(())
+    }
+
+    /// Propagate the pending transactions of the pool each time the
+    /// announce timer fires
+    fn announce_pending_transactions(&mut self) -> Result<(), io::Error> {
+        if self.tx_pool.is_none() {
+            return Ok(());
+        }
+        if self.tx_announce_timer.is_none() {
+            self.tx_announce_timer = Some(Timeout::new(self.tx_announce_interval, self.handle())?);
+        }
+        if self.tx_announce_timer.as_mut().unwrap().poll()?.is_not_ready() {
+            return Ok(());
+        }
+        // Polling the next timer registers the wakeup for it
+        let mut timer = Timeout::new(self.tx_announce_interval, self.handle())?;
+        timer.poll()?;
+        self.tx_announce_timer = Some(timer);
+
+        let pending = match self.tx_pool {
+            Some(ref mut pool) => pool.pending(),
+            None => return Ok(()),
+        };
+        if pending.is_empty() {
+            return Ok(());
+        }
+        self.propagate_transactions(pending)
+    }
+
+    /// Ask a peer for the headers before the block it announced, if we
+    /// do not know its parent, returning the message back otherwise
+    fn start_ancestor_search(&mut self, node: H512, message: ETHMessage) -> Result<Option<ETHMessage>, io::Error> {
+        let depth = match (self.reorg_depth, &self.header_provider, &message) {
+            (Some(depth), Some(provider), ETHMessage::NewBlock { header, .. })
+                if provider.number_for_hash(header.parent_hash).is_none() => depth,
+            _ => return Ok(Some(message)),
+        };
+        if self.ancestor_searches.len() >= MAX_ANCESTOR_SEARCHES ||
+            self.ancestor_searches.keys().any(|&(searched_node, _)| searched_node == node)
+        {
+            debug!("not searching the ancestors of another block announced by {:x}", node);
+            return Ok(Some(message));
+        }
+        let (header, body, total_difficulty) = match message {
+            ETHMessage::NewBlock { header, body, total_difficulty } => (header, body, total_difficulty),
+            _ => unreachable!(),
+        };
+
+        let request_id = self.next_request_id;
+        self.next_request_id += 1;
+        self.send_buffered(ETHSendMessage {
+            node: RLPxNode::Peer(node),
+            request_id: Some(request_id),
+            data: ETHMessage::GetBlockHeaders {
+                start: HashOrNumber::Hash(header.parent_hash),
+                max_headers: depth, skip: 0, reverse: true,
             },
-            RLPxReceiveMessage::Disconnected { node } => {
-                return Ok(Async::Ready(Some(ETHReceiveMessage::Disconnected {
-                    node
-                })))
+        })?;
+        self.poll_complete()?;
+        self.ancestor_searches.insert((node, request_id), (header, body, total_difficulty, Instant::now()));
+        Ok(None)
+    }
+
+    /// Give up the oldest ancestor search the peer did not answer in
+    /// time, if any, returning its event
+    fn expire_ancestor_search(&mut self) -> Option<ETHReceiveMessage> {
+        let timeout = Duration::from_secs(ANCESTOR_SEARCH_TIMEOUT);
+        let (node, request_id) = self.ancestor_searches.iter()
+            .filter(|&(_, &(_, _, _, started))| started.elapsed() >= timeout)
+            .min_by_key(|&(_, &(_, _, _, started))| started)
+            .map(|(&key, _)| key)?;
+        let (header, body, total_difficulty, _) = self.ancestor_searches.remove(&(node, request_id)).unwrap();
+        self.pending_requests.cancel(&node, request_id);
+        debug!("peer {:x} did not answer the search for the ancestors of its block", node);
+        Some(self.ancestor_search_result(node, header, body, total_difficulty, None))
+    }
+
+    /// Event of an announced block once the peer answered the search
+    /// for its ancestor, with `None` if it did not
+    fn ancestor_search_result(
+        &self, node: H512, header: Header, body: BlockBody, total_difficulty: U256,
+        response: Option<&ETHMessage>
+    ) -> ETHReceiveMessage {
+        let found = match (&self.header_provider, response) {
+            (Some(provider), Some(ETHMessage::BlockHeaders(headers))) =>
+                find_branch(&**provider, header.parent_hash, headers),
+            _ => None,
+        };
+        match found {
+            Some((ancestor, branch)) => ETHReceiveMessage::Reorg {
+                node, ancestor, branch, header, body, total_difficulty,
+            },
+            None => ETHReceiveMessage::UnknownAncestor { node, header, body, total_difficulty },
+        }
+    }
+
+    /// Send a message on behalf of the stream, keeping it to be sent
+    /// again by `poll_complete` while its peer is busy. Messages to the
+    /// same peer keep their order.
+    fn send_buffered(&mut self, message: ETHSendMessage) -> Result<(), Error> {
+        if self.outgoing.iter().any(|buffered| buffered.node == message.node) {
+            self.buffer(message);
+            return Ok(());
+        }
+        if let AsyncSink::NotReady(message) = self.start_send(message)? {
+            self.buffer(message);
+        }
+        Ok(())
+    }
+
+    /// Peers a message for several peers is sent to one by one, since
+    /// its envelope or whether it is valid depends on their version.
+    /// `None` if RLPx can send it to all of them as is.
+    fn per_peer_targets(&self, node: RLPxNode, message: &ETHMessage) -> Option<Vec<H512>> {
+        let version_dependent = is_request_id_wrapped(message.id()) ||
+            self.peer_versions.values().any(|&version| !message.is_valid_for_version(version));
+        if !version_dependent {
+            return None;
+        }
+        let peers = self.peer_versions.iter()
+            .filter(|&(_, &version)| message.is_valid_for_version(version))
+            .map(|(&peer, _)| peer);
+        match node {
+            RLPxNode::All => Some(peers.collect()),
+            RLPxNode::AllExcept(except) => Some(peers.filter(|&peer| peer != except).collect()),
+            RLPxNode::Random(count) => {
+                let mut peers: Vec<H512> = peers.collect();
+                thread_rng().shuffle(&mut peers);
+                peers.truncate(count);
+                Some(peers)
             },
-            RLPxReceiveMessage::Normal {
-                node, capability, id, data,
+            RLPxNode::Any | RLPxNode::Peer(_) => None,
+        }
+    }
+
+    fn buffer(&mut self, message: ETHSendMessage) {
+        if self.outgoing.len() >= MAX_BUFFERED_MESSAGES {
+            debug!("dropping message with id {}, too many messages waiting for busy peers", message.data.id());
+            return;
+        }
+        self.outgoing.push_back(message);
+    }
+
+    /// Retry sending the buffered messages, in order for each peer
+    fn flush_buffered(&mut self) -> Result<(), Error> {
+        let mut busy = Vec::new();
+        let mut remaining = VecDeque::new();
+        while let Some(message) = self.outgoing.pop_front() {
+            if busy.contains(&message.node) {
+                remaining.push_back(message);
+                continue;
+            }
+            if let AsyncSink::NotReady(message) = self.start_send(message)? {
+                busy.push(message.node);
+                remaining.push_back(message);
+            }
+        }
+        self.outgoing = remaining;
+        Ok(())
+    }
+
+    /// Count a message breaking protocol limits against a peer
+    fn record_violation(&mut self, node: H512) {
+        *self.violations.entry(node).or_default() += 1;
+        self.stream.report_peer(node, ReputationEvent::InvalidMessage);
+    }
+
+    /// Disconnect peers that sent no ETH message within the idle reap
+    /// interval
+    fn reap_idle_peers(&mut self) {
+        let interval = match self.idle_reap_interval {
+            Some(interval) => interval,
+            None => return,
+        };
+
+        let idle: Vec<H512> = self.last_message.iter()
+            .filter(|&(node, last)| last.elapsed() >= interval && !self.stream.is_trusted(*node))
+            .map(|(&node, _)| node)
+            .collect();
+        for node in idle {
+            debug!("disconnecting idle peer {:x}", node);
+            self.last_message.remove(&node);
+            self.disconnect_peer(node, DisconnectReason::UselessPeer);
+        }
+    }
+
+    /// Ask a peer that just sent its Status for the fork block header
+    fn start_fork_block_check(&mut self, node: H512) -> Result<(), io::Error> {
+        let number = match self.fork_block_check {
+            Some((number, _)) => number,
+            None => return Ok(()),
+        };
+
+        self.pending_fork_block_checks.insert(node, Instant::now());
+        self.send_buffered(ETHSendMessage {
+            node: RLPxNode::Peer(node),
+            request_id: None,
+            data: ETHMessage::GetBlockHeaders {
+                start: HashOrNumber::Number(number),
+                max_headers: 1, skip: 0, reverse: false,
+            },
+        })?;
+        self.poll_complete()?;
+        Ok(())
+    }
+
+    /// Whether the headers are the answer of a pending fork block
+    /// check and, if so, whether the peer passes it
+    fn fork_block_check_result(&mut self, node: H512, headers: &[Header]) -> Option<bool> {
+        let (number, hash) = self.fork_block_check?;
+        if !self.pending_fork_block_checks.contains_key(&node) {
+            return None;
+        }
+
+        let passed = match headers.first() {
+            None => true,
+            Some(header) if headers.len() == 1 && header.number == number =>
+                header.rlp_hash() == hash,
+            Some(_) => return None,
+        };
+        self.pending_fork_block_checks.remove(&node);
+        Some(passed)
+    }
+
+    /// Disconnect peers that did not answer the fork block check in
+    /// time
+    fn expire_fork_block_checks(&mut self) {
+        let timeout = Duration::from_secs(FORK_BLOCK_CHECK_TIMEOUT);
+        let expired: Vec<H512> = self.pending_fork_block_checks.iter()
+            .filter(|&(_, sent)| sent.elapsed() >= timeout)
+            .map(|(&node, _)| node)
+            .collect();
+        for node in expired {
+            self.pending_fork_block_checks.remove(&node);
+            if !self.stream.is_trusted(node) {
+                debug!("disconnecting peer {:x} not answering the fork block check", node);
+                self.disconnect_peer(node, DisconnectReason::UselessPeer);
+            }
+        }
+    }
+
+    /// Our Status message for the given ETH version
+    fn status(&self, version: usize) -> ETHMessage {
+        if version >= 64 {
+            return ETHMessage::StatusWithForkId {
+                protocol_version: version,
+                network_id: self.network_id,
+                total_difficulty: self.total_difficulty,
+                best_hash: self.best_hash,
+                genesis_hash: self.genesis_hash,
+                fork_id: self.fork_filter.fork_id(),
+            };
+        }
+
+        ETHMessage::Status {
+            protocol_version: version,
+            network_id: self.network_id,
+            total_difficulty: self.total_difficulty,
+            best_hash: self.best_hash,
+            genesis_hash: self.genesis_hash,
+        }
+    }
+
+    /// Whether a Status message is for our genesis and network id.
+    /// Other messages always are.
+    fn is_same_chain(&self, message: &ETHMessage) -> bool {
+        match message {
+            &ETHMessage::Status { network_id, genesis_hash, .. } |
+            &ETHMessage::StatusWithForkId { network_id, genesis_hash, .. } =>
+                network_id == self.network_id && genesis_hash == self.genesis_hash,
+            _ => true,
+        }
+    }
+
+    /// Record the Status of a peer, or its new best block from a
+    /// NewBlock message
+    fn record_status(&mut self, node: H512, message: &ETHMessage) {
+        match *message {
+            ETHMessage::Status {
+                protocol_version, network_id, total_difficulty, best_hash, genesis_hash,
             } => {
-                debug!("got eth message with id {}", id);
-                let message = match ETHMessage::decode(&UntrustedRlp::new(&data), id) {
-                    Ok(val) => val,
-                    Err(_) => {
-                        debug!("got an ununderstandable message with id {}, data {:?}, ignoring.", id, data);
-                        return self.poll();
-                    },
-                };
-                return Ok(Async::Ready(Some(ETHReceiveMessage::Normal {
-                    node, version: capability.version,
-                    data: message,
-                })))
+                self.peer_statuses.insert(node, PeerStatus {
+                    protocol_version, network_id, total_difficulty, best_hash, genesis_hash,
+                    fork_id: None,
+                });
             },
+            ETHMessage::StatusWithForkId {
+                protocol_version, network_id, total_difficulty, best_hash, genesis_hash, fork_id,
+            } => {
+                self.peer_statuses.insert(node, PeerStatus {
+                    protocol_version, network_id, total_difficulty, best_hash, genesis_hash,
+                    fork_id: Some(fork_id),
+                });
+            },
+            ETHMessage::NewBlock { ref header, total_difficulty, .. } => {
+                if let Some(status) = self.peer_statuses.get_mut(&node) {
+                    status.best_hash = header.rlp_hash();
+                    status.total_difficulty = total_difficulty;
+                }
+            },
+            _ => (),
+        }
+    }
+
+    /// Record the blocks announced by a NewBlock or NewBlockHashes
+    /// message, returning whether all of them had already been seen
+    fn record_announcement(&mut self, node: H512, message: &ETHMessage) -> bool {
+        let hashes = block_hashes(message);
+        let mut all_seen = !hashes.is_empty();
+        for hash in hashes {
+            if !self.seen_blocks.insert(hash, node) {
+                all_seen = false;
+            }
+        }
+        all_seen
+    }
+}
+
+/// Decode a complete decrypted RLPx message, given its on-wire id,
+/// into its capability name and ETH message. Resolves the id against
+/// the negotiated capability offsets and strips the eth/66 request id
+/// when `version` requires it.
+pub fn decode_rlpx_message(
+    wire_id: usize, payload: &[u8], offsets: &CapabilityOffsets, version: usize
+) -> Result<(String, ETHMessage), DevP2PError> {
+    let (cap, id) = match offsets.resolve(wire_id) {
+        Some(val) => val,
+        None => return Err(DevP2PError::UnknownMessageId(wire_id)),
+    };
+    if cap.name != "eth" {
+        return Err(DevP2PError::UnsupportedCapability(cap.name.to_string()));
+    }
+
+    let (_, data) = unwrap_request_id(version, id, payload)?;
+    let message = ETHMessage::decode(&UntrustedRlp::new(&data), id)?;
+    Ok((cap.name.to_string(), message))
+}
+
+/// Whether a Transactions or PooledTransactions payload lists more
+/// than `max` transactions. Only item headers are walked, none of the
+/// transactions is decoded.
+fn exceeds_transaction_limit(id: usize, rlp: &UntrustedRlp, max: usize) -> bool {
+    match id {
+        0x02 | 0x0a => rlp.is_list() && rlp.iter().nth(max).is_some(),
+        _ => false,
+    }
+}
+
+/// Whether messages with the given id answer a request
+fn is_response(id: usize) -> bool {
+    matches!(id, ids::BLOCK_HEADERS | ids::BLOCK_BODIES | ids::POOLED_TRANSACTIONS |
+        ids::NODE_DATA | ids::RECEIPTS)
+}
+
+/// Split block hash announcements into NewBlockHashes messages of at
+/// most `max` hashes each
+fn batch_block_hashes(hashes: Vec<(H256, U256)>, max: usize) -> Vec<ETHMessage> {
+    hashes.chunks(max.max(1))
+        .map(|chunk| ETHMessage::NewBlockHashes(chunk.to_vec()))
+        .collect()
+}
+
+/// Shuffle the peers and split them into the square root of them
+/// receiving a full block and the rest receiving its hash
+fn propagation_targets(mut peers: Vec<H512>) -> (Vec<H512>, Vec<H512>) {
+    thread_rng().shuffle(&mut peers);
+    let full = (peers.len() as f64).sqrt() as usize;
+    let announced = peers.split_off(full);
+    (peers, announced)
+}
+
+/// Announcement of transactions to a peer running the given ETH
+/// version, `None` before eth/65
+fn transaction_announcement(version: usize, transactions: &[TransactionEnvelope]) -> Option<ETHMessage> {
+    if version >= 68 {
+        Some(ETHMessage::NewPooledTransactionHashes68(transactions.iter().map(|transaction| {
+            (transaction.transaction_type(), transaction.encoded_len(), transaction.hash())
+        }).collect()))
+    } else if version >= 65 {
+        Some(ETHMessage::NewPooledTransactionHashes(transactions.iter().map(|transaction| transaction.hash()).collect()))
+    } else {
+        None
+    }
+}
+
+/// Build the per-peer RLPx messages of a raw broadcast
+fn raw_messages(
+    peer_versions: &HashMap<H512, usize>, id: usize, payload: &[u8], min_version: usize
+) -> Vec<RLPxSendMessage> {
+    peer_versions.iter()
+        .filter(|&(_, &version)| version >= min_version)
+        .map(|(&node, _)| RLPxSendMessage {
+            node: RLPxNode::Peer(node),
+            capability_name: "eth",
+            id,
+            data: payload.to_vec(),
+        })
+        .collect()
+}
+
+impl Stream for ETHStream {
+    type Item = ETHReceiveMessage;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        // Messages that are dropped or handled here are followed by
+        // the next one in the same call
+        loop {
+            if self.awaiting_head {
+                self.head_waiter = Some(task::current());
+                return Ok(Async::NotReady);
+            }
+            self.flush_buffered()?;
+            self.reap_idle_peers();
+            self.expire_fork_block_checks();
+            if let Some(message) = self.expire_ancestor_search() {
+                return Ok(Async::Ready(Some(message)));
+            }
+            self.announce_pending_transactions()?;
+            let timeout = self.request_timeout.unwrap_or_else(|| Duration::from_secs(STALE_REQUEST_AGE));
+            while let Some((node, id, request_id)) = self.pending_requests.expire(timeout, Instant::now()) {
+                self.stream.report_peer(node, ReputationEvent::Timeout);
+                if let Some(request_id) = request_id {
+                    if let Some(sender) = self.waiting.remove(&(node, request_id)) {
+                        let _ = sender.send(Err(io::Error::new(io::ErrorKind::TimedOut, "request timed out")));
+                        continue;
+                    }
+                    if let Some((header, body, total_difficulty, _)) = self.ancestor_searches.remove(&(node, request_id)) {
+                        return Ok(Async::Ready(Some(
+                            self.ancestor_search_result(node, header, body, total_difficulty, None))));
+                    }
+                }
+                if self.request_timeout.is_some() {
+                    return Ok(Async::Ready(Some(ETHReceiveMessage::Timeout { node, id, request_id })));
+                }
+            }
+            let result = try_ready!(self.stream.poll());
+
+            if result.is_none() {
+                return Ok(Async::Ready(None));
+            }
+            let result = result.unwrap();
+
+            match result {
+                RLPxReceiveMessage::Connected { node, capabilities } => {
+                    // Other capabilities may be shared too, so only look at
+                    // the eth one
+                    let version = match capabilities.iter().find(|cap| cap.name == "eth") {
+                        Some(cap) => cap.version,
+                        None => {
+                            debug!("connected a node without matching capability, ignoring.");
+                            continue;
+                        },
+                    };
+                    self.peer_versions.insert(node, version);
+                    self.last_message.insert(node, Instant::now());
+                    let client_version = self.stream.peer_client_version(node)
+                        .unwrap_or_default().to_string();
+
+                    // Send our Status right away without waiting for the
+                    // peer's, which may arrive in any order
+                    let status = self.status(version);
+                    self.send_buffered(ETHSendMessage {
+                        node: RLPxNode::Peer(node),
+                        request_id: None,
+                        data: status,
+                    })?;
+                    self.poll_complete().map_err(|e| e.with_node(node))?;
+
+                    return Ok(Async::Ready(Some(ETHReceiveMessage::Connected {
+                        node, version, client_version,
+                    })))
+                },
+                RLPxReceiveMessage::Disconnected { node, reason, clean } => {
+                    self.peer_versions.remove(&node);
+                    self.peer_statuses.remove(&node);
+                    self.known_transactions.remove_peer(&node);
+                    self.pending_fork_block_checks.remove(&node);
+                    self.latency.remove(&node);
+                    self.pending_requests.remove(&node);
+                    self.inbound_requests.remove(&node);
+                    let waiting: Vec<(H512, u64)> = self.waiting.keys()
+                        .filter(|&&(waiting_node, _)| waiting_node == node)
+                        .cloned()
+                        .collect();
+                    for key in waiting {
+                        let sender = self.waiting.remove(&key).unwrap();
+                        let _ = sender.send(Err(io::Error::new(io::ErrorKind::ConnectionAborted, "peer disconnected")));
+                    }
+                    self.ancestor_searches.retain(|&(searched_node, _), _| searched_node != node);
+                    self.last_message.remove(&node);
+                    self.violations.remove(&node);
+                    self.outgoing.retain(|message| message.node != RLPxNode::Peer(node));
+                    return Ok(Async::Ready(Some(ETHReceiveMessage::Disconnected {
+                        node, reason, clean
+                    })))
+                },
+                RLPxReceiveMessage::Normal {
+                    node, capability, id, data,
+                } => {
+                    if capability.name != "eth" {
+                        if self.subprotocols.contains(&capability.name) && self.peer_versions.contains_key(&node) {
+                            if self.last_message.contains_key(&node) {
+                                self.last_message.insert(node, Instant::now());
+                            }
+                            return Ok(Async::Ready(Some(ETHReceiveMessage::Subprotocol {
+                                node, capability, id, data,
+                            })));
+                        }
+                        debug!("got message of capability {} without handler, ignoring.", capability.name);
+                        continue;
+                    }
+                    debug!("got eth message with id {}", id);
+                    if self.last_message.contains_key(&node) {
+                        self.last_message.insert(node, Instant::now());
+                    }
+                    if !ETHMessage::ids_for_version(capability.version).contains(&id) {
+                        debug!("got message id {} not part of eth/{}, ignoring.", id, capability.version);
+                        continue;
+                    }
+                    let (request_id, data) = match unwrap_request_id(capability.version, id, &data) {
+                        Ok(val) => val,
+                        Err(_) => {
+                            debug!("got a message with id {} without a valid request id envelope, ignoring.", id);
+                            continue;
+                        },
+                    };
+                    if !self.decode_limits.allows(id, &data) {
+                        debug!("dropping message with id {} over the decode limits from {:x}", id, node);
+                        self.record_violation(node);
+                        continue;
+                    }
+                    let rlp = UntrustedRlp::new(&data);
+                    if exceeds_transaction_limit(id, &rlp, self.max_transactions) {
+                        debug!("dropping oversized transaction list from {:x}", node);
+                        self.record_violation(node);
+                        continue;
+                    }
+                    let decoded = if self.strict_decoding {
+                        ETHMessage::decode_strict(&rlp, id)
+                    } else {
+                        ETHMessage::decode(&rlp, id)
+                    };
+                    let message = match decoded {
+                        Ok(val) => val,
+                        Err(e) if self.strict_decoding => {
+                            self.record_violation(node);
+                            if !self.stream.is_trusted(node) {
+                                debug!("disconnecting peer for a malformed message with id {}: {}",
+                                       id, Error::from(DevP2PError::from(e)).with_node(node));
+                                self.disconnect_peer(node, DisconnectReason::BreachOfProtocol);
+                            }
+                            continue;
+                        },
+                        Err(_) => {
+                            debug!("got an ununderstandable message with id {}, data {:?}, ignoring.", id, data);
+                            continue;
+                        },
+                    };
+                    if self.latency.response_received(node, id, Instant::now()).is_some() {
+                        if let Some(threshold) = self.max_average_latency {
+                            if self.syncing && self.latency.is_slow(&node, threshold) &&
+                                !self.stream.is_trusted(node)
+                            {
+                                debug!("disconnecting slow peer {:x}", node);
+                                self.disconnect_peer(node, DisconnectReason::UselessPeer);
+                            }
+                        }
+                    }
+                    let answered = self.pending_requests.response_received(&node, id, request_id);
+                    if answered.is_some() {
+                        self.stream.report_peer(node, ReputationEvent::UsefulResponse);
+                    }
+                    if let Some(Some(answered)) = answered {
+                        if let Some(sender) = self.waiting.remove(&(node, answered)) {
+                            let _ = sender.send(Ok(message));
+                            continue;
+                        }
+                        if let Some((header, body, total_difficulty, _)) = self.ancestor_searches.remove(&(node, answered)) {
+                            return Ok(Async::Ready(Some(
+                                self.ancestor_search_result(node, header, body, total_difficulty, Some(&message)))));
+                        }
+                    }
+                    if self.validate_status && !self.is_same_chain(&message) {
+                        debug!("disconnecting peer {:x} on another chain", node);
+                        self.disconnect_peer(node, DisconnectReason::UselessPeer);
+                        continue;
+                    }
+                    if let ETHMessage::StatusWithForkId { ref fork_id, .. } = message {
+                        if let Err(e) = self.fork_filter.validate(fork_id) {
+                            if !self.stream.is_trusted(node) {
+                                debug!("disconnecting peer {:x} with fork id {:?}: {:?}", node, fork_id, e);
+                                self.disconnect_peer(node, DisconnectReason::UselessPeer);
+                                continue;
+                            }
+                        }
+                    }
+                    self.record_status(node, &message);
+                    for hash in transaction_hashes(&message) {
+                        self.known_transactions.insert(node, hash);
+                    }
+                    match message {
+                        ETHMessage::Status { .. } | ETHMessage::StatusWithForkId { .. } =>
+                            self.start_fork_block_check(node)?,
+                        ETHMessage::BlockHeaders(ref headers) => {
+                            match self.fork_block_check_result(node, headers) {
+                                Some(true) => continue,
+                                Some(false) => {
+                                    if !self.stream.is_trusted(node) {
+                                        debug!("disconnecting peer {:x} failing the fork block check", node);
+                                        self.disconnect_peer(node, DisconnectReason::UselessPeer);
+                                    }
+                                    continue;
+                                },
+                                None => (),
+                            }
+                        },
+                        _ => (),
+                    }
+                    if message.response_id().is_some() {
+                        let pending = self.inbound_requests.entry(node).or_insert(0);
+                        match self.serve_limits.max_pending_requests {
+                            Some(max) if *pending >= max => {
+                                debug!("dropping request from {:x} with too many pending requests", node);
+                                continue;
+                            },
+                            _ => *pending += 1,
+                        }
+                    }
+                    let response = match self.header_provider {
+                        Some(ref provider) => serve_headers(&**provider, &message, &self.serve_limits),
+                        None => None,
+                    }.or_else(|| match self.chain_data_provider {
+                        Some(ref provider) => serve_chain_data(&**provider, &message, &self.serve_limits),
+                        None => None,
+                    }).or_else(|| match self.tx_pool {
+                        Some(ref pool) => serve_pooled(&**pool, &message),
+                        None => None,
+                    });
+                    if let Some(response) = response {
+                        if let Some(max) = self.serve_limits.max_bytes_per_second {
+                            let bytes = rlp::encode(&response).len();
+                            if !self.serve_rate.allow(bytes, max, Instant::now()) {
+                                debug!("dropping {} from {:x} over the serving rate", message, node);
+                                if let Some(pending) = self.inbound_requests.get_mut(&node) {
+                                    *pending = pending.saturating_sub(1);
+                                }
+                                continue;
+                            }
+                        }
+                        debug!("answering {} from {:x}", message, node);
+                        self.send_buffered(ETHSendMessage {
+                            node: RLPxNode::Peer(node),
+                            request_id,
+                            data: response,
+                        })?;
+                        self.poll_complete()?;
+                        continue;
+                    }
+                    let missing = match self.tx_pool {
+                        Some(ref pool) => missing_announced(&**pool, &message),
+                        None => None,
+                    };
+                    if let Some(hashes) = missing {
+                        if !hashes.is_empty() {
+                            let request_id = self.next_request_id;
+                            self.next_request_id += 1;
+                            self.send_buffered(ETHSendMessage {
+                                node: RLPxNode::Peer(node),
+                                request_id: Some(request_id),
+                                data: ETHMessage::GetPooledTransactions(hashes),
+                            })?;
+                            self.poll_complete()?;
+                        }
+                        continue;
+                    }
+                    let message = match self.tx_pool {
+                        Some(ref mut pool) => match message {
+                            ETHMessage::Transactions(transactions) |
+                            ETHMessage::PooledTransactions(transactions) => {
+                                let imported = transactions.into_iter()
+                                    .filter(|transaction| pool.insert(transaction.clone()))
+                                    .count();
+                                debug!("imported {} transactions from {:x}", imported, node);
+                                continue;
+                            },
+                            message => message,
+                        },
+                        None => message,
+                    };
+                    if self.record_announcement(node, &message) {
+                        match self.duplicate_block_policy {
+                            DuplicateBlockPolicy::Suppress => {
+                                debug!("suppressing already seen block announcement from {:x}", node);
+                                continue;
+                            },
+                            DuplicateBlockPolicy::Flag => {
+                                return Ok(Async::Ready(Some(ETHReceiveMessage::Duplicate {
+                                    node, version: capability.version,
+                                    request_id, data: message,
+                                })))
+                            },
+                        }
+                    }
+                    let message = match self.start_ancestor_search(node, message)? {
+                        Some(message) => message,
+                        None => continue,
+                    };
+                    return Ok(Async::Ready(Some(ETHReceiveMessage::Normal {
+                        node, version: capability.version,
+                        request_id, data: message,
+                    })))
+                },
+            }
         }
     }
 }
 
 impl Sink for ETHStream {
     type SinkItem = ETHSendMessage;
-    type SinkError = io::Error;
+    type SinkError = Error;
 
     fn start_send(&mut self, val: ETHSendMessage) -> StartSend<Self::SinkItem, Self::SinkError> {
+        let id = val.data.id();
+        let response_id = val.data.response_id();
+
+        // The request id envelope and the valid messages depend on
+        // the peer's version, so pick the peer here instead of leaving
+        // it to RLPx
+        let node = match val.node {
+            RLPxNode::Any if is_request_id_wrapped(id) && !self.peer_versions.is_empty() => {
+                match self.peer_versions.iter()
+                    .find(|&(_, &version)| ETHMessage::ids_for_version(version).contains(&id))
+                {
+                    Some((&node, _)) => RLPxNode::Peer(node),
+                    None => return Err(io::Error::new(io::ErrorKind::Other,
+                                                      "no peer supports the message").into()),
+                }
+            },
+            node => node,
+        };
+        if let Some(peers) = self.per_peer_targets(node, &val.data) {
+            for peer in peers {
+                self.send_buffered(ETHSendMessage { node: RLPxNode::Peer(peer), ..val.clone() })?;
+            }
+            return Ok(AsyncSink::Ready);
+        }
+        // Peers are never sent transactions or blocks they already
+        // have
+        if let (RLPxNode::Peer(node), ETHMessage::NewBlock { header, .. }) = (node, &val.data) {
+            if self.seen_blocks.knows(&node, &header.rlp_hash()) {
+                return Ok(AsyncSink::Ready);
+            }
+        }
+        let filtered = match node {
+            RLPxNode::Peer(node) => self.known_transactions.filter(&node, &val.data)
+                .or_else(|| self.seen_blocks.filter(&node, &val.data)),
+            _ => None,
+        };
+        let message = filtered.as_ref().unwrap_or(&val.data);
+        let transactions = transaction_hashes(message);
+        let blocks = block_hashes(message);
+        if filtered.is_some() && transactions.is_empty() && blocks.is_empty() {
+            return Ok(AsyncSink::Ready);
+        }
+
+        let mut data = rlp::encode(message).to_vec();
+        if let RLPxNode::Peer(node) = node {
+            if let Some(&version) = self.peer_versions.get(&node) {
+                if !val.data.is_valid_for_version(version) {
+                    let error = io::Error::new(io::ErrorKind::Other,
+                                               format!("message with id {} is not valid on eth/{}", id, version));
+                    return Err(Error::from(error).with_node(node));
+                }
+                data = wrap_request_id(version, id, val.request_id.unwrap_or(0), &data);
+            }
+        }
+
         match self.stream.start_send(RLPxSendMessage {
-            node: val.node,
+            node,
             capability_name: "eth",
-            id: val.data.id(),
-            data: rlp::encode(&val.data).to_vec(),
+            id,
+            data,
         }) {
-            Ok(AsyncSink::Ready) => Ok(AsyncSink::Ready),
-            Ok(AsyncSink::NotReady(v)) => Ok(AsyncSink::NotReady(val)),
+            Ok(AsyncSink::Ready) => {
+                // Only requests to a particular peer can be matched
+                // with their responses
+                if let (RLPxNode::Peer(node), Some(response_id)) = (node, response_id) {
+                    self.latency.request_sent(node, response_id, Instant::now());
+                    self.pending_requests.request_sent(
+                        node, id, response_id, val.request_id, Instant::now());
+                }
+                if let RLPxNode::Peer(node) = node {
+                    if is_response(id) {
+                        if let Some(pending) = self.inbound_requests.get_mut(&node) {
+                            *pending = pending.saturating_sub(1);
+                        }
+                    }
+                    for hash in transactions {
+                        self.known_transactions.insert(node, hash);
+                    }
+                    for hash in blocks {
+                        self.seen_blocks.insert(hash, node);
+                    }
+                }
+                Ok(AsyncSink::Ready)
+            },
+            Ok(AsyncSink::NotReady(_)) => Ok(AsyncSink::NotReady(val)),
             Err(e) => Err(e),
         }
     }
 
+    /// Also retries the messages the stream itself could not send yet,
+    /// not ready until they are all sent
     fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
-        self.stream.poll_complete()
+        self.flush_buffered()?;
+        let flushed = self.stream.poll_complete()?;
+        if !self.outgoing.is_empty() {
+            return Ok(Async::NotReady);
+        }
+        Ok(flushed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ETHStream, ChainConfig, ETHMessage, ETHSendMessage, ETHReceiveMessage, ForkId, ForkFilter, ForkSchedule, DecodeLimits, ServeLimits,
+                PeerStatus, HashOrNumber, ids, raw_messages, propagation_targets, transaction_announcement, decode_rlpx_message, wrap_request_id, unwrap_request_id,
+                TransactionEnvelope, BlockBody, ANCESTOR_SEARCH_TIMEOUT};
+    use super::proto::{sample_transaction, sample_header};
+    use super::pool::tests::MemoryPool;
+    use super::reorg::tests::Known;
+    use super::super::{DevP2PConfig, DevP2PBuilder, Capability, Error};
+    use bigint::{H256, H512, U256};
+    use block::RlpHash;
+    use super::Header;
+    use rlp::{self, UntrustedRlp, RlpStream};
+    use rlpx::{RLPxNode, PeerStream, CapabilityInfo, CapabilityOffsets, DisconnectReason};
+    use secp256k1::SECP256K1;
+    use secp256k1::key::{PublicKey, SecretKey};
+    use tokio_core::reactor::Core;
+    use futures::{future, Async, Poll, Future, Stream, Sink};
+    use rand::os::OsRng;
+    use std::io;
+    use std::collections::HashMap;
+    use std::net::{SocketAddr, TcpListener};
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    fn test_chain(genesis_hash: H256) -> ChainConfig {
+        ChainConfig {
+            network_id: 61,
+            genesis_hash,
+            best_hash: genesis_hash,
+            total_difficulty: U256::from(1),
+        }
+    }
+
+    fn test_config() -> DevP2PConfig {
+        DevP2PConfig {
+            ping_interval: Duration::new(600, 0),
+            ping_timeout_interval: Duration::new(700, 0),
+            optimal_peers_interval: Duration::new(600, 0),
+            max_peers_per_ip: None,
+            ..Default::default()
+        }
+    }
+
+    /// Listen with a new ETH stream and connect a mock eth/62 RLPx
+    /// peer to it, returning the stream, the peer and the peer's id
+    fn connect_mock_peer(
+        core: &mut Core, genesis_hash: H256, config: DevP2PConfig
+    ) -> (ETHStream, PeerStream, H512) {
+        let capability = CapabilityInfo { name: "eth", version: 62, length: ids::ETH62_LENGTH };
+        let (eth, peer, peer_id, version) =
+            connect_mock_peer_with(core, genesis_hash, config, capability);
+        assert_eq!(version, 62);
+
+        (eth, peer, peer_id)
+    }
+
+    /// Like `connect_mock_peer`, with the mock peer advertising the
+    /// given capability. Also returns the negotiated ETH version.
+    fn connect_mock_peer_with(
+        core: &mut Core, genesis_hash: H256, config: DevP2PConfig, capability: CapabilityInfo
+    ) -> (ETHStream, PeerStream, H512, usize) {
+        let handle = core.handle();
+
+        let addr = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let secret_key = SecretKey::new(&SECP256K1, &mut OsRng::new().unwrap());
+        let id = pk2id(&PublicKey::from_secret_key(&SECP256K1, &secret_key).unwrap());
+        let peer_key = SecretKey::new(&SECP256K1, &mut OsRng::new().unwrap());
+        let peer_id = pk2id(&PublicKey::from_secret_key(&SECP256K1, &peer_key).unwrap());
+
+        let mut eth = ETHStream::new(
+            &addr, &addr.ip(), &handle, Arc::new(secret_key),
+            test_chain(genesis_hash), DevP2PBuilder::from(config).client_version("eth".to_string())).unwrap();
+
+        let (peer, version) = connect_peer(core, &mut eth, &addr, id, peer_key, capability);
+
+        (eth, peer, peer_id, version)
+    }
+
+    /// Connect a mock RLPx peer with the given key to an ETH stream
+    /// listening on `addr`, returning the peer and the negotiated ETH
+    /// version
+    fn connect_peer(
+        core: &mut Core, eth: &mut ETHStream, addr: &SocketAddr, id: H512, peer_key: SecretKey,
+        capability: CapabilityInfo
+    ) -> (PeerStream, usize) {
+        let peer = PeerStream::connect(
+            addr, &core.handle(), Arc::new(peer_key), id,
+            5, "peer".to_string(), vec![capability], 0, Default::default());
+        let connected = future::poll_fn(|| -> Poll<usize, io::Error> {
+            loop {
+                match try_ready!(eth.poll()) {
+                    Some(ETHReceiveMessage::Connected { version, .. }) =>
+                        return Ok(Async::Ready(version)),
+                    Some(_) => (),
+                    None => panic!(),
+                }
+            }
+        });
+        core.run(peer.join(connected)).unwrap()
+    }
+
+    /// ETH stream listening without any peer
+    fn unconnected_stream(core: &Core, genesis_hash: H256) -> ETHStream {
+        let addr = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        ETHStream::new(
+            &addr, &addr.ip(), &core.handle(), Arc::new(SecretKey::new(&SECP256K1, &mut OsRng::new().unwrap())),
+            test_chain(genesis_hash), DevP2PBuilder::from(test_config()).client_version("eth".to_string())).unwrap()
+    }
+
+    fn pk2id(key: &PublicKey) -> H512 {
+        H512::from(&key.serialize_vec(&SECP256K1, false)[1..65])
+    }
+
+    #[test]
+    fn status_sent_right_after_handshake() {
+        let mut core = Core::new().unwrap();
+        let genesis_hash = H256::random();
+
+        // The mock peer never sends its own Status
+        let (_eth, peer, _) = connect_mock_peer(&mut core, genesis_hash, test_config());
+
+        let (message, _) = core.run(peer.into_future().map_err(|(e, _)| e)).unwrap();
+        let (cap, id, data) = message.unwrap();
+        assert_eq!(cap.name, "eth");
+        assert_eq!(id, 0);
+        match ETHMessage::decode(&UntrustedRlp::new(&data), id).unwrap() {
+            ETHMessage::Status { protocol_version, network_id, genesis_hash: genesis, .. } => {
+                assert_eq!(protocol_version, 62);
+                assert_eq!(network_id, 61);
+                assert_eq!(genesis, genesis_hash);
+            },
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn status_follows_head() {
+        let core = Core::new().unwrap();
+        let genesis_hash = H256::random();
+        let mut eth = unconnected_stream(&core, genesis_hash);
+
+        let best_hash = H256::random();
+        eth.update_head(best_hash, 100, U256::from(12345));
+        match eth.status(63) {
+            ETHMessage::Status { best_hash: hash, total_difficulty, genesis_hash: genesis, .. } => {
+                assert_eq!(hash, best_hash);
+                assert_eq!(total_difficulty, U256::from(12345));
+                assert_eq!(genesis, genesis_hash);
+            },
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn network_waits_for_head() {
+        let mut core = Core::new().unwrap();
+        let addr = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let genesis_hash = H256::random();
+        let mut eth = ETHStream::new_awaiting_head(
+            &addr, &addr.ip(), &core.handle(), Arc::new(SecretKey::new(&SECP256K1, &mut OsRng::new().unwrap())),
+            61, genesis_hash, DevP2PBuilder::from(test_config()).client_version("eth".to_string())).unwrap();
+
+        let best_hash = H256::random();
+        core.run(future::lazy(|| -> Result<(), io::Error> {
+            assert_eq!(eth.poll()?, Async::NotReady);
+            assert!(eth.head_waiter.is_some());
+            eth.update_head(best_hash, 100, U256::from(12345));
+            Ok(())
+        })).unwrap();
+        assert!(!eth.awaiting_head && eth.head_waiter.is_none());
+        assert_eq!(eth.best_hash, best_hash);
+    }
+
+    #[test]
+    fn best_peer_by_total_difficulty() {
+        let core = Core::new().unwrap();
+        let genesis_hash = H256::random();
+        let mut eth = unconnected_stream(&core, genesis_hash);
+        assert!(eth.best_peer().is_none());
+
+        let nodes: Vec<H512> = (0..3).map(|_| H512::random()).collect();
+        for (&node, &difficulty) in nodes.iter().zip(&[20u64, 30, 10]) {
+            eth.peer_statuses.insert(node, PeerStatus {
+                protocol_version: 63,
+                network_id: 61,
+                total_difficulty: U256::from(difficulty),
+                best_hash: H256::random(),
+                genesis_hash,
+                fork_id: None,
+            });
+        }
+
+        assert_eq!(eth.best_peer().unwrap().0, nodes[1]);
+        let ranked: Vec<H512> = eth.peers_by_total_difficulty().into_iter()
+            .map(|(node, _)| node)
+            .collect();
+        assert_eq!(ranked, vec![nodes[1], nodes[0], nodes[2]]);
+    }
+
+    #[test]
+    fn eth64_status_carries_fork_id() {
+        let mut core = Core::new().unwrap();
+        let genesis_hash = H256::random();
+        let capability = CapabilityInfo { name: "eth", version: 64, length: ids::ETH63_LENGTH };
+        let (_eth, peer, _, version) =
+            connect_mock_peer_with(&mut core, genesis_hash, test_config(), capability);
+        assert_eq!(version, 64);
+
+        let (message, _) = core.run(peer.into_future().map_err(|(e, _)| e)).unwrap();
+        let (_, id, data) = message.unwrap();
+        match ETHMessage::decode(&UntrustedRlp::new(&data), id).unwrap() {
+            ETHMessage::StatusWithForkId { protocol_version, genesis_hash: genesis, fork_id, .. } => {
+                assert_eq!(protocol_version, 64);
+                assert_eq!(genesis, genesis_hash);
+                assert_eq!(fork_id, ForkId::new(genesis_hash, &[], 0));
+            },
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn idle_peer_reaped() {
+        let mut core = Core::new().unwrap();
+        let mut config = test_config();
+        config.optimal_peers_interval = Duration::from_millis(50);
+        config.idle_reap_interval = Some(Duration::from_millis(300));
+
+        // The mock peer stays connected but never sends ETH messages
+        let (mut eth, _peer, peer_id) = connect_mock_peer(&mut core, H256::random(), config);
+        let start = Instant::now();
+
+        let disconnected = future::poll_fn(|| -> Poll<H512, io::Error> {
+            loop {
+                match try_ready!(eth.poll()) {
+                    Some(ETHReceiveMessage::Disconnected { node, .. }) =>
+                        return Ok(Async::Ready(node)),
+                    Some(_) => (),
+                    None => panic!(),
+                }
+            }
+        });
+        let node = core.run(disconnected).unwrap();
+        assert_eq!(node, peer_id);
+        assert!(start.elapsed() >= Duration::from_millis(250));
+    }
+
+    #[test]
+    fn announce_batches_block_hashes() {
+        let mut core = Core::new().unwrap();
+        let (mut eth, peer, _) = connect_mock_peer(&mut core, H256::random(), test_config());
+
+        let hashes: Vec<(H256, U256)> = (0..200)
+            .map(|number| (H256::random(), U256::from(number)))
+            .collect();
+        eth.set_max_announce_hashes(64);
+        core.run(future::lazy(|| eth.announce_block_hashes(hashes.clone()))).unwrap();
+
+        // Status first, then the announcements
+        let messages = core.run(peer.take(5).collect()).unwrap();
+        let mut received = Vec::new();
+        let mut sizes = Vec::new();
+        for (_, id, data) in messages.into_iter().skip(1) {
+            match ETHMessage::decode(&UntrustedRlp::new(&data), id).unwrap() {
+                ETHMessage::NewBlockHashes(batch) => {
+                    sizes.push(batch.len());
+                    received.extend(batch);
+                },
+                _ => panic!(),
+            }
+        }
+        assert_eq!(sizes, vec![64, 64, 64, 8]);
+        assert_eq!(received, hashes);
+    }
+
+    #[test]
+    fn decode_eth66_at_offset() {
+        let offsets = CapabilityOffsets::new(vec![
+            CapabilityInfo { name: "eth", version: 66, length: ids::ETH63_LENGTH },
+            CapabilityInfo { name: "bzz", version: 1, length: 10 },
+        ]);
+        let request = ETHMessage::GetBlockHeaders {
+            start: HashOrNumber::Number(U256::from(1000)), max_headers: 64, skip: 0, reverse: false,
+        };
+        let payload = wrap_request_id(66, request.id(), 42, &rlp::encode(&request));
+        let wire_id = offsets.wire_id("eth", request.id()).unwrap();
+        assert_eq!(wire_id, 0x10 + 10 + 3);
+
+        let (name, message) = decode_rlpx_message(wire_id, &payload, &offsets, 66).unwrap();
+        assert_eq!(name, "eth");
+        assert_eq!(message, request);
+
+        assert!(decode_rlpx_message(0x10, &payload, &offsets, 66).is_err());
+    }
+
+    #[test]
+    fn raw_broadcast_reaches_qualifying_peers() {
+        let message = ETHMessage::NewBlockHashes(vec![(H256::random(), U256::from(42))]);
+        let payload = rlp::encode(&message).to_vec();
+
+        let peers = [H512::random(), H512::random(), H512::random()];
+        let mut peer_versions = HashMap::new();
+        peer_versions.insert(peers[0], 63);
+        peer_versions.insert(peers[1], 62);
+        peer_versions.insert(peers[2], 63);
+
+        let messages = raw_messages(&peer_versions, message.id(), &payload, 63);
+        assert_eq!(messages.len(), 2);
+        for sent in &messages {
+            assert!(sent.node == RLPxNode::Peer(peers[0]) || sent.node == RLPxNode::Peer(peers[2]));
+            assert_eq!(sent.capability_name, "eth");
+            let decoded = ETHMessage::decode(&UntrustedRlp::new(&sent.data), sent.id).unwrap();
+            assert_eq!(decoded, message);
+        }
+    }
+
+    #[test]
+    fn full_blocks_to_square_root_of_peers() {
+        for &(count, full_count) in &[(0, 0), (1, 1), (3, 1), (4, 2), (25, 5), (30, 5)] {
+            let peers: Vec<H512> = (0..count).map(|_| H512::random()).collect();
+            let (full, announced) = propagation_targets(peers.clone());
+            assert_eq!(full.len(), full_count);
+            assert_eq!(full.len() + announced.len(), count);
+            assert!(full.iter().chain(&announced).all(|node| peers.contains(node)));
+        }
+    }
+
+    #[test]
+    fn transactions_announced_by_version() {
+        let legacy: TransactionEnvelope = sample_transaction().into();
+        let typed = TransactionEnvelope::Typed { transaction_type: 2, payload: vec![0xc2, 0x01, 0x02] };
+        let transactions = vec![legacy.clone(), typed.clone()];
+
+        assert_eq!(transaction_announcement(64, &transactions), None);
+        assert_eq!(transaction_announcement(67, &transactions),
+                   Some(ETHMessage::NewPooledTransactionHashes(vec![legacy.hash(), typed.hash()])));
+        assert_eq!(transaction_announcement(68, &transactions),
+                   Some(ETHMessage::NewPooledTransactionHashes68(vec![
+                       (0, rlp::encode(&legacy).len(), legacy.hash()),
+                       (2, 4, typed.hash()),
+                   ])));
+    }
+
+    #[test]
+    fn oversized_transactions_rejected() {
+        let mut core = Core::new().unwrap();
+        let (mut eth, peer, peer_id) = connect_mock_peer(&mut core, H256::random(), test_config());
+        eth.set_max_transactions(16);
+
+        // Items that are not transactions at all, so the message could
+        // only be rejected before decoding
+        let mut oversized = RlpStream::new_list(17);
+        for _ in 0..17 {
+            oversized.append_empty_data();
+        }
+        let valid = ETHMessage::Transactions(vec![sample_transaction().into()]);
+        let peer = core.run(peer.send(("eth", 2, oversized.out().to_vec()))).unwrap();
+        let _peer = core.run(peer.send(("eth", 2, rlp::encode(&valid).to_vec()))).unwrap();
+
+        let received = future::poll_fn(|| -> Poll<ETHMessage, io::Error> {
+            loop {
+                match try_ready!(eth.poll()) {
+                    Some(ETHReceiveMessage::Normal { data, .. }) =>
+                        return Ok(Async::Ready(data)),
+                    Some(_) => (),
+                    None => panic!(),
+                }
+            }
+        });
+        assert_eq!(core.run(received).unwrap(), valid);
+        assert_eq!(eth.peer_info(peer_id).unwrap().violations, 1);
+    }
+
+    #[test]
+    fn pending_requests_capped() {
+        let mut core = Core::new().unwrap();
+        let (mut eth, peer, peer_id) = connect_mock_peer(&mut core, H256::random(), test_config());
+        eth.set_serve_limits(ServeLimits { max_pending_requests: Some(1), ..Default::default() });
+
+        let request = ETHMessage::GetBlockBodies(vec![H256::random()]);
+        let valid = ETHMessage::Transactions(vec![sample_transaction().into()]);
+        let peer = core.run(peer.send(("eth", ids::GET_BLOCK_BODIES, rlp::encode(&request).to_vec()))).unwrap();
+        let peer = core.run(peer.send(("eth", ids::GET_BLOCK_BODIES, rlp::encode(&request).to_vec()))).unwrap();
+        let _peer = core.run(peer.send(("eth", ids::TRANSACTIONS, rlp::encode(&valid).to_vec()))).unwrap();
+
+        let mut received = Vec::new();
+        core.run(future::poll_fn(|| -> Poll<(), io::Error> {
+            while received.len() < 2 {
+                match try_ready!(eth.poll()) {
+                    Some(ETHReceiveMessage::Normal { data, .. }) => received.push(data),
+                    Some(_) => (),
+                    None => panic!(),
+                }
+            }
+            Ok(Async::Ready(()))
+        })).unwrap();
+        assert_eq!(received, vec![request, valid]);
+        assert_eq!(eth.inbound_requests.get(&peer_id), Some(&1));
+    }
+
+    #[test]
+    fn transactions_go_through_pool() {
+        let mut core = Core::new().unwrap();
+        let capability = CapabilityInfo { name: "eth", version: 65, length: ids::ETH63_LENGTH };
+        let (mut eth, peer, _, _) =
+            connect_mock_peer_with(&mut core, H256::random(), test_config(), capability);
+        let known: TransactionEnvelope = sample_transaction().into();
+        let unknown = TransactionEnvelope::Typed { transaction_type: 2, payload: vec![0xc0] };
+        let pending = TransactionEnvelope::Typed { transaction_type: 2, payload: vec![0xc1, 0x01] };
+        let mut pool = MemoryPool::default();
+        pool.transactions.insert(known.hash(), known.clone());
+        pool.transactions.insert(pending.hash(), pending.clone());
+        pool.pending.push(pending.clone());
+        eth.set_tx_pool(Box::new(pool));
+        eth.set_tx_announce_interval(Duration::from_millis(0));
+
+        let marker = ETHMessage::NewBlockHashes(vec![(H256::random(), U256::from(1))]);
+        let mut peer = Some(peer);
+        for message in [
+            ETHMessage::NewPooledTransactionHashes(vec![known.hash(), unknown.hash()]),
+            ETHMessage::GetPooledTransactions(vec![known.hash()]),
+            ETHMessage::PooledTransactions(vec![unknown.clone()]),
+            ETHMessage::Transactions(vec![known.clone()]),
+            marker.clone(),
+        ] {
+            let message = ("eth", message.id(), rlp::encode(&message).to_vec());
+            peer = Some(core.run(peer.take().unwrap().send(message)).unwrap());
+        }
+
+        // Only the marker is delivered
+        let received = future::poll_fn(|| -> Poll<ETHMessage, io::Error> {
+            loop {
+                match try_ready!(eth.poll()) {
+                    Some(ETHReceiveMessage::Normal { data, .. }) => return Ok(Async::Ready(data)),
+                    Some(_) => (),
+                    None => panic!(),
+                }
+            }
+        });
+        assert_eq!(core.run(received).unwrap(), marker);
+
+        // Our Status, the request for the missing transaction, the
+        // answer to the peer's request and, once the announce timer
+        // fired, the pending transaction
+        let drive = future::poll_fn(|| -> Poll<Vec<(CapabilityInfo, usize, Vec<u8>)>, io::Error> {
+            while let Async::Ready(Some(_)) = eth.poll()? { }
+            Ok(Async::NotReady)
+        });
+        let sent = core.run(peer.unwrap().take(4).collect().select(drive).map(|(sent, _)| sent).map_err(|(e, _)| e))
+            .unwrap();
+        let sent: Vec<ETHMessage> = sent.into_iter()
+            .map(|(_, id, data)| ETHMessage::decode(&UntrustedRlp::new(&data), id).unwrap())
+            .collect();
+        assert_eq!(&sent[1..3], &[
+            ETHMessage::GetPooledTransactions(vec![unknown.hash()]),
+            ETHMessage::PooledTransactions(vec![known]),
+        ]);
+        assert_eq!(sent[3], ETHMessage::Transactions(vec![pending]));
+    }
+
+    #[test]
+    fn announced_block_branch_found() {
+        let mut core = Core::new().unwrap();
+        let (mut eth, peer, peer_id) = connect_mock_peer(&mut core, H256::random(), test_config());
+        let ancestor = H256::random();
+        eth.set_header_provider(Box::new(Known(ancestor)));
+        eth.set_reorg_depth(Some(8));
+
+        let mut parent_hash = ancestor;
+        let branch: Vec<Header> = (11..14).map(|number| {
+            let mut header = sample_header();
+            header.number = U256::from(number);
+            header.parent_hash = parent_hash;
+            parent_hash = header.rlp_hash();
+            header
+        }).collect();
+        let mut header = sample_header();
+        header.number = U256::from(14);
+        header.parent_hash = parent_hash;
+        let body = BlockBody { transactions: Vec::new(), ommers: Vec::new() };
+        let announcement = ETHMessage::NewBlock {
+            header: header.clone(), body: body.clone(), total_difficulty: U256::from(100),
+        };
+        let peer = core.run(peer.send(("eth", ids::NEW_BLOCK, rlp::encode(&announcement).to_vec()))).unwrap();
+
+        let mut received = None;
+        core.run(future::poll_fn(|| -> Poll<(), io::Error> {
+            while let Async::Ready(Some(message)) = eth.poll()? {
+                received = Some(message);
+            }
+            Ok(Async::Ready(()))
+        })).unwrap();
+        assert_eq!(received, None);
+
+        // Our Status, then the request for the headers before the block
+        let (_, peer) = core.run(peer.into_future().map_err(|(e, _)| e)).unwrap();
+        let (request, peer) = core.run(peer.into_future().map_err(|(e, _)| e)).unwrap();
+        let (_, id, data) = request.unwrap();
+        assert_eq!(ETHMessage::decode(&UntrustedRlp::new(&data), id).unwrap(), ETHMessage::GetBlockHeaders {
+            start: HashOrNumber::Hash(parent_hash), max_headers: 8, skip: 0, reverse: true,
+        });
+
+        let mut backwards = branch.clone();
+        backwards.reverse();
+        let response = ETHMessage::BlockHeaders(backwards);
+        let _peer = core.run(peer.send(("eth", ids::BLOCK_HEADERS, rlp::encode(&response).to_vec()))).unwrap();
+        let reorg = future::poll_fn(|| -> Poll<ETHReceiveMessage, io::Error> {
+            match try_ready!(eth.poll()) {
+                Some(message) => Ok(Async::Ready(message)),
+                None => panic!(),
+            }
+        });
+        assert_eq!(core.run(reorg).unwrap(), ETHReceiveMessage::Reorg {
+            node: peer_id, ancestor, branch, header, body, total_difficulty: U256::from(100),
+        });
+    }
+
+    #[test]
+    fn ancestor_searches_bounded() {
+        let mut core = Core::new().unwrap();
+        let (mut eth, peer, peer_id) = connect_mock_peer(&mut core, H256::random(), test_config());
+        eth.set_header_provider(Box::new(Known(H256::random())));
+        eth.set_reorg_depth(Some(8));
+
+        let body = BlockBody { transactions: Vec::new(), ommers: Vec::new() };
+        let announcements: Vec<ETHMessage> = (0..2).map(|_| {
+            let mut header = sample_header();
+            header.parent_hash = H256::random();
+            ETHMessage::NewBlock { header, body: body.clone(), total_difficulty: U256::from(100) }
+        }).collect();
+        let peer = core.run(peer.send(("eth", ids::NEW_BLOCK, rlp::encode(&announcements[0]).to_vec()))).unwrap();
+        let _peer = core.run(peer.send(("eth", ids::NEW_BLOCK, rlp::encode(&announcements[1]).to_vec()))).unwrap();
+
+        // The second block is delivered as is while the first one is
+        // searched for
+        let received = future::poll_fn(|| -> Poll<ETHMessage, io::Error> {
+            loop {
+                match try_ready!(eth.poll()) {
+                    Some(ETHReceiveMessage::Normal { data, .. }) => return Ok(Async::Ready(data)),
+                    Some(_) => (),
+                    None => panic!(),
+                }
+            }
+        });
+        assert_eq!(core.run(received).unwrap(), announcements[1]);
+        assert_eq!(eth.ancestor_searches.len(), 1);
+
+        let started = Instant::now() - Duration::from_secs(ANCESTOR_SEARCH_TIMEOUT);
+        for search in eth.ancestor_searches.values_mut() {
+            search.3 = started;
+        }
+        let expired = core.run(future::lazy(|| eth.poll())).unwrap();
+        match expired {
+            Async::Ready(Some(ETHReceiveMessage::UnknownAncestor { node, header, body, total_difficulty })) => {
+                assert_eq!(node, peer_id);
+                assert_eq!(ETHMessage::NewBlock { header, body, total_difficulty }, announcements[0]);
+            },
+            message => panic!("unexpected message {:?}", message),
+        }
+        assert!(eth.ancestor_searches.is_empty());
+    }
+
+    #[test]
+    fn messages_over_decode_limits_dropped() {
+        let mut core = Core::new().unwrap();
+        let (mut eth, peer, peer_id) = connect_mock_peer(&mut core, H256::random(), test_config());
+        let mut limits = DecodeLimits { max_depth: 8, ..Default::default() };
+        limits.message_sizes.insert(5, 40);
+        eth.set_decode_limits(limits);
+
+        let large = ETHMessage::GetBlockBodies(vec![H256::random(), H256::random()]);
+        let mut deep = RlpStream::new();
+        for _ in 0..9 {
+            deep.begin_list(1);
+        }
+        deep.append_empty_data();
+        let valid = ETHMessage::GetBlockBodies(vec![H256::random()]);
+        let peer = core.run(peer.send(("eth", 5, rlp::encode(&large).to_vec()))).unwrap();
+        let peer = core.run(peer.send(("eth", 1, deep.out().to_vec()))).unwrap();
+        let _peer = core.run(peer.send(("eth", 5, rlp::encode(&valid).to_vec()))).unwrap();
+
+        let received = future::poll_fn(|| -> Poll<ETHMessage, io::Error> {
+            loop {
+                match try_ready!(eth.poll()) {
+                    Some(ETHReceiveMessage::Normal { data, .. }) =>
+                        return Ok(Async::Ready(data)),
+                    Some(_) => (),
+                    None => panic!(),
+                }
+            }
+        });
+        assert_eq!(core.run(received).unwrap(), valid);
+        assert_eq!(eth.peer_info(peer_id).unwrap().violations, 2);
+    }
+
+    #[test]
+    fn negotiates_eth65() {
+        let mut core = Core::new().unwrap();
+        let capability = CapabilityInfo { name: "eth", version: 65, length: ids::ETH63_LENGTH };
+        let (_, _, _, version) =
+            connect_mock_peer_with(&mut core, H256::random(), test_config(), capability);
+        assert_eq!(version, 65);
+    }
+
+    /// Acknowledges every message with an empty one
+    struct Ack;
+
+    impl Capability for Ack {
+        fn name(&self) -> &'static str { "aaa" }
+        fn version(&self) -> usize { 1 }
+        fn message_count(&self) -> usize { 2 }
+
+        fn handle(&mut self, _node: H512, _id: usize, _data: &[u8]) -> Vec<(usize, Vec<u8>)> {
+            vec![(1, Vec::new())]
+        }
+    }
+
+    #[test]
+    fn eth_next_to_other_capability() {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let genesis_hash = H256::random();
+
+        let addr = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let secret_key = SecretKey::new(&SECP256K1, &mut OsRng::new().unwrap());
+        let id = pk2id(&PublicKey::from_secret_key(&SECP256K1, &secret_key).unwrap());
+        let peer_key = SecretKey::new(&SECP256K1, &mut OsRng::new().unwrap());
+        let peer_id = pk2id(&PublicKey::from_secret_key(&SECP256K1, &peer_key).unwrap());
+        let mut eth = ETHStream::new(
+            &addr, &addr.ip(), &handle, Arc::new(secret_key),
+            test_chain(genesis_hash), DevP2PBuilder::from(test_config()).client_version("eth".to_string())).unwrap();
+        eth.register_capability(Box::new(Ack));
+
+        // "aaa" sorts first, so eth messages are offset by its length
+        let peer = PeerStream::connect(
+            &addr, &handle, Arc::new(peer_key), id, 5, "peer".to_string(),
+            vec![CapabilityInfo { name: "eth", version: 65, length: ids::ETH63_LENGTH },
+                 CapabilityInfo { name: "aaa", version: 1, length: 2 }],
+            0, Default::default())
+            .and_then(|peer| peer.send(("aaa", 0, Vec::new())))
+            .and_then(|peer| peer.send(("eth", 0x02, rlp::encode_list(&[sample_transaction()]).to_vec())));
+
+        let mut received = Vec::new();
+        let messages = future::poll_fn(|| -> Poll<(), io::Error> {
+            while received.len() < 2 {
+                match try_ready!(eth.poll()) {
+                    Some(message) => received.push(message),
+                    None => panic!(),
+                }
+            }
+            Ok(Async::Ready(()))
+        });
+        core.run(peer.join(messages)).unwrap();
+
+        match received[0] {
+            ETHReceiveMessage::Connected { version, .. } => assert_eq!(version, 65),
+            _ => panic!(),
+        }
+        match received[1] {
+            ETHReceiveMessage::Normal { data: ETHMessage::Transactions(_), .. } => (),
+            ref message => panic!("unexpected message {:?}", message),
+        }
+        let offsets = eth.stream.capability_offsets(peer_id).unwrap();
+        assert_eq!(offsets.wire_id("aaa", 0), Some(0x10));
+        assert_eq!(offsets.wire_id("eth", 0), Some(0x12));
+    }
+
+    #[test]
+    fn negotiates_highest_shared_version() {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+
+        let addr = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let secret_key = SecretKey::new(&SECP256K1, &mut OsRng::new().unwrap());
+        let id = pk2id(&PublicKey::from_secret_key(&SECP256K1, &secret_key).unwrap());
+        let genesis_hash = H256::random();
+        let mut eth = ETHStream::new(
+            &addr, &addr.ip(), &handle, Arc::new(secret_key),
+            test_chain(genesis_hash), DevP2PBuilder::from(test_config()).client_version("eth".to_string())).unwrap();
+
+        let peer = PeerStream::connect(
+            &addr, &handle, Arc::new(SecretKey::new(&SECP256K1, &mut OsRng::new().unwrap())),
+            id, 5, "peer".to_string(),
+            vec![CapabilityInfo { name: "eth", version: 62, length: ids::ETH62_LENGTH },
+                 CapabilityInfo { name: "eth", version: 63, length: ids::ETH63_LENGTH }],
+            0, Default::default());
+        let connected = future::poll_fn(|| -> Poll<(H512, usize), io::Error> {
+            loop {
+                match try_ready!(eth.poll()) {
+                    Some(ETHReceiveMessage::Connected { node, version, client_version }) => {
+                        assert_eq!(client_version, "peer");
+                        return Ok(Async::Ready((node, version)));
+                    },
+                    Some(_) => (),
+                    None => panic!(),
+                }
+            }
+        });
+        let (_, (node, version)) = core.run(peer.join(connected)).unwrap();
+        assert_eq!(version, 63);
+        assert_eq!(eth.peer_version(node), Some(63));
+        assert_eq!(eth.peer_version(H512::random()), None);
+    }
+
+    #[test]
+    fn incompatible_fork_id_disconnected() {
+        let mut core = Core::new().unwrap();
+        let genesis_hash = H256::random();
+        let capability = CapabilityInfo { name: "eth", version: 64, length: ids::ETH63_LENGTH };
+        let (mut eth, peer, peer_id, _) =
+            connect_mock_peer_with(&mut core, genesis_hash, test_config(), capability);
+
+        let status = ETHMessage::StatusWithForkId {
+            protocol_version: 64,
+            network_id: 61,
+            total_difficulty: U256::from(1),
+            best_hash: genesis_hash,
+            genesis_hash,
+            fork_id: ForkId { hash: [0xde, 0xad, 0xbe, 0xef], next: 0 },
+        };
+        let _peer = core.run(peer.send(("eth", 0, rlp::encode(&status).to_vec()))).unwrap();
+
+        let disconnected = future::poll_fn(|| -> Poll<H512, io::Error> {
+            loop {
+                match try_ready!(eth.poll()) {
+                    Some(ETHReceiveMessage::Disconnected { node, .. }) =>
+                        return Ok(Async::Ready(node)),
+                    Some(ETHReceiveMessage::Normal { .. }) => panic!(),
+                    Some(_) => (),
+                    None => panic!(),
+                }
+            }
+        });
+        assert_eq!(core.run(disconnected).unwrap(), peer_id);
+    }
+
+    #[test]
+    fn trusted_peer_survives_fork_id_check() {
+        let mut core = Core::new().unwrap();
+        let genesis_hash = H256::random();
+        let capability = CapabilityInfo { name: "eth", version: 64, length: ids::ETH63_LENGTH };
+        let (mut eth, peer, peer_id, _) =
+            connect_mock_peer_with(&mut core, genesis_hash, test_config(), capability);
+        eth.add_trusted_peer(peer_id);
+
+        let status = ETHMessage::StatusWithForkId {
+            protocol_version: 64,
+            network_id: 61,
+            total_difficulty: U256::from(1),
+            best_hash: genesis_hash,
+            genesis_hash,
+            fork_id: ForkId { hash: [0xde, 0xad, 0xbe, 0xef], next: 0 },
+        };
+        let _peer = core.run(peer.send(("eth", 0, rlp::encode(&status).to_vec()))).unwrap();
+
+        let received = future::poll_fn(|| -> Poll<ETHMessage, io::Error> {
+            loop {
+                match try_ready!(eth.poll()) {
+                    Some(ETHReceiveMessage::Normal { data, .. }) => return Ok(Async::Ready(data)),
+                    Some(ETHReceiveMessage::Disconnected { .. }) | None => panic!(),
+                    Some(_) => (),
+                }
+            }
+        });
+        assert_eq!(core.run(received).unwrap(), status);
+    }
+
+    #[test]
+    fn send_errors_name_the_peer() {
+        let mut core = Core::new().unwrap();
+        let genesis_hash = H256::random();
+        let (mut eth, _peer, peer_id) = connect_mock_peer(&mut core, genesis_hash, test_config());
+
+        // GetNodeData is not part of eth/62
+        let sent = future::lazy(|| eth.start_send(ETHSendMessage {
+            node: RLPxNode::Peer(peer_id),
+            request_id: None,
+            data: ETHMessage::GetNodeData(vec![H256::random()]),
+        }));
+        match core.run(sent) {
+            Err(Error::Transport { node, .. }) => assert_eq!(node, Some(peer_id)),
+            result => panic!("unexpected result {:?}", result),
+        }
+    }
+
+    #[test]
+    fn fork_config_update_drops_incompatible_peers() {
+        let mut core = Core::new().unwrap();
+        let genesis_hash = H256::random();
+        let addr = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let secret_key = SecretKey::new(&SECP256K1, &mut OsRng::new().unwrap());
+        let id = pk2id(&PublicKey::from_secret_key(&SECP256K1, &secret_key).unwrap());
+        let mut eth = ETHStream::new(
+            &addr, &addr.ip(), &core.handle(), Arc::new(secret_key),
+            test_chain(genesis_hash), DevP2PBuilder::from(test_config()).client_version("eth".to_string())).unwrap();
+        eth.set_fork_filter(ForkFilter::new(genesis_hash, &[40]));
+        eth.set_best_number(50);
+
+        // A syncing peer still before the fork at 40, and a peer past
+        // it which knows of no later fork
+        let syncing_id = ForkId::new(genesis_hash, &[40], 0);
+        let stale_id = ForkId::new(genesis_hash, &[40], 50);
+        let mut peers = Vec::new();
+        for fork_id in &[syncing_id, stale_id] {
+            let peer_key = SecretKey::new(&SECP256K1, &mut OsRng::new().unwrap());
+            let peer_id = pk2id(&PublicKey::from_secret_key(&SECP256K1, &peer_key).unwrap());
+            let capability = CapabilityInfo { name: "eth", version: 64, length: ids::ETH63_LENGTH };
+            let (peer, _) = connect_peer(&mut core, &mut eth, &addr, id, peer_key, capability);
+
+            let status = ETHMessage::StatusWithForkId {
+                protocol_version: 64,
+                network_id: 61,
+                total_difficulty: U256::from(1),
+                best_hash: genesis_hash,
+                genesis_hash,
+                fork_id: *fork_id,
+            };
+            let peer = core.run(peer.send(("eth", 0, rlp::encode(&status).to_vec()))).unwrap();
+            let received = future::poll_fn(|| -> Poll<(), io::Error> {
+                loop {
+                    match try_ready!(eth.poll()) {
+                        Some(ETHReceiveMessage::Normal { .. }) => return Ok(Async::Ready(())),
+                        Some(ETHReceiveMessage::Disconnected { .. }) | None => panic!(),
+                        Some(_) => (),
+                    }
+                }
+            });
+            core.run(received).unwrap();
+            peers.push((peer, peer_id));
+        }
+
+        // Activating a fork at 45 leaves the second peer stale
+        let schedule = ForkSchedule { genesis_hash, forks: vec![40, 45] };
+        assert_eq!(eth.update_fork_config(schedule), vec![peers[1].1]);
+        assert_eq!(eth.fork_filter.fork_id(), ForkId::new(genesis_hash, &[40, 45], 50));
+
+        let disconnected = future::poll_fn(|| -> Poll<H512, io::Error> {
+            loop {
+                match try_ready!(eth.poll()) {
+                    Some(ETHReceiveMessage::Disconnected { node, .. }) =>
+                        return Ok(Async::Ready(node)),
+                    Some(_) => (),
+                    None => panic!(),
+                }
+            }
+        });
+        assert_eq!(core.run(disconnected).unwrap(), peers[1].1);
+        assert!(eth.peer_status(peers[0].1).is_some());
+        assert_eq!(eth.peer_status(peers[1].1), None);
+    }
+
+    #[test]
+    fn peer_status_recorded() {
+        let mut core = Core::new().unwrap();
+        let genesis_hash = H256::random();
+        let (mut eth, peer, peer_id) = connect_mock_peer(&mut core, genesis_hash, test_config());
+        assert_eq!(eth.peer_status(peer_id), None);
+
+        let status = ETHMessage::Status {
+            protocol_version: 62,
+            network_id: 61,
+            total_difficulty: U256::from(100),
+            best_hash: H256::from(1),
+            genesis_hash,
+        };
+        let _peer = core.run(peer.send(("eth", 0, rlp::encode(&status).to_vec()))).unwrap();
+
+        let received = future::poll_fn(|| -> Poll<(), io::Error> {
+            loop {
+                match try_ready!(eth.poll()) {
+                    Some(ETHReceiveMessage::Normal { .. }) => return Ok(Async::Ready(())),
+                    Some(_) => (),
+                    None => panic!(),
+                }
+            }
+        });
+        core.run(received).unwrap();
+
+        let expected = PeerStatus {
+            protocol_version: 62,
+            network_id: 61,
+            total_difficulty: U256::from(100),
+            best_hash: H256::from(1),
+            genesis_hash,
+            fork_id: None,
+        };
+        assert_eq!(eth.peer_status(peer_id), Some(&expected));
+        assert_eq!(eth.peers().len(), 1);
+    }
+
+    #[test]
+    fn other_chain_disconnected() {
+        let mut core = Core::new().unwrap();
+        let genesis_hash = H256::random();
+        let (mut eth, peer, peer_id) = connect_mock_peer(&mut core, genesis_hash, test_config());
+        eth.set_validate_status(true);
+
+        let status = ETHMessage::Status {
+            protocol_version: 62,
+            network_id: 1,
+            total_difficulty: U256::from(1),
+            best_hash: genesis_hash,
+            genesis_hash,
+        };
+        let mut peer = core.run(peer.send(("eth", 0, rlp::encode(&status).to_vec()))).unwrap();
+
+        let disconnected = future::poll_fn(|| -> Poll<H512, io::Error> {
+            loop {
+                match try_ready!(eth.poll()) {
+                    Some(ETHReceiveMessage::Disconnected { node, .. }) =>
+                        return Ok(Async::Ready(node)),
+                    Some(ETHReceiveMessage::Normal { .. }) => panic!(),
+                    Some(_) => (),
+                    None => panic!(),
+                }
+            }
+        });
+        assert_eq!(core.run(disconnected).unwrap(), peer_id);
+        assert_eq!(eth.peer_status(peer_id), None);
+
+        core.run(future::poll_fn(|| -> Poll<(), io::Error> {
+            while try_ready!(peer.poll()).is_some() { }
+            Ok(Async::Ready(()))
+        })).unwrap();
+        assert_eq!(peer.disconnect_reason(), Some(DisconnectReason::UselessPeer));
+    }
+
+    #[test]
+    fn unanswered_request_times_out() {
+        let mut core = Core::new().unwrap();
+        let (mut eth, _peer, peer_id) = connect_mock_peer(&mut core, H256::random(), test_config());
+        eth.set_request_timeout(Some(Duration::from_millis(0)));
+
+        let request = |request_id| ETHSendMessage {
+            node: RLPxNode::Peer(peer_id),
+            request_id: Some(request_id),
+            data: ETHMessage::GetBlockBodies(vec![H256::random()]),
+        };
+        let mut eth = core.run(eth.send(request(1))).unwrap();
+        let mut eth = core.run(future::lazy(move || {
+            eth.start_send(request(2)).map(|_| eth)
+        })).unwrap();
+        assert!(eth.cancel_request(peer_id, 2));
+
+        let timeout = core.run(future::poll_fn(|| eth.poll())).unwrap();
+        assert_eq!(timeout, Some(ETHReceiveMessage::Timeout {
+            node: peer_id, id: ids::GET_BLOCK_BODIES, request_id: Some(1),
+        }));
+        assert!(!eth.cancel_request(peer_id, 1));
+    }
+
+    #[test]
+    fn buffered_messages_sent_in_order() {
+        let mut core = Core::new().unwrap();
+        let (mut eth, peer, peer_id) = connect_mock_peer(&mut core, H256::random(), test_config());
+        let message = |hash| ETHSendMessage {
+            node: RLPxNode::Peer(peer_id),
+            request_id: None,
+            data: ETHMessage::NewBlockHashes(vec![(hash, U256::from(1))]),
+        };
+        let (first, second) = (H256::random(), H256::random());
+
+        // As if the peer was busy when the first message was sent
+        eth.outgoing.push_back(message(first));
+        core.run(future::lazy(|| -> Poll<(), Error> {
+            eth.send_buffered(message(second))?;
+            assert_eq!(eth.outgoing.len(), 2);
+            eth.poll_complete()
+        })).unwrap();
+        assert!(eth.outgoing.is_empty());
+
+        // Our Status, then both messages
+        let (_, mut peer) = core.run(peer.into_future().map_err(|(e, _)| e)).unwrap();
+        let mut sent = Vec::new();
+        for _ in 0..2 {
+            let (message, rest) = core.run(peer.into_future().map_err(|(e, _)| e)).unwrap();
+            let (_, id, data) = message.unwrap();
+            sent.push(ETHMessage::decode(&UntrustedRlp::new(&data), id).unwrap());
+            peer = rest;
+        }
+        assert_eq!(sent, vec![message(first).data, message(second).data]);
+    }
+
+    #[test]
+    fn request_futures_fail() {
+        let mut core = Core::new().unwrap();
+        let (mut eth, _peer, peer_id) = connect_mock_peer(&mut core, H256::random(), test_config());
+        eth.set_request_timeout(Some(Duration::from_millis(0)));
+
+        let absent = core.run(future::lazy(|| -> Result<_, io::Error> {
+            Ok(eth.request(H512::random(), ETHMessage::GetBlockBodies(vec![H256::random()])))
+        })).unwrap();
+        assert_eq!(core.run(absent).unwrap_err().kind(), io::ErrorKind::NotConnected);
+
+        let unanswered = core.run(future::lazy(|| -> Result<_, io::Error> {
+            Ok(eth.request(peer_id, ETHMessage::GetBlockBodies(vec![H256::random()])))
+        })).unwrap();
+        let drive = future::poll_fn(|| -> Poll<ETHMessage, io::Error> {
+            match try_ready!(eth.poll()) {
+                Some(message) => panic!("unexpected message {:?}", message),
+                None => panic!(),
+            }
+        });
+        let error = core.run(unanswered.select(drive).map_err(|(e, _)| e)).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn header_request_future() {
+        let mut core = Core::new().unwrap();
+        let (mut eth, peer, peer_id) = connect_mock_peer(&mut core, H256::random(), test_config());
+        let headers = core.run(future::lazy(|| -> Result<_, io::Error> {
+            Ok(eth.request_headers(peer_id, HashOrNumber::Number(U256::from(5)), 1))
+        })).unwrap();
+
+        // Our Status, then the request
+        let (_, peer) = core.run(peer.into_future().map_err(|(e, _)| e)).unwrap();
+        let (request, peer) = core.run(peer.into_future().map_err(|(e, _)| e)).unwrap();
+        assert_eq!(request.unwrap().1, ids::GET_BLOCK_HEADERS);
+
+        let mut header = sample_header();
+        header.number = U256::from(5);
+        let response = ETHMessage::BlockHeaders(vec![header.clone()]);
+        let _peer = core.run(peer.send(("eth", ids::BLOCK_HEADERS, rlp::encode(&response).to_vec()))).unwrap();
+
+        // The response resolves the future instead of being delivered
+        let drive = future::poll_fn(|| -> Poll<Vec<Header>, io::Error> {
+            match try_ready!(eth.poll()) {
+                Some(message) => panic!("unexpected message {:?}", message),
+                None => panic!(),
+            }
+        });
+        let received = core.run(headers.select(drive).map(|(headers, _)| headers).map_err(|(e, _)| e)).unwrap();
+        assert_eq!(received, vec![header]);
+    }
+
+    #[test]
+    fn fork_block_check_disconnects() {
+        let mut core = Core::new().unwrap();
+        let genesis_hash = H256::random();
+        let (mut eth, peer, peer_id) = connect_mock_peer(&mut core, genesis_hash, test_config());
+        let fork_number = U256::from(1920000);
+        eth.set_fork_block_check(Some((fork_number, H256::random())));
+
+        let status = ETHMessage::Status {
+            protocol_version: 62,
+            network_id: 61,
+            total_difficulty: U256::from(1),
+            best_hash: genesis_hash,
+            genesis_hash,
+        };
+        let peer = core.run(peer.send(("eth", 0, rlp::encode(&status).to_vec()))).unwrap();
+
+        // Our Status, then the fork block request once ours arrived
+        let mut requests = Vec::new();
+        let mut peer = Some(peer);
+        core.run(future::poll_fn(|| -> Poll<(), io::Error> {
+            while let Async::Ready(Some(_)) = eth.poll()? { }
+            while requests.len() < 2 {
+                match try_ready!(peer.as_mut().unwrap().poll()) {
+                    Some((_, id, data)) => requests.push((id, data)),
+                    None => panic!(),
+                }
+            }
+            Ok(Async::Ready(()))
+        })).unwrap();
+        let (id, ref data) = requests[1];
+        assert_eq!(ETHMessage::decode(&UntrustedRlp::new(data), id).unwrap(), ETHMessage::GetBlockHeaders {
+            start: HashOrNumber::Number(fork_number), max_headers: 1, skip: 0, reverse: false,
+        });
+
+        let mut header = sample_header();
+        header.number = fork_number;
+        let response = ETHMessage::BlockHeaders(vec![header]);
+        let mut peer = core.run(peer.take().unwrap().send(("eth", 4, rlp::encode(&response).to_vec()))).unwrap();
+
+        let disconnected = future::poll_fn(|| -> Poll<H512, io::Error> {
+            loop {
+                match try_ready!(eth.poll()) {
+                    Some(ETHReceiveMessage::Disconnected { node, .. }) =>
+                        return Ok(Async::Ready(node)),
+                    Some(ETHReceiveMessage::Normal { data: ETHMessage::BlockHeaders(_), .. }) => panic!(),
+                    Some(_) => (),
+                    None => panic!(),
+                }
+            }
+        });
+        assert_eq!(core.run(disconnected).unwrap(), peer_id);
+
+        core.run(future::poll_fn(|| -> Poll<(), io::Error> {
+            while try_ready!(peer.poll()).is_some() { }
+            Ok(Async::Ready(()))
+        })).unwrap();
+        assert_eq!(peer.disconnect_reason(), Some(DisconnectReason::UselessPeer));
+    }
+
+    #[test]
+    fn strict_decoding_disconnects() {
+        let mut core = Core::new().unwrap();
+        let (mut eth, peer, peer_id) = connect_mock_peer(&mut core, H256::random(), test_config());
+        eth.set_strict_decoding(true);
+
+        // A header request with a leading zero in its amount
+        let request = vec![0xc6, 0x01, 0x82, 0x00, 0x01, 0x80, 0x80];
+        let _peer = core.run(peer.send(("eth", 3, request))).unwrap();
+
+        let disconnected = future::poll_fn(|| -> Poll<H512, io::Error> {
+            loop {
+                match try_ready!(eth.poll()) {
+                    Some(ETHReceiveMessage::Disconnected { node, .. }) =>
+                        return Ok(Async::Ready(node)),
+                    Some(ETHReceiveMessage::Normal { .. }) => panic!(),
+                    Some(_) => (),
+                    None => panic!(),
+                }
+            }
+        });
+        assert_eq!(core.run(disconnected).unwrap(), peer_id);
+    }
+
+    #[test]
+    fn eth66_request_ids() {
+        let mut core = Core::new().unwrap();
+        let capability = CapabilityInfo { name: "eth", version: 66, length: ids::ETH63_LENGTH };
+        let (mut eth, peer, peer_id, version) =
+            connect_mock_peer_with(&mut core, H256::random(), test_config(), capability);
+        assert_eq!(version, 66);
+
+        let request = ETHMessage::GetBlockBodies(vec![H256::random()]);
+        let payload = wrap_request_id(66, request.id(), 7, &rlp::encode(&request));
+        let peer = core.run(peer.send(("eth", request.id(), payload))).unwrap();
+
+        let received = future::poll_fn(|| -> Poll<(Option<u64>, ETHMessage), io::Error> {
+            loop {
+                match try_ready!(eth.poll()) {
+                    Some(ETHReceiveMessage::Normal { request_id, data, .. }) =>
+                        return Ok(Async::Ready((request_id, data))),
+                    Some(_) => (),
+                    None => panic!(),
+                }
+            }
+        });
+        assert_eq!(core.run(received).unwrap(), (Some(7), request));
+
+        let response = ETHMessage::BlockBodies(Vec::new());
+        let eth = core.run(eth.send(ETHSendMessage {
+            node: RLPxNode::Peer(peer_id),
+            request_id: Some(7),
+            data: response.clone(),
+        })).unwrap();
+        drop(eth);
+
+        // Status first, then the response
+        let messages = core.run(peer.take(2).collect()).unwrap();
+        let (_, id, ref data) = messages[1];
+        let (request_id, data) = unwrap_request_id(66, id, data).unwrap();
+        assert_eq!(request_id, Some(7));
+        assert_eq!(ETHMessage::decode(&UntrustedRlp::new(&data), id).unwrap(), response);
+    }
+
+    #[test]
+    fn requests_to_several_peers_sent_per_version() {
+        let mut core = Core::new().unwrap();
+        let addr = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let secret_key = SecretKey::new(&SECP256K1, &mut OsRng::new().unwrap());
+        let id = pk2id(&PublicKey::from_secret_key(&SECP256K1, &secret_key).unwrap());
+        let mut eth = ETHStream::new(
+            &addr, &addr.ip(), &core.handle(), Arc::new(secret_key),
+            test_chain(H256::random()), DevP2PBuilder::from(test_config()).client_version("eth".to_string())).unwrap();
+
+        let mut peers = Vec::new();
+        for &version in &[65, 66, 67] {
+            let peer_key = SecretKey::new(&SECP256K1, &mut OsRng::new().unwrap());
+            let capability = CapabilityInfo { name: "eth", version, length: ids::ETH63_LENGTH };
+            let (peer, negotiated) = connect_peer(&mut core, &mut eth, &addr, id, peer_key, capability);
+            assert_eq!(negotiated, version);
+            peers.push(peer);
+        }
+
+        // GetNodeData is wrapped for eth/66 and left out on eth/67,
+        // which only gets the marker after it
+        let request = ETHMessage::GetNodeData(vec![H256::random()]);
+        let marker = ETHMessage::NewBlockHashes(vec![(H256::random(), U256::from(1))]);
+        let eth = core.run(eth.send(ETHSendMessage {
+            node: RLPxNode::All,
+            request_id: Some(9),
+            data: request.clone(),
+        })).unwrap();
+        let eth = core.run(eth.send(ETHSendMessage {
+            node: RLPxNode::All,
+            request_id: None,
+            data: marker.clone(),
+        })).unwrap();
+        drop(eth);
+
+        let mut received = Vec::new();
+        for (peer, &version) in peers.into_iter().zip(&[65, 66, 67]) {
+            // Status first
+            let messages = core.run(peer.skip(1).take(1).collect()).unwrap();
+            let (_, id, ref data) = messages[0];
+            let (request_id, data) = unwrap_request_id(version, id, data).unwrap();
+            received.push((request_id, ETHMessage::decode(&UntrustedRlp::new(&data), id).unwrap()));
+        }
+        assert_eq!(received, vec![
+            (None, request.clone()),
+            (Some(9), request),
+            (None, marker),
+        ]);
+    }
+
+    #[test]
+    fn node_data_rejected_on_eth67() {
+        let mut core = Core::new().unwrap();
+        let request = ETHMessage::GetNodeData(vec![H256::random()]);
+
+        for &(version, supported) in &[(66, true), (67, false)] {
+            let capability = CapabilityInfo { name: "eth", version, length: ids::ETH63_LENGTH };
+            let (mut eth, _peer, peer_id, negotiated) =
+                connect_mock_peer_with(&mut core, H256::random(), test_config(), capability);
+            assert_eq!(negotiated, version);
+
+            let sent = core.run(future::lazy(|| eth.start_send(ETHSendMessage {
+                node: RLPxNode::Peer(peer_id),
+                request_id: Some(1),
+                data: request.clone(),
+            })));
+            assert_eq!(sent.is_ok(), supported);
+        }
     }
 }
@@ -0,0 +1,117 @@
+use bigint::H512;
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+/// A request sent to a peer and not answered yet
+struct PendingRequest {
+    id: usize,
+    response_id: usize,
+    request_id: Option<u64>,
+    sent: Instant,
+}
+
+/// Outstanding requests of each peer, matched with their responses by
+/// request id when the response has one, or else with the oldest
+/// request expecting that response
+pub struct PendingRequests {
+    pending: HashMap<H512, VecDeque<PendingRequest>>,
+}
+
+impl PendingRequests {
+    pub fn new() -> Self {
+        PendingRequests {
+            pending: HashMap::new(),
+        }
+    }
+
+    /// Record a request with message id `id` sent to `peer` at `at`,
+    /// expecting a response with message id `response_id`
+    pub fn request_sent(
+        &mut self, peer: H512, id: usize, response_id: usize, request_id: Option<u64>, at: Instant
+    ) {
+        self.pending.entry(peer).or_default().push_back(PendingRequest {
+            id, response_id, request_id, sent: at,
+        });
+    }
+
+    /// Record a message with id `id` received from `peer`. If it
+    /// answers an outstanding request, returns the request id of that
+    /// request.
+    pub fn response_received(
+        &mut self, peer: &H512, id: usize, request_id: Option<u64>
+    ) -> Option<Option<u64>> {
+        let pending = self.pending.get_mut(peer)?;
+        let index = match request_id {
+            Some(request_id) => pending.iter().position(|request| {
+                request.response_id == id && request.request_id == Some(request_id)
+            }),
+            None => pending.iter().position(|request| request.response_id == id),
+        };
+        Some(pending.remove(index?)?.request_id)
+    }
+
+    /// Stop waiting for the request with the given id sent to `peer`,
+    /// returning whether it was outstanding
+    pub fn cancel(&mut self, peer: &H512, request_id: u64) -> bool {
+        let pending = match self.pending.get_mut(peer) {
+            Some(pending) => pending,
+            None => return false,
+        };
+        match pending.iter().position(|request| request.request_id == Some(request_id)) {
+            Some(index) => pending.remove(index).is_some(),
+            None => false,
+        }
+    }
+
+    /// Remove one request sent more than `timeout` before `now`,
+    /// returning its peer, message id and request id
+    pub fn expire(&mut self, timeout: Duration, now: Instant) -> Option<(H512, usize, Option<u64>)> {
+        for (&peer, pending) in self.pending.iter_mut() {
+            if let Some(index) = pending.iter().position(|request| now.duration_since(request.sent) >= timeout) {
+                let request = pending.remove(index)?;
+                return Some((peer, request.id, request.request_id));
+            }
+        }
+        None
+    }
+
+    /// Forget the requests of a peer
+    pub fn remove(&mut self, peer: &H512) {
+        self.pending.remove(peer);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PendingRequests;
+    use super::super::ids;
+    use bigint::H512;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn match_and_expire() {
+        let mut requests = PendingRequests::new();
+        let peer = H512::random();
+        let start = Instant::now();
+        let timeout = Duration::from_secs(10);
+
+        requests.request_sent(peer, ids::GET_BLOCK_HEADERS, ids::BLOCK_HEADERS, Some(1), start);
+        requests.request_sent(peer, ids::GET_BLOCK_HEADERS, ids::BLOCK_HEADERS, Some(2), start);
+        requests.request_sent(peer, ids::GET_BLOCK_BODIES, ids::BLOCK_BODIES, None, start);
+
+        // By request id, then by message id for responses without one
+        assert_eq!(requests.response_received(&peer, ids::BLOCK_HEADERS, Some(2)), Some(Some(2)));
+        assert_eq!(requests.response_received(&peer, ids::BLOCK_HEADERS, Some(2)), None);
+        assert_eq!(requests.response_received(&peer, ids::BLOCK_BODIES, None), Some(None));
+        assert_eq!(requests.response_received(&H512::random(), ids::BLOCK_HEADERS, None), None);
+
+        assert_eq!(requests.expire(timeout, start + Duration::from_secs(5)), None);
+        assert_eq!(requests.expire(timeout, start + timeout),
+                   Some((peer, ids::GET_BLOCK_HEADERS, Some(1))));
+        assert_eq!(requests.expire(timeout, start + timeout), None);
+
+        requests.request_sent(peer, ids::GET_RECEIPTS, ids::RECEIPTS, Some(3), start);
+        assert!(requests.cancel(&peer, 3));
+        assert!(!requests.cancel(&peer, 3));
+    }
+}
@@ -0,0 +1,92 @@
+use bigint::H256;
+use super::{ETHMessage, TransactionEnvelope};
+
+/// Transaction pool used by `ETHStream` to import transactions sent
+/// by peers, answer GetPooledTransactions and announce its pending
+/// transactions, see `ETHStream::set_tx_pool`
+pub trait TxPool {
+    /// Add a transaction received from a peer, returning whether it
+    /// was new to the pool
+    fn insert(&mut self, transaction: TransactionEnvelope) -> bool;
+
+    /// Whether the pool has the transaction with the given hash
+    fn contains(&self, hash: &H256) -> bool;
+
+    /// The transaction with the given hash
+    fn get(&self, hash: &H256) -> Option<TransactionEnvelope>;
+
+    /// Transactions waiting to be announced to peers, which are not
+    /// returned again afterwards
+    fn pending(&mut self) -> Vec<TransactionEnvelope>;
+}
+
+/// Build the PooledTransactions answering a GetPooledTransactions
+/// from the pool, leaving out transactions it does not have
+pub fn serve_pooled<P: TxPool + ?Sized>(pool: &P, message: &ETHMessage) -> Option<ETHMessage> {
+    match message {
+        ETHMessage::GetPooledTransactions(hashes) =>
+            Some(ETHMessage::PooledTransactions(hashes.iter().filter_map(|hash| pool.get(hash)).collect())),
+        _ => None,
+    }
+}
+
+/// Hashes of announced transactions missing from the pool
+pub fn missing_announced<P: TxPool + ?Sized>(pool: &P, message: &ETHMessage) -> Option<Vec<H256>> {
+    let hashes: Vec<H256> = match *message {
+        ETHMessage::NewPooledTransactionHashes(ref hashes) => hashes.clone(),
+        ETHMessage::NewPooledTransactionHashes68(ref announced) =>
+            announced.iter().map(|&(_, _, hash)| hash).collect(),
+        _ => return None,
+    };
+    Some(hashes.into_iter().filter(|hash| !pool.contains(hash)).collect())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::{TxPool, serve_pooled, missing_announced};
+    use super::super::{ETHMessage, TransactionEnvelope};
+    use super::super::proto::sample_transaction;
+    use bigint::H256;
+    use std::collections::HashMap;
+
+    #[derive(Default)]
+    /// Pool keeping every transaction, all pending until announced
+    pub struct MemoryPool {
+        pub transactions: HashMap<H256, TransactionEnvelope>,
+        pub pending: Vec<TransactionEnvelope>,
+    }
+
+    impl TxPool for MemoryPool {
+        fn insert(&mut self, transaction: TransactionEnvelope) -> bool {
+            self.transactions.insert(transaction.hash(), transaction).is_none()
+        }
+
+        fn contains(&self, hash: &H256) -> bool {
+            self.transactions.contains_key(hash)
+        }
+
+        fn get(&self, hash: &H256) -> Option<TransactionEnvelope> {
+            self.transactions.get(hash).cloned()
+        }
+
+        fn pending(&mut self) -> Vec<TransactionEnvelope> {
+            ::std::mem::take(&mut self.pending)
+        }
+    }
+
+    #[test]
+    fn serves_and_requests_pooled() {
+        let mut pool = MemoryPool::default();
+        let transaction: TransactionEnvelope = sample_transaction().into();
+        assert!(pool.insert(transaction.clone()));
+        assert!(!pool.insert(transaction.clone()));
+
+        let unknown = H256::random();
+        let request = ETHMessage::GetPooledTransactions(vec![unknown, transaction.hash()]);
+        assert_eq!(serve_pooled(&pool, &request), Some(ETHMessage::PooledTransactions(vec![transaction.clone()])));
+        assert_eq!(serve_pooled(&pool, &ETHMessage::Transactions(Vec::new())), None);
+
+        let announcement = ETHMessage::NewPooledTransactionHashes68(vec![(0, 100, transaction.hash()), (2, 120, unknown)]);
+        assert_eq!(missing_announced(&pool, &announcement), Some(vec![unknown]));
+    }
+}
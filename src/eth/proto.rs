@@ -1,6 +1,93 @@
-use rlp::{Encodable, Decodable, RlpStream, DecoderError, UntrustedRlp};
-use bigint::{Address, Gas, H256, U256, B256};
-use block::{Header, Transaction, Block};
+use rlp::{self, Encodable, Decodable, RlpStream, DecoderError, UntrustedRlp};
+use bigint::{Address, Gas, H64, H256, U256, B256};
+use block::{Transaction, Log, TransactionAction, TransactionSignature};
+use bloom::LogsBloom;
+use super::forkid::ForkId;
+use super::envelope::TransactionEnvelope;
+use super::body::BlockBody;
+use super::header::Header;
+use super::receipt::{Receipt, ReceiptOutcome};
+use super::ids;
+use super::limits::{max_items, bounded_item_count, MAX_ANNOUNCED_ITEMS};
+use std::fmt;
+
+/// ETH protocol versions implemented by this crate
+pub const SUPPORTED_ETH_VERSIONS: &[usize] = &[62, 63, 64, 65, 66, 67, 68];
+
+const ETH62_IDS: &[usize] = &[ids::STATUS, ids::NEW_BLOCK_HASHES, ids::TRANSACTIONS,
+                              ids::GET_BLOCK_HEADERS, ids::BLOCK_HEADERS,
+                              ids::GET_BLOCK_BODIES, ids::BLOCK_BODIES, ids::NEW_BLOCK];
+const ETH63_IDS: &[usize] = &[ids::STATUS, ids::NEW_BLOCK_HASHES, ids::TRANSACTIONS,
+                              ids::GET_BLOCK_HEADERS, ids::BLOCK_HEADERS,
+                              ids::GET_BLOCK_BODIES, ids::BLOCK_BODIES, ids::NEW_BLOCK,
+                              ids::GET_NODE_DATA, ids::NODE_DATA,
+                              ids::GET_RECEIPTS, ids::RECEIPTS];
+const ETH65_IDS: &[usize] = &[ids::STATUS, ids::NEW_BLOCK_HASHES, ids::TRANSACTIONS,
+                              ids::GET_BLOCK_HEADERS, ids::BLOCK_HEADERS,
+                              ids::GET_BLOCK_BODIES, ids::BLOCK_BODIES, ids::NEW_BLOCK,
+                              ids::NEW_POOLED_TRANSACTION_HASHES,
+                              ids::GET_POOLED_TRANSACTIONS, ids::POOLED_TRANSACTIONS,
+                              ids::GET_NODE_DATA, ids::NODE_DATA,
+                              ids::GET_RECEIPTS, ids::RECEIPTS];
+const ETH67_IDS: &[usize] = &[ids::STATUS, ids::NEW_BLOCK_HASHES, ids::TRANSACTIONS,
+                              ids::GET_BLOCK_HEADERS, ids::BLOCK_HEADERS,
+                              ids::GET_BLOCK_BODIES, ids::BLOCK_BODIES, ids::NEW_BLOCK,
+                              ids::NEW_POOLED_TRANSACTION_HASHES,
+                              ids::GET_POOLED_TRANSACTIONS, ids::POOLED_TRANSACTIONS,
+                              ids::GET_RECEIPTS, ids::RECEIPTS];
+
+/// Start block of a GetBlockHeaders request, either a block hash or
+/// a block number
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HashOrNumber {
+    Hash(H256),
+    Number(U256),
+}
+
+impl Encodable for HashOrNumber {
+    fn rlp_append(&self, s: &mut RlpStream) {
+        match *self {
+            HashOrNumber::Hash(ref hash) => hash.rlp_append(s),
+            HashOrNumber::Number(ref number) => number.rlp_append(s),
+        }
+    }
+}
+
+impl Decodable for HashOrNumber {
+    fn decode(rlp: &UntrustedRlp) -> Result<Self, DecoderError> {
+        // A hash is always 32 bytes, a number never is once its
+        // leading zeros are dropped
+        if rlp.is_list() {
+            return Err(DecoderError::RlpExpectedToBeData);
+        }
+        Ok(if rlp.data()?.len() == 32 {
+            HashOrNumber::Hash(rlp.as_val()?)
+        } else {
+            HashOrNumber::Number(rlp.as_val()?)
+        })
+    }
+}
+
+impl From<H256> for HashOrNumber {
+    fn from(hash: H256) -> Self {
+        HashOrNumber::Hash(hash)
+    }
+}
+
+impl From<U256> for HashOrNumber {
+    fn from(number: U256) -> Self {
+        HashOrNumber::Number(number)
+    }
+}
+
+impl fmt::Display for HashOrNumber {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            HashOrNumber::Hash(ref hash) => write!(f, "{}", hash),
+            HashOrNumber::Number(ref number) => write!(f, "#{}", number),
+        }
+    }
+}
 
 /// ETH message version 62 and 63
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -12,51 +99,139 @@ pub enum ETHMessage {
         best_hash: H256,
         genesis_hash: H256,
     },
-    NewBlockHashes(Vec<(H256, U256)>),
-    Transactions(Vec<Transaction>),
-    GetBlockHeadersByNumber {
-        number: U256, // TODO: this can also be a hash.
-        max_headers: usize,
-        skip: usize,
-        reverse: bool,
+    /// Status message of eth/64 and later, carrying the EIP-2124
+    /// fork id
+    StatusWithForkId {
+        protocol_version: usize,
+        network_id: usize,
+        total_difficulty: U256,
+        best_hash: H256,
+        genesis_hash: H256,
+        fork_id: ForkId,
     },
-    GetBlockHeadersByHash {
-        hash: H256,
+    NewBlockHashes(Vec<(H256, U256)>),
+    Transactions(Vec<TransactionEnvelope>),
+    GetBlockHeaders {
+        start: HashOrNumber,
         max_headers: usize,
         skip: usize,
         reverse: bool,
     },
     BlockHeaders(Vec<Header>),
     GetBlockBodies(Vec<H256>),
-    BlockBodies(Vec<(Vec<Transaction>, Vec<Header>)>),
+    BlockBodies(Vec<BlockBody>),
+    /// A full block, encoded as the header and body in one list
     NewBlock {
-        block: Block,
+        header: Header,
+        body: BlockBody,
         total_difficulty: U256
     },
-    Unknown,
+    NewPooledTransactionHashes(Vec<H256>),
+    /// eth/68 transaction announcement of (type, size, hash) triples
+    NewPooledTransactionHashes68(Vec<(u8, usize, H256)>),
+    GetPooledTransactions(Vec<H256>),
+    PooledTransactions(Vec<TransactionEnvelope>),
+    GetNodeData(Vec<H256>),
+    NodeData(Vec<Vec<u8>>),
+    GetReceipts(Vec<H256>),
+    /// Receipts of each requested block, in request order
+    Receipts(Vec<Vec<Receipt>>),
+    /// Message with an id this crate does not know, kept as its raw
+    /// RLP. `ETHStream` drops ids outside the negotiated version, so
+    /// these only come out of `decode` and `decode_rlpx_message`.
+    Unknown {
+        id: usize,
+        data: Vec<u8>,
+    },
 }
 
 impl ETHMessage {
     /// Get the message id of the ETH message
     pub fn id(&self) -> usize {
         match self {
-            &ETHMessage::Status { .. } => 0,
-            &ETHMessage::NewBlockHashes(_) => 1,
-            &ETHMessage::Transactions(_) => 2,
-            &ETHMessage::GetBlockHeadersByNumber { .. } => 3,
-            &ETHMessage::GetBlockHeadersByHash { .. } => 3,
-            &ETHMessage::BlockHeaders(_) => 4,
-            &ETHMessage::GetBlockBodies(_) => 5,
-            &ETHMessage::BlockBodies(_) => 6,
-            &ETHMessage::NewBlock { .. } => 7,
-            &ETHMessage::Unknown => 127,
+            &ETHMessage::Status { .. } => ids::STATUS,
+            &ETHMessage::StatusWithForkId { .. } => ids::STATUS,
+            &ETHMessage::NewBlockHashes(_) => ids::NEW_BLOCK_HASHES,
+            &ETHMessage::Transactions(_) => ids::TRANSACTIONS,
+            &ETHMessage::GetBlockHeaders { .. } => ids::GET_BLOCK_HEADERS,
+            &ETHMessage::BlockHeaders(_) => ids::BLOCK_HEADERS,
+            &ETHMessage::GetBlockBodies(_) => ids::GET_BLOCK_BODIES,
+            &ETHMessage::BlockBodies(_) => ids::BLOCK_BODIES,
+            &ETHMessage::NewBlock { .. } => ids::NEW_BLOCK,
+            &ETHMessage::NewPooledTransactionHashes(_) => ids::NEW_POOLED_TRANSACTION_HASHES,
+            &ETHMessage::NewPooledTransactionHashes68(_) => ids::NEW_POOLED_TRANSACTION_HASHES,
+            &ETHMessage::GetPooledTransactions(_) => ids::GET_POOLED_TRANSACTIONS,
+            &ETHMessage::PooledTransactions(_) => ids::POOLED_TRANSACTIONS,
+            &ETHMessage::GetNodeData(_) => ids::GET_NODE_DATA,
+            &ETHMessage::NodeData(_) => ids::NODE_DATA,
+            &ETHMessage::GetReceipts(_) => ids::GET_RECEIPTS,
+            &ETHMessage::Receipts(_) => ids::RECEIPTS,
+            &ETHMessage::Unknown { id, .. } => id,
+        }
+    }
+
+    /// Valid message ids of the given ETH protocol version according
+    /// to the spec, or an empty slice for unknown versions. eth/63
+    /// adds node data and receipts, eth/65 adds pooled transactions
+    /// and eth/67 removes node data.
+    pub fn ids_for_version(version: usize) -> &'static [usize] {
+        match version {
+            62 => ETH62_IDS,
+            63 | 64 => ETH63_IDS,
+            65 | 66 => ETH65_IDS,
+            67 | 68 => ETH67_IDS,
+            _ => &[],
+        }
+    }
+
+    /// Whether the message can be sent to a peer of the given ETH
+    /// protocol version. Transaction announcements changed format in
+    /// eth/68.
+    pub fn is_valid_for_version(&self, version: usize) -> bool {
+        if !ETHMessage::ids_for_version(version).contains(&self.id()) {
+            return false;
+        }
+
+        match *self {
+            ETHMessage::NewPooledTransactionHashes(_) => version < 68,
+            ETHMessage::NewPooledTransactionHashes68(_) => version >= 68,
+            _ => true,
         }
     }
 
-    /// Decode a RLP into ETH message using the given message id
+    /// Message id of the response expected for this message, if it
+    /// is a request
+    pub fn response_id(&self) -> Option<usize> {
+        match *self {
+            ETHMessage::GetBlockHeaders { .. } => Some(4),
+            ETHMessage::GetBlockBodies(_) => Some(6),
+            ETHMessage::GetPooledTransactions(_) => Some(0x0a),
+            ETHMessage::GetNodeData(_) => Some(0x0e),
+            ETHMessage::GetReceipts(_) => Some(0x10),
+            _ => None,
+        }
+    }
+
+    /// Decode a RLP into ETH message using the given message id. Lists
+    /// with more items than `max_items` allows for the id are rejected
+    /// before their items are decoded.
     pub fn decode(rlp: &UntrustedRlp, id: usize) -> Result<Self, DecoderError> {
+        if let Some(max) = max_items(id) {
+            bounded_item_count(rlp, max)?;
+        }
+
         Ok(match id {
-            0 => {
+            ids::STATUS if rlp.item_count()? > 5 => {
+                ETHMessage::StatusWithForkId {
+                    protocol_version: rlp.val_at(0)?,
+                    network_id: rlp.val_at(1)?,
+                    total_difficulty: rlp.val_at(2)?,
+                    best_hash: rlp.val_at(3)?,
+                    genesis_hash: rlp.val_at(4)?,
+                    fork_id: rlp.val_at(5)?,
+                }
+            },
+            ids::STATUS => {
                 ETHMessage::Status {
                     protocol_version: rlp.val_at(0)?,
                     network_id: rlp.val_at(1)?,
@@ -65,7 +240,7 @@ impl ETHMessage {
                     genesis_hash: rlp.val_at(4)?,
                 }
             },
-            1 => {
+            ids::NEW_BLOCK_HASHES => {
                 let mut r = Vec::new();
                 for i in 0..rlp.item_count()? {
                     let d = rlp.at(i)?;
@@ -73,52 +248,299 @@ impl ETHMessage {
                 }
                 ETHMessage::NewBlockHashes(r)
             },
-            2 => {
+            ids::TRANSACTIONS => {
                 ETHMessage::Transactions(rlp.as_list()?)
             },
-            3 => {
+            ids::GET_BLOCK_HEADERS => {
                 let reverse: u32 = rlp.val_at(3)?;
-                if rlp.at(0)?.size() == 32 {
-                    ETHMessage::GetBlockHeadersByHash {
-                        hash: rlp.val_at(0)?,
-                        max_headers: rlp.val_at(1)?,
-                        skip: rlp.val_at(2)?,
-                        reverse: if reverse == 0 { false } else { true },
-                    }
-                } else {
-                    ETHMessage::GetBlockHeadersByNumber {
-                        number: rlp.val_at(0)?,
-                        max_headers: rlp.val_at(1)?,
-                        skip: rlp.val_at(2)?,
-                        reverse: if reverse == 0 { false } else { true },
-                    }
+                ETHMessage::GetBlockHeaders {
+                    start: rlp.val_at(0)?,
+                    max_headers: rlp.val_at(1)?,
+                    skip: rlp.val_at(2)?,
+                    reverse: reverse != 0,
                 }
             },
-            4 => {
+            ids::BLOCK_HEADERS => {
                 ETHMessage::BlockHeaders(rlp.as_list()?)
             },
-            5 => {
+            ids::GET_BLOCK_BODIES => {
                 ETHMessage::GetBlockBodies(rlp.as_list()?)
             },
-            6 => {
-                let mut r = Vec::new();
-                for i in 0..rlp.item_count()? {
-                    let d = rlp.at(i)?;
-                    r.push((d.list_at(0)?, d.list_at(1)?));
-                }
-                ETHMessage::BlockBodies(r)
+            ids::BLOCK_BODIES => {
+                ETHMessage::BlockBodies(rlp.as_list()?)
             },
-            7 => {
+            ids::NEW_BLOCK => {
+                let block = rlp.at(0)?;
                 ETHMessage::NewBlock {
-                    block: rlp.val_at(0)?,
+                    header: block.val_at(0)?,
+                    body: BlockBody {
+                        transactions: block.list_at(1)?,
+                        ommers: block.list_at(2)?,
+                    },
                     total_difficulty: rlp.val_at(1)?,
                 }
             },
+            // eth/68 announcements are [types, [sizes...], [hashes...]]
+            ids::NEW_POOLED_TRANSACTION_HASHES if rlp.item_count()? == 3 && rlp.at(1)?.is_list() => {
+                let types: Vec<u8> = rlp.val_at(0)?;
+                let sizes: Vec<usize> = rlp.list_at(1)?;
+                bounded_item_count(&rlp.at(2)?, MAX_ANNOUNCED_ITEMS)?;
+                let hashes: Vec<H256> = rlp.list_at(2)?;
+                if types.len() != hashes.len() || sizes.len() != hashes.len() {
+                    return Err(DecoderError::RlpIncorrectListLen);
+                }
+                ETHMessage::NewPooledTransactionHashes68(
+                    types.into_iter().zip(sizes).zip(hashes)
+                        .map(|((t, size), hash)| (t, size, hash)).collect())
+            },
+            ids::NEW_POOLED_TRANSACTION_HASHES => {
+                ETHMessage::NewPooledTransactionHashes(rlp.as_list()?)
+            },
+            ids::GET_POOLED_TRANSACTIONS => {
+                ETHMessage::GetPooledTransactions(rlp.as_list()?)
+            },
+            ids::POOLED_TRANSACTIONS => {
+                ETHMessage::PooledTransactions(rlp.as_list()?)
+            },
+            ids::GET_NODE_DATA => {
+                ETHMessage::GetNodeData(rlp.as_list()?)
+            },
+            ids::NODE_DATA => {
+                ETHMessage::NodeData(rlp.as_list()?)
+            },
+            ids::GET_RECEIPTS => {
+                ETHMessage::GetReceipts(rlp.as_list()?)
+            },
+            ids::RECEIPTS => {
+                let mut r = Vec::new();
+                for i in 0..rlp.item_count()? {
+                    r.push(rlp.list_at(i)?);
+                }
+                ETHMessage::Receipts(r)
+            },
             _ => {
-                ETHMessage::Unknown
+                ETHMessage::Unknown { id, data: rlp.as_raw().to_vec() }
             },
         })
     }
+
+    /// Decode like `decode`, but reject any encoding other than the
+    /// canonical one: trailing list items or bytes, wrong list
+    /// lengths and integers with leading zeros. The message is encoded
+    /// again and compared to the input, which relies on every type in
+    /// it keeping all the items it was decoded from, like the fork
+    /// fields of headers and the status of receipts.
+    pub fn decode_strict(rlp: &UntrustedRlp, id: usize) -> Result<Self, DecoderError> {
+        let message = ETHMessage::decode(rlp, id)?;
+        if &rlp::encode(&message)[..] != rlp.as_raw() {
+            return Err(DecoderError::Custom("non-canonical eth message encoding"));
+        }
+        Ok(message)
+    }
+
+    /// Representative instances of every ETH message variant
+    pub(crate) fn samples() -> Vec<ETHMessage> {
+        let header = sample_header();
+        let envelope = TransactionEnvelope::from(sample_transaction());
+        let receipt = sample_receipt();
+
+        vec![
+            ETHMessage::Status {
+                protocol_version: 63,
+                network_id: 1,
+                total_difficulty: U256::from(17179869184u64),
+                best_hash: H256::from(1),
+                genesis_hash: H256::from(2),
+            },
+            ETHMessage::StatusWithForkId {
+                protocol_version: 64,
+                network_id: 1,
+                total_difficulty: U256::from(17179869184u64),
+                best_hash: H256::from(1),
+                genesis_hash: H256::from(2),
+                fork_id: ForkId { hash: [0xfc, 0x64, 0xec, 0x04], next: 1150000 },
+            },
+            ETHMessage::NewBlockHashes(vec![(H256::from(1), U256::from(1)),
+                                            (H256::from(2), U256::from(2))]),
+            ETHMessage::Transactions(vec![envelope.clone(), envelope.clone()]),
+            ETHMessage::GetBlockHeaders {
+                start: HashOrNumber::Number(U256::from(1920000)), max_headers: 192, skip: 1, reverse: true,
+            },
+            ETHMessage::GetBlockHeaders {
+                start: HashOrNumber::Hash(H256::from(1)), max_headers: 192, skip: 0, reverse: false,
+            },
+            ETHMessage::BlockHeaders(vec![header.clone(), header.clone()]),
+            ETHMessage::GetBlockBodies(vec![H256::from(1), H256::from(2)]),
+            ETHMessage::BlockBodies(vec![
+                BlockBody { transactions: vec![envelope.clone()], ommers: vec![header.clone()] },
+                BlockBody { transactions: Vec::new(), ommers: Vec::new() },
+            ]),
+            ETHMessage::NewBlock {
+                header: header.clone(),
+                body: BlockBody { transactions: vec![envelope.clone()], ommers: vec![header.clone()] },
+                total_difficulty: U256::from(17179869184u64),
+            },
+            ETHMessage::NewPooledTransactionHashes(vec![H256::from(1), H256::from(2)]),
+            ETHMessage::NewPooledTransactionHashes68(vec![(0x00, 110, H256::from(1)),
+                                                          (0x02, 2048, H256::from(2))]),
+            ETHMessage::GetPooledTransactions(vec![H256::from(1), H256::from(2)]),
+            ETHMessage::PooledTransactions(vec![envelope.clone(), envelope.clone()]),
+            ETHMessage::GetNodeData(vec![H256::from(1), H256::from(2)]),
+            ETHMessage::NodeData(vec![vec![0xc0], Vec::new(), vec![0x42; 64]]),
+            ETHMessage::GetReceipts(vec![H256::from(1), H256::from(2)]),
+            ETHMessage::Receipts(vec![vec![receipt.clone(), receipt.clone()], Vec::new()]),
+            ETHMessage::Unknown { id: 0x11, data: vec![0xc2, 0x01, 0x02] },
+        ]
+    }
+
+    /// Encode and decode a representative instance of every ETH
+    /// message variant, returning a description of the first one
+    /// that fails to round-trip
+    pub fn self_test() -> Result<(), String> {
+        for message in ETHMessage::samples() {
+            let encoded = rlp::encode(&message).to_vec();
+            match ETHMessage::decode(&UntrustedRlp::new(&encoded), message.id()) {
+                Ok(ref decoded) if decoded == &message => (),
+                Ok(decoded) => return Err(format!(
+                    "message id {} decoded into {:?}, expected {:?}",
+                    message.id(), decoded, message)),
+                Err(e) => return Err(format!(
+                    "message id {} failed to decode: {:?}", message.id(), e)),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A header with arbitrary but valid contents
+pub fn sample_header() -> Header {
+    Header {
+        parent_hash: H256::from(1),
+        ommers_hash: H256::from(2),
+        beneficiary: Address::default(),
+        state_root: H256::from(3),
+        transactions_root: H256::from(4),
+        receipts_root: H256::from(5),
+        logs_bloom: LogsBloom::default(),
+        difficulty: U256::from(17179869184u64),
+        number: U256::from(1920000),
+        gas_limit: Gas::from(5000u64),
+        gas_used: Gas::zero(),
+        timestamp: 1469020840,
+        extra_data: B256::new(&[0x42u8]),
+        mix_hash: H256::from(6),
+        nonce: H64::from(7),
+        base_fee_per_gas: None,
+        withdrawals_root: None,
+        blob_gas_used: None,
+        excess_blob_gas: None,
+        parent_beacon_block_root: None,
+        requests_hash: None,
+    }
+}
+
+/// A transaction with arbitrary but valid contents
+pub fn sample_transaction() -> Transaction {
+    Transaction {
+        nonce: U256::zero(),
+        gas_price: Gas::from(20000000000u64),
+        gas_limit: Gas::from(21000u64),
+        action: TransactionAction::Call(Address::default()),
+        value: U256::from(1000000000u64),
+        signature: TransactionSignature {
+            v: 27, r: H256::from(1), s: H256::from(1),
+        },
+        input: Vec::new(),
+    }
+}
+
+/// A receipt with arbitrary but valid contents
+pub fn sample_receipt() -> Receipt {
+    Receipt {
+        transaction_type: 0,
+        outcome: ReceiptOutcome::StateRoot(H256::from(1)),
+        used_gas: Gas::from(21000u64),
+        logs_bloom: LogsBloom::default(),
+        logs: vec![Log {
+            address: Address::default(),
+            topics: vec![H256::from(2)],
+            data: vec![0x42],
+        }],
+    }
+}
+
+/// Write `count` items of the given name, followed by the block
+/// number range of the first and last item if there are any
+fn write_range(f: &mut fmt::Formatter, count: usize, name: &str,
+               range: Option<(U256, U256)>) -> fmt::Result {
+    write!(f, "{} {}", count, name)?;
+    match range {
+        Some((first, last)) => write!(f, ", #{}..#{}", first, last),
+        None => Ok(()),
+    }
+}
+
+impl fmt::Display for ETHMessage {
+    /// One-line summary of the message, counting list items instead
+    /// of printing them
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ETHMessage::Status { protocol_version, network_id, ref total_difficulty, ref best_hash, .. } =>
+                write!(f, "Status(eth/{}, network {}, td {}, best {})",
+                       protocol_version, network_id, total_difficulty, best_hash),
+            ETHMessage::StatusWithForkId {
+                protocol_version, network_id, ref total_difficulty, ref best_hash, ref fork_id, ..
+            } => write!(f, "Status(eth/{}, network {}, td {}, best {}, fork {:02x}{:02x}{:02x}{:02x}/{})",
+                        protocol_version, network_id, total_difficulty, best_hash,
+                        fork_id.hash[0], fork_id.hash[1], fork_id.hash[2], fork_id.hash[3], fork_id.next),
+            ETHMessage::NewBlockHashes(ref hashes) => {
+                write!(f, "NewBlockHashes(")?;
+                write_range(f, hashes.len(), "hashes",
+                            hashes.first().map(|first| (first.1, hashes[hashes.len() - 1].1)))?;
+                write!(f, ")")
+            },
+            ETHMessage::Transactions(ref transactions) =>
+                write!(f, "Transactions({} transactions)", transactions.len()),
+            ETHMessage::GetBlockHeaders { start, max_headers, skip, reverse } =>
+                write!(f, "GetBlockHeaders(from {}, max {}, skip {}{})",
+                       start, max_headers, skip, if reverse { ", reverse" } else { "" }),
+            ETHMessage::BlockHeaders(ref headers) => {
+                write!(f, "BlockHeaders(")?;
+                write_range(f, headers.len(), "headers",
+                            headers.first().map(|first| (first.number, headers[headers.len() - 1].number)))?;
+                write!(f, ")")
+            },
+            ETHMessage::GetBlockBodies(ref hashes) =>
+                write!(f, "GetBlockBodies({} hashes)", hashes.len()),
+            ETHMessage::BlockBodies(ref bodies) =>
+                write!(f, "BlockBodies({} bodies, {} transactions)", bodies.len(),
+                       bodies.iter().map(|body| body.transactions.len()).sum::<usize>()),
+            ETHMessage::NewBlock { ref header, ref body, ref total_difficulty } =>
+                write!(f, "NewBlock(#{}, {} transactions, td {})",
+                       header.number, body.transactions.len(), total_difficulty),
+            ETHMessage::NewPooledTransactionHashes(ref hashes) =>
+                write!(f, "NewPooledTransactionHashes({} hashes)", hashes.len()),
+            ETHMessage::NewPooledTransactionHashes68(ref announcements) =>
+                write!(f, "NewPooledTransactionHashes({} hashes, {} bytes)", announcements.len(),
+                       announcements.iter().map(|&(_, size, _)| size).sum::<usize>()),
+            ETHMessage::GetPooledTransactions(ref hashes) =>
+                write!(f, "GetPooledTransactions({} hashes)", hashes.len()),
+            ETHMessage::PooledTransactions(ref transactions) =>
+                write!(f, "PooledTransactions({} transactions)", transactions.len()),
+            ETHMessage::GetNodeData(ref hashes) =>
+                write!(f, "GetNodeData({} hashes)", hashes.len()),
+            ETHMessage::NodeData(ref data) =>
+                write!(f, "NodeData({} entries, {} bytes)", data.len(),
+                       data.iter().map(|entry| entry.len()).sum::<usize>()),
+            ETHMessage::GetReceipts(ref hashes) =>
+                write!(f, "GetReceipts({} hashes)", hashes.len()),
+            ETHMessage::Receipts(ref receipts) =>
+                write!(f, "Receipts({} blocks, {} receipts)", receipts.len(),
+                       receipts.iter().map(|block| block.len()).sum::<usize>()),
+            ETHMessage::Unknown { id, ref data } =>
+                write!(f, "Unknown(id 0x{:02x}, {} bytes)", id, data.len()),
+        }
+    }
 }
 
 impl Encodable for ETHMessage {
@@ -134,6 +556,18 @@ impl Encodable for ETHMessage {
                 s.append(&best_hash);
                 s.append(&genesis_hash);
             },
+            &ETHMessage::StatusWithForkId {
+                protocol_version, network_id, total_difficulty, best_hash, genesis_hash,
+                ref fork_id
+            } => {
+                s.begin_list(6);
+                s.append(&protocol_version);
+                s.append(&network_id);
+                s.append(&total_difficulty);
+                s.append(&best_hash);
+                s.append(&genesis_hash);
+                s.append(fork_id);
+            },
             &ETHMessage::NewBlockHashes(ref hashes) => {
                 s.begin_list(hashes.len());
                 for &(hash, number) in hashes {
@@ -145,22 +579,12 @@ impl Encodable for ETHMessage {
             &ETHMessage::Transactions(ref transactions) => {
                 s.append_list(&transactions);
             },
-            &ETHMessage::GetBlockHeadersByNumber {
-                number,
-                max_headers, skip, reverse
-            } => {
-                s.begin_list(4);
-                s.append(&number);
-                s.append(&max_headers);
-                s.append(&skip);
-                s.append(&if reverse { 1u32 } else { 0u32 });
-            },
-            &ETHMessage::GetBlockHeadersByHash {
-                hash,
+            &ETHMessage::GetBlockHeaders {
+                start,
                 max_headers, skip, reverse
             } => {
                 s.begin_list(4);
-                s.append(&hash);
+                s.append(&start);
                 s.append(&max_headers);
                 s.append(&skip);
                 s.append(&if reverse { 1u32 } else { 0u32 });
@@ -172,19 +596,55 @@ impl Encodable for ETHMessage {
                 s.append_list(&hashes);
             },
             &ETHMessage::BlockBodies(ref bodies) => {
-                for &(ref transactions, ref ommers) in bodies {
-                    s.begin_list(2);
-                    s.append_list(&transactions);
-                    s.append_list(&ommers);
-                }
+                s.append_list(bodies);
             },
-            &ETHMessage::NewBlock { ref block, ref total_difficulty } => {
+            &ETHMessage::NewBlock { ref header, ref body, ref total_difficulty } => {
                 s.begin_list(2);
-                s.append(block);
+                s.begin_list(3);
+                s.append(header);
+                s.append_list(&body.transactions);
+                s.append_list(&body.ommers);
                 s.append(total_difficulty);
             }
-            &ETHMessage::Unknown => {
-                s.begin_list(0);
+            &ETHMessage::NewPooledTransactionHashes(ref hashes) => {
+                s.append_list(hashes);
+            },
+            &ETHMessage::NewPooledTransactionHashes68(ref announcements) => {
+                let types: Vec<u8> = announcements.iter().map(|&(t, _, _)| t).collect();
+                s.begin_list(3);
+                s.append(&types);
+                s.begin_list(announcements.len());
+                for &(_, size, _) in announcements {
+                    s.append(&size);
+                }
+                s.begin_list(announcements.len());
+                for &(_, _, ref hash) in announcements {
+                    s.append(hash);
+                }
+            },
+            &ETHMessage::GetPooledTransactions(ref hashes) => {
+                s.append_list(hashes);
+            },
+            &ETHMessage::PooledTransactions(ref transactions) => {
+                s.append_list(transactions);
+            },
+            &ETHMessage::GetNodeData(ref hashes) => {
+                s.append_list(hashes);
+            },
+            &ETHMessage::NodeData(ref values) => {
+                s.append_list::<Vec<u8>, _>(values);
+            },
+            &ETHMessage::GetReceipts(ref hashes) => {
+                s.append_list(hashes);
+            },
+            &ETHMessage::Receipts(ref receipts) => {
+                s.begin_list(receipts.len());
+                for block_receipts in receipts {
+                    s.append_list(block_receipts);
+                }
+            },
+            &ETHMessage::Unknown { ref data, .. } => {
+                s.append_raw(data, 1);
             },
         }
     }
@@ -192,9 +652,117 @@ impl Encodable for ETHMessage {
 
 #[cfg(test)]
 mod tests {
-    use super::ETHMessage;
-    use rlp::{self, Encodable, Decodable, RlpStream, DecoderError, UntrustedRlp};
-    use bigint::H256;
+    use super::{ETHMessage, HashOrNumber, SUPPORTED_ETH_VERSIONS, sample_header, sample_transaction, sample_receipt};
+    use super::super::ReceiptOutcome;
+    use super::super::{ids, BlockBody, TransactionEnvelope};
+    use rlp::{self, RlpStream, UntrustedRlp};
+    use bigint::{H256, U256};
+
+    #[test]
+    fn test_ids_for_version() {
+        let eth62 = ETHMessage::ids_for_version(62);
+        for id in 13..17 {
+            assert!(!eth62.contains(&id));
+        }
+        let eth65 = ETHMessage::ids_for_version(65);
+        for id in 8..11 {
+            assert!(eth65.contains(&id));
+        }
+        assert!(ETHMessage::ids_for_version(61).is_empty());
+
+        for &version in SUPPORTED_ETH_VERSIONS {
+            let length = ids::length(version);
+            assert!(ETHMessage::ids_for_version(version).iter().all(|&id| id < length));
+        }
+    }
+
+    #[test]
+    fn test_eth68_announcements() {
+        let hash = H256::random();
+        let legacy = ETHMessage::NewPooledTransactionHashes(vec![hash, hash, hash]);
+        let typed = ETHMessage::NewPooledTransactionHashes68(vec![(0x02, 150, hash)]);
+
+        // Three hashes must not be taken for the eth/68 layout
+        assert_eq!(ETHMessage::decode(&UntrustedRlp::new(&rlp::encode(&legacy)), 8).unwrap(), legacy);
+        let encoded = rlp::encode(&typed);
+        let layout = UntrustedRlp::new(&encoded);
+        assert_eq!(layout.val_at::<Vec<u8>>(0).unwrap(), vec![0x02]);
+        assert_eq!(layout.list_at::<usize>(1).unwrap(), vec![150]);
+        assert_eq!(ETHMessage::decode(&layout, 8).unwrap(), typed);
+
+        assert!(legacy.is_valid_for_version(67) && !legacy.is_valid_for_version(68));
+        assert!(typed.is_valid_for_version(68) && !typed.is_valid_for_version(67));
+        assert!(!typed.is_valid_for_version(62));
+    }
+
+    #[test]
+    fn test_new_block_typed_transactions() {
+        let typed = TransactionEnvelope::Typed { transaction_type: 0x02, payload: vec![0xc3, 0x01, 0x02, 0x03] };
+        let message = ETHMessage::NewBlock {
+            header: sample_header(),
+            body: BlockBody { transactions: vec![sample_transaction().into(), typed], ommers: Vec::new() },
+            total_difficulty: U256::from(1000),
+        };
+
+        // [[header, transactions, ommers], total difficulty]
+        let encoded = rlp::encode(&message);
+        let layout = UntrustedRlp::new(&encoded);
+        assert_eq!(layout.at(0).unwrap().item_count().unwrap(), 3);
+        assert_eq!(layout.at(0).unwrap().at(1).unwrap().at(1).unwrap().data().unwrap()[0], 0x02);
+        assert_eq!(ETHMessage::decode(&layout, ids::NEW_BLOCK).unwrap(), message);
+    }
+
+    #[test]
+    fn test_self_test() {
+        ETHMessage::self_test().unwrap();
+    }
+
+    #[test]
+    fn test_decode_strict() {
+        for message in ETHMessage::samples() {
+            let encoded = rlp::encode(&message);
+            assert_eq!(ETHMessage::decode_strict(&UntrustedRlp::new(&encoded), message.id()).unwrap(),
+                       message);
+        }
+
+        // Headers, blocks and receipts of later forks round-trip
+        let mut header = sample_header();
+        header.base_fee_per_gas = Some(U256::from(7));
+        header.withdrawals_root = Some(H256::from(8));
+        let mut receipt = sample_receipt();
+        receipt.outcome = ReceiptOutcome::Status(true);
+        receipt.transaction_type = 0x02;
+        let messages = vec![
+            ETHMessage::BlockHeaders(vec![header.clone()]),
+            ETHMessage::NewBlock {
+                header: header.clone(),
+                body: BlockBody { transactions: Vec::new(), ommers: Vec::new() },
+                total_difficulty: U256::zero(),
+            },
+            ETHMessage::Receipts(vec![vec![receipt]]),
+        ];
+        for message in messages {
+            let encoded = rlp::encode(&message);
+            assert_eq!(ETHMessage::decode_strict(&UntrustedRlp::new(&encoded), message.id()).unwrap(),
+                       message);
+        }
+
+        // Header request with a trailing item
+        let mut s = RlpStream::new_list(5);
+        s.append(&H256::from(1)).append(&192u32).append(&0u32).append(&0u32).append(&0u32);
+        let request = s.out();
+        assert!(ETHMessage::decode(&UntrustedRlp::new(&request), 3).is_ok());
+        assert!(ETHMessage::decode_strict(&UntrustedRlp::new(&request), 3).is_err());
+
+        // Reverse flag of 2 instead of 1
+        let data = [199, 131, 29, 76, 0, 1, 128, 2];
+        assert!(ETHMessage::decode(&UntrustedRlp::new(&data), 3).is_ok());
+        assert!(ETHMessage::decode_strict(&UntrustedRlp::new(&data), 3).is_err());
+
+        // Trailing bytes after the message
+        let data = [199, 131, 29, 76, 0, 1, 128, 128, 0];
+        assert!(ETHMessage::decode_strict(&UntrustedRlp::new(&data), 3).is_err());
+    }
 
     #[test]
     fn test_new_block_hashes_message() {
@@ -211,12 +779,43 @@ mod tests {
     #[test]
     fn test_get_block_headers_hash_message() {
         let hash = H256::random();
-        let message = ETHMessage::GetBlockHeadersByHash {
-            hash, max_headers: 2048, skip: 0, reverse: false,
+        let message = ETHMessage::GetBlockHeaders {
+            start: HashOrNumber::Hash(hash), max_headers: 2048, skip: 0, reverse: false,
         };
         assert_eq!(message, ETHMessage::decode(&UntrustedRlp::new(&rlp::encode(&message)), 3).unwrap());
     }
 
+    #[test]
+    fn test_hash_or_number() {
+        // A hash with leading zero bytes is still a hash
+        let hash = HashOrNumber::Hash(H256::from(1));
+        assert_eq!(UntrustedRlp::new(&rlp::encode(&hash)).as_val::<HashOrNumber>().unwrap(), hash);
+
+        let number = HashOrNumber::Number(U256::from(u64::MAX));
+        assert_eq!(UntrustedRlp::new(&rlp::encode(&number)).as_val::<HashOrNumber>().unwrap(), number);
+
+        let mut s = RlpStream::new_list(1);
+        s.append(&1u8);
+        assert!(UntrustedRlp::new(&s.out()).as_val::<HashOrNumber>().is_err());
+    }
+
+    #[test]
+    fn test_display_summary() {
+        let headers: Vec<_> = [450000u64, 450191].iter().map(|&number| {
+            let mut header = sample_header();
+            header.number = U256::from(number);
+            header
+        }).collect();
+        assert_eq!(ETHMessage::BlockHeaders(headers).to_string(),
+                   "BlockHeaders(2 headers, #450000..#450191)");
+        assert_eq!(ETHMessage::BlockHeaders(Vec::new()).to_string(), "BlockHeaders(0 headers)");
+        assert_eq!(ETHMessage::GetBlockHeaders {
+            start: HashOrNumber::Number(U256::from(1920000)), max_headers: 192, skip: 0, reverse: true,
+        }.to_string(), "GetBlockHeaders(from #1920000, max 192, skip 0, reverse)");
+        assert_eq!(ETHMessage::Unknown { id: 0x11, data: vec![0xc0] }.to_string(),
+                   "Unknown(id 0x11, 1 bytes)");
+    }
+
     #[test]
     fn test_block_headers_message() {
         let data: [u8; 2148] = [249, 8, 97, 249, 2, 20, 160, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 160, 29, 204, 77, 232, 222, 199, 93, 122, 171, 133, 181, 103, 182, 204, 212, 26, 211, 18, 69, 27, 148, 138, 116, 19, 240, 161, 66, 253, 64, 212, 147, 71, 148, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 160, 215, 248, 151, 79, 181, 172, 120, 217, 172, 9, 155, 154, 213, 1, 139, 237, 194, 206, 10, 114, 218, 209, 130, 122, 23, 9, 218, 48, 88, 15, 5, 68, 160, 86, 232, 31, 23, 27, 204, 85, 166, 255, 131, 69, 230, 146, 192, 248, 110, 91, 72, 224, 27, 153, 108, 173, 192, 1, 98, 47, 181, 227, 99, 180, 33, 160, 86, 232, 31, 23, 27, 204, 85, 166, 255, 131, 69, 230, 146, 192, 248, 110, 91, 72, 224, 27, 153, 108, 173, 192, 1, 98, 47, 181, 227, 99, 180, 33, 185, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 133, 4, 0, 0, 0, 0, 128, 130, 19, 136, 128, 128, 160, 17, 187, 232, 219, 78, 52, 123, 78, 140, 147, 124, 28, 131, 112, 228, 181, 237, 51, 173, 179, 219, 105, 203, 219, 122, 56, 225, 229, 11, 27, 130, 250, 160, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 136, 0, 0, 0, 0, 0, 0, 0, 66, 249, 2, 17, 160, 212, 229, 103, 64, 248, 118, 174, 248, 192, 16, 184, 106, 64, 213, 245, 103, 69, 161, 24, 208, 144, 106, 52, 230, 154, 236, 140, 13, 177, 203, 143, 163, 160, 29, 204, 77, 232, 222, 199, 93, 122, 171, 133, 181, 103, 182, 204, 212, 26, 211, 18, 69, 27, 148, 138, 116, 19, 240, 161, 66, 253, 64, 212, 147, 71, 148, 5, 165, 110, 45, 82, 200, 23, 22, 24, 131, 245, 12, 68, 28, 50, 40, 207, 229, 77, 159, 160, 214, 126, 77, 69, 3, 67, 4, 100, 37, 174, 66, 113, 71, 67, 83, 133, 122, 184, 96, 219, 192, 161, 221, 230, 75, 65, 181, 205, 58, 83, 43, 243, 160, 86, 232, 31, 23, 27, 204, 85, 166, 255, 131, 69, 230, 146, 192, 248, 110, 91, 72, 224, 27, 153, 108, 173, 192, 1, 98, 47, 181, 227, 99, 180, 33, 160, 86, 232, 31, 23, 27, 204, 85, 166, 255, 131, 69, 230, 146, 192, 248, 110, 91, 72, 224, 27, 153, 108, 173, 192, 1, 98, 47, 181, 227, 99, 180, 33, 185, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 133, 3, 255, 128, 0, 0, 1, 130, 19, 136, 128, 132, 85, 186, 66, 36, 153, 71, 101, 116, 104, 47, 118, 49, 46, 48, 46, 48, 47, 108, 105, 110, 117, 120, 47, 103, 111, 49, 46, 52, 46, 50, 160, 150, 155, 144, 13, 226, 123, 106, 198, 166, 119, 66, 54, 93, 214, 95, 85, 160, 82, 108, 65, 253, 24, 225, 177, 111, 26, 18, 21, 194, 230, 111, 89, 136, 83, 155, 212, 151, 159, 239, 30, 196, 249, 2, 24, 160, 136, 233, 109, 69, 55, 190, 164, 217, 192, 93, 18, 84, 153, 7, 179, 37, 97, 211, 191, 49, 244, 90, 174, 115, 76, 220, 17, 159, 19, 64, 108, 182, 160, 29, 204, 77, 232, 222, 199, 93, 122, 171, 133, 181, 103, 182, 204, 212, 26, 211, 18, 69, 27, 148, 138, 116, 19, 240, 161, 66, 253, 64, 212, 147, 71, 148, 221, 47, 30, 110, 73, 130, 2, 232, 109, 143, 84, 66, 175, 89, 101, 128, 164, 240, 60, 44, 160, 73, 67, 217, 65, 99, 116, 17, 16, 116, 148, 218, 158, 200, 188, 4, 53, 157, 115, 27, 253, 8, 183, 43, 77, 14, 220, 189, 76, 210, 236, 179, 65, 160, 86, 232, 31, 23, 27, 204, 85, 166, 255, 131, 69, 230, 146, 192, 248, 110, 91, 72, 224, 27, 153, 108, 173, 192, 1, 98, 47, 181, 227, 99, 180, 33, 160, 86, 232, 31, 23, 27, 204, 85, 166, 255, 131, 69, 230, 146, 192, 248, 110, 91, 72, 224, 27, 153, 108, 173, 192, 1, 98, 47, 181, 227, 99, 180, 33, 185, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 133, 3, 255, 0, 16, 0, 2, 130, 19, 136, 128, 132, 85, 186, 66, 65, 160, 71, 101, 116, 104, 47, 118, 49, 46, 48, 46, 48, 45, 48, 99, 100, 99, 55, 54, 52, 55, 47, 108, 105, 110, 117, 120, 47, 103, 111, 49, 46, 52, 160, 47, 7, 144, 197, 170, 49, 171, 148, 25, 94, 31, 100, 67, 214, 69, 175, 91, 117, 196, 108, 4, 251, 249, 145, 23, 17, 25, 138, 12, 232, 253, 218, 136, 184, 83, 250, 38, 26, 134, 170, 158, 249, 2, 24, 160, 180, 149, 161, 215, 230, 102, 49, 82, 174, 146, 112, 141, 164, 132, 51, 55, 185, 88, 20, 96, 21, 162, 128, 47, 65, 147, 164, 16, 4, 70, 152, 201, 160, 107, 23, 185, 56, 198, 228, 239, 24, 178, 106, 216, 27, 156, 163, 81, 95, 39, 253, 156, 78, 130, 170, 197, 106, 31, 216, 234, 178, 136, 120, 94, 65, 148, 80, 136, 214, 35, 186, 15, 207, 1, 49, 224, 137, 122, 145, 115, 74, 77, 131, 89, 106, 160, 160, 118, 171, 11, 137, 158, 131, 135, 67, 111, 242, 101, 142, 41, 136, 248, 60, 191, 26, 241, 89, 11, 159, 233, 254, 202, 55, 20, 248, 209, 130, 73, 64, 160, 86, 232, 31, 23, 27, 204, 85, 166, 255, 131, 69, 230, 146, 192, 248, 110, 91, 72, 224, 27, 153, 108, 173, 192, 1, 98, 47, 181, 227, 99, 180, 33, 160, 86, 232, 31, 23, 27, 204, 85, 166, 255, 131, 69, 230, 146, 192, 248, 110, 91, 72, 224, 27, 153, 108, 173, 192, 1, 98, 47, 181, 227, 99, 180, 33, 185, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 133, 3, 254, 128, 47, 254, 3, 130, 19, 136, 128, 132, 85, 186, 66, 96, 160, 71, 101, 116, 104, 47, 118, 49, 46, 48, 46, 48, 45, 102, 99, 55, 57, 100, 51, 50, 100, 47, 108, 105, 110, 117, 120, 47, 103, 111, 49, 46, 52, 160, 101, 225, 46, 236, 35, 254, 101, 85, 230, 188, 219, 71, 170, 37, 38, 154, 225, 6, 229, 241, 107, 84, 225, 233, 45, 206, 226, 94, 28, 138, 208, 55, 136, 46, 147, 68, 224, 203, 222, 131, 206];
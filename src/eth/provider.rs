@@ -0,0 +1,311 @@
+use bigint::{H256, U256};
+use super::header::Header;
+use super::receipt::Receipt;
+use std::time::{Duration, Instant};
+use super::{ETHMessage, HashOrNumber, BlockBody};
+
+/// Default maximum number of headers in a served BlockHeaders
+pub const DEFAULT_MAX_SERVED_HEADERS: usize = 192;
+/// Default maximum number of items in served BlockBodies, Receipts
+/// and NodeData
+pub const DEFAULT_MAX_SERVED_BODIES: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Limits on serving requests. Requests asking for more items than
+/// allowed are answered with the first items only.
+pub struct ServeLimits {
+    /// Maximum number of headers answering a GetBlockHeaders
+    pub max_headers: usize,
+    /// Maximum number of bodies, receipts or node data entries
+    /// answering a GetBlockBodies, GetReceipts or GetNodeData
+    pub max_bodies: usize,
+    /// Maximum number of unanswered requests of a peer. Further
+    /// requests are dropped until some are answered.
+    pub max_pending_requests: Option<usize>,
+    /// Maximum number of bytes of responses built by the providers
+    /// per second, over all peers. Requests over it are dropped.
+    pub max_bytes_per_second: Option<usize>,
+}
+
+impl Default for ServeLimits {
+    fn default() -> Self {
+        ServeLimits {
+            max_headers: DEFAULT_MAX_SERVED_HEADERS,
+            max_bodies: DEFAULT_MAX_SERVED_BODIES,
+            max_pending_requests: None,
+            max_bytes_per_second: None,
+        }
+    }
+}
+
+/// Bytes of responses served within the current one second window
+pub struct ServeRate {
+    window_start: Instant,
+    served: usize,
+}
+
+impl ServeRate {
+    pub fn new(now: Instant) -> Self {
+        ServeRate {
+            window_start: now,
+            served: 0,
+        }
+    }
+
+    /// Whether a response of `bytes` bytes may be served at `now`
+    /// without exceeding `max` bytes per second, counting it if so
+    pub fn allow(&mut self, bytes: usize, max: usize, now: Instant) -> bool {
+        if now.duration_since(self.window_start) >= Duration::from_secs(1) {
+            self.window_start = now;
+            self.served = 0;
+        }
+        if self.served + bytes > max {
+            return false;
+        }
+        self.served += bytes;
+        true
+    }
+}
+
+/// Source of canonical chain headers used to answer GetBlockHeaders
+/// requests automatically
+pub trait HeaderProvider {
+    /// Header of the canonical block with the given number
+    fn header_by_number(&self, number: U256) -> Option<Header>;
+
+    /// Number of the canonical block with the given hash
+    fn number_for_hash(&self, hash: H256) -> Option<U256>;
+}
+
+/// Source of chain data used to answer requests automatically. Each
+/// method returns `None`, the default, for requests left to the
+/// consumer. Responses should leave out unknown items.
+pub trait ChainDataProvider {
+    /// Headers answering a GetBlockHeaders request
+    fn headers(
+        &self, _start: HashOrNumber, _max_headers: usize, _skip: usize, _reverse: bool
+    ) -> Option<Vec<Header>> {
+        None
+    }
+
+    /// Bodies of the blocks with the given hashes
+    fn bodies(&self, _hashes: &[H256]) -> Option<Vec<BlockBody>> {
+        None
+    }
+
+    /// Receipts of the blocks with the given hashes
+    fn receipts(&self, _hashes: &[H256]) -> Option<Vec<Vec<Receipt>>> {
+        None
+    }
+
+    /// State trie nodes and contract codes with the given hashes
+    fn node_data(&self, _hashes: &[H256]) -> Option<Vec<Vec<u8>>> {
+        None
+    }
+}
+
+/// Walk the canonical chain from `start`, collecting at most
+/// `max_headers` headers that are `skip` blocks apart
+pub fn walk_headers<P: HeaderProvider + ?Sized>(
+    provider: &P, start: U256, max_headers: usize, skip: usize, reverse: bool
+) -> Vec<Header> {
+    let step = U256::from(skip) + U256::one();
+    let mut headers = Vec::new();
+    let mut number = start;
+
+    while headers.len() < max_headers {
+        match provider.header_by_number(number) {
+            Some(header) => headers.push(header),
+            None => break,
+        }
+
+        if reverse {
+            if number < step {
+                break;
+            }
+            number = number - step;
+        } else {
+            number = number + step;
+        }
+    }
+
+    headers
+}
+
+/// Build the BlockHeaders response to a GetBlockHeaders request,
+/// or `None` if the message is not a header request. A request
+/// starting from an unknown hash gets an empty response.
+pub fn serve_headers<P: HeaderProvider + ?Sized>(
+    provider: &P, message: &ETHMessage, limits: &ServeLimits
+) -> Option<ETHMessage> {
+    let headers = match *message {
+        ETHMessage::GetBlockHeaders { start: HashOrNumber::Number(number), max_headers, skip, reverse } => {
+            walk_headers(provider, number, max_headers.min(limits.max_headers), skip, reverse)
+        },
+        ETHMessage::GetBlockHeaders { start: HashOrNumber::Hash(hash), max_headers, skip, reverse } => {
+            match provider.number_for_hash(hash) {
+                Some(number) =>
+                    walk_headers(provider, number, max_headers.min(limits.max_headers), skip, reverse),
+                None => Vec::new(),
+            }
+        },
+        _ => return None,
+    };
+
+    Some(ETHMessage::BlockHeaders(headers))
+}
+
+/// Build the response to a request the provider answers, or `None`
+/// if the message is not a request or is left to the consumer. The
+/// provider is only asked for as many items as the limits allow.
+pub fn serve_chain_data<P: ChainDataProvider + ?Sized>(
+    provider: &P, message: &ETHMessage, limits: &ServeLimits
+) -> Option<ETHMessage> {
+    let clamp = |hashes: &[H256]| hashes.len().min(limits.max_bodies);
+    match *message {
+        ETHMessage::GetBlockHeaders { start, max_headers, skip, reverse } =>
+            provider.headers(start, max_headers.min(limits.max_headers), skip, reverse)
+                .map(|mut headers| {
+                    headers.truncate(limits.max_headers);
+                    ETHMessage::BlockHeaders(headers)
+                }),
+        ETHMessage::GetBlockBodies(ref hashes) =>
+            provider.bodies(&hashes[..clamp(hashes)]).map(ETHMessage::BlockBodies),
+        ETHMessage::GetReceipts(ref hashes) =>
+            provider.receipts(&hashes[..clamp(hashes)]).map(ETHMessage::Receipts),
+        ETHMessage::GetNodeData(ref hashes) =>
+            provider.node_data(&hashes[..clamp(hashes)]).map(ETHMessage::NodeData),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{HeaderProvider, ChainDataProvider, ServeLimits, ServeRate, serve_headers, serve_chain_data};
+    use super::super::{ETHMessage, HashOrNumber, BlockBody};
+    use super::super::proto::sample_header;
+    use bigint::{H256, U256};
+    use super::super::header::Header;
+    use std::time::{Duration, Instant};
+
+    struct MockProvider {
+        headers: Vec<Header>,
+    }
+
+    impl MockProvider {
+        fn new(len: usize) -> Self {
+            let headers = (0..len).map(|number| {
+                let mut header = sample_header();
+                header.number = U256::from(number);
+                header
+            }).collect();
+            MockProvider { headers }
+        }
+
+        fn hash(number: usize) -> H256 {
+            H256::from(number as u64 + 1000)
+        }
+    }
+
+    impl HeaderProvider for MockProvider {
+        fn header_by_number(&self, number: U256) -> Option<Header> {
+            self.headers.get(number.as_u64() as usize).cloned()
+        }
+
+        fn number_for_hash(&self, hash: H256) -> Option<U256> {
+            (0..self.headers.len()).find(|&n| MockProvider::hash(n) == hash)
+                .map(U256::from)
+        }
+    }
+
+    fn numbers(message: ETHMessage) -> Vec<u64> {
+        match message {
+            ETHMessage::BlockHeaders(headers) =>
+                headers.iter().map(|h| h.number.as_u64()).collect(),
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn hash_origin_skip_forward() {
+        let provider = MockProvider::new(200);
+        let response = serve_headers(&provider, &ETHMessage::GetBlockHeaders {
+            start: HashOrNumber::Hash(MockProvider::hash(100)), max_headers: 4, skip: 1, reverse: false,
+        }, &ServeLimits::default()).unwrap();
+        assert_eq!(numbers(response), vec![100, 102, 104, 106]);
+    }
+
+    #[test]
+    fn hash_origin_unknown() {
+        let provider = MockProvider::new(200);
+        let response = serve_headers(&provider, &ETHMessage::GetBlockHeaders {
+            start: HashOrNumber::Hash(H256::from(1)), max_headers: 4, skip: 0, reverse: false,
+        }, &ServeLimits::default()).unwrap();
+        assert_eq!(response, ETHMessage::BlockHeaders(Vec::new()));
+    }
+
+    #[test]
+    fn number_origin_reverse() {
+        let provider = MockProvider::new(200);
+        let response = serve_headers(&provider, &ETHMessage::GetBlockHeaders {
+            start: HashOrNumber::Number(U256::from(5)), max_headers: 10, skip: 2, reverse: true,
+        }, &ServeLimits::default()).unwrap();
+        assert_eq!(numbers(response), vec![5, 2]);
+    }
+
+    struct BodyProvider;
+
+    impl ChainDataProvider for BodyProvider {
+        fn bodies(&self, hashes: &[H256]) -> Option<Vec<BlockBody>> {
+            Some(hashes.iter().filter(|&&hash| hash == H256::from(1)).map(|_| BlockBody {
+                transactions: Vec::new(),
+                ommers: vec![sample_header()],
+            }).collect())
+        }
+    }
+
+    #[test]
+    fn serves_provided_data_only() {
+        let limits = ServeLimits::default();
+        let response = serve_chain_data(&BodyProvider, &ETHMessage::GetBlockBodies(
+            vec![H256::from(1), H256::from(2)]), &limits).unwrap();
+        match response {
+            ETHMessage::BlockBodies(bodies) => assert_eq!(bodies.len(), 1),
+            _ => panic!(),
+        }
+
+        let headers = ETHMessage::GetBlockHeaders {
+            start: HashOrNumber::Number(U256::from(1)), max_headers: 1, skip: 0, reverse: false,
+        };
+        assert_eq!(serve_chain_data(&BodyProvider, &headers, &limits), None);
+        assert_eq!(serve_chain_data(&BodyProvider, &ETHMessage::GetReceipts(vec![H256::from(1)]), &limits),
+                   None);
+    }
+
+    #[test]
+    fn hostile_requests_clamped() {
+        let provider = MockProvider::new(2000);
+        let limits = ServeLimits { max_headers: 192, max_bodies: 1, ..Default::default() };
+        let response = serve_headers(&provider, &ETHMessage::GetBlockHeaders {
+            start: HashOrNumber::Number(U256::from(0)), max_headers: 1 << 32, skip: 0, reverse: false,
+        }, &limits).unwrap();
+        assert_eq!(numbers(response).len(), 192);
+
+        let response = serve_chain_data(&BodyProvider, &ETHMessage::GetBlockBodies(
+            vec![H256::from(1), H256::from(1)]), &limits).unwrap();
+        assert_eq!(response, ETHMessage::BlockBodies(vec![BlockBody {
+            transactions: Vec::new(),
+            ommers: vec![sample_header()],
+        }]));
+    }
+
+    #[test]
+    fn serve_rate_window() {
+        let start = Instant::now();
+        let mut rate = ServeRate::new(start);
+        assert!(rate.allow(600, 1000, start));
+        assert!(!rate.allow(600, 1000, start + Duration::from_millis(500)));
+        assert!(rate.allow(400, 1000, start + Duration::from_millis(500)));
+        assert!(rate.allow(600, 1000, start + Duration::from_secs(1)));
+    }
+}
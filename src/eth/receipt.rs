@@ -0,0 +1,167 @@
+use rlp::{self, Encodable, Decodable, RlpStream, DecoderError, UntrustedRlp};
+use bigint::{Gas, H256, U256};
+use block::Log;
+use bloom::LogsBloom;
+use trie::MemoryTrie;
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Outcome of a transaction as recorded in its receipt: the state
+/// root after it before Byzantium, whether it succeeded since EIP-658
+pub enum ReceiptOutcome {
+    StateRoot(H256),
+    Status(bool),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// Receipt of a transaction as sent in Receipts messages. Receipts of
+/// EIP-2718 typed transactions are byte strings holding the type
+/// followed by the RLP list legacy receipts are sent as.
+pub struct Receipt {
+    /// EIP-2718 type of the transaction, 0 for legacy transactions
+    pub transaction_type: u8,
+    pub outcome: ReceiptOutcome,
+    /// Gas used in the block up to and including the transaction
+    pub used_gas: Gas,
+    pub logs_bloom: LogsBloom,
+    pub logs: Vec<Log>,
+}
+
+impl Receipt {
+    /// Encoding of the receipt in the receipts trie, without the
+    /// string header wrapping typed receipts in RLP
+    fn trie_value(&self) -> Vec<u8> {
+        let mut s = RlpStream::new();
+        self.append_fields(&mut s);
+        let fields = s.out();
+        if self.transaction_type == 0 {
+            return fields.to_vec();
+        }
+        let mut value = Vec::with_capacity(fields.len() + 1);
+        value.push(self.transaction_type);
+        value.extend_from_slice(&fields);
+        value
+    }
+
+    fn append_fields(&self, s: &mut RlpStream) {
+        s.begin_list(4);
+        match self.outcome {
+            ReceiptOutcome::StateRoot(ref state_root) => s.append(state_root),
+            ReceiptOutcome::Status(true) => s.append(&1u8),
+            ReceiptOutcome::Status(false) => s.append_empty_data(),
+        };
+        s.append(&self.used_gas);
+        s.append(&self.logs_bloom);
+        s.append_list(&self.logs);
+    }
+
+    fn decode_fields(transaction_type: u8, rlp: &UntrustedRlp) -> Result<Self, DecoderError> {
+        if rlp.item_count()? != 4 {
+            return Err(DecoderError::RlpIncorrectListLen);
+        }
+        let outcome = rlp.at(0)?;
+        let outcome = match outcome.data()? {
+            data if data.len() == 32 => ReceiptOutcome::StateRoot(outcome.as_val()?),
+            &[] => ReceiptOutcome::Status(false),
+            &[1] => ReceiptOutcome::Status(true),
+            _ => return Err(DecoderError::Custom("invalid receipt status")),
+        };
+        Ok(Receipt {
+            transaction_type,
+            outcome,
+            used_gas: rlp.val_at(1)?,
+            logs_bloom: rlp.val_at(2)?,
+            logs: rlp.list_at(3)?,
+        })
+    }
+}
+
+/// Root of the trie of the receipts of a block, keyed by index
+pub fn receipts_root(receipts: &[Receipt]) -> H256 {
+    let mut trie = MemoryTrie::empty(HashMap::new());
+    for (i, receipt) in receipts.iter().enumerate() {
+        trie.insert_raw(rlp::encode(&U256::from(i)).to_vec(), receipt.trie_value());
+    }
+    trie.root()
+}
+
+impl Encodable for Receipt {
+    fn rlp_append(&self, s: &mut RlpStream) {
+        if self.transaction_type == 0 {
+            self.append_fields(s);
+        } else {
+            s.append(&self.trie_value());
+        }
+    }
+}
+
+impl Decodable for Receipt {
+    fn decode(rlp: &UntrustedRlp) -> Result<Self, DecoderError> {
+        if rlp.is_list() {
+            return Receipt::decode_fields(0, rlp);
+        }
+
+        let data = rlp.data()?;
+        // EIP-2718 types range from 0x01 to 0x7f, legacy receipts are
+        // never wrapped
+        if data.is_empty() || data[0] == 0 || data[0] > 0x7f {
+            return Err(DecoderError::Custom("invalid receipt type"));
+        }
+        Receipt::decode_fields(data[0], &UntrustedRlp::new(&data[1..]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Receipt, ReceiptOutcome, receipts_root};
+    use super::super::proto::sample_receipt;
+    use bigint::H256;
+    use block;
+    use rlp::{self, RlpStream, UntrustedRlp};
+
+    #[test]
+    fn legacy_receipts_unchanged() {
+        let receipt = sample_receipt();
+        let legacy = block::Receipt {
+            state_root: H256::from(1),
+            used_gas: receipt.used_gas,
+            logs_bloom: receipt.logs_bloom.clone(),
+            logs: receipt.logs.clone(),
+        };
+        assert_eq!(rlp::encode(&receipt).to_vec(), rlp::encode(&legacy).to_vec());
+        assert_eq!(receipts_root(&[receipt.clone(), receipt]),
+                   block::receipts_root(&[legacy.clone(), legacy]));
+    }
+
+    #[test]
+    fn status_and_typed_receipts() {
+        let mut success = sample_receipt();
+        success.outcome = ReceiptOutcome::Status(true);
+        let mut failure = success.clone();
+        failure.outcome = ReceiptOutcome::Status(false);
+        failure.transaction_type = 0x02;
+
+        let encoded = rlp::encode_list(&[success.clone(), failure.clone()]);
+        let rlp = UntrustedRlp::new(&encoded);
+        assert_eq!(rlp.at(0).unwrap().at(0).unwrap().data().unwrap(), &[1]);
+        // The typed receipt is a byte string starting with its type
+        assert_eq!(rlp.at(1).unwrap().data().unwrap()[0], 0x02);
+        assert_eq!(rlp.as_list::<Receipt>().unwrap(), vec![success.clone(), failure.clone()]);
+
+        // Typed receipts are stored in the trie without the string header
+        assert_eq!(&failure.trie_value()[..], rlp.at(1).unwrap().data().unwrap());
+        assert_eq!(&success.trie_value()[..], rlp.at(0).unwrap().as_raw());
+    }
+
+    #[test]
+    fn invalid_receipts_rejected() {
+        let receipt = sample_receipt();
+        let mut s = RlpStream::new_list(4);
+        s.append(&2u8).append(&receipt.used_gas).append(&receipt.logs_bloom).append_list(&receipt.logs);
+        assert!(UntrustedRlp::new(&s.out()).as_val::<Receipt>().is_err());
+
+        for data in &[vec![0x00u8, 0xc0], vec![0x80, 0xc0], Vec::new()] {
+            assert!(UntrustedRlp::new(&rlp::encode(data)).as_val::<Receipt>().is_err());
+        }
+    }
+}
@@ -0,0 +1,69 @@
+use bigint::H256;
+use block::RlpHash;
+use super::header::Header;
+use super::HeaderProvider;
+
+/// Find where the chain of `headers`, fetched backwards from the block
+/// with hash `parent_hash`, branches off from the blocks known to
+/// `provider`. Returns the hash of the closest known ancestor and the
+/// headers following it up to `parent_hash`, oldest first, or `None`
+/// if no ancestor is known or the headers do not link.
+pub fn find_branch<P: HeaderProvider + ?Sized>(
+    provider: &P, parent_hash: H256, headers: &[Header]
+) -> Option<(H256, Vec<Header>)> {
+    let mut expected = parent_hash;
+    for (index, header) in headers.iter().enumerate() {
+        if header.rlp_hash() != expected {
+            return None;
+        }
+        if provider.number_for_hash(header.parent_hash).is_some() {
+            let mut branch = headers[..index + 1].to_vec();
+            branch.reverse();
+            return Some((header.parent_hash, branch));
+        }
+        expected = header.parent_hash;
+    }
+    None
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::find_branch;
+    use super::super::{HeaderProvider, sample_header};
+    use bigint::{H256, U256};
+    use block::RlpHash;
+    use super::super::header::Header;
+
+    /// Provider knowing a single block
+    pub struct Known(pub H256);
+
+    impl HeaderProvider for Known {
+        fn header_by_number(&self, _number: U256) -> Option<Header> {
+            None
+        }
+
+        fn number_for_hash(&self, hash: H256) -> Option<U256> {
+            if hash == self.0 { Some(U256::from(10)) } else { None }
+        }
+    }
+
+    #[test]
+    fn branch_from_known_ancestor() {
+        let ancestor = H256::random();
+        let mut parent_hash = ancestor;
+        let branch: Vec<Header> = (11..14).map(|number| {
+            let mut header = sample_header();
+            header.number = U256::from(number);
+            header.parent_hash = parent_hash;
+            parent_hash = header.rlp_hash();
+            header
+        }).collect();
+        let mut backwards = branch.clone();
+        backwards.reverse();
+
+        assert_eq!(find_branch(&Known(ancestor), parent_hash, &backwards), Some((ancestor, branch)));
+        // Too shallow, or not linking to the announced block
+        assert_eq!(find_branch(&Known(ancestor), parent_hash, &backwards[..2]), None);
+        assert_eq!(find_branch(&Known(ancestor), H256::random(), &backwards), None);
+    }
+}
@@ -0,0 +1,98 @@
+use rlp::{RlpStream, UntrustedRlp, DecoderError};
+use super::ids;
+
+/// First ETH version framing requests and responses with a request id
+pub const REQUEST_ID_VERSION: usize = 66;
+
+/// Whether messages with the given id carry a request id from eth/66
+/// on. Only request/response pairs are wrapped:
+///
+/// * GetBlockHeaders (0x03) and BlockHeaders (0x04)
+/// * GetBlockBodies (0x05) and BlockBodies (0x06)
+/// * GetPooledTransactions (0x09) and PooledTransactions (0x0a)
+/// * GetNodeData (0x0d) and NodeData (0x0e)
+/// * GetReceipts (0x0f) and Receipts (0x10)
+///
+/// Status (0x00), NewBlockHashes (0x01), Transactions (0x02),
+/// NewBlock (0x07) and NewPooledTransactionHashes (0x08) are never
+/// wrapped.
+pub fn is_request_id_wrapped(id: usize) -> bool {
+    matches!(id, ids::GET_BLOCK_HEADERS | ids::BLOCK_HEADERS |
+        ids::GET_BLOCK_BODIES | ids::BLOCK_BODIES |
+        ids::GET_POOLED_TRANSACTIONS | ids::POOLED_TRANSACTIONS |
+        ids::GET_NODE_DATA | ids::NODE_DATA |
+        ids::GET_RECEIPTS | ids::RECEIPTS)
+}
+
+/// Wrap an RLP-encoded ETH message payload as `[request_id, payload]`
+/// if the version and message id require it, otherwise return the
+/// payload unchanged
+pub fn wrap_request_id(version: usize, id: usize, request_id: u64, payload: &[u8]) -> Vec<u8> {
+    if version < REQUEST_ID_VERSION || !is_request_id_wrapped(id) {
+        return payload.to_vec();
+    }
+
+    let mut s = RlpStream::new_list(2);
+    s.append(&request_id);
+    s.append_raw(payload, 1);
+    s.out().to_vec()
+}
+
+/// Split a received ETH message payload into its request id, if the
+/// version and message id require one, and the inner payload
+pub fn unwrap_request_id(
+    version: usize, id: usize, data: &[u8]
+) -> Result<(Option<u64>, Vec<u8>), DecoderError> {
+    if version < REQUEST_ID_VERSION || !is_request_id_wrapped(id) {
+        return Ok((None, data.to_vec()));
+    }
+
+    let rlp = UntrustedRlp::new(data);
+    if rlp.item_count()? != 2 {
+        return Err(DecoderError::RlpIncorrectListLen);
+    }
+    let request_id: u64 = rlp.val_at(0)?;
+    Ok((Some(request_id), rlp.at(1)?.as_raw().to_vec()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{wrap_request_id, unwrap_request_id};
+    use super::super::{ETHMessage, HashOrNumber};
+    use bigint::{H256, U256};
+    use rlp::{self, UntrustedRlp};
+
+    #[test]
+    fn status_unwrapped_on_eth66() {
+        let status = ETHMessage::Status {
+            protocol_version: 66,
+            network_id: 1,
+            total_difficulty: U256::from(17179869184u64),
+            best_hash: H256::random(),
+            genesis_hash: H256::random(),
+        };
+        let payload = rlp::encode(&status).to_vec();
+
+        let framed = wrap_request_id(66, status.id(), 7, &payload);
+        assert_eq!(framed, payload);
+        let (request_id, data) = unwrap_request_id(66, status.id(), &framed).unwrap();
+        assert_eq!(request_id, None);
+        assert_eq!(ETHMessage::decode(&UntrustedRlp::new(&data), status.id()).unwrap(), status);
+    }
+
+    #[test]
+    fn get_block_headers_wrapped_on_eth66() {
+        let request = ETHMessage::GetBlockHeaders {
+            start: HashOrNumber::Hash(H256::random()), max_headers: 16, skip: 0, reverse: false,
+        };
+        let payload = rlp::encode(&request).to_vec();
+
+        let framed = wrap_request_id(66, request.id(), 7, &payload);
+        assert!(framed != payload);
+        let (request_id, data) = unwrap_request_id(66, request.id(), &framed).unwrap();
+        assert_eq!(request_id, Some(7));
+        assert_eq!(ETHMessage::decode(&UntrustedRlp::new(&data), request.id()).unwrap(), request);
+
+        assert_eq!(wrap_request_id(65, request.id(), 7, &payload), payload);
+    }
+}
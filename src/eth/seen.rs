@@ -0,0 +1,155 @@
+use bigint::{H256, H512};
+use block::RlpHash;
+use std::collections::{HashMap, VecDeque};
+use super::ETHMessage;
+
+/// Bounded least-recently-used history of block hashes, remembering
+/// which peers have each of them, because they announced it to us or
+/// we sent it to them
+pub struct SeenBlocks {
+    capacity: usize,
+    order: VecDeque<H256>,
+    announcers: HashMap<H256, Vec<H512>>,
+}
+
+impl SeenBlocks {
+    /// Create a new history holding at most `capacity` hashes
+    pub fn new(capacity: usize) -> Self {
+        SeenBlocks {
+            capacity,
+            order: VecDeque::new(),
+            announcers: HashMap::new(),
+        }
+    }
+
+    /// Change the capacity, evicting the oldest hashes if needed
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        self.evict();
+    }
+
+    /// Record that `peer` has the block of the given hash. Returns
+    /// whether the hash had already been seen.
+    pub fn insert(&mut self, hash: H256, peer: H512) -> bool {
+        let seen = match self.announcers.get_mut(&hash) {
+            Some(announcers) => {
+                if !announcers.contains(&peer) {
+                    announcers.push(peer);
+                }
+                true
+            },
+            None => false,
+        };
+
+        if seen {
+            if let Some(index) = self.order.iter().position(|h| *h == hash) {
+                self.order.remove(index);
+            }
+        } else {
+            self.announcers.insert(hash, vec![peer]);
+        }
+        self.order.push_back(hash);
+        self.evict();
+
+        seen
+    }
+
+    /// Whether the hash is in the history
+    pub fn contains(&self, hash: &H256) -> bool {
+        self.announcers.contains_key(hash)
+    }
+
+    /// Peers known to have the block of the given hash
+    pub fn announcers(&self, hash: &H256) -> &[H512] {
+        match self.announcers.get(hash) {
+            Some(announcers) => announcers.as_ref(),
+            None => &[],
+        }
+    }
+
+    /// Whether `peer` is known to have the block of the given hash
+    pub fn knows(&self, peer: &H512, hash: &H256) -> bool {
+        self.announcers(hash).contains(peer)
+    }
+
+    /// Copy of a NewBlockHashes message without the blocks `peer`
+    /// already has, which may leave it empty. `None` for other
+    /// messages.
+    pub fn filter(&self, peer: &H512, message: &ETHMessage) -> Option<ETHMessage> {
+        match message {
+            ETHMessage::NewBlockHashes(hashes) =>
+                Some(ETHMessage::NewBlockHashes(
+                    hashes.iter().filter(|&&(hash, _)| !self.knows(peer, &hash)).cloned().collect())),
+            _ => None,
+        }
+    }
+
+    fn evict(&mut self) {
+        while self.order.len() > self.capacity {
+            if let Some(hash) = self.order.pop_front() {
+                self.announcers.remove(&hash);
+            }
+        }
+    }
+}
+
+/// Hashes of the blocks sent or announced by a NewBlock or
+/// NewBlockHashes message
+pub fn block_hashes(message: &ETHMessage) -> Vec<H256> {
+    match *message {
+        ETHMessage::NewBlock { ref header, .. } => vec![header.rlp_hash()],
+        ETHMessage::NewBlockHashes(ref hashes) =>
+            hashes.iter().map(|&(hash, _)| hash).collect(),
+        _ => Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SeenBlocks;
+    use super::super::ETHMessage;
+    use bigint::{H256, H512, U256};
+
+    #[test]
+    fn same_block_from_three_peers() {
+        let mut seen = SeenBlocks::new(16);
+        let hash = H256::random();
+        let peers = [H512::random(), H512::random(), H512::random()];
+
+        assert!(!seen.insert(hash, peers[0]));
+        assert!(seen.insert(hash, peers[1]));
+        assert!(seen.insert(hash, peers[2]));
+        assert_eq!(seen.announcers(&hash), &peers[..]);
+    }
+
+    #[test]
+    fn evicts_least_recently_seen() {
+        let mut seen = SeenBlocks::new(2);
+        let peer = H512::random();
+        let hashes = [H256::random(), H256::random(), H256::random()];
+
+        seen.insert(hashes[0], peer);
+        seen.insert(hashes[1], peer);
+        seen.insert(hashes[0], peer);
+        seen.insert(hashes[2], peer);
+
+        assert!(seen.contains(&hashes[0]));
+        assert!(!seen.contains(&hashes[1]));
+        assert!(seen.contains(&hashes[2]));
+    }
+
+    #[test]
+    fn filters_known_blocks() {
+        let mut seen = SeenBlocks::new(16);
+        let peer = H512::random();
+        let hashes = [H256::random(), H256::random()];
+        seen.insert(hashes[0], peer);
+
+        let message = ETHMessage::NewBlockHashes(vec![(hashes[0], U256::from(1)),
+                                                      (hashes[1], U256::from(2))]);
+        assert_eq!(seen.filter(&peer, &message),
+                   Some(ETHMessage::NewBlockHashes(vec![(hashes[1], U256::from(2))])));
+        assert_eq!(seen.filter(&H512::random(), &message), Some(message.clone()));
+        assert_eq!(seen.filter(&peer, &ETHMessage::GetBlockBodies(Vec::new())), None);
+    }
+}
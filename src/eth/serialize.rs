@@ -0,0 +1,74 @@
+use serde::{Serialize, Serializer, Deserialize, Deserializer};
+use serde::de::Error;
+use rlp::{self, UntrustedRlp};
+use hexutil::{to_hex, read_hex};
+use super::ETHMessage;
+
+/// Serialized form of an `ETHMessage`: its id, its RLP payload as a
+/// hex string, and a one-line summary for readers, ignored when
+/// deserializing
+#[derive(Serialize, Deserialize)]
+struct MessageRecord {
+    id: usize,
+    #[serde(default)]
+    summary: String,
+    rlp: String,
+}
+
+impl Serialize for ETHMessage {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        MessageRecord {
+            id: self.id(),
+            summary: self.to_string(),
+            rlp: to_hex(&rlp::encode(self)),
+        }.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for ETHMessage {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let record = MessageRecord::deserialize(deserializer)?;
+        let data = read_hex(&record.rlp)
+            .map_err(|_| D::Error::custom("invalid rlp hex"))?;
+        ETHMessage::decode(&UntrustedRlp::new(&data), record.id)
+            .map_err(|e| D::Error::custom(format!("{:?}", e)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{ETHMessage, PeerInfo, LatencyStats};
+    use dpt::DPTNode;
+    use bigint::H512;
+    use serde_json;
+    use std::time::Duration;
+
+    #[test]
+    fn messages_round_trip() {
+        for message in ETHMessage::samples() {
+            let json = serde_json::to_string(&message).unwrap();
+            assert_eq!(serde_json::from_str::<ETHMessage>(&json).unwrap(), message);
+        }
+    }
+
+    #[test]
+    fn peers_round_trip() {
+        let info = PeerInfo {
+            version: 66,
+            latency: Some(LatencyStats {
+                average: Duration::from_millis(20), max: Duration::from_millis(50), samples: 3,
+            }),
+            violations: 1,
+            score: -10,
+        };
+        let json = serde_json::to_string(&info).unwrap();
+        assert_eq!(serde_json::from_str::<PeerInfo>(&json).unwrap(), info);
+
+        let node = DPTNode {
+            address: "127.0.0.1".parse().unwrap(), tcp_port: 30303, udp_port: 30301,
+            id: H512::random(),
+        };
+        let json = serde_json::to_string(&node).unwrap();
+        assert_eq!(serde_json::from_str::<DPTNode>(&json).unwrap(), node);
+    }
+}
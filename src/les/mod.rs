@@ -0,0 +1,281 @@
+mod proto;
+
+use futures::{StartSend, Async, Poll, Stream, Sink, AsyncSink};
+use rlp::{self, UntrustedRlp};
+use bigint::{H256, H512, U256};
+use rlpx::{RLPxSendMessage, RLPxReceiveMessage, RLPxNode, CapabilityInfo, DisconnectReason, PeerInfo,
+           PeerTable, NodeSigner};
+use tokio_core::reactor::Handle;
+use std::io;
+use std::sync::Arc;
+use std::net::{IpAddr, SocketAddr};
+
+use super::{DevP2PStream, DevP2PBuilder, Error};
+
+pub use self::proto::{LESMessage, LESStatus, FlowControl, ProofRequest};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// Receiving message of LES
+pub enum LESReceiveMessage {
+    Connected {
+        node: H512,
+        version: usize,
+    },
+    /// A peer went away, see `RLPxReceiveMessage::Disconnected`
+    Disconnected {
+        node: H512,
+        reason: Option<DisconnectReason>,
+        clean: bool,
+    },
+    Normal {
+        node: H512,
+        version: usize,
+        data: Box<LESMessage>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// Sending message of LES
+pub struct LESSendMessage {
+    pub node: RLPxNode,
+    pub data: LESMessage,
+}
+
+/// Represent a LES stream over DevP2P protocol, advertising the les/2
+/// capability. Our Status is sent to every peer right after the
+/// handshake; flow control is left to the consumer, which gets the
+/// server parameters from its Status and buffer values from its
+/// responses.
+pub struct LESStream {
+    stream: DevP2PStream,
+    status: LESStatus,
+}
+
+impl LESStream {
+    /// Create a new LES stream. `status` is the Status sent to peers,
+    /// its protocol version is set to the negotiated one. The network
+    /// is set up by `network`.
+    pub fn new(addr: &SocketAddr, public_addr: &IpAddr,
+               handle: &Handle, signer: Arc<dyn NodeSigner>,
+               status: LESStatus, network: DevP2PBuilder,
+    ) -> Result<Self, io::Error> {
+        Ok(LESStream {
+            stream: network.capability(CapabilityInfo { name: "les", version: 2, length: 22 })
+                .build(addr, public_addr, handle, signer)?,
+            status,
+        })
+    }
+
+    /// Force disconnecting a peer if it is already connected or about
+    /// to be connected, telling a connected peer the reason in a
+    /// Disconnect message
+    pub fn disconnect_peer(&mut self, remote_id: H512, reason: DisconnectReason) {
+        self.stream.disconnect_peer(remote_id, reason);
+    }
+
+    /// Active peers
+    pub fn active_peers(&self) -> &[H512] {
+        self.stream.active_peers()
+    }
+
+    /// Table of the connected peers, see `DevP2PStream::peer_table`
+    pub fn peer_table(&self) -> PeerTable {
+        self.stream.peer_table()
+    }
+
+    /// Summary of a connected peer, see `DevP2PStream::peer_info`
+    pub fn peer_info(&self, remote_id: H512) -> Option<PeerInfo> {
+        self.stream.peer_info(remote_id)
+    }
+
+    /// Summaries of all connected peers
+    pub fn peers(&self) -> Vec<PeerInfo> {
+        self.stream.peers()
+    }
+
+    /// Update the head announced in the Status sent to new peers
+    pub fn set_head(&mut self, head_hash: H256, head_number: U256, head_td: U256) {
+        self.status.head_hash = head_hash;
+        self.status.head_number = head_number;
+        self.status.head_td = head_td;
+    }
+}
+
+impl Stream for LESStream {
+    type Item = LESReceiveMessage;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        let result = match try_ready!(self.stream.poll()) {
+            Some(result) => result,
+            None => return Ok(Async::Ready(None)),
+        };
+
+        match result {
+            RLPxReceiveMessage::Connected { node, capabilities } => {
+                let version = match capabilities.iter().find(|cap| cap.name == "les") {
+                    Some(cap) => cap.version,
+                    None => {
+                        debug!("connected a node without matching capability, ignoring.");
+                        return self.poll();
+                    },
+                };
+
+                let mut status = self.status.clone();
+                status.protocol_version = version;
+                self.start_send(LESSendMessage {
+                    node: RLPxNode::Peer(node),
+                    data: LESMessage::Status(status),
+                })?;
+                self.poll_complete()?;
+
+                Ok(Async::Ready(Some(LESReceiveMessage::Connected {
+                    node, version
+                })))
+            },
+            RLPxReceiveMessage::Disconnected { node, reason, clean } => {
+                Ok(Async::Ready(Some(LESReceiveMessage::Disconnected {
+                    node, reason, clean
+                })))
+            },
+            RLPxReceiveMessage::Normal {
+                node, capability, id, data,
+            } => {
+                debug!("got les message with id {}", id);
+                match LESMessage::decode(&UntrustedRlp::new(&data), id) {
+                    Ok(message) => Ok(Async::Ready(Some(LESReceiveMessage::Normal {
+                        node, version: capability.version,
+                        data: Box::new(message),
+                    }))),
+                    Err(_) => {
+                        debug!("got an ununderstandable message with id {}, data {:?}, ignoring.", id, data);
+                        self.poll()
+                    },
+                }
+            },
+        }
+    }
+}
+
+impl Sink for LESStream {
+    type SinkItem = LESSendMessage;
+    type SinkError = Error;
+
+    fn start_send(&mut self, val: LESSendMessage) -> StartSend<Self::SinkItem, Self::SinkError> {
+        match self.stream.start_send(RLPxSendMessage {
+            node: val.node,
+            capability_name: "les",
+            id: val.data.id(),
+            data: rlp::encode(&val.data).to_vec(),
+        })? {
+            AsyncSink::Ready => Ok(AsyncSink::Ready),
+            AsyncSink::NotReady(_) => Ok(AsyncSink::NotReady(val)),
+        }
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
+        self.stream.poll_complete()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{LESStream, LESMessage, LESStatus, LESReceiveMessage};
+    use super::super::{DevP2PConfig, DevP2PBuilder};
+    use eth::HashOrNumber;
+    use bigint::{H256, H512, U256};
+    use rlp::UntrustedRlp;
+    use rlpx::{PeerStream, CapabilityInfo};
+    use secp256k1::SECP256K1;
+    use secp256k1::key::{PublicKey, SecretKey};
+    use tokio_core::reactor::Core;
+    use futures::{future, Async, Poll, Future, Stream, Sink};
+    use rand::os::OsRng;
+    use std::io;
+    use std::net::TcpListener;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    fn pk2id(key: &PublicKey) -> H512 {
+        H512::from(&key.serialize_vec(&SECP256K1, false)[1..65])
+    }
+
+    #[test]
+    fn status_then_header_request() {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let config = DevP2PConfig {
+     ping_interval: Duration::new(600, 0),
+     ping_timeout_interval: Duration::new(700, 0),
+     optimal_peers_interval: Duration::new(600, 0),
+     max_peers_per_ip: None,
+     ..Default::default()
+        };
+        let status = LESStatus {
+            protocol_version: 0,
+            network_id: 1,
+            head_td: U256::from(1),
+            head_hash: H256::from(1),
+            head_number: U256::zero(),
+            genesis_hash: H256::from(1),
+            serve_headers: false,
+            serve_chain_since: None,
+            serve_state_since: None,
+            tx_relay: false,
+            flow_control: None,
+        };
+
+        let addr = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let secret_key = SecretKey::new(&SECP256K1, &mut OsRng::new().unwrap());
+        let id = pk2id(&PublicKey::from_secret_key(&SECP256K1, &secret_key).unwrap());
+        let mut les = LESStream::new(&addr, &addr.ip(), &handle, Arc::new(secret_key), status,
+                                     DevP2PBuilder::from(config).client_version("les".to_string())).unwrap();
+        les.set_head(H256::from(2), U256::from(10), U256::from(20));
+
+        let peer_key = SecretKey::new(&SECP256K1, &mut OsRng::new().unwrap());
+        let peer = PeerStream::connect(
+            &addr, &handle, Arc::new(peer_key), id,
+            5, "peer".to_string(), vec![CapabilityInfo { name: "les", version: 2, length: 22 }],
+            0, Default::default());
+        let request = LESMessage::GetBlockHeaders {
+            request_id: 7, start: HashOrNumber::Hash(H256::from(2)), max_headers: 1, skip: 0, reverse: false,
+        };
+        let payload = ::rlp::encode(&request).to_vec();
+        let peer = peer
+            .and_then(|peer| peer.into_future().map_err(|(e, _)| e))
+            .and_then(|(message, peer)| {
+                let (cap, id, data) = message.unwrap();
+                assert_eq!((cap.name, id), ("les", 0x00));
+                match LESMessage::decode(&UntrustedRlp::new(&data), id).unwrap() {
+                    LESMessage::Status(status) => {
+                        assert_eq!(status.protocol_version, 2);
+                        assert_eq!(status.head_hash, H256::from(2));
+                        assert_eq!(status.head_number, U256::from(10));
+                    },
+                    _ => panic!(),
+                }
+                peer.send(("les", 0x02, payload))
+            });
+
+        let mut received = Vec::new();
+        let messages = future::poll_fn(|| -> Poll<(), io::Error> {
+            while received.len() < 2 {
+                match try_ready!(les.poll()) {
+                    Some(message) => received.push(message),
+                    None => panic!(),
+                }
+            }
+            Ok(Async::Ready(()))
+        });
+        core.run(peer.join(messages)).unwrap();
+
+        match received[0] {
+            LESReceiveMessage::Connected { version, .. } => assert_eq!(version, 2),
+            _ => panic!(),
+        }
+        match received[1] {
+            LESReceiveMessage::Normal { ref data, .. } => assert_eq!(**data, request),
+            _ => panic!(),
+        }
+    }
+}
@@ -0,0 +1,425 @@
+use rlp::{Encodable, RlpStream, DecoderError, UntrustedRlp};
+use bigint::{H256, U256};
+use eth::{Header, HashOrNumber};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// Flow control parameters announced by a LES server
+pub struct FlowControl {
+    /// Buffer limit
+    pub buffer_limit: u64,
+    /// Minimum rate of recharge
+    pub minimum_recharge: u64,
+    /// Maximum request costs as (message id, base cost, cost per
+    /// requested item)
+    pub request_costs: Vec<(usize, u64, u64)>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// LES Status message, sent as a list of key-value pairs
+pub struct LESStatus {
+    pub protocol_version: usize,
+    pub network_id: usize,
+    pub head_td: U256,
+    pub head_hash: H256,
+    pub head_number: U256,
+    pub genesis_hash: H256,
+    pub serve_headers: bool,
+    pub serve_chain_since: Option<U256>,
+    pub serve_state_since: Option<U256>,
+    pub tx_relay: bool,
+    /// Set by servers only
+    pub flow_control: Option<FlowControl>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// A GetProofs request for the Merkle proof of an account, or of a
+/// storage slot of it if `key` is not empty
+pub struct ProofRequest {
+    pub block_hash: H256,
+    pub account_key: H256,
+    pub key: Vec<u8>,
+    pub from_level: u64,
+}
+
+/// LES message version 2. Responses carry the server's buffer value
+/// for flow control.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LESMessage {
+    Status(LESStatus),
+    Announce {
+        head_hash: H256,
+        head_number: U256,
+        head_td: U256,
+        reorg_depth: u64,
+    },
+    GetBlockHeaders {
+        request_id: u64,
+        start: HashOrNumber,
+        max_headers: usize,
+        skip: usize,
+        reverse: bool,
+    },
+    BlockHeaders {
+        request_id: u64,
+        buffer_value: u64,
+        headers: Vec<Header>,
+    },
+    /// Code of the account with the given key at the given block
+    GetContractCodes {
+        request_id: u64,
+        requests: Vec<(H256, H256)>,
+    },
+    ContractCodes {
+        request_id: u64,
+        buffer_value: u64,
+        codes: Vec<Vec<u8>>,
+    },
+    GetProofs {
+        request_id: u64,
+        requests: Vec<ProofRequest>,
+    },
+    /// Deduplicated trie nodes of all requested proofs
+    Proofs {
+        request_id: u64,
+        buffer_value: u64,
+        nodes: Vec<Vec<u8>>,
+    },
+}
+
+impl LESMessage {
+    /// Get the message id of the LES message
+    pub fn id(&self) -> usize {
+        match *self {
+            LESMessage::Status(_) => 0x00,
+            LESMessage::Announce { .. } => 0x01,
+            LESMessage::GetBlockHeaders { .. } => 0x02,
+            LESMessage::BlockHeaders { .. } => 0x03,
+            LESMessage::GetContractCodes { .. } => 0x0a,
+            LESMessage::ContractCodes { .. } => 0x0b,
+            LESMessage::GetProofs { .. } => 0x0f,
+            LESMessage::Proofs { .. } => 0x10,
+        }
+    }
+
+    /// Decode a RLP into LES message using the given message id
+    pub fn decode(rlp: &UntrustedRlp, id: usize) -> Result<Self, DecoderError> {
+        Ok(match id {
+            0x00 => LESMessage::Status(decode_status(rlp)?),
+            0x01 => {
+                LESMessage::Announce {
+                    head_hash: rlp.val_at(0)?,
+                    head_number: rlp.val_at(1)?,
+                    head_td: rlp.val_at(2)?,
+                    reorg_depth: rlp.val_at(3)?,
+                }
+            },
+            0x02 => {
+                let request_id = rlp.val_at(0)?;
+                let query = rlp.at(1)?;
+                let reverse: u32 = query.val_at(3)?;
+                LESMessage::GetBlockHeaders {
+                    request_id,
+                    start: query.val_at(0)?,
+                    max_headers: query.val_at(1)?,
+                    skip: query.val_at(2)?,
+                    reverse: reverse != 0,
+                }
+            },
+            0x03 => {
+                LESMessage::BlockHeaders {
+                    request_id: rlp.val_at(0)?,
+                    buffer_value: rlp.val_at(1)?,
+                    headers: rlp.list_at(2)?,
+                }
+            },
+            0x0a => {
+                let mut requests = Vec::new();
+                for request in rlp.at(1)?.iter() {
+                    requests.push((request.val_at(0)?, request.val_at(1)?));
+                }
+                LESMessage::GetContractCodes {
+                    request_id: rlp.val_at(0)?,
+                    requests,
+                }
+            },
+            0x0b => {
+                LESMessage::ContractCodes {
+                    request_id: rlp.val_at(0)?,
+                    buffer_value: rlp.val_at(1)?,
+                    codes: rlp.list_at(2)?,
+                }
+            },
+            0x0f => {
+                let mut requests = Vec::new();
+                for request in rlp.at(1)?.iter() {
+                    requests.push(ProofRequest {
+                        block_hash: request.val_at(0)?,
+                        account_key: request.val_at(1)?,
+                        key: request.val_at(2)?,
+                        from_level: request.val_at(3)?,
+                    });
+                }
+                LESMessage::GetProofs {
+                    request_id: rlp.val_at(0)?,
+                    requests,
+                }
+            },
+            0x10 => {
+                LESMessage::Proofs {
+                    request_id: rlp.val_at(0)?,
+                    buffer_value: rlp.val_at(1)?,
+                    nodes: rlp.list_at(2)?,
+                }
+            },
+            _ => return Err(DecoderError::Custom("unsupported les message id")),
+        })
+    }
+}
+
+/// Decode the key-value pairs of a Status message. Unknown keys are
+/// skipped and flags are set by the presence of their key.
+fn decode_status(rlp: &UntrustedRlp) -> Result<LESStatus, DecoderError> {
+    let mut status = LESStatus {
+        protocol_version: 0,
+        network_id: 0,
+        head_td: U256::zero(),
+        head_hash: H256::zero(),
+        head_number: U256::zero(),
+        genesis_hash: H256::zero(),
+        serve_headers: false,
+        serve_chain_since: None,
+        serve_state_since: None,
+        tx_relay: false,
+        flow_control: None,
+    };
+    let mut buffer_limit = None;
+    let mut minimum_recharge = None;
+    let mut request_costs = None;
+
+    for pair in rlp.iter() {
+        let key: String = pair.val_at(0)?;
+        match key.as_str() {
+            "protocolVersion" => status.protocol_version = pair.val_at(1)?,
+            "networkId" => status.network_id = pair.val_at(1)?,
+            "headTd" => status.head_td = pair.val_at(1)?,
+            "headHash" => status.head_hash = pair.val_at(1)?,
+            "headNum" => status.head_number = pair.val_at(1)?,
+            "genesisHash" => status.genesis_hash = pair.val_at(1)?,
+            "serveHeaders" => status.serve_headers = true,
+            "serveChainSince" => status.serve_chain_since = Some(pair.val_at(1)?),
+            "serveStateSince" => status.serve_state_since = Some(pair.val_at(1)?),
+            "txRelay" => status.tx_relay = true,
+            "flowControl/BL" => buffer_limit = Some(pair.val_at(1)?),
+            "flowControl/MRR" => minimum_recharge = Some(pair.val_at(1)?),
+            "flowControl/MRC" => {
+                let mut costs = Vec::new();
+                for cost in pair.at(1)?.iter() {
+                    costs.push((cost.val_at(0)?, cost.val_at(1)?, cost.val_at(2)?));
+                }
+                request_costs = Some(costs);
+            },
+            _ => (),
+        }
+    }
+
+    if let (Some(buffer_limit), Some(minimum_recharge), Some(request_costs)) =
+        (buffer_limit, minimum_recharge, request_costs)
+    {
+        status.flow_control = Some(FlowControl { buffer_limit, minimum_recharge, request_costs });
+    }
+    Ok(status)
+}
+
+/// Append a key-value pair of a Status message
+fn append_pair<E: Encodable>(pairs: &mut Vec<Vec<u8>>, key: &str, value: &E) {
+    let mut s = RlpStream::new_list(2);
+    s.append(&key);
+    s.append(value);
+    pairs.push(s.out().to_vec());
+}
+
+/// Append a key-value pair without value, used for flags
+fn append_flag(pairs: &mut Vec<Vec<u8>>, key: &str) {
+    let mut s = RlpStream::new_list(2);
+    s.append(&key);
+    s.append_empty_data();
+    pairs.push(s.out().to_vec());
+}
+
+fn encode_status(status: &LESStatus, s: &mut RlpStream) {
+    let mut pairs = Vec::new();
+    append_pair(&mut pairs, "protocolVersion", &status.protocol_version);
+    append_pair(&mut pairs, "networkId", &status.network_id);
+    append_pair(&mut pairs, "headTd", &status.head_td);
+    append_pair(&mut pairs, "headHash", &status.head_hash);
+    append_pair(&mut pairs, "headNum", &status.head_number);
+    append_pair(&mut pairs, "genesisHash", &status.genesis_hash);
+    if status.serve_headers {
+        append_flag(&mut pairs, "serveHeaders");
+    }
+    if let Some(ref number) = status.serve_chain_since {
+        append_pair(&mut pairs, "serveChainSince", number);
+    }
+    if let Some(ref number) = status.serve_state_since {
+        append_pair(&mut pairs, "serveStateSince", number);
+    }
+    if status.tx_relay {
+        append_flag(&mut pairs, "txRelay");
+    }
+    if let Some(ref flow_control) = status.flow_control {
+        append_pair(&mut pairs, "flowControl/BL", &flow_control.buffer_limit);
+        append_pair(&mut pairs, "flowControl/MRR", &flow_control.minimum_recharge);
+        let mut costs = RlpStream::new_list(flow_control.request_costs.len());
+        for &(id, base, per_item) in &flow_control.request_costs {
+            costs.begin_list(3);
+            costs.append(&id);
+            costs.append(&base);
+            costs.append(&per_item);
+        }
+        let mut pair = RlpStream::new_list(2);
+        pair.append(&"flowControl/MRC");
+        pair.append_raw(&costs.out(), 1);
+        pairs.push(pair.out().to_vec());
+    }
+
+    s.begin_list(pairs.len());
+    for pair in &pairs {
+        s.append_raw(pair, 1);
+    }
+}
+
+impl Encodable for LESMessage {
+    fn rlp_append(&self, s: &mut RlpStream) {
+        match *self {
+            LESMessage::Status(ref status) => encode_status(status, s),
+            LESMessage::Announce { head_hash, head_number, head_td, reorg_depth } => {
+                // No announcement parameters
+                s.begin_list(5);
+                s.append(&head_hash);
+                s.append(&head_number);
+                s.append(&head_td);
+                s.append(&reorg_depth);
+                s.begin_list(0);
+            },
+            LESMessage::GetBlockHeaders { request_id, start, max_headers, skip, reverse } => {
+                s.begin_list(2);
+                s.append(&request_id);
+                s.begin_list(4);
+                s.append(&start);
+                s.append(&max_headers);
+                s.append(&skip);
+                s.append(&if reverse { 1u32 } else { 0u32 });
+            },
+            LESMessage::BlockHeaders { request_id, buffer_value, ref headers } => {
+                s.begin_list(3);
+                s.append(&request_id);
+                s.append(&buffer_value);
+                s.append_list(headers);
+            },
+            LESMessage::GetContractCodes { request_id, ref requests } => {
+                s.begin_list(2);
+                s.append(&request_id);
+                s.begin_list(requests.len());
+                for &(block_hash, account_key) in requests {
+                    s.begin_list(2);
+                    s.append(&block_hash);
+                    s.append(&account_key);
+                }
+            },
+            LESMessage::ContractCodes { request_id, buffer_value, ref codes } => {
+                s.begin_list(3);
+                s.append(&request_id);
+                s.append(&buffer_value);
+                s.append_list::<Vec<u8>, _>(codes);
+            },
+            LESMessage::GetProofs { request_id, ref requests } => {
+                s.begin_list(2);
+                s.append(&request_id);
+                s.begin_list(requests.len());
+                for request in requests {
+                    s.begin_list(4);
+                    s.append(&request.block_hash);
+                    s.append(&request.account_key);
+                    s.append(&request.key);
+                    s.append(&request.from_level);
+                }
+            },
+            LESMessage::Proofs { request_id, buffer_value, ref nodes } => {
+                s.begin_list(3);
+                s.append(&request_id);
+                s.append(&buffer_value);
+                s.append_list::<Vec<u8>, _>(nodes);
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{LESMessage, LESStatus, FlowControl, ProofRequest};
+    use eth::{sample_header, HashOrNumber};
+    use rlp::{self, UntrustedRlp};
+    use bigint::{H256, U256};
+
+    fn status() -> LESStatus {
+        LESStatus {
+            protocol_version: 2,
+            network_id: 1,
+            head_td: U256::from(17179869184u64),
+            head_hash: H256::from(1),
+            head_number: U256::from(1920000),
+            genesis_hash: H256::from(2),
+            serve_headers: true,
+            serve_chain_since: Some(U256::zero()),
+            serve_state_since: None,
+            tx_relay: true,
+            flow_control: Some(FlowControl {
+                buffer_limit: 300000000,
+                minimum_recharge: 50000,
+                request_costs: vec![(0x02, 150000, 30000), (0x0f, 0, 700000)],
+            }),
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let messages = vec![
+            LESMessage::Status(status()),
+            LESMessage::Announce {
+                head_hash: H256::from(1), head_number: U256::from(2),
+                head_td: U256::from(3), reorg_depth: 0,
+            },
+            LESMessage::GetBlockHeaders {
+                request_id: 1, start: HashOrNumber::Number(U256::from(1920000)), max_headers: 192, skip: 0, reverse: false,
+            },
+            LESMessage::GetBlockHeaders {
+                request_id: 2, start: HashOrNumber::Hash(H256::from(1)), max_headers: 1, skip: 0, reverse: true,
+            },
+            LESMessage::BlockHeaders { request_id: 2, buffer_value: 1000, headers: vec![sample_header()] },
+            LESMessage::GetContractCodes { request_id: 3, requests: vec![(H256::from(1), H256::from(2))] },
+            LESMessage::ContractCodes { request_id: 3, buffer_value: 1000, codes: vec![vec![0x60, 0x00]] },
+            LESMessage::GetProofs {
+                request_id: 4,
+                requests: vec![ProofRequest {
+                    block_hash: H256::from(1), account_key: H256::from(2), key: Vec::new(), from_level: 0,
+                }],
+            },
+            LESMessage::Proofs { request_id: 4, buffer_value: 1000, nodes: vec![vec![0xc0]] },
+        ];
+
+        for message in messages {
+            let encoded = rlp::encode(&message);
+            assert_eq!(LESMessage::decode(&UntrustedRlp::new(&encoded), message.id()).unwrap(), message);
+        }
+    }
+
+    #[test]
+    fn status_key_values() {
+        let encoded = rlp::encode(&LESMessage::Status(status()));
+        let rlp = UntrustedRlp::new(&encoded);
+        let keys: Vec<String> = rlp.iter().map(|pair| pair.val_at(0).unwrap()).collect();
+        assert_eq!(keys, vec!["protocolVersion", "networkId", "headTd", "headHash", "headNum",
+                              "genesisHash", "serveHeaders", "serveChainSince", "txRelay",
+                              "flowControl/BL", "flowControl/MRR", "flowControl/MRC"]);
+    }
+}
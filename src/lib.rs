@@ -13,10 +13,63 @@ extern crate secp256k1;
 extern crate bigint;
 extern crate rlp;
 extern crate block;
+extern crate trie;
+extern crate bloom;
 extern crate rand;
+extern crate hexutil;
+extern crate sha3;
+extern crate serde_json;
+#[cfg(feature = "serialize")]
+extern crate serde;
+#[cfg(feature = "serialize")]
+#[macro_use]
+extern crate serde_derive;
 
 mod raw;
+mod capability;
 mod eth;
+mod snap;
+mod les;
+mod par;
+mod shh;
+mod wit;
+mod nodekey;
+mod error;
+mod reputation;
+mod nodedb;
+mod nodefile;
+mod nat;
+pub mod bootnodes;
 
-pub use raw::{DevP2PStream, DevP2PConfig};
-pub use eth::{ETHStream, ETHSendMessage, ETHReceiveMessage, ETHMessage};
+pub use raw::{DevP2PStream, DevP2PConfig, DevP2PBuilder};
+pub use capability::Capability;
+pub use eth::{ETHStream, ChainConfig, ETHSendMessage, ETHReceiveMessage, ETHMessage, HashOrNumber,
+              SUPPORTED_ETH_VERSIONS, ForkId, ForkFilter, ForkIdError, ForkSchedule, TransactionEnvelope, BlockBody, Header, Receipt, ReceiptOutcome,
+              DecodeLimits, DEFAULT_MAX_MESSAGE_SIZE, DEFAULT_MAX_DEPTH,
+              MAX_BLOCK_ITEMS, MAX_ANNOUNCED_ITEMS, max_items,
+              DuplicateBlockPolicy, HeaderProvider, ChainDataProvider, ServeLimits,
+              DEFAULT_MAX_SERVED_HEADERS, DEFAULT_MAX_SERVED_BODIES,
+              HeaderDownloader, Checkpoint, DEFAULT_HEADER_BATCH, DEFAULT_HEADER_RETRIES,
+              Fetcher, BlockData, BodyFetcher, ReceiptFetcher, DEFAULT_FETCH_BATCH, DEFAULT_FETCH_RETRY,
+              HeadTracker, NetworkHead, TxPool,
+              PeerInfo, PeerStatus, LatencyStats, REQUEST_ID_VERSION, is_request_id_wrapped,
+              wrap_request_id, unwrap_request_id, decode_rlpx_message};
+pub use eth::ids as eth_ids;
+pub use snap::{SNAPStream, SNAPSendMessage, SNAPReceiveMessage, SNAPMessage,
+               SnapProvider, SOFT_RESPONSE_LIMIT, SnapSync, SnapSyncEvent, AccountRange,
+               DEFAULT_RESPONSE_BYTES};
+pub use les::{LESStream, LESSendMessage, LESReceiveMessage, LESMessage, LESStatus,
+              FlowControl, ProofRequest};
+pub use par::{PARStream, PARSendMessage, PARReceiveMessage, PARMessage, ManifestData,
+              SnapshotProvider};
+pub use shh::{SHHStream, SHHSendMessage, SHHReceiveMessage, SHHMessage, Envelope,
+              full_bloom, topic_bloom, bloom_matches};
+pub use wit::{WITStream, WITSendMessage, WITReceiveMessage, WITMessage};
+pub use nodekey::{load_node_key, generate_and_save_node_key};
+pub use nodefile::load_nodes;
+pub use nat::{PortMapper, ExternalEndpoint, Gateway, UpnpGateway, MappingProtocol,
+              PORT_MAPPING_LIFETIME};
+pub use nodedb::{NodeDatabase, NodeRecord, MAX_NODE_FAILURES, NODE_DATABASE_SAVE_INTERVAL};
+pub use error::{DevP2PError, Error};
+pub use reputation::{Reputation, ReputationEvent, ReputationAction, MAX_REPUTATION,
+                     DEFAULT_DISCONNECT_SCORE, DEFAULT_BAN_SCORE, DEFAULT_BAN_DURATION};
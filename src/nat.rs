@@ -0,0 +1,488 @@
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, TcpStream, UdpSocket};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Sender, RecvTimeoutError};
+use std::thread;
+use std::time::Duration;
+
+/// Lifetime requested for port mappings, in seconds. Mappings are
+/// renewed after half of it.
+pub const PORT_MAPPING_LIFETIME: u64 = 7200;
+/// Delay before retrying to map the ports after a failure, in seconds
+const PORT_MAPPING_RETRY: u64 = 60;
+/// Port NAT-PMP gateways listen on
+const NATPMP_PORT: u16 = 5351;
+/// Number of NAT-PMP requests sent before giving up, each waiting
+/// twice as long as the one before, starting at 250ms
+const NATPMP_TRIES: u32 = 4;
+/// Time UPnP gateways are waited for to answer the search
+const UPNP_SEARCH_TIMEOUT: u64 = 2;
+/// Time UPnP gateways are waited for to answer an HTTP request
+const UPNP_HTTP_TIMEOUT: u64 = 5;
+const SSDP_ADDRESS: &str = "239.255.255.250:1900";
+const IGD_DEVICE: &str = "urn:schemas-upnp-org:device:InternetGatewayDevice:1";
+const IGD_SERVICES: [&str; 2] = ["urn:schemas-upnp-org:service:WANIPConnection:1",
+                                 "urn:schemas-upnp-org:service:WANPPPConnection:1"];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Transport protocol of a port mapping
+pub enum MappingProtocol {
+    Tcp,
+    Udp,
+}
+
+impl MappingProtocol {
+    fn name(&self) -> &'static str {
+        match *self {
+            MappingProtocol::Tcp => "TCP",
+            MappingProtocol::Udp => "UDP",
+        }
+    }
+
+    fn natpmp_opcode(&self) -> u8 {
+        match *self {
+            MappingProtocol::Udp => 1,
+            MappingProtocol::Tcp => 2,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// UPnP Internet gateway device, reached through the control URL of
+/// its WAN connection service
+pub struct UpnpGateway {
+    /// Gateway address and port of the control URL
+    pub host: String,
+    /// Path of the control URL
+    pub control_path: String,
+    /// Type of the WAN connection service
+    pub service: String,
+    /// Our address on the gateway's network, which mappings point to
+    pub local_address: IpAddr,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// Router able to forward ports to us
+pub enum Gateway {
+    Upnp(UpnpGateway),
+    NatPmp(SocketAddr),
+}
+
+impl Gateway {
+    /// Find the gateway of the local network, trying UPnP first and
+    /// then NAT-PMP on the default route
+    pub fn discover() -> Result<Gateway, io::Error> {
+        match upnp_discover() {
+            Ok(gateway) => return Ok(Gateway::Upnp(gateway)),
+            Err(e) => debug!("no UPnP gateway found: {}", e),
+        }
+        let address = default_gateway()?;
+        let gateway = SocketAddr::new(IpAddr::V4(address), NATPMP_PORT);
+        natpmp_external_address(&gateway)?;
+        Ok(Gateway::NatPmp(gateway))
+    }
+
+    /// Public address of the gateway
+    pub fn external_address(&self) -> Result<IpAddr, io::Error> {
+        match *self {
+            Gateway::Upnp(ref gateway) => gateway.external_address(),
+            Gateway::NatPmp(ref gateway) => natpmp_external_address(gateway).map(IpAddr::V4),
+        }
+    }
+
+    /// Forward an external port to an internal port of ours, returning
+    /// the external port the gateway chose, which may differ from the
+    /// requested one
+    pub fn add_mapping(&self, protocol: MappingProtocol, internal_port: u16, external_port: u16,
+                       lifetime: Duration) -> Result<u16, io::Error> {
+        match *self {
+            Gateway::Upnp(ref gateway) => {
+                gateway.add_mapping(protocol, internal_port, external_port, lifetime)?;
+                Ok(external_port)
+            },
+            Gateway::NatPmp(ref gateway) =>
+                natpmp_map(gateway, protocol, internal_port, external_port, lifetime.as_secs() as u32),
+        }
+    }
+
+    /// Remove a mapping added by `add_mapping`
+    pub fn remove_mapping(&self, protocol: MappingProtocol, internal_port: u16,
+                          external_port: u16) -> Result<(), io::Error> {
+        match *self {
+            Gateway::Upnp(ref gateway) => gateway.remove_mapping(protocol, external_port),
+            Gateway::NatPmp(ref gateway) => natpmp_map(gateway, protocol, internal_port, 0, 0).map(|_| ()),
+        }
+    }
+}
+
+impl UpnpGateway {
+    /// Public address of the gateway
+    pub fn external_address(&self) -> Result<IpAddr, io::Error> {
+        let response = self.soap_request("GetExternalIPAddress", "")?;
+        xml_value(&response, "NewExternalIPAddress")
+            .and_then(|address| address.trim().parse().ok())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "invalid UPnP external address"))
+    }
+
+    /// Forward an external port to the same or another internal port
+    pub fn add_mapping(&self, protocol: MappingProtocol, internal_port: u16, external_port: u16,
+                       lifetime: Duration) -> Result<(), io::Error> {
+        let arguments = format!(
+            "<NewRemoteHost></NewRemoteHost><NewExternalPort>{}</NewExternalPort>\
+             <NewProtocol>{}</NewProtocol><NewInternalPort>{}</NewInternalPort>\
+             <NewInternalClient>{}</NewInternalClient><NewEnabled>1</NewEnabled>\
+             <NewPortMappingDescription>devp2p-rs</NewPortMappingDescription>\
+             <NewLeaseDuration>{}</NewLeaseDuration>",
+            external_port, protocol.name(), internal_port, self.local_address, lifetime.as_secs());
+        self.soap_request("AddPortMapping", &arguments).map(|_| ())
+    }
+
+    /// Remove the mapping of an external port
+    pub fn remove_mapping(&self, protocol: MappingProtocol, external_port: u16) -> Result<(), io::Error> {
+        let arguments = format!(
+            "<NewRemoteHost></NewRemoteHost><NewExternalPort>{}</NewExternalPort>\
+             <NewProtocol>{}</NewProtocol>", external_port, protocol.name());
+        self.soap_request("DeletePortMapping", &arguments).map(|_| ())
+    }
+
+    fn soap_request(&self, action: &str, arguments: &str) -> Result<String, io::Error> {
+        let body = format!(
+            "<?xml version=\"1.0\"?><s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" \
+             s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\"><s:Body>\
+             <u:{action} xmlns:u=\"{service}\">{arguments}</u:{action}></s:Body></s:Envelope>",
+            action = action, service = self.service, arguments = arguments);
+        let headers = format!("Content-Type: text/xml; charset=\"utf-8\"\r\nSOAPAction: \"{}#{}\"\r\n",
+                              self.service, action);
+        http_request(&self.host, "POST", &self.control_path, &headers, &body).map(|(_, body)| body)
+    }
+}
+
+/// Search the local network for a UPnP Internet gateway device
+pub fn upnp_discover() -> Result<UpnpGateway, io::Error> {
+    let socket = UdpSocket::bind("0.0.0.0:0")?;
+    socket.set_read_timeout(Some(Duration::from_secs(UPNP_SEARCH_TIMEOUT)))?;
+    let search = format!("M-SEARCH * HTTP/1.1\r\nHOST: {}\r\nST: {}\r\nMAN: \"ssdp:discover\"\r\nMX: {}\r\n\r\n",
+                         SSDP_ADDRESS, IGD_DEVICE, UPNP_SEARCH_TIMEOUT);
+    socket.send_to(search.as_bytes(), SSDP_ADDRESS)?;
+
+    let mut buf = [0u8; 2048];
+    loop {
+        let (len, _) = socket.recv_from(&mut buf)?;
+        let location = match ssdp_location(&String::from_utf8_lossy(&buf[..len])) {
+            Some(location) => location,
+            None => continue,
+        };
+        match upnp_gateway(&location) {
+            Ok(gateway) => return Ok(gateway),
+            Err(e) => debug!("ignoring UPnP device at {}: {}", location, e),
+        }
+    }
+}
+
+/// Read the description of a UPnP device to find its WAN connection
+/// service
+fn upnp_gateway(location: &str) -> Result<UpnpGateway, io::Error> {
+    let invalid = |message| io::Error::new(io::ErrorKind::InvalidData, message);
+    let (host, path) = split_url(location).ok_or_else(|| invalid("invalid UPnP location"))?;
+    let (local_address, description) = http_request(&host, "GET", &path, "", "")?;
+    let (service, control_url) = control_url(&description).ok_or_else(|| invalid("no UPnP WAN connection service"))?;
+    let (host, control_path) = if control_url.starts_with("http://") {
+        split_url(&control_url).ok_or_else(|| invalid("invalid UPnP control URL"))?
+    } else if control_url.starts_with('/') {
+        (host, control_url)
+    } else {
+        (host, format!("/{}", control_url))
+    };
+    Ok(UpnpGateway { host, control_path, service, local_address })
+}
+
+/// Location of the device description in an SSDP response
+fn ssdp_location(response: &str) -> Option<String> {
+    response.lines().find(|line| line.to_lowercase().starts_with("location:"))
+        .map(|line| line["location:".len()..].trim().to_string())
+}
+
+/// Type and control URL of the first WAN connection service in a
+/// device description
+fn control_url(description: &str) -> Option<(String, String)> {
+    description.split("<service>").skip(1).filter_map(|service| {
+        let service_type = xml_value(service, "serviceType")?;
+        if IGD_SERVICES.contains(&service_type.trim()) {
+            Some((service_type.trim().to_string(), xml_value(service, "controlURL")?.trim().to_string()))
+        } else {
+            None
+        }
+    }).next()
+}
+
+/// Content of the first element with the given name
+fn xml_value<'a>(xml: &'a str, name: &str) -> Option<&'a str> {
+    let start = xml.find(&format!("<{}>", name))? + name.len() + 2;
+    let end = xml[start..].find(&format!("</{}>", name))? + start;
+    Some(&xml[start..end])
+}
+
+/// Host with port and path of an HTTP URL
+fn split_url(url: &str) -> Option<(String, String)> {
+    if !url.starts_with("http://") {
+        return None;
+    }
+    let rest = &url["http://".len()..];
+    let (host, path) = match rest.find('/') {
+        Some(index) => (&rest[..index], &rest[index..]),
+        None => (rest, "/"),
+    };
+    let host = if host.contains(':') { host.to_string() } else { format!("{}:80", host) };
+    Some((host, path.to_string()))
+}
+
+/// Send an HTTP/1.0 request, returning our address on the connection
+/// and the response body if the status is 200
+fn http_request(host: &str, method: &str, path: &str, headers: &str,
+                body: &str) -> Result<(IpAddr, String), io::Error> {
+    let addr: SocketAddr = host.parse()
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid UPnP host"))?;
+    let mut stream = TcpStream::connect_timeout(&addr, Duration::from_secs(UPNP_HTTP_TIMEOUT))?;
+    stream.set_read_timeout(Some(Duration::from_secs(UPNP_HTTP_TIMEOUT)))?;
+    stream.set_write_timeout(Some(Duration::from_secs(UPNP_HTTP_TIMEOUT)))?;
+    write!(stream, "{} {} HTTP/1.0\r\nHost: {}\r\n{}Content-Length: {}\r\n\r\n{}",
+           method, path, host, headers, body.len(), body)?;
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    let status = response.lines().next().and_then(|line| line.split_whitespace().nth(1));
+    if status != Some("200") {
+        return Err(io::Error::new(io::ErrorKind::Other,
+                                  format!("UPnP request failed: {}", response.lines().next().unwrap_or(""))));
+    }
+    let body = match response.find("\r\n\r\n") {
+        Some(index) => response[index + 4..].to_string(),
+        None => String::new(),
+    };
+    Ok((stream.local_addr()?.ip(), body))
+}
+
+/// Address of the default IPv4 route, read from the Linux routing
+/// table
+pub fn default_gateway() -> Result<Ipv4Addr, io::Error> {
+    let mut table = String::new();
+    File::open("/proc/net/route")?.read_to_string(&mut table)?;
+    parse_default_route(&table)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no default route"))
+}
+
+fn parse_default_route(table: &str) -> Option<Ipv4Addr> {
+    table.lines().skip(1).filter_map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() < 3 || fields[1] != "00000000" {
+            return None;
+        }
+        // The kernel prints the address in host byte order
+        u32::from_str_radix(fields[2], 16).ok().map(|gateway| Ipv4Addr::from(gateway.to_le_bytes()))
+    }).find(|gateway| !gateway.is_unspecified())
+}
+
+/// Send a NAT-PMP request, retrying with a doubling timeout, and
+/// return the response once its result code is success
+fn natpmp_request(gateway: &SocketAddr, request: &[u8], len: usize) -> Result<Vec<u8>, io::Error> {
+    let socket = UdpSocket::bind(if gateway.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" })?;
+    socket.connect(gateway)?;
+    let mut timeout = Duration::from_millis(250);
+    let mut buf = [0u8; 16];
+    for _ in 0..NATPMP_TRIES {
+        socket.send(request)?;
+        socket.set_read_timeout(Some(timeout))?;
+        match socket.recv(&mut buf) {
+            Ok(received) if received >= len && buf[0] == 0 && buf[1] == request[1] + 128 => {
+                let result = ((buf[2] as u16) << 8) | buf[3] as u16;
+                if result != 0 {
+                    return Err(io::Error::new(io::ErrorKind::Other,
+                                              format!("NAT-PMP request failed with result {}", result)));
+                }
+                return Ok(buf[..len].to_vec());
+            },
+            Ok(_) => (),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => (),
+            Err(e) => return Err(e),
+        }
+        timeout *= 2;
+    }
+    Err(io::Error::new(io::ErrorKind::TimedOut, "NAT-PMP gateway did not answer"))
+}
+
+/// Public address of a NAT-PMP gateway
+pub fn natpmp_external_address(gateway: &SocketAddr) -> Result<Ipv4Addr, io::Error> {
+    let response = natpmp_request(gateway, &[0, 0], 12)?;
+    Ok(Ipv4Addr::new(response[8], response[9], response[10], response[11]))
+}
+
+/// Ask a NAT-PMP gateway to map a port for `lifetime` seconds,
+/// returning the external port it chose. A lifetime of 0 with an
+/// external port of 0 removes the mapping.
+pub fn natpmp_map(gateway: &SocketAddr, protocol: MappingProtocol, internal_port: u16,
+                  external_port: u16, lifetime: u32) -> Result<u16, io::Error> {
+    let request = [
+        0, protocol.natpmp_opcode(), 0, 0,
+        (internal_port >> 8) as u8, internal_port as u8,
+        (external_port >> 8) as u8, external_port as u8,
+        (lifetime >> 24) as u8, (lifetime >> 16) as u8, (lifetime >> 8) as u8, lifetime as u8,
+    ];
+    let response = natpmp_request(gateway, &request, 16)?;
+    Ok(((response[10] as u16) << 8) | response[11] as u16)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Public endpoint under which the gateway forwards our ports, with a
+/// port of 0 for a port that is not mapped
+pub struct ExternalEndpoint {
+    pub address: IpAddr,
+    pub tcp_port: u16,
+    pub udp_port: u16,
+}
+
+/// Keeps the TCP listen port and the UDP discovery port mapped on the
+/// gateway of the local network, from a background thread that
+/// renews the mappings, and removes them when dropped
+pub struct PortMapper {
+    endpoint: Arc<Mutex<Option<ExternalEndpoint>>>,
+    stop: Sender<()>,
+}
+
+impl PortMapper {
+    /// Start mapping the given ports, asking for the same external
+    /// ports. `None` leaves a protocol unmapped.
+    pub fn spawn(tcp_port: Option<u16>, udp_port: Option<u16>) -> PortMapper {
+        let endpoint = Arc::new(Mutex::new(None));
+        let (stop, stopped) = channel();
+        let shared = endpoint.clone();
+        thread::spawn(move || {
+            let mut mapped: Option<(Gateway, ExternalEndpoint)> = None;
+            loop {
+                let result = match mapped {
+                    Some((ref gateway, _)) => map_ports(gateway, tcp_port, udp_port),
+                    None => Gateway::discover().and_then(|gateway| {
+                        let endpoint = map_ports(&gateway, tcp_port, udp_port)?;
+                        info!("mapped ports on {:?} to {:?}", gateway, endpoint);
+                        mapped = Some((gateway, endpoint));
+                        Ok(endpoint)
+                    }),
+                };
+                let wait = match result {
+                    Ok(endpoint) => {
+                        if let Some((_, ref mut current)) = mapped {
+                            *current = endpoint;
+                        }
+                        *shared.lock().unwrap() = Some(endpoint);
+                        PORT_MAPPING_LIFETIME / 2
+                    },
+                    Err(e) => {
+                        error!("port mapping failed: {}", e);
+                        mapped = None;
+                        *shared.lock().unwrap() = None;
+                        PORT_MAPPING_RETRY
+                    },
+                };
+                match stopped.recv_timeout(Duration::from_secs(wait)) {
+                    Err(RecvTimeoutError::Timeout) => (),
+                    _ => break,
+                }
+            }
+            if let Some((gateway, endpoint)) = mapped {
+                let removed = tcp_port.map_or(Ok(()), |port| {
+                    gateway.remove_mapping(MappingProtocol::Tcp, port, endpoint.tcp_port)
+                }).and_then(|_| udp_port.map_or(Ok(()), |port| {
+                    gateway.remove_mapping(MappingProtocol::Udp, port, endpoint.udp_port)
+                }));
+                if let Err(e) = removed {
+                    debug!("removing port mappings failed: {}", e);
+                }
+            }
+        });
+        PortMapper { endpoint, stop }
+    }
+
+    /// Public endpoint of the mapped ports, `None` until they are
+    /// mapped or when mapping failed
+    pub fn external_endpoint(&self) -> Option<ExternalEndpoint> {
+        *self.endpoint.lock().unwrap()
+    }
+}
+
+impl Drop for PortMapper {
+    fn drop(&mut self) {
+        let _ = self.stop.send(());
+    }
+}
+
+fn map_ports(gateway: &Gateway, tcp_port: Option<u16>, udp_port: Option<u16>) -> Result<ExternalEndpoint, io::Error> {
+    let lifetime = Duration::from_secs(PORT_MAPPING_LIFETIME);
+    let map = |protocol, port: Option<u16>| match port {
+        Some(port) => gateway.add_mapping(protocol, port, port, lifetime),
+        None => Ok(0),
+    };
+    Ok(ExternalEndpoint {
+        address: gateway.external_address()?,
+        tcp_port: map(MappingProtocol::Tcp, tcp_port)?,
+        udp_port: map(MappingProtocol::Udp, udp_port)?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MappingProtocol, natpmp_map, natpmp_external_address, parse_default_route,
+                ssdp_location, control_url, split_url};
+    use std::net::{Ipv4Addr, UdpSocket};
+    use std::thread;
+
+    #[test]
+    fn natpmp_requests() {
+        let gateway = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = gateway.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let mut buf = [0u8; 16];
+            let (len, from) = gateway.recv_from(&mut buf).unwrap();
+            assert_eq!(&buf[..len], &[0, 0]);
+            gateway.send_to(&[0, 128, 0, 0, 0, 0, 0, 1, 203, 0, 113, 7], from).unwrap();
+
+            let (len, from) = gateway.recv_from(&mut buf).unwrap();
+            assert_eq!(&buf[..len], &[0, 2, 0, 0, 0x76, 0x5f, 0x76, 0x5f, 0, 0, 0x1c, 0x20]);
+            gateway.send_to(&[0, 130, 0, 0, 0, 0, 0, 2, 0x76, 0x5f, 0x76, 0x60, 0, 0, 0x1c, 0x20], from).unwrap();
+
+            let (_, from) = gateway.recv_from(&mut buf).unwrap();
+            gateway.send_to(&[0, 129, 0, 3, 0, 0, 0, 3, 0, 0, 0, 0, 0, 0, 0, 0], from).unwrap();
+        });
+
+        assert_eq!(natpmp_external_address(&addr).unwrap(), Ipv4Addr::new(203, 0, 113, 7));
+        assert_eq!(natpmp_map(&addr, MappingProtocol::Tcp, 30303, 30303, 7200).unwrap(), 30304);
+        assert!(natpmp_map(&addr, MappingProtocol::Udp, 30303, 30303, 7200).is_err());
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn parses_upnp_responses() {
+        let response = "HTTP/1.1 200 OK\r\nCACHE-CONTROL: max-age=120\r\nLocation: http://192.168.1.1:5000/rootDesc.xml\r\n\r\n";
+        assert_eq!(ssdp_location(response), Some("http://192.168.1.1:5000/rootDesc.xml".to_string()));
+        assert_eq!(split_url("http://192.168.1.1:5000/rootDesc.xml"),
+                   Some(("192.168.1.1:5000".to_string(), "/rootDesc.xml".to_string())));
+        assert_eq!(split_url("http://192.168.1.1"), Some(("192.168.1.1:80".to_string(), "/".to_string())));
+
+        let description = "<root><device><serviceList>\
+            <service><serviceType>urn:schemas-upnp-org:service:Layer3Forwarding:1</serviceType>\
+            <controlURL>/ctl/L3F</controlURL></service>\
+            <service><serviceType>urn:schemas-upnp-org:service:WANIPConnection:1</serviceType>\
+            <controlURL>/ctl/IPConn</controlURL></service></serviceList></device></root>";
+        assert_eq!(control_url(description), Some(("urn:schemas-upnp-org:service:WANIPConnection:1".to_string(),
+                                                   "/ctl/IPConn".to_string())));
+        assert_eq!(control_url("<root></root>"), None);
+    }
+
+    #[test]
+    fn parses_default_route() {
+        let table = "Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\n\
+                     eth0\t0000A8C0\t00000000\t0001\t0\t0\t0\t00FFFFFF\n\
+                     eth0\t00000000\t0101A8C0\t0003\t0\t0\t0\t00000000\n";
+        assert_eq!(parse_default_route(table), Some(Ipv4Addr::new(192, 168, 1, 1)));
+        assert_eq!(parse_default_route("Iface\tDestination\tGateway\n"), None);
+    }
+}
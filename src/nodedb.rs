@@ -0,0 +1,236 @@
+use dpt::DPTNode;
+use bigint::H512;
+use hexutil::{read_hex, to_hex, clean_0x};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Dials in a row without a connection after which a node is no longer
+/// dialed from the database, nor saved
+pub const MAX_NODE_FAILURES: u32 = 5;
+/// Least time between two saves of a changed node database, in seconds
+pub const NODE_DATABASE_SAVE_INTERVAL: u64 = 60;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// Node remembered by the node database
+pub struct NodeRecord {
+    pub node: DPTNode,
+    /// Seconds since the Unix epoch when we last connected the node, 0
+    /// if we never did
+    pub last_seen: u64,
+    /// Dials since we last connected the node
+    pub fail_count: u32,
+}
+
+/// Nodes we knew about, saved to a file so that a restarted node can
+/// dial them right away. The file has one node per line, as its id in
+/// hex, address, TCP and UDP ports, last seen time and fail count.
+pub struct NodeDatabase {
+    path: PathBuf,
+    nodes: HashMap<H512, NodeRecord>,
+    changed: bool,
+    saved: Instant,
+}
+
+impl NodeDatabase {
+    /// Load the database from a file, starting empty if it does not
+    /// exist
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<NodeDatabase, io::Error> {
+        let mut database = NodeDatabase {
+            path: path.as_ref().to_path_buf(),
+            nodes: HashMap::new(),
+            changed: false,
+            saved: Instant::now(),
+        };
+        let mut file = match File::open(path.as_ref()) {
+            Ok(file) => file,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(database),
+            Err(e) => return Err(e),
+        };
+
+        let mut content = String::new();
+        file.read_to_string(&mut content)?;
+        for line in content.lines().filter(|line| !line.trim().is_empty()) {
+            let record = parse_record(line).ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidData, "invalid node database entry")
+            })?;
+            database.nodes.insert(record.node.id, record);
+        }
+        Ok(database)
+    }
+
+    /// Record of a node
+    pub fn get(&self, id: &H512) -> Option<&NodeRecord> {
+        self.nodes.get(id)
+    }
+
+    /// Number of nodes
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    /// Whether there is no node
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    /// Up to `count` nodes worth dialing, most recently seen first
+    pub fn best_nodes(&self, count: usize) -> Vec<DPTNode> {
+        let mut records: Vec<&NodeRecord> = self.nodes.values()
+            .filter(|record| record.fail_count < MAX_NODE_FAILURES)
+            .collect();
+        records.sort_by(|a, b| b.last_seen.cmp(&a.last_seen).then(a.fail_count.cmp(&b.fail_count)));
+        records.into_iter().take(count).map(|record| record.node.clone()).collect()
+    }
+
+    /// Remember a node we are dialing, counting the dial as failed
+    /// until the node connects
+    pub fn dialed(&mut self, node: &DPTNode) {
+        let record = self.nodes.entry(node.id).or_insert_with(|| NodeRecord {
+            node: node.clone(), last_seen: 0, fail_count: 0,
+        });
+        record.node = node.clone();
+        record.fail_count += 1;
+        self.changed = true;
+    }
+
+    /// Mark a node as connected now, remembering it with the given
+    /// endpoints if it is new
+    pub fn connected(&mut self, id: H512, node: Option<DPTNode>) {
+        let now = unix_time();
+        match (self.nodes.get_mut(&id), node) {
+            (Some(record), _) => {
+                record.last_seen = now;
+                record.fail_count = 0;
+            },
+            (None, Some(node)) => {
+                self.nodes.insert(id, NodeRecord { node, last_seen: now, fail_count: 0 });
+            },
+            (None, None) => return,
+        }
+        self.changed = true;
+    }
+
+    /// Forget a node
+    pub fn remove(&mut self, id: &H512) {
+        if self.nodes.remove(id).is_some() {
+            self.changed = true;
+        }
+    }
+
+    /// Write the database to its file, leaving out nodes that failed
+    /// too often
+    pub fn save(&mut self) -> Result<(), io::Error> {
+        let mut content = String::new();
+        for record in self.nodes.values().filter(|record| record.fail_count < MAX_NODE_FAILURES) {
+            content.push_str(&format_record(record));
+            content.push('\n');
+        }
+
+        // Write to a temporary file first so that a crash never leaves
+        // a truncated database
+        let mut temp = self.path.clone().into_os_string();
+        temp.push(".tmp");
+        File::create(&temp)?.write_all(content.as_bytes())?;
+        fs::rename(&temp, &self.path)?;
+        self.changed = false;
+        self.saved = Instant::now();
+        Ok(())
+    }
+
+    /// Save the database if it changed and was not saved for the given
+    /// time
+    pub fn save_if_due(&mut self, interval: Duration) -> Result<(), io::Error> {
+        if self.changed && self.saved.elapsed() >= interval {
+            self.save()
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Seconds since the Unix epoch
+pub(crate) fn unix_time() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_secs()).unwrap_or(0)
+}
+
+fn format_record(record: &NodeRecord) -> String {
+    format!("{} {} {} {} {} {}", clean_0x(&to_hex(&record.node.id)), record.node.address,
+            record.node.tcp_port, record.node.udp_port, record.last_seen, record.fail_count)
+}
+
+fn parse_record(line: &str) -> Option<NodeRecord> {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    if fields.len() != 6 {
+        return None;
+    }
+    let id = read_hex(fields[0]).ok()?;
+    if id.len() != 64 {
+        return None;
+    }
+    Some(NodeRecord {
+        node: DPTNode {
+            id: H512::from(&id[..]),
+            address: fields[1].parse().ok()?,
+            tcp_port: fields[2].parse().ok()?,
+            udp_port: fields[3].parse().ok()?,
+        },
+        last_seen: fields[4].parse().ok()?,
+        fail_count: fields[5].parse().ok()?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{NodeDatabase, MAX_NODE_FAILURES};
+    use dpt::DPTNode;
+    use bigint::H512;
+    use rand::{thread_rng, Rng};
+    use std::env;
+    use std::fs;
+    use std::io::Write;
+    use std::path::PathBuf;
+
+    fn temp_path() -> PathBuf {
+        env::temp_dir().join(format!("devp2p-nodes-{}", thread_rng().gen::<u64>()))
+    }
+
+    fn node(address: &str) -> DPTNode {
+        DPTNode { address: address.parse().unwrap(), tcp_port: 30303, udp_port: 30301, id: H512::random() }
+    }
+
+    #[test]
+    fn save_and_load() {
+        let path = temp_path();
+        let mut database = NodeDatabase::open(&path).unwrap();
+        assert!(database.is_empty());
+
+        let (seen, dialed, failing) = (node("127.0.0.1"), node("::1"), node("10.0.0.1"));
+        database.dialed(&seen);
+        database.connected(seen.id, None);
+        database.dialed(&dialed);
+        for _ in 0..MAX_NODE_FAILURES {
+            database.dialed(&failing);
+        }
+        assert_eq!(database.best_nodes(5), vec![seen.clone(), dialed.clone()]);
+        assert_eq!(database.best_nodes(1), vec![seen.clone()]);
+        database.save().unwrap();
+
+        let loaded = NodeDatabase::open(&path).unwrap();
+        assert_eq!(loaded.len(), 2);
+        assert_eq!(loaded.get(&seen.id), database.get(&seen.id));
+        assert_eq!(loaded.get(&dialed.id).unwrap().fail_count, 1);
+        assert_eq!(loaded.get(&failing.id), None);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn invalid_entry_rejected() {
+        let path = temp_path();
+        fs::File::create(&path).unwrap().write_all(b"00 127.0.0.1 30303 30301 0 0\n").unwrap();
+        assert!(NodeDatabase::open(&path).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,74 @@
+use dpt::{DPTNode, DPTNodeParseError};
+use serde_json;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+
+/// Load a list of nodes from a file, either a JSON array of
+/// `enode://` URLs like Geth's `static-nodes.json`, or one URL per
+/// line with `#` starting comments. The position of an invalid URL is
+/// given in the error.
+pub fn load_nodes<P: AsRef<Path>>(path: P) -> Result<Vec<DPTNode>, io::Error> {
+    let path = path.as_ref();
+    let mut text = String::new();
+    File::open(path)?.read_to_string(&mut text)?;
+
+    let urls: Vec<(String, String)> = if text.trim_start().starts_with('[') {
+        let urls: Vec<String> = serde_json::from_str(&text).map_err(|e| {
+            io::Error::new(io::ErrorKind::InvalidData,
+                           format!("{}: not a JSON array of enode URLs: {}", path.display(), e))
+        })?;
+        urls.into_iter().enumerate().map(|(i, url)| (format!("entry {}", i), url)).collect()
+    } else {
+        text.lines().enumerate()
+            .map(|(i, line)| (i, line.split('#').next().unwrap().trim()))
+            .filter(|&(_, url)| !url.is_empty())
+            .map(|(i, url)| (format!("line {}", i + 1), url.to_string()))
+            .collect()
+    };
+
+    urls.into_iter().map(|(position, url)| {
+        url.parse().map_err(|e| {
+            let reason = match e {
+                DPTNodeParseError::UrlError => "malformed enode URL",
+                DPTNodeParseError::HexError => "invalid node id",
+            };
+            io::Error::new(io::ErrorKind::InvalidData,
+                           format!("{}, {}: {} {:?}", path.display(), position, reason, url))
+        })
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::load_nodes;
+    use rand::{thread_rng, Rng};
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::{ErrorKind, Write};
+
+    const NODE: &str = "enode://22a8232c3abc76a16ae9d6c3b164f98775fe226f0917b0ca871128a74a8e9630b458460865bab457221f1d448dd9791d24c4e5d88786180ac185df813a68d4de@3.209.45.79:30303";
+
+    fn load(text: &str) -> Result<usize, String> {
+        let path = env::temp_dir().join(format!("devp2p-nodes-{}", thread_rng().gen::<u64>()));
+        File::create(&path).unwrap().write_all(text.as_bytes()).unwrap();
+        let result = load_nodes(&path);
+        fs::remove_file(&path).unwrap();
+        result.map(|nodes| nodes.len()).map_err(|e| {
+            assert_eq!(e.kind(), ErrorKind::InvalidData);
+            e.to_string()
+        })
+    }
+
+    #[test]
+    fn node_files() {
+        assert_eq!(load(&format!("[\"{}\", \"{}\"]", NODE, NODE)), Ok(2));
+        assert_eq!(load(&format!("# mainnet\n{}\n\n{} # us-east\n", NODE, NODE)), Ok(2));
+        assert_eq!(load("[]"), Ok(0));
+
+        assert!(load(&format!("[\"{}\",", NODE)).unwrap_err().contains("not a JSON array"));
+        assert!(load(&format!("[\"{}\", \"enode://00@127.0.0.1:1\"]", NODE)).unwrap_err().contains("entry 1: invalid node id"));
+        assert!(load(&format!("{}\n{}:", NODE, NODE)).unwrap_err().contains("line 2: malformed enode URL"));
+        assert!(load_nodes(env::temp_dir().join("devp2p-no-such-nodes")).is_err());
+    }
+}
@@ -0,0 +1,90 @@
+use secp256k1::SECP256K1;
+use secp256k1::key::SecretKey;
+use hexutil::{read_hex, to_hex, clean_0x};
+use rand::os::OsRng;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+/// Load the node secret key from a Geth-style `nodekey` file holding
+/// the key as 64 hex characters. A fresh key is generated and saved
+/// if the file does not exist.
+pub fn load_node_key<P: AsRef<Path>>(path: P) -> Result<SecretKey, io::Error> {
+    let mut file = match File::open(path.as_ref()) {
+        Ok(file) => file,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+            return generate_and_save_node_key(path);
+        },
+        Err(e) => return Err(e),
+    };
+
+    let mut hex = String::new();
+    file.read_to_string(&mut hex)?;
+    let bytes = match read_hex(hex.trim()) {
+        Ok(bytes) => bytes,
+        Err(_) => return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                            "node key is not valid hex")),
+    };
+    if bytes.len() != 32 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                  "node key must be 32 bytes"));
+    }
+
+    match SecretKey::from_slice(&SECP256K1, &bytes) {
+        Ok(secret_key) => Ok(secret_key),
+        Err(_) => Err(io::Error::new(io::ErrorKind::InvalidData,
+                                     "node key is not a valid secret key")),
+    }
+}
+
+/// Generate a fresh node secret key and save it to a Geth-style
+/// `nodekey` file, overwriting any existing one
+pub fn generate_and_save_node_key<P: AsRef<Path>>(path: P) -> Result<SecretKey, io::Error> {
+    let secret_key = SecretKey::new(&SECP256K1, &mut OsRng::new()?);
+
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+
+    let mut file = options.open(path)?;
+    file.write_all(clean_0x(&to_hex(&secret_key[..])).as_bytes())?;
+    Ok(secret_key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{load_node_key, generate_and_save_node_key};
+    use secp256k1::SECP256K1;
+    use secp256k1::key::PublicKey;
+    use rand::{thread_rng, Rng};
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+
+    fn temp_path() -> PathBuf {
+        env::temp_dir().join(format!("devp2p-nodekey-{}", thread_rng().gen::<u64>()))
+    }
+
+    #[test]
+    fn save_and_load() {
+        let path = temp_path();
+        let secret_key = generate_and_save_node_key(&path).unwrap();
+        assert!(PublicKey::from_secret_key(&SECP256K1, &secret_key).is_ok());
+        assert_eq!(fs::metadata(&path).unwrap().len(), 64);
+        assert_eq!(load_node_key(&path).unwrap(), secret_key);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn load_absent_generates() {
+        let path = temp_path();
+        let secret_key = load_node_key(&path).unwrap();
+        assert!(path.exists());
+        assert_eq!(load_node_key(&path).unwrap(), secret_key);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,339 @@
+mod proto;
+mod provider;
+
+use futures::{StartSend, Async, Poll, Stream, Sink, AsyncSink};
+use rlp::{self, UntrustedRlp};
+use bigint::{H256, H512, U256};
+use rlpx::{RLPxSendMessage, RLPxReceiveMessage, RLPxNode, CapabilityInfo, DisconnectReason, PeerInfo,
+           PeerTable, NodeSigner};
+use tokio_core::reactor::Handle;
+use std::io;
+use std::sync::Arc;
+use std::net::{IpAddr, SocketAddr};
+
+use super::{DevP2PStream, DevP2PBuilder, ChainConfig, Error};
+
+pub use self::proto::{PARMessage, ManifestData};
+pub use self::provider::SnapshotProvider;
+use self::provider::serve_par;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// Receiving message of PAR
+pub enum PARReceiveMessage {
+    Connected {
+        node: H512,
+        version: usize,
+    },
+    /// A peer went away, see `RLPxReceiveMessage::Disconnected`
+    Disconnected {
+        node: H512,
+        reason: Option<DisconnectReason>,
+        clean: bool,
+    },
+    Normal {
+        node: H512,
+        version: usize,
+        data: PARMessage,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// Sending message of PAR
+pub struct PARSendMessage {
+    pub node: RLPxNode,
+    pub data: PARMessage,
+}
+
+/// Represent a PAR (warp sync) stream over DevP2P protocol,
+/// advertising the par/1 capability. Only the Status and snapshot
+/// messages are understood; the eth/63 messages `par` also carries are
+/// ignored, so use it alongside an `ETHStream` for block sync.
+pub struct PARStream {
+    stream: DevP2PStream,
+    genesis_hash: H256,
+    best_hash: H256,
+    total_difficulty: U256,
+    network_id: usize,
+    snapshot_hash: H256,
+    snapshot_number: u64,
+    provider: Option<Box<dyn SnapshotProvider>>,
+}
+
+impl PARStream {
+    /// Create a new PAR stream on the network set up by `network`
+    pub fn new(addr: &SocketAddr, public_addr: &IpAddr,
+               handle: &Handle, signer: Arc<dyn NodeSigner>,
+               chain: ChainConfig, network: DevP2PBuilder,
+    ) -> Result<Self, io::Error> {
+        Ok(PARStream {
+            stream: network.capability(CapabilityInfo { name: "par", version: 1, length: 0x15 })
+                .build(addr, public_addr, handle, signer)?,
+            genesis_hash: chain.genesis_hash,
+            best_hash: chain.best_hash,
+            total_difficulty: chain.total_difficulty,
+            network_id: chain.network_id,
+            snapshot_hash: H256::zero(),
+            snapshot_number: 0,
+            provider: None,
+        })
+    }
+
+    /// Force disconnecting a peer if it is already connected or about
+    /// to be connected, telling a connected peer the reason in a
+    /// Disconnect message
+    pub fn disconnect_peer(&mut self, remote_id: H512, reason: DisconnectReason) {
+        self.stream.disconnect_peer(remote_id, reason);
+    }
+
+    /// Active peers
+    pub fn active_peers(&self) -> &[H512] {
+        self.stream.active_peers()
+    }
+
+    /// Table of the connected peers, see `DevP2PStream::peer_table`
+    pub fn peer_table(&self) -> PeerTable {
+        self.stream.peer_table()
+    }
+
+    /// Summary of a connected peer, see `DevP2PStream::peer_info`
+    pub fn peer_info(&self, remote_id: H512) -> Option<PeerInfo> {
+        self.stream.peer_info(remote_id)
+    }
+
+    /// Summaries of all connected peers
+    pub fn peers(&self) -> Vec<PeerInfo> {
+        self.stream.peers()
+    }
+
+    /// Update the best block announced in the Status sent to new peers
+    pub fn set_best(&mut self, best_hash: H256, total_difficulty: U256) {
+        self.best_hash = best_hash;
+        self.total_difficulty = total_difficulty;
+    }
+
+    /// Answer manifest and chunk requests automatically from the given
+    /// provider, announcing its snapshot, identified by the hash of its
+    /// manifest, in the Status sent to new peers. Requests answered
+    /// this way are not returned by the stream.
+    pub fn set_snapshot_provider(
+        &mut self, provider: Box<dyn SnapshotProvider>, snapshot_hash: H256, snapshot_number: u64
+    ) {
+        self.provider = Some(provider);
+        self.snapshot_hash = snapshot_hash;
+        self.snapshot_number = snapshot_number;
+    }
+
+    /// Our Status message for the given PAR version
+    fn status(&self, version: usize) -> PARMessage {
+        PARMessage::Status {
+            protocol_version: version,
+            network_id: self.network_id,
+            total_difficulty: self.total_difficulty,
+            best_hash: self.best_hash,
+            genesis_hash: self.genesis_hash,
+            snapshot_hash: self.snapshot_hash,
+            snapshot_number: self.snapshot_number,
+        }
+    }
+}
+
+impl Stream for PARStream {
+    type Item = PARReceiveMessage;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        let result = match try_ready!(self.stream.poll()) {
+            Some(result) => result,
+            None => return Ok(Async::Ready(None)),
+        };
+
+        match result {
+            RLPxReceiveMessage::Connected { node, capabilities } => {
+                let version = match capabilities.iter().find(|cap| cap.name == "par") {
+                    Some(cap) => cap.version,
+                    None => {
+                        debug!("connected a node without matching capability, ignoring.");
+                        return self.poll();
+                    },
+                };
+
+                let status = self.status(version);
+                self.start_send(PARSendMessage {
+                    node: RLPxNode::Peer(node),
+                    data: status,
+                })?;
+                self.poll_complete()?;
+
+                Ok(Async::Ready(Some(PARReceiveMessage::Connected {
+                    node, version
+                })))
+            },
+            RLPxReceiveMessage::Disconnected { node, reason, clean } => {
+                Ok(Async::Ready(Some(PARReceiveMessage::Disconnected {
+                    node, reason, clean
+                })))
+            },
+            RLPxReceiveMessage::Normal {
+                node, capability, id, data,
+            } => {
+                debug!("got par message with id {}", id);
+                let message = match PARMessage::decode(&UntrustedRlp::new(&data), id) {
+                    Ok(val) => val,
+                    Err(_) => {
+                        debug!("got an ununderstandable message with id {}, data {:?}, ignoring.", id, data);
+                        return self.poll();
+                    },
+                };
+                let response = match self.provider {
+                    Some(ref provider) => serve_par(&**provider, &message),
+                    None => None,
+                };
+                if let Some(response) = response {
+                    debug!("answering par request from {:x}", node);
+                    self.start_send(PARSendMessage {
+                        node: RLPxNode::Peer(node),
+                        data: response,
+                    })?;
+                    self.poll_complete()?;
+                    return self.poll();
+                }
+                Ok(Async::Ready(Some(PARReceiveMessage::Normal {
+                    node, version: capability.version,
+                    data: message,
+                })))
+            },
+        }
+    }
+}
+
+impl Sink for PARStream {
+    type SinkItem = PARSendMessage;
+    type SinkError = Error;
+
+    fn start_send(&mut self, val: PARSendMessage) -> StartSend<Self::SinkItem, Self::SinkError> {
+        match self.stream.start_send(RLPxSendMessage {
+            node: val.node,
+            capability_name: "par",
+            id: val.data.id(),
+            data: rlp::encode(&val.data).to_vec(),
+        })? {
+            AsyncSink::Ready => Ok(AsyncSink::Ready),
+            AsyncSink::NotReady(_) => Ok(AsyncSink::NotReady(val)),
+        }
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
+        self.stream.poll_complete()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{PARStream, PARMessage, PARReceiveMessage, ManifestData, SnapshotProvider};
+    use super::super::{DevP2PConfig, DevP2PBuilder, ChainConfig};
+    use bigint::{H256, H512, U256};
+    use rlp::UntrustedRlp;
+    use rlpx::{PeerStream, CapabilityInfo};
+    use secp256k1::SECP256K1;
+    use secp256k1::key::{PublicKey, SecretKey};
+    use tokio_core::reactor::Core;
+    use futures::{future, Async, Poll, Future, Stream, Sink};
+    use rand::os::OsRng;
+    use std::io;
+    use std::net::TcpListener;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    fn pk2id(key: &PublicKey) -> H512 {
+        H512::from(&key.serialize_vec(&SECP256K1, false)[1..65])
+    }
+
+    struct MockProvider;
+
+    impl SnapshotProvider for MockProvider {
+        fn manifest(&self) -> Option<ManifestData> {
+            None
+        }
+
+        fn chunk(&self, hash: H256) -> Option<Vec<u8>> {
+            if hash == H256::from(1) { Some(vec![0x42; 16]) } else { None }
+        }
+    }
+
+    /// Read the next par message received by the peer
+    fn next_message(peer: PeerStream) -> Box<dyn Future<Item = (PARMessage, PeerStream), Error = io::Error>> {
+        Box::new(peer.into_future().map_err(|(e, _)| e).map(|(message, peer)| {
+            let (cap, id, data) = message.unwrap();
+            assert_eq!(cap.name, "par");
+            (PARMessage::decode(&UntrustedRlp::new(&data), id).unwrap(), peer)
+        }))
+    }
+
+    #[test]
+    fn announces_and_serves_snapshot() {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let config = DevP2PConfig {
+     ping_interval: Duration::new(600, 0),
+     ping_timeout_interval: Duration::new(700, 0),
+     optimal_peers_interval: Duration::new(600, 0),
+     max_peers_per_ip: None,
+     ..Default::default()
+        };
+
+        let addr = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let secret_key = SecretKey::new(&SECP256K1, &mut OsRng::new().unwrap());
+        let id = pk2id(&PublicKey::from_secret_key(&SECP256K1, &secret_key).unwrap());
+        let chain = ChainConfig {
+            network_id: 1,
+            genesis_hash: H256::from(1),
+            best_hash: H256::from(2),
+            total_difficulty: U256::from(3),
+        };
+        let mut par = PARStream::new(&addr, &addr.ip(), &handle, Arc::new(secret_key), chain,
+                                     DevP2PBuilder::from(config).client_version("par".to_string())).unwrap();
+        par.set_snapshot_provider(Box::new(MockProvider), H256::from(4), 5000);
+
+        let peer_key = SecretKey::new(&SECP256K1, &mut OsRng::new().unwrap());
+        let peer = PeerStream::connect(
+            &addr, &handle, Arc::new(peer_key), id,
+            5, "peer".to_string(), vec![CapabilityInfo { name: "par", version: 1, length: 0x15 }],
+            0, Default::default());
+        let request = ::rlp::encode(&PARMessage::GetSnapshotData(H256::from(1))).to_vec();
+        let peer = peer
+            .and_then(next_message)
+            .and_then(|(status, peer)| {
+                match status {
+                    PARMessage::Status { snapshot_hash, snapshot_number, .. } => {
+                        assert_eq!(snapshot_hash, H256::from(4));
+                        assert_eq!(snapshot_number, 5000);
+                    },
+                    _ => panic!(),
+                }
+                peer.send(("par", 0x13, request))
+            })
+            .and_then(next_message)
+            .map(|(response, _)| response);
+
+        let mut connected = None;
+        let messages = future::poll_fn(|| -> Poll<(), io::Error> {
+            // The request is answered without being returned
+            while connected.is_none() {
+                connected = try_ready!(par.poll());
+            }
+            match par.poll()? {
+                Async::Ready(message) => panic!("unexpected message {:?}", message),
+                Async::NotReady => Ok(Async::NotReady),
+            }
+        });
+        let response = match core.run(peer.select2(messages)).ok().unwrap() {
+            future::Either::A((response, _)) => response,
+            _ => panic!(),
+        };
+        assert_eq!(response, PARMessage::SnapshotData(vec![0x42; 16]));
+        match connected {
+            Some(PARReceiveMessage::Connected { version, .. }) => assert_eq!(version, 1),
+            _ => panic!(),
+        }
+    }
+}
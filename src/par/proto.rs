@@ -0,0 +1,212 @@
+use rlp::{Encodable, Decodable, RlpStream, DecoderError, UntrustedRlp};
+use bigint::{H256, U256};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// Manifest of a warp snapshot, listing the hashes of its chunks
+pub struct ManifestData {
+    /// Snapshot format version, 1 for manifests without one
+    pub version: u64,
+    pub state_hashes: Vec<H256>,
+    pub block_hashes: Vec<H256>,
+    pub state_root: H256,
+    pub block_number: u64,
+    pub block_hash: H256,
+}
+
+impl Encodable for ManifestData {
+    fn rlp_append(&self, s: &mut RlpStream) {
+        s.begin_list(6);
+        s.append(&self.version);
+        s.append_list(&self.state_hashes);
+        s.append_list(&self.block_hashes);
+        s.append(&self.state_root);
+        s.append(&self.block_number);
+        s.append(&self.block_hash);
+    }
+}
+
+impl Decodable for ManifestData {
+    fn decode(rlp: &UntrustedRlp) -> Result<Self, DecoderError> {
+        // Version 1 manifests do not start with their version
+        let (version, start) = if rlp.item_count()? == 5 { (1, 0) } else { (rlp.val_at(0)?, 1) };
+        Ok(ManifestData {
+            version,
+            state_hashes: rlp.list_at(start)?,
+            block_hashes: rlp.list_at(start + 1)?,
+            state_root: rlp.val_at(start + 2)?,
+            block_number: rlp.val_at(start + 3)?,
+            block_hash: rlp.val_at(start + 4)?,
+        })
+    }
+}
+
+/// PAR message version 1, the warp sync messages of the Parity
+/// protocol. The message ids follow the ones of eth/63, which `par`
+/// extends.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PARMessage {
+    /// Status with the hash and block number of the latest snapshot
+    /// the peer serves, zero if none
+    Status {
+        protocol_version: usize,
+        network_id: usize,
+        total_difficulty: U256,
+        best_hash: H256,
+        genesis_hash: H256,
+        snapshot_hash: H256,
+        snapshot_number: u64,
+    },
+    GetSnapshotManifest,
+    /// `None` if the peer has no snapshot to serve
+    SnapshotManifest(Option<ManifestData>),
+    GetSnapshotData(H256),
+    /// Compressed chunk, empty if the peer does not have it
+    SnapshotData(Vec<u8>),
+}
+
+impl PARMessage {
+    /// Get the message id of the PAR message
+    pub fn id(&self) -> usize {
+        match *self {
+            PARMessage::Status { .. } => 0x00,
+            PARMessage::GetSnapshotManifest => 0x11,
+            PARMessage::SnapshotManifest(_) => 0x12,
+            PARMessage::GetSnapshotData(_) => 0x13,
+            PARMessage::SnapshotData(_) => 0x14,
+        }
+    }
+
+    /// Decode a RLP into PAR message using the given message id
+    pub fn decode(rlp: &UntrustedRlp, id: usize) -> Result<Self, DecoderError> {
+        Ok(match id {
+            0x00 => {
+                PARMessage::Status {
+                    protocol_version: rlp.val_at(0)?,
+                    network_id: rlp.val_at(1)?,
+                    total_difficulty: rlp.val_at(2)?,
+                    best_hash: rlp.val_at(3)?,
+                    genesis_hash: rlp.val_at(4)?,
+                    snapshot_hash: rlp.val_at(5)?,
+                    snapshot_number: rlp.val_at(6)?,
+                }
+            },
+            0x11 => PARMessage::GetSnapshotManifest,
+            0x12 => {
+                if rlp.item_count()? == 0 {
+                    PARMessage::SnapshotManifest(None)
+                } else {
+                    PARMessage::SnapshotManifest(Some(rlp.val_at(0)?))
+                }
+            },
+            0x13 => PARMessage::GetSnapshotData(rlp.val_at(0)?),
+            0x14 => {
+                if rlp.item_count()? == 0 {
+                    PARMessage::SnapshotData(Vec::new())
+                } else {
+                    PARMessage::SnapshotData(rlp.val_at(0)?)
+                }
+            },
+            _ => return Err(DecoderError::Custom("unsupported par message id")),
+        })
+    }
+}
+
+impl Encodable for PARMessage {
+    fn rlp_append(&self, s: &mut RlpStream) {
+        match *self {
+            PARMessage::Status {
+                protocol_version, network_id, total_difficulty, best_hash,
+                genesis_hash, snapshot_hash, snapshot_number
+            } => {
+                s.begin_list(7);
+                s.append(&protocol_version);
+                s.append(&network_id);
+                s.append(&total_difficulty);
+                s.append(&best_hash);
+                s.append(&genesis_hash);
+                s.append(&snapshot_hash);
+                s.append(&snapshot_number);
+            },
+            PARMessage::GetSnapshotManifest => {
+                s.begin_list(0);
+            },
+            PARMessage::SnapshotManifest(ref manifest) => {
+                match *manifest {
+                    Some(ref manifest) => {
+                        s.begin_list(1);
+                        s.append(manifest);
+                    },
+                    None => {
+                        s.begin_list(0);
+                    },
+                }
+            },
+            PARMessage::GetSnapshotData(hash) => {
+                s.begin_list(1);
+                s.append(&hash);
+            },
+            PARMessage::SnapshotData(ref data) => {
+                if data.is_empty() {
+                    s.begin_list(0);
+                } else {
+                    s.begin_list(1);
+                    s.append(data);
+                }
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{PARMessage, ManifestData};
+    use rlp::{self, RlpStream, UntrustedRlp};
+    use bigint::{H256, U256};
+
+    fn manifest() -> ManifestData {
+        ManifestData {
+            version: 2,
+            state_hashes: vec![H256::from(1), H256::from(2)],
+            block_hashes: vec![H256::from(3)],
+            state_root: H256::from(4),
+            block_number: 5000000,
+            block_hash: H256::from(5),
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let messages = vec![
+            PARMessage::Status {
+                protocol_version: 1, network_id: 1, total_difficulty: U256::from(100),
+                best_hash: H256::from(1), genesis_hash: H256::from(2),
+                snapshot_hash: H256::from(3), snapshot_number: 5000000,
+            },
+            PARMessage::GetSnapshotManifest,
+            PARMessage::SnapshotManifest(Some(manifest())),
+            PARMessage::SnapshotManifest(None),
+            PARMessage::GetSnapshotData(H256::from(1)),
+            PARMessage::SnapshotData(vec![0x01, 0x02, 0x03]),
+            PARMessage::SnapshotData(Vec::new()),
+        ];
+
+        for message in messages {
+            let encoded = rlp::encode(&message);
+            assert_eq!(PARMessage::decode(&UntrustedRlp::new(&encoded), message.id()).unwrap(), message);
+        }
+    }
+
+    #[test]
+    fn version_one_manifest() {
+        let manifest = manifest();
+        let mut s = RlpStream::new_list(5);
+        s.append_list(&manifest.state_hashes);
+        s.append_list(&manifest.block_hashes);
+        s.append(&manifest.state_root);
+        s.append(&manifest.block_number);
+        s.append(&manifest.block_hash);
+
+        let decoded: ManifestData = UntrustedRlp::new(&s.out()).as_val().unwrap();
+        assert_eq!(decoded, ManifestData { version: 1, ..manifest });
+    }
+}
@@ -0,0 +1,24 @@
+use bigint::H256;
+use super::{PARMessage, ManifestData};
+
+/// Source of the warp snapshot used to answer par requests
+/// automatically
+pub trait SnapshotProvider {
+    /// Manifest of the snapshot served, if any
+    fn manifest(&self) -> Option<ManifestData>;
+
+    /// Compressed chunk with the given hash
+    fn chunk(&self, hash: H256) -> Option<Vec<u8>>;
+}
+
+/// Build the response to a par request, or `None` if the message is
+/// not a request
+pub fn serve_par<P: SnapshotProvider + ?Sized>(provider: &P, message: &PARMessage) -> Option<PARMessage> {
+    match *message {
+        PARMessage::GetSnapshotManifest =>
+            Some(PARMessage::SnapshotManifest(provider.manifest())),
+        PARMessage::GetSnapshotData(hash) =>
+            Some(PARMessage::SnapshotData(provider.chunk(hash).unwrap_or_default())),
+        _ => None,
+    }
+}
@@ -1,15 +1,41 @@
-use dpt::{DPTNode, DPTStream, DPTMessage};
-use rlpx::{RLPxSendMessage, RLPxReceiveMessage, CapabilityInfo, RLPxStream};
+use dpt::{DPTNode, DPTStream, Discv5Stream, Discovery, DPTMessage, LookupStatus, Enr,
+          DnsDiscovery, DnsResolver, EnrTreeUrl};
+use rlpx::{RLPxSendMessage, RLPxReceiveMessage, RLPxNode, CapabilityInfo, CapabilityOffsets,
+           RLPxStream, SocketBuffers, DisconnectReason, NetRestrict, PeerInfo, PeerTable, NodeSigner};
 use tokio_core::reactor::{Handle, Timeout};
 use std::time::Duration;
 use std::net::{IpAddr, SocketAddr};
 use std::cmp::min;
 use std::io;
-use secp256k1::key::SecretKey;
+use std::sync::Arc;
+use std::path::PathBuf;
 use futures::{StartSend, Async, Poll, Stream, Sink, Future, future};
 use bigint::H512;
 use rand::{thread_rng, Rng};
 
+use super::{Capability, Error};
+use reputation::{Reputation, ReputationEvent, ReputationAction};
+use nodedb::{NodeDatabase, NODE_DATABASE_SAVE_INTERVAL};
+use nodefile::load_nodes;
+use nat::{PortMapper, ExternalEndpoint};
+
+/// Default interval between discovery pings, in seconds
+const DEFAULT_PING_INTERVAL: u64 = 15;
+/// Default time discovery pings are waited for, in seconds
+const DEFAULT_PING_TIMEOUT: u64 = 20;
+/// Default number of peers to keep connected
+const DEFAULT_OPTIMAL_PEERS: usize = 25;
+/// Default interval between checks for missing peers, in seconds
+const DEFAULT_OPTIMAL_PEERS_INTERVAL: u64 = 5;
+/// Default divisor of the known nodes dialed at once
+const DEFAULT_RECONNECT_DIVIDEND: usize = 5;
+/// Default number of peers that may connect from the same address
+const DEFAULT_MAX_PEERS_PER_IP: usize = 2;
+/// Delay before redialing a static peer that went away, in seconds
+const STATIC_PEER_MIN_BACKOFF: u64 = 2;
+/// Longest delay between redials of a static peer, in seconds
+const STATIC_PEER_MAX_BACKOFF: u64 = 300;
+
 /// Config for DevP2P
 pub struct DevP2PConfig {
     pub ping_interval: Duration,
@@ -17,12 +43,369 @@ pub struct DevP2PConfig {
     pub optimal_peers_len: usize,
     pub optimal_peers_interval: Duration,
     pub reconnect_dividend: usize,
+    /// Accept incoming connections. Without it the node only dials
+    /// out: no TCP port is bound, and we announce a TCP port of 0 in
+    /// the Hello and in discovery so that nobody dials us.
     pub listen: bool,
+    /// Find peers with discovery. Without it no UDP socket is opened,
+    /// bootstrap nodes are ignored, and only static peers and peers
+    /// dialing us are connected.
+    pub discovery: bool,
+    /// Find peers with discovery v5 instead of the DPT, which is
+    /// discovery v4. Nodes only speaking discovery v4, like most
+    /// bootstrap nodes of older networks, are not found then.
+    pub discovery_v5: bool,
+    /// UDP address discovery runs on, `None` for the address RLPx
+    /// listens on
+    pub discovery_addr: Option<SocketAddr>,
+    /// TCP receive buffer size for RLPx connections, `None` for the
+    /// OS default. The OS may clamp the value.
+    pub tcp_recv_buffer: Option<usize>,
+    /// TCP send buffer size for RLPx connections, `None` for the OS
+    /// default. The OS may clamp the value.
+    pub tcp_send_buffer: Option<usize>,
+    /// Disconnect peers that sent no ETH message for this long, even
+    /// if they still answer pings. `None` disables reaping. Checked
+    /// whenever the stream is polled.
+    pub idle_reap_interval: Option<Duration>,
+    /// Maximum number of peers that connected to us, `None` for no
+    /// limit. Peers beyond it are disconnected with `TooManyPeers`.
+    pub max_inbound_peers: Option<usize>,
+    /// Maximum number of peers we connect to, `None` for no limit
+    pub max_outbound_peers: Option<usize>,
+    /// Maximum number of peers connected from the same address, so
+    /// that a single host cannot take all peer slots with many node
+    /// keys. `None` for no limit. Trusted peers are not counted.
+    pub max_peers_per_ip: Option<usize>,
+    /// Addresses peers may have, applied to discovered nodes and to
+    /// dialed and accepted connections. `None` allows any address.
+    pub net_restrict: Option<NetRestrict>,
+    /// Map the listen and discovery ports on the gateway of the local
+    /// network with UPnP or NAT-PMP, and announce the mapped endpoint
+    /// in discovery
+    pub map_ports: bool,
+    /// Address and port announced in discovery and the Hello instead
+    /// of the public address given to the stream and the listen port,
+    /// like the address of the NAT or container host forwarding to us.
+    /// The port replaces the discovery port too unless
+    /// `discovery_addr` is set.
+    pub advertised_addr: Option<SocketAddr>,
+    /// Learn our public address from the pongs of discovery peers and
+    /// announce it once enough of them agree. Ignored when
+    /// `advertised_addr` is set.
+    pub detect_external_address: bool,
+}
+
+impl Default for DevP2PConfig {
+    /// Values typical of mainnet nodes, listening for connections
+    fn default() -> Self {
+        DevP2PConfig {
+            ping_interval: Duration::from_secs(DEFAULT_PING_INTERVAL),
+            ping_timeout_interval: Duration::from_secs(DEFAULT_PING_TIMEOUT),
+            optimal_peers_len: DEFAULT_OPTIMAL_PEERS,
+            optimal_peers_interval: Duration::from_secs(DEFAULT_OPTIMAL_PEERS_INTERVAL),
+            reconnect_dividend: DEFAULT_RECONNECT_DIVIDEND,
+            listen: true,
+            discovery: true,
+            discovery_v5: false,
+            discovery_addr: None,
+            tcp_recv_buffer: None,
+            tcp_send_buffer: None,
+            idle_reap_interval: None,
+            max_inbound_peers: None,
+            max_outbound_peers: None,
+            max_peers_per_ip: Some(DEFAULT_MAX_PEERS_PER_IP),
+            net_restrict: None,
+            map_ports: false,
+            advertised_addr: None,
+            detect_external_address: false,
+        }
+    }
+}
+
+/// Builder of a `DevP2PStream`, starting from the default config, the
+/// RLPx protocol version 4, no capability, no bootstrap, static or
+/// trusted node, no node database or ban list and this crate as client
+/// version
+pub struct DevP2PBuilder {
+    config: DevP2PConfig,
+    protocol_version: usize,
+    client_version: String,
+    capabilities: Vec<CapabilityInfo>,
+    bootstrap_nodes: Vec<DPTNode>,
+    static_peers: Vec<DPTNode>,
+    trusted_peers: Vec<H512>,
+    node_database: Option<NodeDatabase>,
+    ban_list: Option<PathBuf>,
+    dns_trees: Vec<EnrTreeUrl>,
+    bootstrap_nodes_file: Option<PathBuf>,
+    static_peers_file: Option<PathBuf>,
+}
+
+impl Default for DevP2PBuilder {
+    fn default() -> Self {
+        DevP2PBuilder::new()
+    }
+}
+
+impl From<DevP2PConfig> for DevP2PBuilder {
+    /// Builder starting from the given config instead of the defaults
+    fn from(config: DevP2PConfig) -> Self {
+        DevP2PBuilder { config, ..DevP2PBuilder::new() }
+    }
+}
+
+impl DevP2PBuilder {
+    /// Create a builder with the defaults
+    pub fn new() -> Self {
+        DevP2PBuilder {
+            config: DevP2PConfig::default(),
+            protocol_version: 4,
+            client_version: concat!("devp2p-rs/", env!("CARGO_PKG_VERSION")).to_string(),
+            capabilities: Vec::new(),
+            bootstrap_nodes: Vec::new(),
+            static_peers: Vec::new(),
+            trusted_peers: Vec::new(),
+            node_database: None,
+            ban_list: None,
+            dns_trees: Vec::new(),
+            bootstrap_nodes_file: None,
+            static_peers_file: None,
+        }
+    }
+
+    /// Interval between discovery pings, and how long they are waited
+    /// for
+    pub fn ping_intervals(mut self, interval: Duration, timeout: Duration) -> Self {
+        self.config.ping_interval = interval;
+        self.config.ping_timeout_interval = timeout;
+        self
+    }
+
+    /// Number of peers to keep connected, and how often missing ones
+    /// are dialed
+    pub fn optimal_peers(mut self, len: usize, interval: Duration) -> Self {
+        self.config.optimal_peers_len = len;
+        self.config.optimal_peers_interval = interval;
+        self
+    }
+
+    /// Divisor of the known nodes dialed at once when peers are missing
+    pub fn reconnect_dividend(mut self, dividend: usize) -> Self {
+        self.config.reconnect_dividend = dividend;
+        self
+    }
+
+    /// Whether to accept incoming connections, see
+    /// `DevP2PConfig::listen`
+    pub fn listen(mut self, listen: bool) -> Self {
+        self.config.listen = listen;
+        self
+    }
+
+    /// TCP receive and send buffer sizes of RLPx connections
+    pub fn tcp_buffers(mut self, recv: Option<usize>, send: Option<usize>) -> Self {
+        self.config.tcp_recv_buffer = recv;
+        self.config.tcp_send_buffer = send;
+        self
+    }
+
+    /// See `DevP2PConfig::idle_reap_interval`
+    pub fn idle_reap_interval(mut self, interval: Option<Duration>) -> Self {
+        self.config.idle_reap_interval = interval;
+        self
+    }
+
+    /// Maximum numbers of inbound and outbound peers
+    pub fn max_peers(mut self, inbound: Option<usize>, outbound: Option<usize>) -> Self {
+        self.config.max_inbound_peers = inbound;
+        self.config.max_outbound_peers = outbound;
+        self
+    }
+
+    /// Maximum number of peers connected from the same address
+    pub fn max_peers_per_ip(mut self, max: Option<usize>) -> Self {
+        self.config.max_peers_per_ip = max;
+        self
+    }
+
+    /// Map the ports on the local gateway, see
+    /// `DevP2PConfig::map_ports`
+    pub fn map_ports(mut self, map_ports: bool) -> Self {
+        self.config.map_ports = map_ports;
+        self
+    }
+
+    /// Whether to find peers with discovery, see
+    /// `DevP2PConfig::discovery`
+    pub fn discovery(mut self, discovery: bool) -> Self {
+        self.config.discovery = discovery;
+        self
+    }
+
+    /// Whether to find peers with discovery v5, see
+    /// `DevP2PConfig::discovery_v5`
+    pub fn discovery_v5(mut self, discovery_v5: bool) -> Self {
+        self.config.discovery_v5 = discovery_v5;
+        self
+    }
+
+    /// UDP address to run discovery on, see
+    /// `DevP2PConfig::discovery_addr`
+    pub fn discovery_addr(mut self, addr: SocketAddr) -> Self {
+        self.config.discovery_addr = Some(addr);
+        self
+    }
+
+    /// Address and port to announce, see
+    /// `DevP2PConfig::advertised_addr`
+    pub fn advertised_addr(mut self, addr: SocketAddr) -> Self {
+        self.config.advertised_addr = Some(addr);
+        self
+    }
+
+    /// Learn our public address from discovery, see
+    /// `DevP2PConfig::detect_external_address`
+    pub fn detect_external_address(mut self, detect: bool) -> Self {
+        self.config.detect_external_address = detect;
+        self
+    }
+
+    /// Addresses peers may have, see `DevP2PConfig::net_restrict`
+    pub fn net_restrict(mut self, restrict: NetRestrict) -> Self {
+        self.config.net_restrict = Some(restrict);
+        self
+    }
+
+    /// RLPx protocol version advertised in the Hello
+    pub fn protocol_version(mut self, version: usize) -> Self {
+        self.protocol_version = version;
+        self
+    }
+
+    /// Client version advertised in the Hello
+    pub fn client_version(mut self, client_version: String) -> Self {
+        self.client_version = client_version;
+        self
+    }
+
+    /// Advertise a capability, whose messages the stream returns
+    pub fn capability(mut self, capability: CapabilityInfo) -> Self {
+        self.capabilities.push(capability);
+        self
+    }
+
+    /// Nodes discovery starts from
+    pub fn bootstrap_nodes(mut self, nodes: Vec<DPTNode>) -> Self {
+        self.bootstrap_nodes = nodes;
+        self
+    }
+
+    /// Peers to keep connected, see `DevP2PStream::add_static_peer`
+    pub fn static_peers(mut self, nodes: Vec<DPTNode>) -> Self {
+        self.static_peers = nodes;
+        self
+    }
+
+    /// File of more nodes discovery starts from, loaded when building,
+    /// see `load_nodes` for the formats
+    pub fn bootstrap_nodes_file(mut self, path: PathBuf) -> Self {
+        self.bootstrap_nodes_file = Some(path);
+        self
+    }
+
+    /// File of more peers to keep connected, loaded when building, see
+    /// `load_nodes` for the formats
+    pub fn static_peers_file(mut self, path: PathBuf) -> Self {
+        self.static_peers_file = Some(path);
+        self
+    }
+
+    /// Peers exempt from peer limits, see
+    /// `DevP2PStream::add_trusted_peer`
+    pub fn trusted_peers(mut self, ids: Vec<H512>) -> Self {
+        self.trusted_peers = ids;
+        self
+    }
+
+    /// Nodes known from previous runs, which discovery starts from
+    /// next to the bootstrap nodes, see
+    /// `DevP2PStream::set_node_database`
+    pub fn node_database(mut self, database: NodeDatabase) -> Self {
+        self.node_database = Some(database);
+        self
+    }
+
+    /// File the bans are loaded from and saved to, see
+    /// `DevP2PStream::set_ban_list`
+    pub fn ban_list(mut self, path: PathBuf) -> Self {
+        self.ban_list = Some(path);
+        self
+    }
+
+    /// Node trees resolved over DNS with the system's name server,
+    /// see `DevP2PStream::add_dns_discovery`
+    pub fn dns_trees(mut self, urls: Vec<EnrTreeUrl>) -> Self {
+        self.dns_trees = urls;
+        self
+    }
+
+    /// Config built so far
+    pub fn config(&self) -> &DevP2PConfig {
+        &self.config
+    }
+
+    /// Create the stream, listening on `addr`, and discovering on it as
+    /// well unless a discovery address is set
+    pub fn build(self, addr: &SocketAddr, public_addr: &IpAddr,
+                 handle: &Handle, signer: Arc<dyn NodeSigner>) -> Result<DevP2PStream, io::Error> {
+        let mut bootstrap_nodes = self.bootstrap_nodes;
+        if let Some(ref path) = self.bootstrap_nodes_file {
+            bootstrap_nodes.extend(load_nodes(path)?);
+        }
+        let mut static_peers = self.static_peers;
+        if let Some(ref path) = self.static_peers_file {
+            static_peers.extend(load_nodes(path)?);
+        }
+        if let Some(ref database) = self.node_database {
+            bootstrap_nodes.extend(database.best_nodes(self.config.optimal_peers_len));
+        }
+        let mut stream = DevP2PStream::new(addr, public_addr, handle, signer,
+                                           self.protocol_version, self.client_version,
+                                           self.capabilities, bootstrap_nodes, self.config)?;
+        for id in self.trusted_peers {
+            stream.add_trusted_peer(id);
+        }
+        if let Some(path) = self.ban_list {
+            stream.set_ban_list(path)?;
+        }
+        for node in static_peers {
+            stream.add_static_peer(node);
+        }
+        if let Some(database) = self.node_database {
+            stream.set_node_database(database);
+        }
+        for url in self.dns_trees {
+            let resolver = DnsResolver::system(handle)?;
+            stream.add_dns_discovery(DnsDiscovery::new(url, Box::new(resolver)));
+        }
+        Ok(stream)
+    }
+}
+
+/// Peer kept connected regardless of discovery
+struct StaticPeer {
+    node: DPTNode,
+    /// Delay before the next redial
+    backoff: Duration,
+    /// Pending redial, once the peer went away
+    redial: Option<Timeout>,
 }
 
 /// An Ethereum DevP2P stream that handles peers management
 pub struct DevP2PStream {
-    dpt: DPTStream,
+    dpt: Option<Discovery>,
+    dns_discovery: Vec<DnsDiscovery>,
     rlpx: RLPxStream,
 
     ping_timeout: Timeout,
@@ -30,12 +413,22 @@ pub struct DevP2PStream {
     handle: Handle,
 
     config: DevP2PConfig,
+    capabilities: Vec<Box<dyn Capability>>,
+    max_message_size: Option<usize>,
+    static_peers: Vec<StaticPeer>,
+    reputation: Reputation,
+    node_database: Option<NodeDatabase>,
+    ban_list: Option<PathBuf>,
+    port_mapper: Option<PortMapper>,
+    external_endpoint: Option<ExternalEndpoint>,
 }
 
 impl DevP2PStream {
-    /// Create a new DevP2P stream
+    /// Create a new DevP2P stream, listening on `addr` and discovering
+    /// on it as well unless `config.discovery_addr` is set or
+    /// `config.discovery` is off
     pub fn new(addr: &SocketAddr, public_addr: &IpAddr,
-               handle: &Handle, secret_key: SecretKey,
+               handle: &Handle, signer: Arc<dyn NodeSigner>,
                protocol_version: usize, client_version: String,
                capabilities: Vec<CapabilityInfo>,
                bootstrap_nodes: Vec<DPTNode>,
@@ -43,7 +436,7 @@ impl DevP2PStream {
     ) -> Result<Self, io::Error> {
         let port = addr.port();
 
-        let mut rlpx = RLPxStream::new(handle, secret_key.clone(),
+        let mut rlpx = RLPxStream::new_with_signer(handle, signer.clone(),
                                        protocol_version, client_version,
                                        capabilities,
                                        if config.listen {
@@ -51,9 +444,45 @@ impl DevP2PStream {
                                        } else {
                                            None
                                        })?;
+        rlpx.set_socket_buffers(SocketBuffers {
+            recv: config.tcp_recv_buffer,
+            send: config.tcp_send_buffer,
+        });
+        rlpx.set_peer_limits(config.max_inbound_peers, config.max_outbound_peers);
+        rlpx.set_max_peers_per_ip(config.max_peers_per_ip);
+        rlpx.set_net_restrict(config.net_restrict.clone());
 
-        let dpt = DPTStream::new(addr, handle, secret_key.clone(),
-                                 bootstrap_nodes, public_addr, port)?;
+        let discovery_addr = config.discovery_addr.unwrap_or(*addr);
+        let tcp_port = if config.listen { port } else { 0 };
+        let mut dpt = if config.discovery && config.discovery_v5 {
+            Some(Discovery::V5(Discv5Stream::new(&discovery_addr, handle, signer,
+                                                 bootstrap_nodes, public_addr, tcp_port)?))
+        } else if config.discovery {
+            Some(Discovery::V4(DPTStream::new(&discovery_addr, handle, signer,
+                                              bootstrap_nodes, public_addr, tcp_port)?))
+        } else {
+            None
+        };
+        if let Some(advertised) = config.advertised_addr {
+            let udp_port = if config.discovery_addr.is_some() { discovery_addr.port() } else { advertised.port() };
+            let tcp_port = if config.listen { advertised.port() } else { 0 };
+            if let Some(ref mut dpt) = dpt {
+                dpt.set_endpoint(advertised.ip(), udp_port, tcp_port);
+            }
+            rlpx.set_advertised_port(tcp_port);
+        } else if let Some(ref mut dpt) = dpt {
+            dpt.set_address_detection(config.detect_external_address);
+        }
+        if let Some(ref mut dpt) = dpt {
+            dpt.set_net_restrict(config.net_restrict.clone());
+        }
+
+        let port_mapper = if config.map_ports {
+            Some(PortMapper::spawn(if config.listen { Some(port) } else { None },
+                                   if config.discovery { Some(discovery_addr.port()) } else { None }))
+        } else {
+            None
+        };
 
         let ping_timeout = Timeout::new(config.ping_interval, handle)?;
         let optimal_peers_timeout = Timeout::new(config.optimal_peers_interval, handle)?;
@@ -61,36 +490,354 @@ impl DevP2PStream {
         Ok(DevP2PStream {
             dpt, rlpx, ping_timeout,
             optimal_peers_timeout,
+            dns_discovery: Vec::new(),
             config,
-            handle: handle.clone()
+            handle: handle.clone(),
+            capabilities: Vec::new(),
+            max_message_size: None,
+            static_peers: Vec::new(),
+            reputation: Reputation::new(),
+            node_database: None,
+            ban_list: None,
+            port_mapper,
+            external_endpoint: None,
         })
     }
 
     /// Force disconnecting a peer if it is already connected or about
-    /// to be connected. Useful for removing peers on a different hard
-    /// fork network
-    pub fn disconnect_peer(&mut self, remote_id: H512) {
-        self.rlpx.disconnect_peer(remote_id);
-        self.dpt.disconnect_peer(remote_id);
+    /// to be connected, telling a connected peer the reason in a
+    /// Disconnect message. Useful for removing peers on a different
+    /// hard fork network
+    pub fn disconnect_peer(&mut self, remote_id: H512, reason: DisconnectReason) {
+        self.rlpx.disconnect_peer(remote_id, reason);
+        if let Some(ref mut dpt) = self.dpt {
+            dpt.disconnect_peer(remote_id);
+        }
+    }
+
+    /// Dial the nodes of a DNS node tree while it is walked, next to
+    /// the nodes discovery finds. The tree is dropped once walked
+    pub fn add_dns_discovery(&mut self, discovery: DnsDiscovery) {
+        self.dns_discovery.push(discovery);
+    }
+
+    /// Dial a peer now and keep it connected, redialing it with an
+    /// increasing delay whenever it goes away, whether or not discovery
+    /// knows about it
+    pub fn add_static_peer(&mut self, node: DPTNode) {
+        if self.static_peers.iter().any(|peer| peer.node.id == node.id) {
+            return;
+        }
+        dial(&mut self.rlpx, &mut self.node_database, &node);
+        self.static_peers.push(StaticPeer {
+            node,
+            backoff: Duration::from_secs(STATIC_PEER_MIN_BACKOFF),
+            redial: None,
+        });
+    }
+
+    /// Stop redialing a static peer. It stays connected if it is.
+    pub fn remove_static_peer(&mut self, remote_id: H512) {
+        self.static_peers.retain(|peer| peer.node.id != remote_id);
+    }
+
+    /// Ids of the static peers
+    pub fn static_peers(&self) -> Vec<H512> {
+        self.static_peers.iter().map(|peer| peer.node.id).collect()
+    }
+
+    /// Trust a peer, which is then accepted and dialed even beyond the
+    /// peer limits, and never disconnected by the checks of protocol
+    /// streams like `ETHStream`
+    pub fn add_trusted_peer(&mut self, remote_id: H512) {
+        self.rlpx.add_trusted_peer(remote_id);
+    }
+
+    /// Stop trusting a peer
+    pub fn remove_trusted_peer(&mut self, remote_id: H512) {
+        self.rlpx.remove_trusted_peer(remote_id);
+    }
+
+    /// Whether a peer is trusted
+    pub fn is_trusted(&self, remote_id: H512) -> bool {
+        self.rlpx.is_trusted(remote_id)
+    }
+
+    /// Remember the nodes we dial and connect in a node database,
+    /// saved every minute while it changes, and dial its best nodes
+    /// now
+    pub fn set_node_database(&mut self, database: NodeDatabase) {
+        let nodes = database.best_nodes(self.config.optimal_peers_len);
+        self.node_database = Some(database);
+        for node in nodes {
+            dial(&mut self.rlpx, &mut self.node_database, &node);
+        }
+    }
+
+    /// Node database, if one is set
+    pub fn node_database(&self) -> Option<&NodeDatabase> {
+        self.node_database.as_ref()
+    }
+
+    /// Save the node database now, like before shutting down
+    pub fn save_node_database(&mut self) -> Result<(), io::Error> {
+        match self.node_database {
+            Some(ref mut database) => database.save(),
+            None => Ok(()),
+        }
+    }
+
+    /// Reputation of the peers
+    pub fn reputation(&self) -> &Reputation {
+        &self.reputation
+    }
+
+    /// Set the scores at or below which peers are disconnected and
+    /// banned, see `Reputation::set_thresholds`
+    pub fn set_reputation_thresholds(&mut self, disconnect_score: i32, ban_score: i32) {
+        self.reputation.set_thresholds(disconnect_score, ban_score);
+    }
+
+    /// Set how long peers stay banned
+    pub fn set_ban_duration(&mut self, duration: Duration) {
+        self.reputation.set_ban_duration(duration);
+    }
+
+    /// Score a peer for its behaviour, disconnecting or banning it once
+    /// its score is too low. Trusted peers are not scored. Protocol
+    /// streams like `ETHStream` report responses, timeouts and invalid
+    /// messages, and disconnect reasons are reported by this stream.
+    pub fn report_peer(&mut self, remote_id: H512, event: ReputationEvent) {
+        if self.is_trusted(remote_id) {
+            return;
+        }
+        match self.reputation.record(remote_id, event) {
+            ReputationAction::None => (),
+            ReputationAction::Disconnect => {
+                debug!("disconnecting peer {:x} with score {}", remote_id, self.reputation.score(&remote_id));
+                self.disconnect_peer(remote_id, DisconnectReason::UselessPeer);
+            },
+            ReputationAction::Ban => self.ban_peer(remote_id),
+        }
+    }
+
+    /// Disconnect a peer and refuse to connect it again, or to learn
+    /// about it from discovery, for the ban duration
+    pub fn ban_peer(&mut self, remote_id: H512) {
+        debug!("banning peer {:x}", remote_id);
+        self.reputation.ban(remote_id);
+        self.rlpx.ban_peer(remote_id);
+        self.disconnect_peer(remote_id, DisconnectReason::UselessPeer);
+        if let Some(ref mut dpt) = self.dpt {
+            dpt.ban_node(remote_id);
+        }
+        self.save_ban_list();
+    }
+
+    /// Lift the ban of a peer
+    pub fn unban_peer(&mut self, remote_id: H512) {
+        self.reputation.unban(&remote_id);
+        self.rlpx.unban_peer(remote_id);
+        if let Some(ref mut dpt) = self.dpt {
+            dpt.unban_node(remote_id);
+        }
+        self.save_ban_list();
+    }
+
+    /// Refuse connections from an address, and stop dialing or learning
+    /// about nodes at it, for the ban duration. Peers already connected
+    /// from it stay connected.
+    pub fn ban_ip(&mut self, ip: IpAddr) {
+        debug!("banning address {}", ip);
+        self.reputation.ban_ip(ip);
+        self.rlpx.ban_ip(ip);
+        if let Some(ref mut dpt) = self.dpt {
+            dpt.ban_ip(ip);
+        }
+        self.save_ban_list();
+    }
+
+    /// Lift the ban of an address
+    pub fn unban_ip(&mut self, ip: IpAddr) {
+        self.reputation.unban_ip(&ip);
+        self.rlpx.unban_ip(ip);
+        if let Some(ref mut dpt) = self.dpt {
+            dpt.unban_ip(ip);
+        }
+        self.save_ban_list();
+    }
+
+    /// Load the bans saved in a file which did not run out yet, and
+    /// save the bans there whenever they change, so that they survive
+    /// restarts
+    pub fn set_ban_list(&mut self, path: PathBuf) -> Result<(), io::Error> {
+        self.reputation.load_bans(&path)?;
+        for remote_id in self.reputation.banned_peers() {
+            self.rlpx.ban_peer(remote_id);
+            if let Some(ref mut dpt) = self.dpt {
+                dpt.ban_node(remote_id);
+            }
+        }
+        for ip in self.reputation.banned_ips() {
+            self.rlpx.ban_ip(ip);
+            if let Some(ref mut dpt) = self.dpt {
+                dpt.ban_ip(ip);
+            }
+        }
+        self.ban_list = Some(path);
+        Ok(())
+    }
+
+    fn save_ban_list(&self) {
+        if let Some(ref path) = self.ban_list {
+            if let Err(e) = self.reputation.save_bans(path) {
+                error!("failed to save the ban list: {}", e);
+            }
+        }
+    }
+
+    /// Client version advertised in the Hello of new connections
+    pub fn client_version(&self) -> &str {
+        self.rlpx.client_version()
+    }
+
+    /// Change the client version advertised in the Hello. Only
+    /// affects connections established afterwards.
+    pub fn set_client_version(&mut self, client_version: String) {
+        self.rlpx.set_client_version(client_version);
+    }
+
+    /// Advertise a custom capability and handle its messages with it.
+    /// Messages of the capability are no longer returned by the stream
+    /// and only connections established afterwards negotiate it.
+    pub fn register_capability(&mut self, capability: Box<dyn Capability>) {
+        self.rlpx.add_capability(CapabilityInfo {
+            name: capability.name(),
+            version: capability.version(),
+            length: capability.message_count(),
+        });
+        self.capabilities.push(capability);
+    }
+
+    /// Advertise a capability whose messages are returned by the
+    /// stream, for capabilities run on top of another one
+    pub(crate) fn add_capability(&mut self, capability: CapabilityInfo) {
+        self.rlpx.add_capability(capability);
+    }
+
+    /// Drop capability messages whose payload is larger than the given
+    /// size before they are handled or returned. `None` disables the
+    /// limit.
+    pub fn set_max_message_size(&mut self, max: Option<usize>) {
+        self.max_message_size = max;
+    }
+
+    /// Public endpoint of the ports mapped on the local gateway, see
+    /// `DevP2PConfig::map_ports`
+    pub fn external_endpoint(&self) -> Option<ExternalEndpoint> {
+        self.port_mapper.as_ref().and_then(|mapper| mapper.external_endpoint())
+    }
+
+    /// Public address discovery peers agree they see us at, see
+    /// `DevP2PConfig::detect_external_address`
+    pub fn observed_address(&self) -> Option<IpAddr> {
+        self.dpt.as_ref().and_then(|dpt| dpt.observed_address())
+    }
+
+    /// Our node record announced in discovery, `None` without
+    /// discovery
+    pub fn local_record(&self) -> Option<&Enr> {
+        self.dpt.as_ref().map(|dpt| dpt.local_record())
+    }
+
+    /// Outstanding discovery lookups, for debugging
+    pub fn active_lookups(&self) -> Vec<LookupStatus> {
+        self.dpt.as_ref().map(|dpt| dpt.active_lookups()).unwrap_or_default()
     }
 
     /// Active peers
-    pub fn active_peers(&mut self) -> &[H512] {
+    pub fn active_peers(&self) -> &[H512] {
         self.rlpx.active_peers()
     }
 
-    fn poll_dpt_receive_peers(&mut self) -> Poll<(), io::Error> {
+    /// Table of the connected peers, which can be kept to read
+    /// them while the stream is polled
+    pub fn peer_table(&self) -> PeerTable {
+        self.rlpx.peer_table()
+    }
+
+    /// Message id ranges negotiated with a connected peer, telling
+    /// which capabilities it shares with us
+    pub fn capability_offsets(&self, remote_id: H512) -> Option<&CapabilityOffsets> {
+        self.rlpx.capability_offsets(remote_id)
+    }
+
+    /// Client version a connected peer announced in its Hello
+    pub fn peer_client_version(&self, remote_id: H512) -> Option<&str> {
+        self.rlpx.peer_client_version(remote_id)
+    }
+
+    /// Summary of a connected peer, like its client version, address
+    /// and connection direction
+    pub fn peer_info(&self, remote_id: H512) -> Option<PeerInfo> {
+        self.rlpx.peer_info(remote_id)
+    }
+
+    /// Summaries of all connected peers
+    pub fn peers(&self) -> Vec<PeerInfo> {
+        self.rlpx.peers()
+    }
+
+    /// Event loop the stream runs on
+    pub(crate) fn handle(&self) -> &Handle {
+        &self.handle
+    }
+
+    fn poll_dpt_receive_peers(&mut self) -> Poll<(), Error> {
         loop {
-            let node = match self.dpt.poll() {
-                Ok(Async::Ready(Some(node))) => node,
-                Ok(_) => return Ok(Async::Ready(())),
-                Err(e) => return Err(e),
+            let node = match self.dpt.as_mut().map(|dpt| dpt.poll()) {
+                Some(Ok(Async::Ready(Some(node)))) => node,
+                Some(Err(e)) => return Err(Error::Discovery(e)),
+                _ => return Ok(Async::Ready(())),
             };
-            self.rlpx.add_peer(&SocketAddr::new(node.address, node.tcp_port), node.id);
+            self.dial_discovered(&node);
+        }
+    }
+
+    fn poll_dns_discovery(&mut self) -> Poll<(), Error> {
+        let mut i = 0;
+        while i < self.dns_discovery.len() {
+            match self.dns_discovery[i].poll() {
+                Ok(Async::Ready(Some(node))) => self.dial_discovered(&node),
+                Ok(Async::NotReady) => i += 1,
+                Ok(Async::Ready(None)) => {
+                    self.dns_discovery.remove(i);
+                },
+                Err(e) => return Err(Error::Discovery(e)),
+            }
         }
+        Ok(Async::Ready(()))
     }
 
-    fn poll_dpt_request_new_peers(&mut self) -> Poll<(), io::Error> {
+    /// Dial a node found by discovery unless it is banned or at a
+    /// restricted address
+    fn dial_discovered(&mut self, node: &DPTNode) {
+        if self.reputation.is_banned(&node.id) || self.reputation.is_ip_banned(&node.address) {
+            debug!("not dialing banned node {:x}", node.id);
+            return;
+        }
+        if !self.rlpx.is_allowed(&node.address) {
+            debug!("not dialing node {:x} at restricted address {}", node.id, node.address);
+            return;
+        }
+        dial(&mut self.rlpx, &mut self.node_database, node);
+    }
+
+    fn poll_dpt_request_new_peers(&mut self) -> Poll<(), Error> {
+        let dpt = match self.dpt {
+            Some(ref mut dpt) => dpt,
+            None => return Ok(Async::Ready(())),
+        };
         let mut result = self.optimal_peers_timeout.poll()?;
 
         loop {
@@ -99,17 +846,15 @@ impl DevP2PStream {
                 Async::Ready(()) => {
                     if self.rlpx.active_peers().len() < self.config.optimal_peers_len {
                         error!("not enough peers (only {}), requesting new ...", self.rlpx.active_peers().len());
-                        self.dpt.start_send(DPTMessage::RequestNewPeer)?;
-                        self.dpt.poll_complete()?;
+                        dpt.start_send(DPTMessage::RequestNewPeer).map_err(Error::Discovery)?;
+                        dpt.poll_complete().map_err(Error::Discovery)?;
 
                         debug!("reconnect to old connected peers ...");
-                        let mut connected: Vec<DPTNode> = self.dpt.connected_peers().into();
+                        let mut connected: Vec<DPTNode> = dpt.connected_peers().into();
                         thread_rng().shuffle(&mut connected);
                         for i in 0..min(self.config.optimal_peers_len - self.rlpx.active_peers().len(),
                                         connected.len() / self.config.reconnect_dividend) {
-                            self.rlpx.add_peer(&SocketAddr::new(connected[i].address,
-                                                                connected[i].tcp_port),
-                                               connected[i].id);
+                            dial(&mut self.rlpx, &mut self.node_database, &connected[i]);
                         }
                     }
 
@@ -125,16 +870,54 @@ impl DevP2PStream {
         Ok(Async::Ready(()))
     }
 
-    fn poll_dpt_ping(&mut self) -> Poll<(), io::Error> {
+    fn poll_static_peers(&mut self) -> Poll<(), Error> {
+        for peer in &mut self.static_peers {
+            if self.rlpx.active_peers().contains(&peer.node.id) {
+                peer.redial = None;
+                continue;
+            }
+            if peer.redial.is_none() {
+                debug!("static peer {:x} went away, redialing in {:?}", peer.node.id, peer.backoff);
+                peer.redial = Some(Timeout::new(peer.backoff, &self.handle)?);
+            }
+            if peer.redial.as_mut().unwrap().poll()?.is_ready() {
+                peer.redial = None;
+                dial(&mut self.rlpx, &mut self.node_database, &peer.node);
+                peer.backoff = min(peer.backoff * 2, Duration::from_secs(STATIC_PEER_MAX_BACKOFF));
+            }
+        }
+        Ok(Async::Ready(()))
+    }
+
+    fn poll_port_mapping(&mut self) {
+        let endpoint = self.external_endpoint();
+        if endpoint == self.external_endpoint {
+            return;
+        }
+        if let (Some(endpoint), None) = (endpoint, self.config.advertised_addr) {
+            info!("announcing mapped endpoint {}:{}", endpoint.address, endpoint.tcp_port);
+            if let Some(ref mut dpt) = self.dpt {
+                dpt.set_endpoint(endpoint.address, endpoint.udp_port, endpoint.tcp_port);
+            }
+            self.rlpx.set_advertised_port(endpoint.tcp_port);
+        }
+        self.external_endpoint = endpoint;
+    }
+
+    fn poll_dpt_ping(&mut self) -> Poll<(), Error> {
+        let dpt = match self.dpt {
+            Some(ref mut dpt) => dpt,
+            None => return Ok(Async::Ready(())),
+        };
         let mut result = self.ping_timeout.poll()?;
 
         loop {
             match result {
                 Async::NotReady => return Ok(Async::Ready(())),
                 Async::Ready(()) => {
-                    self.dpt.start_send(DPTMessage::Ping(Timeout::new(
-                        self.config.ping_timeout_interval, &self.handle)?))?;
-                    self.dpt.poll_complete()?;
+                    dpt.start_send(DPTMessage::Ping(Timeout::new(
+                        self.config.ping_timeout_interval, &self.handle)?)).map_err(Error::Discovery)?;
+                    dpt.poll_complete().map_err(Error::Discovery)?;
                     self.ping_timeout = Timeout::new(self.config.ping_interval, &self.handle)?;
 
                     result = self.ping_timeout.poll()?;
@@ -148,32 +931,503 @@ impl DevP2PStream {
 
 impl Stream for DevP2PStream {
     type Item = RLPxReceiveMessage;
-    type Error = io::Error;
+    type Error = Error;
 
     fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
-        self.poll_dpt_receive_peers()?;
-        let result = self.rlpx.poll()?;
-        self.poll_dpt_request_new_peers()?;
-        self.poll_dpt_ping()?;
-        Ok(result)
+        loop {
+            self.poll_dpt_receive_peers()?;
+            self.poll_dns_discovery()?;
+            let result = self.rlpx.poll()?;
+            self.poll_dpt_request_new_peers()?;
+            self.poll_static_peers()?;
+            self.poll_port_mapping();
+            self.poll_dpt_ping()?;
+            let expired = self.reputation.expire_bans();
+            let expired_ips = self.reputation.expire_ip_bans();
+            for &remote_id in &expired {
+                self.rlpx.unban_peer(remote_id);
+                if let Some(ref mut dpt) = self.dpt {
+                    dpt.unban_node(remote_id);
+                }
+            }
+            for &ip in &expired_ips {
+                self.rlpx.unban_ip(ip);
+                if let Some(ref mut dpt) = self.dpt {
+                    dpt.unban_ip(ip);
+                }
+            }
+            if !expired.is_empty() || !expired_ips.is_empty() {
+                self.save_ban_list();
+            }
+
+            if let Async::Ready(Some(RLPxReceiveMessage::Connected { node, .. })) = result {
+                for peer in self.static_peers.iter_mut().filter(|peer| peer.node.id == node) {
+                    peer.backoff = Duration::from_secs(STATIC_PEER_MIN_BACKOFF);
+                }
+                if let Some(ref mut database) = self.node_database {
+                    database.connected(node, self.dpt.as_ref().and_then(|dpt| dpt.get_peer(node)));
+                }
+            }
+            if let Some(ref mut database) = self.node_database {
+                if let Err(e) = database.save_if_due(Duration::from_secs(NODE_DATABASE_SAVE_INTERVAL)) {
+                    error!("failed to save the node database: {}", e);
+                }
+            }
+            if let Async::Ready(Some(RLPxReceiveMessage::Disconnected { node, reason, .. })) = result {
+                self.report_peer(node, ReputationEvent::Disconnected(reason));
+            }
+
+            if let Async::Ready(Some(RLPxReceiveMessage::Normal {
+                node, ref capability, id, ref data
+            })) = result {
+                if self.max_message_size.map(|max| data.len() > max).unwrap_or(false) {
+                    debug!("dropping {} message of {} bytes from {:x}", capability.name, data.len(), node);
+                    continue;
+                }
+                let handler = self.capabilities.iter_mut().find(|handler| {
+                    handler.name() == capability.name && handler.version() == capability.version
+                });
+                if let Some(handler) = handler {
+                    for (id, data) in handler.handle(node, id, data) {
+                        self.rlpx.start_send(RLPxSendMessage {
+                            node: RLPxNode::Peer(node),
+                            capability_name: capability.name,
+                            id, data,
+                        }).map_err(|e| Error::from(e).with_node(node))?;
+                    }
+                    self.rlpx.poll_complete().map_err(|e| Error::from(e).with_node(node))?;
+                    continue;
+                }
+            }
+            return Ok(result);
+        }
     }
 }
 
 impl Sink for DevP2PStream {
     type SinkItem = RLPxSendMessage;
-    type SinkError = io::Error;
+    type SinkError = Error;
 
     fn start_send(&mut self, val: RLPxSendMessage) -> StartSend<Self::SinkItem, Self::SinkError> {
         self.poll_dpt_receive_peers()?;
-        let result = self.rlpx.start_send(val)?;
+        self.poll_dns_discovery()?;
+        let node = val.node;
+        let result = self.rlpx.start_send(val).map_err(|e| match node {
+            RLPxNode::Peer(node) => Error::from(e).with_node(node),
+            _ => Error::from(e),
+        })?;
         self.poll_dpt_request_new_peers()?;
+        self.poll_static_peers()?;
         self.poll_dpt_ping()?;
         Ok(result)
     }
 
+    /// Flush the DPT and RLPx sinks independently, so that pending
+    /// discovery traffic never holds back peer messages. Not ready if
+    /// either of them still has pending data.
     fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
-        try_ready!(self.dpt.poll_complete());
-        try_ready!(self.rlpx.poll_complete());
+        let result = match self.dpt {
+            Some(ref mut dpt) => poll_complete_both(dpt, &mut self.rlpx),
+            None => self.rlpx.poll_complete(),
+        };
+        Ok(result?)
+    }
+}
+
+/// Dial a node, remembering it in the node database if a dial starts
+fn dial(rlpx: &mut RLPxStream, node_database: &mut Option<NodeDatabase>, node: &DPTNode) {
+    let dialing = !rlpx.active_peers().contains(&node.id);
+    rlpx.add_peer(&node.tcp_addr(), node.id);
+    if dialing && rlpx.active_peers().contains(&node.id) {
+        if let Some(ref mut database) = *node_database {
+            database.dialed(node);
+        }
+    }
+}
+
+/// Flush both sinks, making progress on each regardless of the other
+fn poll_complete_both<A, B>(a: &mut A, b: &mut B) -> Poll<(), io::Error>
+    where A: Sink<SinkError = io::Error>, B: Sink<SinkError = io::Error>
+{
+    let a = a.poll_complete()?;
+    let b = b.poll_complete()?;
+    if a.is_ready() && b.is_ready() {
         Ok(Async::Ready(()))
+    } else {
+        Ok(Async::NotReady)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{poll_complete_both, DevP2PStream, DevP2PConfig, DevP2PBuilder};
+    use dpt::{DPTNode, Discovery, DnsDiscovery, TxtResolver};
+    use nodedb::NodeDatabase;
+    use reputation::Reputation;
+    use super::super::Capability;
+    use bigint::H512;
+    use rlpx::{PeerStream, CapabilityInfo, RLPxReceiveMessage, NetRestrict};
+    use secp256k1::SECP256K1;
+    use secp256k1::key::{PublicKey, SecretKey};
+    use tokio_core::reactor::Core;
+    use futures::{future, StartSend, Async, AsyncSink, Poll, Future, Stream, Sink};
+    use rand::os::OsRng;
+    use std::io;
+    use std::net::{TcpListener, UdpSocket};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    struct MockSink {
+        ready: bool,
+        pending: Vec<u8>,
+        flushed: Vec<u8>,
+    }
+
+    impl Sink for MockSink {
+        type SinkItem = u8;
+        type SinkError = io::Error;
+
+        fn start_send(&mut self, item: u8) -> StartSend<u8, io::Error> {
+            self.pending.push(item);
+            Ok(AsyncSink::Ready)
+        }
+
+        fn poll_complete(&mut self) -> Poll<(), io::Error> {
+            if self.ready {
+                self.flushed.append(&mut self.pending);
+                Ok(Async::Ready(()))
+            } else {
+                Ok(Async::NotReady)
+            }
+        }
+    }
+
+    #[test]
+    fn stuck_dpt_does_not_block_rlpx() {
+        let mut dpt = MockSink { ready: false, pending: Vec::new(), flushed: Vec::new() };
+        let mut rlpx = MockSink { ready: true, pending: Vec::new(), flushed: Vec::new() };
+        dpt.start_send(1).unwrap();
+        rlpx.start_send(2).unwrap();
+        rlpx.start_send(3).unwrap();
+
+        assert_eq!(poll_complete_both(&mut dpt, &mut rlpx).unwrap(), Async::NotReady);
+        assert_eq!(rlpx.flushed, vec![2, 3]);
+        assert!(dpt.flushed.is_empty());
+
+        dpt.ready = true;
+        assert_eq!(poll_complete_both(&mut dpt, &mut rlpx).unwrap(), Async::Ready(()));
+        assert_eq!(dpt.flushed, vec![1]);
+    }
+
+    /// Sends every message back with its bytes reversed
+    struct Echo;
+
+    impl Capability for Echo {
+        fn name(&self) -> &'static str { "echo" }
+        fn version(&self) -> usize { 1 }
+        fn message_count(&self) -> usize { 2 }
+
+        fn handle(&mut self, _node: H512, id: usize, data: &[u8]) -> Vec<(usize, Vec<u8>)> {
+            vec![(id + 1, data.iter().rev().cloned().collect())]
+        }
+    }
+
+    #[test]
+    fn registered_capability_answers() {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let config = DevP2PConfig {
+     ping_interval: Duration::new(600, 0),
+     ping_timeout_interval: Duration::new(700, 0),
+     optimal_peers_interval: Duration::new(600, 0),
+     max_peers_per_ip: None,
+     ..Default::default()
+        };
+
+        let addr = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let secret_key = SecretKey::new(&SECP256K1, &mut OsRng::new().unwrap());
+        let key = PublicKey::from_secret_key(&SECP256K1, &secret_key).unwrap();
+        let id = H512::from(&key.serialize_vec(&SECP256K1, false)[1..65]);
+        let mut stream = DevP2PStream::new(&addr, &addr.ip(), &handle, Arc::new(secret_key), 4,
+                                           "devp2p".to_string(), Vec::new(), Vec::new(),
+                                           config).unwrap();
+        stream.register_capability(Box::new(Echo));
+
+        let peer_key = SecretKey::new(&SECP256K1, &mut OsRng::new().unwrap());
+        let peer = PeerStream::connect(
+            &addr, &handle, Arc::new(peer_key), id,
+            5, "peer".to_string(), vec![CapabilityInfo { name: "echo", version: 1, length: 2 }],
+            0, Default::default())
+            .and_then(|peer| peer.send(("echo", 0, vec![1, 2, 3])))
+            .and_then(|peer| peer.into_future().map_err(|(e, _)| e))
+            .map(|(message, _)| message.unwrap());
+
+        // Only the connection is returned, the message is answered
+        let messages = future::poll_fn(|| -> Poll<(), io::Error> {
+            loop {
+                match try_ready!(stream.poll()) {
+                    Some(RLPxReceiveMessage::Connected { .. }) => (),
+                    message => panic!("unexpected message {:?}", message),
+                }
+            }
+        });
+        let (cap, id, data) = match core.run(peer.select2(messages)).ok().unwrap() {
+            future::Either::A((message, _)) => message,
+            _ => panic!(),
+        };
+        assert_eq!((cap.name, id, data), ("echo", 1, vec![3, 2, 1]));
+    }
+
+    #[test]
+    fn builder_applies_settings() {
+        let core = Core::new().unwrap();
+        let addr = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let builder = DevP2PBuilder::new()
+            .optimal_peers(50, Duration::from_secs(10))
+            .listen(false)
+            .max_peers(Some(30), Some(10))
+            .client_version("test".to_string())
+            .capability(CapabilityInfo { name: "echo", version: 1, length: 2 });
+        assert_eq!(builder.config().optimal_peers_len, 50);
+        assert_eq!(builder.config().ping_interval, DevP2PConfig::default().ping_interval);
+        assert!(!builder.config().listen);
+
+        let stream = builder.build(&addr, &addr.ip(), &core.handle(),
+                                   Arc::new(SecretKey::new(&SECP256K1, &mut OsRng::new().unwrap()))).unwrap();
+        assert_eq!(stream.client_version(), "test");
+        assert_eq!(stream.config.optimal_peers_interval, Duration::from_secs(10));
+        assert_eq!(stream.config.max_outbound_peers, Some(10));
+    }
+
+    #[test]
+    fn static_peer_redialed() {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let capability = CapabilityInfo { name: "echo", version: 1, length: 2 };
+
+        let remote_key = SecretKey::new(&SECP256K1, &mut OsRng::new().unwrap());
+        let key = PublicKey::from_secret_key(&SECP256K1, &remote_key).unwrap();
+        let remote_id = H512::from(&key.serialize_vec(&SECP256K1, false)[1..65]);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let remote_addr = listener.local_addr().unwrap();
+        let listener = ::tokio_core::net::TcpListener::from_listener(listener, &remote_addr, &handle).unwrap();
+        let remote_key = Arc::new(remote_key);
+        // The first connection is dropped right away
+        let remote = listener.incoming().take(2)
+            .and_then(move |(socket, _)| PeerStream::incoming(socket, remote_key.clone(), 5,
+                                                              "remote".to_string(),
+                                                              vec![capability], 0))
+            .skip(1).into_future().map_err(|(e, _)| e);
+
+        let addr = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let mut stream = DevP2PBuilder::new()
+            .listen(false)
+            .capability(capability)
+            .static_peers(vec![DPTNode {
+                address: remote_addr.ip(), tcp_port: remote_addr.port(), udp_port: 0, id: remote_id,
+            }])
+            .build(&addr, &addr.ip(), &handle, Arc::new(SecretKey::new(&SECP256K1, &mut OsRng::new().unwrap())))
+            .unwrap();
+        assert_eq!(stream.static_peers(), vec![remote_id]);
+
+        let mut events = Vec::new();
+        let local = future::poll_fn(|| -> Poll<(), io::Error> {
+            while events.len() < 3 {
+                match try_ready!(stream.poll()) {
+                    Some(RLPxReceiveMessage::Connected { node, .. }) => {
+                        events.push((node, true));
+                        stream.static_peers[0].backoff = Duration::from_millis(10);
+                    },
+                    Some(RLPxReceiveMessage::Disconnected { node, .. }) => events.push((node, false)),
+                    message => panic!("unexpected message {:?}", message),
+                }
+            }
+            Ok(Async::Ready(()))
+        });
+        let (_remote, ()) = core.run(remote.join(local)).unwrap();
+        assert_eq!(events, vec![(remote_id, true), (remote_id, false), (remote_id, true)]);
+    }
+
+    #[test]
+    fn node_database_dialed() {
+        let core = Core::new().unwrap();
+        let path = ::std::env::temp_dir().join(format!("devp2p-nodes-{}", H512::random().low_u64()));
+        let known = DPTNode { address: "127.0.0.1".parse().unwrap(), tcp_port: 1, udp_port: 1, id: H512::random() };
+        let mut database = NodeDatabase::open(&path).unwrap();
+        database.connected(known.id, Some(known.clone()));
+
+        let addr = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let mut stream = DevP2PBuilder::new()
+            .listen(false)
+            .node_database(database)
+            .build(&addr, &addr.ip(), &core.handle(), Arc::new(SecretKey::new(&SECP256K1, &mut OsRng::new().unwrap())))
+            .unwrap();
+        assert_eq!(stream.active_peers(), &[known.id]);
+        assert_eq!(stream.node_database().unwrap().get(&known.id).unwrap().fail_count, 1);
+
+        stream.save_node_database().unwrap();
+        assert_eq!(NodeDatabase::open(&path).unwrap().len(), 1);
+        ::std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn ban_list_persisted() {
+        let mut core = Core::new().unwrap();
+        let path = ::std::env::temp_dir().join(format!("devp2p-bans-{}", H512::random().low_u64()));
+        let banned = DPTNode { address: "127.0.0.1".parse().unwrap(), tcp_port: 1, udp_port: 1, id: H512::random() };
+        let mut reputation = Reputation::new();
+        reputation.ban(banned.id);
+        reputation.save_bans(&path).unwrap();
+
+        let addr = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let mut stream = DevP2PBuilder::new()
+            .listen(false)
+            .bootstrap_nodes(vec![banned.clone()])
+            .ban_list(path.clone())
+            .build(&addr, &addr.ip(), &core.handle(), Arc::new(SecretKey::new(&SECP256K1, &mut OsRng::new().unwrap())))
+            .unwrap();
+        core.run(future::poll_fn(|| stream.poll_dpt_receive_peers())).unwrap();
+        assert!(stream.active_peers().is_empty());
+
+        let ip = "10.0.0.1".parse().unwrap();
+        stream.ban_ip(ip);
+        stream.unban_peer(banned.id);
+        let mut loaded = Reputation::new();
+        loaded.load_bans(&path).unwrap();
+        assert!(loaded.is_ip_banned(&ip));
+        assert!(!loaded.is_banned(&banned.id));
+        ::std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn restricted_nodes_not_dialed() {
+        let mut core = Core::new().unwrap();
+        let node = |address: &str| DPTNode { address: address.parse().unwrap(), tcp_port: 1, udp_port: 1, id: H512::random() };
+        let (lab, outside) = (node("127.0.0.2"), node("10.1.0.1"));
+
+        let addr = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let mut stream = DevP2PBuilder::new()
+            .listen(false)
+            .bootstrap_nodes(vec![lab.clone(), outside])
+            .net_restrict(NetRestrict::parse("127.0.0.0/8", "").unwrap())
+            .build(&addr, &addr.ip(), &core.handle(), Arc::new(SecretKey::new(&SECP256K1, &mut OsRng::new().unwrap())))
+            .unwrap();
+        core.run(future::poll_fn(|| stream.poll_dpt_receive_peers())).unwrap();
+        assert_eq!(stream.active_peers(), &[lab.id]);
+    }
+
+    #[test]
+    fn walked_dns_tree_dropped() {
+        struct Unresolved;
+
+        impl TxtResolver for Unresolved {
+            fn resolve_txt(&self, _name: &str) -> Box<dyn Future<Item = Vec<String>, Error = io::Error>> {
+                Box::new(future::err(io::Error::new(io::ErrorKind::NotFound, "not found")))
+            }
+        }
+
+        let mut core = Core::new().unwrap();
+        let addr = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let mut stream = DevP2PBuilder::new()
+            .listen(false)
+            .discovery(false)
+            .build(&addr, &addr.ip(), &core.handle(), Arc::new(SecretKey::new(&SECP256K1, &mut OsRng::new().unwrap())))
+            .unwrap();
+        let url = "enrtree://AM5FCQLWIZX2QFPNJAP7VUERCCRNGRHWZG3YYHIUV7BVDQ5FDPRT2@nodes.example.org".parse().unwrap();
+        stream.add_dns_discovery(DnsDiscovery::new(url, Box::new(Unresolved)));
+        core.run(future::poll_fn(|| stream.poll_dns_discovery())).unwrap();
+        assert!(stream.dns_discovery.is_empty());
+        assert!(stream.active_peers().is_empty());
+    }
+
+    #[test]
+    fn static_peers_file_loaded() {
+        let core = Core::new().unwrap();
+        let remote = DPTNode { address: "127.0.0.1".parse().unwrap(), tcp_port: 1, udp_port: 1, id: H512::random() };
+        let path = ::std::env::temp_dir().join(format!("devp2p-static-{}", H512::random().low_u64()));
+        ::std::fs::write(&path, format!("[\"{}\"]", remote)).unwrap();
+
+        let addr = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let stream = DevP2PBuilder::new()
+            .listen(false)
+            .static_peers_file(path.clone())
+            .build(&addr, &addr.ip(), &core.handle(), Arc::new(SecretKey::new(&SECP256K1, &mut OsRng::new().unwrap())))
+            .unwrap();
+        assert_eq!(stream.static_peers(), vec![remote.id]);
+        ::std::fs::remove_file(&path).unwrap();
+
+        assert!(DevP2PBuilder::new()
+                .listen(false)
+                .bootstrap_nodes_file(path)
+                .build(&addr, &addr.ip(), &core.handle(), Arc::new(SecretKey::new(&SECP256K1, &mut OsRng::new().unwrap())))
+                .is_err());
+    }
+
+    #[test]
+    fn separate_discovery_port() {
+        let core = Core::new().unwrap();
+        let addr = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let discovery_addr = UdpSocket::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let _stream = DevP2PBuilder::new()
+            .discovery_addr(discovery_addr)
+            .build(&addr, &addr.ip(), &core.handle(), Arc::new(SecretKey::new(&SECP256K1, &mut OsRng::new().unwrap())))
+            .unwrap();
+        assert!(TcpListener::bind(addr).is_err());
+        assert!(UdpSocket::bind(discovery_addr).is_err());
+        assert!(UdpSocket::bind(addr).is_ok());
+    }
+
+    #[test]
+    fn static_only_without_discovery() {
+        let mut core = Core::new().unwrap();
+        let node = |address: &str| DPTNode { address: address.parse().unwrap(), tcp_port: 1, udp_port: 1, id: H512::random() };
+        let (bootstrap, fixed) = (node("127.0.0.1"), node("127.0.0.2"));
+
+        let addr = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let mut stream = DevP2PBuilder::new()
+            .listen(false)
+            .discovery(false)
+            .bootstrap_nodes(vec![bootstrap])
+            .static_peers(vec![fixed.clone()])
+            .build(&addr, &addr.ip(), &core.handle(), Arc::new(SecretKey::new(&SECP256K1, &mut OsRng::new().unwrap())))
+            .unwrap();
+        assert!(UdpSocket::bind(addr).is_ok());
+
+        core.run(future::poll_fn(|| stream.poll_dpt_receive_peers())).unwrap();
+        core.run(future::poll_fn(|| stream.poll_dpt_ping())).unwrap();
+        assert_eq!(stream.active_peers(), &[fixed.id]);
+        assert!(stream.active_lookups().is_empty());
+    }
+
+    #[test]
+    fn outbound_only_without_listener() {
+        let core = Core::new().unwrap();
+        let remote = DPTNode { address: "127.0.0.1".parse().unwrap(), tcp_port: 1, udp_port: 1, id: H512::random() };
+
+        let addr = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let stream = DevP2PBuilder::new()
+            .listen(false)
+            .static_peers(vec![remote.clone()])
+            .build(&addr, &addr.ip(), &core.handle(), Arc::new(SecretKey::new(&SECP256K1, &mut OsRng::new().unwrap())))
+            .unwrap();
+        assert!(TcpListener::bind(addr).is_ok());
+        assert!(UdpSocket::bind(addr).is_err());
+        assert_eq!(stream.active_peers(), &[remote.id]);
+    }
+
+    #[test]
+    fn discovery_v5_selected() {
+        let core = Core::new().unwrap();
+        let addr = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let stream = DevP2PBuilder::new()
+            .discovery_v5(true)
+            .build(&addr, &addr.ip(), &core.handle(), Arc::new(SecretKey::new(&SECP256K1, &mut OsRng::new().unwrap())))
+            .unwrap();
+        assert!(UdpSocket::bind(addr).is_err());
+        match stream.dpt {
+            Some(Discovery::V5(_)) => (),
+            _ => panic!("discovery v4 running"),
+        }
+        assert_eq!(stream.local_record().and_then(|record| record.udp_port()), Some(addr.port()));
     }
 }
@@ -0,0 +1,354 @@
+use bigint::H512;
+use rlpx::DisconnectReason;
+use hexutil::{read_hex, to_hex, clean_0x};
+use nodedb::unix_time;
+use std::cmp::min;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::net::IpAddr;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// Highest score a peer can reach, so that a long useful past does
+/// not hide later misbehaviour
+pub const MAX_REPUTATION: i32 = 100;
+/// Default score at or below which a peer is disconnected
+pub const DEFAULT_DISCONNECT_SCORE: i32 = -50;
+/// Default score at or below which a peer is banned
+pub const DEFAULT_BAN_SCORE: i32 = -100;
+/// Default time a peer stays banned, in seconds
+pub const DEFAULT_BAN_DURATION: u64 = 3600;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Peer behaviour changing its reputation
+pub enum ReputationEvent {
+    /// Answered one of our requests
+    UsefulResponse,
+    /// Did not answer one of our requests in time
+    Timeout,
+    /// Sent a message breaking the protocol or its limits
+    InvalidMessage,
+    /// Went away, with the reason it gave if any
+    Disconnected(Option<DisconnectReason>),
+}
+
+impl ReputationEvent {
+    /// Score change caused by the event
+    pub fn score(&self) -> i32 {
+        match *self {
+            ReputationEvent::UsefulResponse => 1,
+            ReputationEvent::Timeout => -5,
+            ReputationEvent::InvalidMessage => -10,
+            ReputationEvent::Disconnected(reason) => match reason {
+                Some(DisconnectReason::BreachOfProtocol) |
+                Some(DisconnectReason::UselessPeer) |
+                Some(DisconnectReason::IncompatibleVersion) |
+                Some(DisconnectReason::InvalidIdentity) |
+                Some(DisconnectReason::UnexpectedIdentity) |
+                Some(DisconnectReason::ConnectedToSelf) |
+                Some(DisconnectReason::SubprotocolError) => -10,
+                Some(DisconnectReason::TcpSubsystemError) |
+                Some(DisconnectReason::PingTimeout) => -2,
+                _ => 0,
+            },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// What a peer's new score calls for
+pub enum ReputationAction {
+    None,
+    Disconnect,
+    Ban,
+}
+
+/// Scores peers by their behaviour, telling when one should be
+/// disconnected or banned, and keeps track of banned peers and
+/// addresses
+pub struct Reputation {
+    scores: HashMap<H512, i32>,
+    banned: HashMap<H512, Instant>,
+    banned_ips: HashMap<IpAddr, Instant>,
+    disconnect_score: i32,
+    ban_score: i32,
+    ban_duration: Duration,
+}
+
+impl Default for Reputation {
+    fn default() -> Self {
+        Reputation::new()
+    }
+}
+
+impl Reputation {
+    /// Create a tracker with the default thresholds and ban duration
+    pub fn new() -> Self {
+        Reputation {
+            scores: HashMap::new(),
+            banned: HashMap::new(),
+            banned_ips: HashMap::new(),
+            disconnect_score: DEFAULT_DISCONNECT_SCORE,
+            ban_score: DEFAULT_BAN_SCORE,
+            ban_duration: Duration::from_secs(DEFAULT_BAN_DURATION),
+        }
+    }
+
+    /// Set the scores at or below which peers are disconnected and
+    /// banned
+    pub fn set_thresholds(&mut self, disconnect_score: i32, ban_score: i32) {
+        self.disconnect_score = disconnect_score;
+        self.ban_score = ban_score;
+    }
+
+    /// Set how long peers stay banned
+    pub fn set_ban_duration(&mut self, duration: Duration) {
+        self.ban_duration = duration;
+    }
+
+    /// Current score of a peer, 0 for peers never scored
+    pub fn score(&self, peer: &H512) -> i32 {
+        self.scores.get(peer).cloned().unwrap_or(0)
+    }
+
+    /// Apply an event to the score of a peer, banning it if the score
+    /// falls to the ban threshold
+    pub fn record(&mut self, peer: H512, event: ReputationEvent) -> ReputationAction {
+        let score = min(self.score(&peer) + event.score(), MAX_REPUTATION);
+        if score <= self.ban_score {
+            self.ban(peer);
+            return ReputationAction::Ban;
+        }
+        if score == 0 {
+            self.scores.remove(&peer);
+        } else {
+            self.scores.insert(peer, score);
+        }
+        if score <= self.disconnect_score {
+            ReputationAction::Disconnect
+        } else {
+            ReputationAction::None
+        }
+    }
+
+    /// Ban a peer for the ban duration. Its score starts over once the
+    /// ban ends.
+    pub fn ban(&mut self, peer: H512) {
+        self.scores.remove(&peer);
+        self.banned.insert(peer, Instant::now() + self.ban_duration);
+    }
+
+    /// Lift the ban of a peer
+    pub fn unban(&mut self, peer: &H512) {
+        self.banned.remove(peer);
+    }
+
+    /// Whether a peer is banned
+    pub fn is_banned(&self, peer: &H512) -> bool {
+        self.banned.get(peer).map(|&until| until > Instant::now()).unwrap_or(false)
+    }
+
+    /// Ban an address for the ban duration
+    pub fn ban_ip(&mut self, ip: IpAddr) {
+        self.banned_ips.insert(ip, Instant::now() + self.ban_duration);
+    }
+
+    /// Lift the ban of an address
+    pub fn unban_ip(&mut self, ip: &IpAddr) {
+        self.banned_ips.remove(ip);
+    }
+
+    /// Whether an address is banned
+    pub fn is_ip_banned(&self, ip: &IpAddr) -> bool {
+        self.banned_ips.get(ip).map(|&until| until > Instant::now()).unwrap_or(false)
+    }
+
+    /// Banned peers
+    pub fn banned_peers(&self) -> Vec<H512> {
+        self.banned.keys().cloned().collect()
+    }
+
+    /// Banned addresses
+    pub fn banned_ips(&self) -> Vec<IpAddr> {
+        self.banned_ips.keys().cloned().collect()
+    }
+
+    /// Lift the bans that ran out, returning the peers they applied to
+    pub fn expire_bans(&mut self) -> Vec<H512> {
+        let now = Instant::now();
+        let expired: Vec<H512> = self.banned.iter()
+            .filter(|&(_, &until)| until <= now)
+            .map(|(&peer, _)| peer)
+            .collect();
+        for peer in &expired {
+            self.banned.remove(peer);
+        }
+        expired
+    }
+
+    /// Lift the address bans that ran out, returning the addresses they
+    /// applied to
+    pub fn expire_ip_bans(&mut self) -> Vec<IpAddr> {
+        let now = Instant::now();
+        let expired: Vec<IpAddr> = self.banned_ips.iter()
+            .filter(|&(_, &until)| until <= now)
+            .map(|(&ip, _)| ip)
+            .collect();
+        for ip in &expired {
+            self.banned_ips.remove(ip);
+        }
+        expired
+    }
+
+    /// Add the bans saved to a file by `save_bans` which did not run
+    /// out yet, doing nothing if the file does not exist
+    pub fn load_bans<P: AsRef<Path>>(&mut self, path: P) -> Result<(), io::Error> {
+        let mut file = match File::open(path.as_ref()) {
+            Ok(file) => file,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        };
+
+        let mut content = String::new();
+        file.read_to_string(&mut content)?;
+        let (now, unix_now) = (Instant::now(), unix_time());
+        for line in content.lines().filter(|line| !line.trim().is_empty()) {
+            let entry = parse_ban(line).ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidData, "invalid ban list entry")
+            })?;
+            match entry {
+                (_, expires) if expires <= unix_now => (),
+                (Banned::Peer(peer), expires) => {
+                    self.banned.insert(peer, now + Duration::from_secs(expires - unix_now));
+                },
+                (Banned::Ip(ip), expires) => {
+                    self.banned_ips.insert(ip, now + Duration::from_secs(expires - unix_now));
+                },
+            }
+        }
+        Ok(())
+    }
+
+    /// Write the bans to a file, one per line as the peer id in hex or
+    /// the address, followed by the time it runs out in seconds since
+    /// the Unix epoch
+    pub fn save_bans<P: AsRef<Path>>(&self, path: P) -> Result<(), io::Error> {
+        let (now, unix_now) = (Instant::now(), unix_time());
+        let expires = |until: &Instant| if *until > now { unix_now + (*until - now).as_secs() } else { unix_now };
+        let mut content = String::new();
+        for (peer, until) in &self.banned {
+            content.push_str(&format!("{} {}\n", clean_0x(&to_hex(peer)), expires(until)));
+        }
+        for (ip, until) in &self.banned_ips {
+            content.push_str(&format!("{} {}\n", ip, expires(until)));
+        }
+
+        let mut temp = path.as_ref().to_path_buf().into_os_string();
+        temp.push(".tmp");
+        File::create(&temp)?.write_all(content.as_bytes())?;
+        fs::rename(&temp, path.as_ref())
+    }
+}
+
+/// What a ban list entry applies to
+enum Banned {
+    Peer(H512),
+    Ip(IpAddr),
+}
+
+fn parse_ban(line: &str) -> Option<(Banned, u64)> {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    if fields.len() != 2 {
+        return None;
+    }
+    let banned = match fields[0].parse() {
+        Ok(ip) => Banned::Ip(ip),
+        Err(_) => {
+            let id = read_hex(fields[0]).ok()?;
+            if id.len() != 64 {
+                return None;
+            }
+            Banned::Peer(H512::from(&id[..]))
+        },
+    };
+    Some((banned, fields[1].parse().ok()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Reputation, ReputationEvent, ReputationAction, MAX_REPUTATION};
+    use bigint::H512;
+    use rlpx::DisconnectReason;
+    use rand::{thread_rng, Rng};
+    use std::env;
+    use std::fs;
+    use std::net::IpAddr;
+    use std::time::Duration;
+
+    #[test]
+    fn disconnects_then_bans() {
+        let mut reputation = Reputation::new();
+        let peer = H512::random();
+        for _ in 0..4 {
+            assert_eq!(reputation.record(peer, ReputationEvent::InvalidMessage), ReputationAction::None);
+        }
+        assert_eq!(reputation.record(peer, ReputationEvent::Timeout), ReputationAction::None);
+        assert_eq!(reputation.record(peer, ReputationEvent::Timeout), ReputationAction::Disconnect);
+        assert_eq!(reputation.score(&peer), -50);
+        assert_eq!(reputation.record(peer, ReputationEvent::Disconnected(Some(DisconnectReason::ClientQuitting))),
+                   ReputationAction::Disconnect);
+
+        for _ in 0..4 {
+            assert_eq!(reputation.record(peer, ReputationEvent::InvalidMessage), ReputationAction::Disconnect);
+        }
+        assert_eq!(reputation.record(peer, ReputationEvent::Disconnected(Some(DisconnectReason::UselessPeer))),
+                   ReputationAction::Ban);
+        assert!(reputation.is_banned(&peer));
+        assert_eq!(reputation.score(&peer), 0);
+        assert!(reputation.expire_bans().is_empty());
+        reputation.unban(&peer);
+        assert!(!reputation.is_banned(&peer));
+    }
+
+    #[test]
+    fn scores_capped_and_bans_expire() {
+        let mut reputation = Reputation::new();
+        let peer = H512::random();
+        for _ in 0..MAX_REPUTATION + 10 {
+            reputation.record(peer, ReputationEvent::UsefulResponse);
+        }
+        assert_eq!(reputation.score(&peer), MAX_REPUTATION);
+
+        reputation.set_ban_duration(Duration::from_secs(0));
+        reputation.ban(peer);
+        assert!(!reputation.is_banned(&peer));
+        assert_eq!(reputation.expire_bans(), vec![peer]);
+    }
+
+    #[test]
+    fn bans_saved_and_loaded() {
+        let path = env::temp_dir().join(format!("devp2p-bans-{}", thread_rng().gen::<u64>()));
+        let mut reputation = Reputation::new();
+        let (peer, expired_peer) = (H512::random(), H512::random());
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        reputation.ban(peer);
+        reputation.ban_ip(ip);
+        reputation.set_ban_duration(Duration::from_secs(0));
+        reputation.ban(expired_peer);
+        reputation.save_bans(&path).unwrap();
+
+        let mut loaded = Reputation::new();
+        loaded.load_bans(&path).unwrap();
+        assert!(loaded.is_banned(&peer));
+        assert!(loaded.is_ip_banned(&ip));
+        assert!(!loaded.is_ip_banned(&"10.0.0.2".parse().unwrap()));
+        assert_eq!(loaded.banned_peers(), vec![peer]);
+        fs::remove_file(&path).unwrap();
+
+        assert!(loaded.load_bans("/nonexistent/devp2p-bans").is_ok());
+        fs::write(&path, "00 1\n").unwrap();
+        assert!(loaded.load_bans(&path).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
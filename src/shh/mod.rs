@@ -0,0 +1,358 @@
+mod proto;
+
+use futures::{StartSend, Async, Poll, Stream, Sink, AsyncSink};
+use rlp::{self, UntrustedRlp};
+use bigint::H512;
+use rlpx::{RLPxSendMessage, RLPxReceiveMessage, RLPxNode, CapabilityInfo, DisconnectReason, PeerInfo,
+           PeerTable, NodeSigner};
+use tokio_core::reactor::Handle;
+use std::io;
+use std::sync::Arc;
+use std::net::{IpAddr, SocketAddr};
+use std::collections::HashMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::{DevP2PStream, DevP2PBuilder, Error};
+
+pub use self::proto::{SHHMessage, Envelope, full_bloom, topic_bloom, bloom_matches};
+
+/// Default minimum proof of work of accepted envelopes
+const DEFAULT_POW_REQUIREMENT: f64 = 0.2;
+
+#[derive(Debug, Clone, PartialEq)]
+/// Receiving message of SHH
+pub enum SHHReceiveMessage {
+    Connected {
+        node: H512,
+        version: usize,
+    },
+    /// A peer went away, see `RLPxReceiveMessage::Disconnected`
+    Disconnected {
+        node: H512,
+        reason: Option<DisconnectReason>,
+        clean: bool,
+    },
+    Normal {
+        node: H512,
+        version: usize,
+        data: SHHMessage,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// Sending message of SHH
+pub struct SHHSendMessage {
+    pub node: RLPxNode,
+    pub data: SHHMessage,
+}
+
+/// Proof of work requirement and bloom filter announced by a peer
+struct PeerFilter {
+    pow_requirement: f64,
+    bloom: H512,
+}
+
+/// Represent a Whisper stream over DevP2P protocol, advertising the
+/// shh/6 capability. Expired envelopes and envelopes below our proof
+/// of work requirement are dropped. Envelopes sent to
+/// `RLPxNode::All` only go to the peers whose requirement and bloom
+/// filter they meet.
+pub struct SHHStream {
+    stream: DevP2PStream,
+    pow_requirement: f64,
+    bloom: H512,
+    peers: HashMap<H512, PeerFilter>,
+}
+
+impl SHHStream {
+    /// Create a new SHH stream on the network set up by `network`
+    pub fn new(addr: &SocketAddr, public_addr: &IpAddr,
+               handle: &Handle, signer: Arc<dyn NodeSigner>,
+               network: DevP2PBuilder,
+    ) -> Result<Self, io::Error> {
+        Ok(SHHStream {
+            stream: network.capability(CapabilityInfo { name: "shh", version: 6, length: 128 })
+                .build(addr, public_addr, handle, signer)?,
+            pow_requirement: DEFAULT_POW_REQUIREMENT,
+            bloom: full_bloom(),
+            peers: HashMap::new(),
+        })
+    }
+
+    /// Force disconnecting a peer if it is already connected or about
+    /// to be connected, telling a connected peer the reason in a
+    /// Disconnect message
+    pub fn disconnect_peer(&mut self, remote_id: H512, reason: DisconnectReason) {
+        self.stream.disconnect_peer(remote_id, reason);
+    }
+
+    /// Active peers
+    pub fn active_peers(&self) -> &[H512] {
+        self.stream.active_peers()
+    }
+
+    /// Table of the connected peers, see `DevP2PStream::peer_table`
+    pub fn peer_table(&self) -> PeerTable {
+        self.stream.peer_table()
+    }
+
+    /// Summary of a connected peer, see `DevP2PStream::peer_info`
+    pub fn peer_info(&self, remote_id: H512) -> Option<PeerInfo> {
+        self.stream.peer_info(remote_id)
+    }
+
+    /// Summaries of all connected peers
+    pub fn peers(&self) -> Vec<PeerInfo> {
+        self.stream.peers()
+    }
+
+    /// Set the minimum proof of work of accepted envelopes, telling
+    /// connected peers about it
+    pub fn set_pow_requirement(&mut self, pow_requirement: f64) -> Result<(), io::Error> {
+        self.pow_requirement = pow_requirement;
+        self.start_send(SHHSendMessage {
+            node: RLPxNode::All,
+            data: SHHMessage::PowRequirement(pow_requirement),
+        })?;
+        self.poll_complete()?;
+        Ok(())
+    }
+
+    /// Set the bloom filter of the topics we are interested in,
+    /// telling connected peers about it
+    pub fn set_bloom_filter(&mut self, bloom: H512) -> Result<(), io::Error> {
+        self.bloom = bloom;
+        self.start_send(SHHSendMessage {
+            node: RLPxNode::All,
+            data: SHHMessage::BloomFilter(bloom),
+        })?;
+        self.poll_complete()?;
+        Ok(())
+    }
+
+    /// Envelopes we accept, dropping expired ones and ones below our
+    /// proof of work requirement
+    fn accepted(&self, envelopes: Vec<Envelope>) -> Vec<Envelope> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs()).unwrap_or(0);
+        envelopes.into_iter()
+            .filter(|envelope| envelope.expiry as u64 >= now)
+            .filter(|envelope| envelope.pow() >= self.pow_requirement)
+            .collect()
+    }
+}
+
+impl Stream for SHHStream {
+    type Item = SHHReceiveMessage;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        let result = match try_ready!(self.stream.poll()) {
+            Some(result) => result,
+            None => return Ok(Async::Ready(None)),
+        };
+
+        match result {
+            RLPxReceiveMessage::Connected { node, capabilities } => {
+                let version = match capabilities.iter().find(|cap| cap.name == "shh") {
+                    Some(cap) => cap.version,
+                    None => {
+                        debug!("connected a node without matching capability, ignoring.");
+                        return self.poll();
+                    },
+                };
+
+                self.peers.insert(node, PeerFilter {
+                    pow_requirement: 0.0,
+                    bloom: full_bloom(),
+                });
+                let status = SHHMessage::Status {
+                    protocol_version: version,
+                    pow_requirement: self.pow_requirement,
+                    bloom: self.bloom,
+                    light_node: false,
+                };
+                self.start_send(SHHSendMessage {
+                    node: RLPxNode::Peer(node),
+                    data: status,
+                })?;
+                self.poll_complete()?;
+
+                Ok(Async::Ready(Some(SHHReceiveMessage::Connected {
+                    node, version
+                })))
+            },
+            RLPxReceiveMessage::Disconnected { node, reason, clean } => {
+                self.peers.remove(&node);
+                Ok(Async::Ready(Some(SHHReceiveMessage::Disconnected {
+                    node, reason, clean
+                })))
+            },
+            RLPxReceiveMessage::Normal {
+                node, capability, id, data,
+            } => {
+                debug!("got shh message with id {}", id);
+                let message = match SHHMessage::decode(&UntrustedRlp::new(&data), id) {
+                    Ok(val) => val,
+                    Err(_) => {
+                        debug!("got an ununderstandable message with id {}, data {:?}, ignoring.", id, data);
+                        return self.poll();
+                    },
+                };
+
+                let message = match message {
+                    SHHMessage::Messages(envelopes) => {
+                        let envelopes = self.accepted(envelopes);
+                        if envelopes.is_empty() {
+                            return self.poll();
+                        }
+                        SHHMessage::Messages(envelopes)
+                    },
+                    message => {
+                        if let Some(peer) = self.peers.get_mut(&node) {
+                            match message {
+                                SHHMessage::Status { pow_requirement, bloom, .. } => {
+                                    peer.pow_requirement = pow_requirement;
+                                    peer.bloom = bloom;
+                                },
+                                SHHMessage::PowRequirement(pow_requirement) =>
+                                    peer.pow_requirement = pow_requirement,
+                                SHHMessage::BloomFilter(bloom) => peer.bloom = bloom,
+                                _ => (),
+                            }
+                        }
+                        message
+                    },
+                };
+
+                Ok(Async::Ready(Some(SHHReceiveMessage::Normal {
+                    node, version: capability.version,
+                    data: message,
+                })))
+            },
+        }
+    }
+}
+
+impl Sink for SHHStream {
+    type SinkItem = SHHSendMessage;
+    type SinkError = Error;
+
+    fn start_send(&mut self, val: SHHSendMessage) -> StartSend<Self::SinkItem, Self::SinkError> {
+        if let (&RLPxNode::All, SHHMessage::Messages(envelopes)) = (&val.node, &val.data) {
+            for (node, peer) in &self.peers {
+                let wanted: Vec<Envelope> = envelopes.iter()
+                    .filter(|envelope| bloom_matches(&peer.bloom, &envelope.topic))
+                    .filter(|envelope| envelope.pow() >= peer.pow_requirement)
+                    .cloned().collect();
+                if wanted.is_empty() {
+                    continue;
+                }
+                self.stream.start_send(RLPxSendMessage {
+                    node: RLPxNode::Peer(*node),
+                    capability_name: "shh",
+                    id: 0x01,
+                    data: rlp::encode(&SHHMessage::Messages(wanted)).to_vec(),
+                })?;
+            }
+            return Ok(AsyncSink::Ready);
+        }
+
+        match self.stream.start_send(RLPxSendMessage {
+            node: val.node,
+            capability_name: "shh",
+            id: val.data.id(),
+            data: rlp::encode(&val.data).to_vec(),
+        })? {
+            AsyncSink::Ready => Ok(AsyncSink::Ready),
+            AsyncSink::NotReady(_) => Ok(AsyncSink::NotReady(val)),
+        }
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
+        self.stream.poll_complete()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SHHStream, SHHMessage, SHHReceiveMessage, Envelope};
+    use super::super::{DevP2PConfig, DevP2PBuilder};
+    use bigint::H512;
+    use rlp;
+    use rlpx::{PeerStream, CapabilityInfo};
+    use secp256k1::SECP256K1;
+    use secp256k1::key::{PublicKey, SecretKey};
+    use tokio_core::reactor::Core;
+    use futures::{future, Async, Poll, Future, Stream, Sink};
+    use rand::os::OsRng;
+    use std::io;
+    use std::net::TcpListener;
+    use std::sync::Arc;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    fn pk2id(key: &PublicKey) -> H512 {
+        H512::from(&key.serialize_vec(&SECP256K1, false)[1..65])
+    }
+
+    #[test]
+    fn drops_envelopes_below_requirement() {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let config = DevP2PConfig {
+     ping_interval: Duration::new(600, 0),
+     ping_timeout_interval: Duration::new(700, 0),
+     optimal_peers_interval: Duration::new(600, 0),
+     max_peers_per_ip: None,
+     ..Default::default()
+        };
+
+        let addr = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let secret_key = SecretKey::new(&SECP256K1, &mut OsRng::new().unwrap());
+        let id = pk2id(&PublicKey::from_secret_key(&SECP256K1, &secret_key).unwrap());
+        let mut shh = SHHStream::new(&addr, &addr.ip(), &handle, Arc::new(secret_key),
+                                     DevP2PBuilder::from(config).client_version("shh".to_string())).unwrap();
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as u32;
+        let envelope = |topic: u8| Envelope {
+            expiry: now + 50, ttl: 50, topic: [topic; 4], data: vec![topic; 32], nonce: 0,
+        };
+        let mut strong = envelope(1);
+        assert!(strong.seal(0.2, 1000000));
+        let mut weak = envelope(2);
+        while weak.pow() >= 0.2 {
+            weak.nonce += 1;
+        }
+        let expired = Envelope { expiry: now - 1, ..strong.clone() };
+
+        let peer_key = SecretKey::new(&SECP256K1, &mut OsRng::new().unwrap());
+        let peer = PeerStream::connect(
+            &addr, &handle, Arc::new(peer_key), id,
+            5, "peer".to_string(), vec![CapabilityInfo { name: "shh", version: 6, length: 128 }],
+            0, Default::default());
+        let payload = rlp::encode(&SHHMessage::Messages(vec![weak, strong.clone(), expired])).to_vec();
+        let peer = peer.and_then(|peer| peer.send(("shh", 0x01, payload)));
+
+        let mut received = Vec::new();
+        let messages = future::poll_fn(|| -> Poll<(), io::Error> {
+            while received.len() < 2 {
+                match try_ready!(shh.poll()) {
+                    Some(message) => received.push(message),
+                    None => panic!(),
+                }
+            }
+            Ok(Async::Ready(()))
+        });
+        core.run(peer.join(messages)).unwrap();
+
+        match received[0] {
+            SHHReceiveMessage::Connected { version, .. } => assert_eq!(version, 6),
+            _ => panic!(),
+        }
+        match received[1] {
+            SHHReceiveMessage::Normal { ref data, .. } =>
+                assert_eq!(data, &SHHMessage::Messages(vec![strong])),
+            _ => panic!(),
+        }
+    }
+}
@@ -0,0 +1,245 @@
+use rlp::{Encodable, Decodable, RlpStream, DecoderError, UntrustedRlp};
+use bigint::H512;
+use sha3::{Digest, Keccak256};
+
+/// Size of the envelope fields other than the data, counted in the
+/// proof of work
+const ENVELOPE_HEADER_LENGTH: usize = 20;
+
+/// Bloom filter matching every topic
+pub fn full_bloom() -> H512 {
+    H512::from(&[0xffu8; 64][..])
+}
+
+/// Bloom filter of a single topic
+pub fn topic_bloom(topic: &[u8; 4]) -> H512 {
+    let mut bloom = [0u8; 64];
+    for j in 0..3 {
+        let index = topic[j] as usize | (((topic[3] >> j) & 1) as usize) << 8;
+        bloom[index / 8] |= 1 << (index % 8);
+    }
+    H512::from(&bloom[..])
+}
+
+/// Whether a peer with the given bloom filter wants the topic
+pub fn bloom_matches(filter: &H512, topic: &[u8; 4]) -> bool {
+    let topic = topic_bloom(topic);
+    filter.iter().zip(topic.iter()).all(|(f, t)| f & t == *t)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// Whisper envelope carrying one encrypted message
+pub struct Envelope {
+    /// Unix time in seconds after which the envelope is dropped
+    pub expiry: u32,
+    /// Time to live in seconds
+    pub ttl: u32,
+    pub topic: [u8; 4],
+    pub data: Vec<u8>,
+    pub nonce: u64,
+}
+
+impl Envelope {
+    /// Proof of work of the envelope: 2 to the number of leading zero
+    /// bits of its hash, divided by its size and time to live
+    pub fn pow(&self) -> f64 {
+        let mut s = RlpStream::new_list(4);
+        s.append(&self.expiry);
+        s.append(&self.ttl);
+        s.append(&&self.topic[..]);
+        s.append(&self.data);
+        let mut hasher = Keccak256::new();
+        hasher.input(&s.out());
+        let seed = hasher.result();
+
+        let mut nonce = [0u8; 32];
+        nonce[24..].copy_from_slice(&self.nonce.to_be_bytes());
+        let mut hasher = Keccak256::new();
+        hasher.input(&seed);
+        hasher.input(&nonce);
+        let hash = hasher.result();
+
+        let mut zeros = 0;
+        for &byte in hash.iter() {
+            zeros += byte.leading_zeros();
+            if byte != 0 {
+                break;
+            }
+        }
+        let size = (ENVELOPE_HEADER_LENGTH + self.data.len()) as f64;
+        2f64.powi(zeros as i32) / size / self.ttl.max(1) as f64
+    }
+
+    /// Search for a nonce giving at least `target` proof of work,
+    /// trying at most `max_tries` of them. Returns whether one was
+    /// found.
+    pub fn seal(&mut self, target: f64, max_tries: u64) -> bool {
+        for nonce in 0..max_tries {
+            self.nonce = nonce;
+            if self.pow() >= target {
+                return true;
+            }
+        }
+        false
+    }
+}
+
+impl Encodable for Envelope {
+    fn rlp_append(&self, s: &mut RlpStream) {
+        s.begin_list(5);
+        s.append(&self.expiry);
+        s.append(&self.ttl);
+        s.append(&&self.topic[..]);
+        s.append(&self.data);
+        s.append(&self.nonce);
+    }
+}
+
+impl Decodable for Envelope {
+    fn decode(rlp: &UntrustedRlp) -> Result<Self, DecoderError> {
+        let topic_rlp = rlp.at(2)?;
+        let topic_data = topic_rlp.data()?;
+        if topic_data.len() < 4 {
+            return Err(DecoderError::RlpIsTooShort);
+        }
+        if topic_data.len() > 4 {
+            return Err(DecoderError::RlpIsTooBig);
+        }
+        let mut topic = [0u8; 4];
+        topic.copy_from_slice(topic_data);
+
+        Ok(Envelope {
+            expiry: rlp.val_at(0)?,
+            ttl: rlp.val_at(1)?,
+            topic,
+            data: rlp.val_at(3)?,
+            nonce: rlp.val_at(4)?,
+        })
+    }
+}
+
+/// SHH message version 6. Proof of work requirements are sent as the
+/// bits of a 64-bit float.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SHHMessage {
+    Status {
+        protocol_version: usize,
+        pow_requirement: f64,
+        bloom: H512,
+        light_node: bool,
+    },
+    Messages(Vec<Envelope>),
+    PowRequirement(f64),
+    BloomFilter(H512),
+}
+
+impl SHHMessage {
+    /// Get the message id of the SHH message
+    pub fn id(&self) -> usize {
+        match *self {
+            SHHMessage::Status { .. } => 0x00,
+            SHHMessage::Messages(_) => 0x01,
+            SHHMessage::PowRequirement(_) => 0x02,
+            SHHMessage::BloomFilter(_) => 0x03,
+        }
+    }
+
+    /// Decode a RLP into SHH message using the given message id
+    pub fn decode(rlp: &UntrustedRlp, id: usize) -> Result<Self, DecoderError> {
+        Ok(match id {
+            0x00 => {
+                // Everything but the version is optional
+                let count = rlp.item_count()?;
+                let pow_requirement: u64 = if count > 1 { rlp.val_at(1)? } else { 0 };
+                let light_node: u32 = if count > 3 { rlp.val_at(3)? } else { 0 };
+                SHHMessage::Status {
+                    protocol_version: rlp.val_at(0)?,
+                    pow_requirement: f64::from_bits(pow_requirement),
+                    bloom: if count > 2 { rlp.val_at(2)? } else { full_bloom() },
+                    light_node: light_node != 0,
+                }
+            },
+            0x01 => SHHMessage::Messages(rlp.as_list()?),
+            0x02 => {
+                let pow_requirement: u64 = rlp.as_val()?;
+                SHHMessage::PowRequirement(f64::from_bits(pow_requirement))
+            },
+            0x03 => SHHMessage::BloomFilter(rlp.as_val()?),
+            _ => return Err(DecoderError::Custom("unsupported shh message id")),
+        })
+    }
+}
+
+impl Encodable for SHHMessage {
+    fn rlp_append(&self, s: &mut RlpStream) {
+        match *self {
+            SHHMessage::Status { protocol_version, pow_requirement, bloom, light_node } => {
+                s.begin_list(4);
+                s.append(&protocol_version);
+                s.append(&pow_requirement.to_bits());
+                s.append(&bloom);
+                s.append(&if light_node { 1u32 } else { 0u32 });
+            },
+            SHHMessage::Messages(ref envelopes) => {
+                s.append_list(envelopes);
+            },
+            SHHMessage::PowRequirement(pow_requirement) => {
+                s.append(&pow_requirement.to_bits());
+            },
+            SHHMessage::BloomFilter(bloom) => {
+                s.append(&bloom);
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SHHMessage, Envelope, topic_bloom, bloom_matches, full_bloom};
+    use rlp::{self, UntrustedRlp};
+    use bigint::H512;
+
+    fn envelope() -> Envelope {
+        Envelope {
+            expiry: 1500000000, ttl: 50, topic: [0x01, 0x02, 0x03, 0x04],
+            data: vec![0x42; 64], nonce: 0,
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let messages = vec![
+            SHHMessage::Status {
+                protocol_version: 6, pow_requirement: 0.2, bloom: topic_bloom(&[0, 1, 2, 3]),
+                light_node: true,
+            },
+            SHHMessage::Messages(vec![envelope(), envelope()]),
+            SHHMessage::PowRequirement(2.5),
+            SHHMessage::BloomFilter(full_bloom()),
+        ];
+
+        for message in messages {
+            let encoded = rlp::encode(&message);
+            assert_eq!(SHHMessage::decode(&UntrustedRlp::new(&encoded), message.id()).unwrap(), message);
+        }
+    }
+
+    #[test]
+    fn seal_reaches_target() {
+        let mut envelope = envelope();
+        assert!(envelope.seal(0.01, 100000));
+        assert!(envelope.pow() >= 0.01);
+        assert_eq!(envelope.pow(), envelope.clone().pow());
+    }
+
+    #[test]
+    fn bloom_filtering() {
+        let topic = [0xab, 0xcd, 0xef, 0x05];
+        let bloom = topic_bloom(&topic);
+        assert_eq!(bloom.iter().map(|b| b.count_ones()).sum::<u32>(), 3);
+        assert!(bloom_matches(&bloom, &topic));
+        assert!(bloom_matches(&full_bloom(), &topic));
+        assert!(!bloom_matches(&H512::zero(), &topic));
+        assert!(!bloom_matches(&bloom, &[0x00, 0x00, 0x00, 0x00]));
+    }
+}
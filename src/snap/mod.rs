@@ -0,0 +1,294 @@
+mod proto;
+mod proof;
+mod provider;
+mod sync;
+
+use futures::{StartSend, Async, Poll, Stream, Sink, AsyncSink};
+use rlp::{self, UntrustedRlp};
+use bigint::H512;
+use rlpx::{RLPxSendMessage, RLPxNode, CapabilityInfo, DisconnectReason, PeerInfo, PeerTable};
+use std::collections::{HashMap, VecDeque};
+
+use super::{ETHStream, ETHReceiveMessage, Error};
+
+pub use self::proto::SNAPMessage;
+pub use self::provider::{SnapProvider, SOFT_RESPONSE_LIMIT};
+pub use self::sync::{SnapSync, SnapSyncEvent, AccountRange, DEFAULT_RESPONSE_BYTES};
+use self::provider::serve_snap;
+
+/// The snap capability run next to eth
+const SNAP_CAPABILITY: CapabilityInfo = CapabilityInfo { name: "snap", version: 1, length: 8 };
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// Receiving message of SNAP
+pub enum SNAPReceiveMessage {
+    /// A peer sharing eth shares snap too, returned right after the
+    /// eth `Connected` message
+    Connected {
+        node: H512,
+        version: usize,
+    },
+    /// A snap peer went away, see `RLPxReceiveMessage::Disconnected`
+    Disconnected {
+        node: H512,
+        reason: Option<DisconnectReason>,
+        clean: bool,
+    },
+    Normal {
+        node: H512,
+        version: usize,
+        data: SNAPMessage,
+    },
+    /// A message of the underlying ETH stream
+    Eth(Box<ETHReceiveMessage>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// Sending message of SNAP
+pub struct SNAPSendMessage {
+    pub node: RLPxNode,
+    pub data: SNAPMessage,
+}
+
+/// Represent a SNAP stream, running snap/1 next to eth on an ETH
+/// stream as clients only serve snap to peers that also run eth. The
+/// ETH stream does the Status handshake and its messages are returned
+/// too.
+pub struct SNAPStream {
+    eth: ETHStream,
+    provider: Option<Box<dyn SnapProvider>>,
+    snap_peers: HashMap<H512, usize>,
+    pending: VecDeque<SNAPReceiveMessage>,
+}
+
+impl SNAPStream {
+    /// Create a new SNAP stream on top of an ETH stream, advertising
+    /// snap/1 to the connections it establishes afterwards
+    pub fn new(mut eth: ETHStream) -> Self {
+        eth.add_subprotocol(SNAP_CAPABILITY);
+        SNAPStream {
+            eth,
+            provider: None,
+            snap_peers: HashMap::new(),
+            pending: VecDeque::new(),
+        }
+    }
+
+    /// Underlying ETH stream
+    pub fn eth(&self) -> &ETHStream {
+        &self.eth
+    }
+
+    /// Underlying ETH stream, to send eth messages or configure it
+    pub fn eth_mut(&mut self) -> &mut ETHStream {
+        &mut self.eth
+    }
+
+    /// Force disconnecting a peer if it is already connected or about
+    /// to be connected, telling a connected peer the reason in a
+    /// Disconnect message
+    pub fn disconnect_peer(&mut self, remote_id: H512, reason: DisconnectReason) {
+        self.eth.disconnect_peer(remote_id, reason);
+    }
+
+    /// Answer snap requests automatically from the given provider.
+    /// Requests answered this way are not returned by the stream.
+    pub fn set_snap_provider(&mut self, provider: Box<dyn SnapProvider>) {
+        self.provider = Some(provider);
+    }
+
+    /// Active peers
+    pub fn active_peers(&self) -> &[H512] {
+        self.eth.active_peers()
+    }
+
+    /// Table of the connected peers, see `DevP2PStream::peer_table`
+    pub fn peer_table(&self) -> PeerTable {
+        self.eth.peer_table()
+    }
+
+    /// Summary of a connected peer, see `DevP2PStream::peer_info`
+    pub fn peer_info(&self, remote_id: H512) -> Option<PeerInfo> {
+        self.eth.connection_info(remote_id)
+    }
+
+    /// Summaries of all connected peers
+    pub fn peers(&self) -> Vec<PeerInfo> {
+        self.eth.connections()
+    }
+
+    /// snap version negotiated with a connected peer
+    pub fn peer_version(&self, node: H512) -> Option<usize> {
+        self.snap_peers.get(&node).cloned()
+    }
+}
+
+impl Stream for SNAPStream {
+    type Item = SNAPReceiveMessage;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        if let Some(message) = self.pending.pop_front() {
+            return Ok(Async::Ready(Some(message)));
+        }
+        let message = match try_ready!(self.eth.poll()) {
+            Some(message) => message,
+            None => return Ok(Async::Ready(None)),
+        };
+
+        match message {
+            ETHReceiveMessage::Connected { node, .. } => {
+                let version = self.eth.connection_info(node).and_then(|info| {
+                    info.capabilities.iter().find(|cap| cap.name == "snap").map(|cap| cap.version)
+                });
+                if let Some(version) = version {
+                    self.snap_peers.insert(node, version);
+                    self.pending.push_back(SNAPReceiveMessage::Connected { node, version });
+                }
+            },
+            ETHReceiveMessage::Disconnected { node, reason, clean } if self.snap_peers.contains_key(&node) => {
+                self.snap_peers.remove(&node);
+                self.pending.push_back(SNAPReceiveMessage::Disconnected { node, reason, clean });
+            },
+            ETHReceiveMessage::Subprotocol { node, ref capability, id, ref data } if capability.name == "snap" => {
+                debug!("got snap message with id {}", id);
+                let message = match SNAPMessage::decode(&UntrustedRlp::new(data), id) {
+                    Ok(val) => val,
+                    Err(_) => {
+                        debug!("got an ununderstandable message with id {}, data {:?}, ignoring.", id, data);
+                        return self.poll();
+                    },
+                };
+                let response = match self.provider {
+                    Some(ref provider) => serve_snap(&**provider, &message),
+                    None => None,
+                };
+                if let Some(response) = response {
+                    debug!("answering snap request from {:x}", node);
+                    self.start_send(SNAPSendMessage {
+                        node: RLPxNode::Peer(node),
+                        data: response,
+                    })?;
+                    self.poll_complete()?;
+                    return self.poll();
+                }
+                return Ok(Async::Ready(Some(SNAPReceiveMessage::Normal {
+                    node, version: capability.version,
+                    data: message,
+                })));
+            },
+            _ => (),
+        }
+        Ok(Async::Ready(Some(SNAPReceiveMessage::Eth(Box::new(message)))))
+    }
+}
+
+impl Sink for SNAPStream {
+    type SinkItem = SNAPSendMessage;
+    type SinkError = Error;
+
+    fn start_send(&mut self, val: SNAPSendMessage) -> StartSend<Self::SinkItem, Self::SinkError> {
+        match self.eth.start_send_subprotocol(RLPxSendMessage {
+            node: val.node,
+            capability_name: "snap",
+            id: val.data.id(),
+            data: rlp::encode(&val.data).to_vec(),
+        })? {
+            AsyncSink::Ready => Ok(AsyncSink::Ready),
+            AsyncSink::NotReady(_) => Ok(AsyncSink::NotReady(val)),
+        }
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
+        self.eth.poll_complete()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SNAPStream, SNAPMessage, SNAPReceiveMessage};
+    use super::super::{DevP2PConfig, DevP2PBuilder, ChainConfig, ETHStream, ETHReceiveMessage};
+    use eth::ids;
+    use bigint::{H256, H512, U256};
+    use rlp;
+    use rlpx::{PeerStream, CapabilityInfo};
+    use secp256k1::SECP256K1;
+    use secp256k1::key::{PublicKey, SecretKey};
+    use tokio_core::reactor::Core;
+    use futures::{future, Async, Poll, Future, Stream, Sink};
+    use rand::os::OsRng;
+    use std::io;
+    use std::net::TcpListener;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    fn pk2id(key: &PublicKey) -> H512 {
+        H512::from(&key.serialize_vec(&SECP256K1, false)[1..65])
+    }
+
+    #[test]
+    fn receives_snap_request() {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let config = DevP2PConfig {
+     ping_interval: Duration::new(600, 0),
+     ping_timeout_interval: Duration::new(700, 0),
+     optimal_peers_interval: Duration::new(600, 0),
+     max_peers_per_ip: None,
+     ..Default::default()
+        };
+
+        let addr = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let secret_key = SecretKey::new(&SECP256K1, &mut OsRng::new().unwrap());
+        let id = pk2id(&PublicKey::from_secret_key(&SECP256K1, &secret_key).unwrap());
+        let genesis_hash = H256::random();
+        let chain = ChainConfig { network_id: 61, genesis_hash, best_hash: genesis_hash, total_difficulty: U256::from(1) };
+        let eth = ETHStream::new(&addr, &addr.ip(), &handle, Arc::new(secret_key), chain,
+                                 DevP2PBuilder::from(config).client_version("snap".to_string())).unwrap();
+        let mut snap = SNAPStream::new(eth);
+
+        let peer_key = SecretKey::new(&SECP256K1, &mut OsRng::new().unwrap());
+        let peer = PeerStream::connect(
+            &addr, &handle, Arc::new(peer_key), id,
+            5, "peer".to_string(),
+            vec![CapabilityInfo { name: "eth", version: 68, length: ids::length(68) },
+                 CapabilityInfo { name: "snap", version: 1, length: 8 }],
+            0, Default::default());
+        let request = SNAPMessage::GetByteCodes {
+            request_id: 9, hashes: vec![H256::random()], response_bytes: 1024,
+        };
+        let payload = rlp::encode(&request).to_vec();
+        let peer = peer.and_then(|peer| peer.send(("snap", 0x04, payload)));
+
+        let mut received = Vec::new();
+        let messages = future::poll_fn(|| -> Poll<(), io::Error> {
+            while received.len() < 3 {
+                match try_ready!(snap.poll()) {
+                    Some(message) => received.push(message),
+                    None => panic!(),
+                }
+            }
+            Ok(Async::Ready(()))
+        });
+        core.run(peer.join(messages)).unwrap();
+
+        match received[0] {
+            SNAPReceiveMessage::Eth(ref message) => match **message {
+                ETHReceiveMessage::Connected { version, .. } => assert_eq!(version, 68),
+                ref message => panic!("unexpected message {:?}", message),
+            },
+            ref message => panic!("unexpected message {:?}", message),
+        }
+        match received[1] {
+            SNAPReceiveMessage::Connected { node, version } => {
+                assert_eq!(version, 1);
+                assert_eq!(snap.peer_version(node), Some(1));
+            },
+            ref message => panic!("unexpected message {:?}", message),
+        }
+        match received[2] {
+            SNAPReceiveMessage::Normal { ref data, .. } => assert_eq!(data, &request),
+            ref message => panic!("unexpected message {:?}", message),
+        }
+    }
+}
@@ -0,0 +1,449 @@
+use rlp::{RlpStream, UntrustedRlp};
+use bigint::H256;
+use sha3::{Digest, Keccak256};
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::io;
+
+/// Number of nibbles of a key
+const KEY_NIBBLES: usize = 64;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// Trie node with its path segments as nibbles. Subtries that were
+/// not resolved from the proof are kept as their hash.
+enum Node {
+    Empty,
+    Leaf(Vec<u8>, Vec<u8>),
+    Extension(Vec<u8>, Box<Node>),
+    Branch(Vec<Node>, Option<Vec<u8>>),
+    Hash(H256),
+}
+
+/// Where the subtrie at a path lies relative to the range being proven
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Position {
+    Left,
+    Edge,
+    Inside,
+    Right,
+}
+
+fn keccak(data: &[u8]) -> H256 {
+    H256::from(Keccak256::digest(data).as_slice())
+}
+
+fn invalid(message: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn nibbles(key: &H256) -> Vec<u8> {
+    key.iter().flat_map(|byte| vec![byte >> 4, byte & 0x0f]).collect()
+}
+
+fn position(path: &[u8], left: &[u8], right: &[u8]) -> Position {
+    let length = ::std::cmp::min(path.len(), KEY_NIBBLES);
+    if left.starts_with(path) || right.starts_with(path) {
+        Position::Edge
+    } else if path[..length].cmp(&left[..length]) == Ordering::Less {
+        Position::Left
+    } else if path[..length].cmp(&right[..length]) == Ordering::Greater {
+        Position::Right
+    } else {
+        Position::Inside
+    }
+}
+
+/// Path segment and whether it ends in a leaf, from its hex prefix
+/// encoding
+fn decode_path(encoded: &[u8]) -> Result<(Vec<u8>, bool), io::Error> {
+    let flags = match encoded.first() {
+        Some(&first) if first >> 4 <= 3 => first >> 4,
+        _ => return Err(invalid("invalid trie node path")),
+    };
+    let mut path = Vec::with_capacity(encoded.len() * 2);
+    if flags & 1 == 1 {
+        path.push(encoded[0] & 0x0f);
+    } else if encoded[0] & 0x0f != 0 {
+        return Err(invalid("invalid trie node path"));
+    }
+    for byte in &encoded[1..] {
+        path.push(byte >> 4);
+        path.push(byte & 0x0f);
+    }
+    Ok((path, flags & 2 == 2))
+}
+
+fn encode_path(path: &[u8], leaf: bool) -> Vec<u8> {
+    let flags = if leaf { 2 } else { 0 } + (path.len() % 2) as u8;
+    let mut encoded = vec![flags << 4];
+    let mut rest = path;
+    if path.len() % 2 == 1 {
+        encoded[0] |= path[0];
+        rest = &path[1..];
+    }
+    for pair in rest.chunks(2) {
+        encoded.push(pair[0] << 4 | pair[1]);
+    }
+    encoded
+}
+
+fn common_prefix(a: &[u8], b: &[u8]) -> usize {
+    a.iter().zip(b.iter()).take_while(|&(x, y)| x == y).count()
+}
+
+impl Node {
+    fn encode(&self) -> Vec<u8> {
+        let mut s = RlpStream::new();
+        match *self {
+            Node::Empty => { s.append_empty_data(); },
+            Node::Leaf(ref path, ref value) => {
+                s.begin_list(2);
+                s.append(&encode_path(path, true));
+                s.append(value);
+            },
+            Node::Extension(ref path, ref child) => {
+                s.begin_list(2);
+                s.append(&encode_path(path, false));
+                child.append_reference(&mut s);
+            },
+            Node::Branch(ref children, ref value) => {
+                s.begin_list(17);
+                for child in children {
+                    child.append_reference(&mut s);
+                }
+                match *value {
+                    Some(ref value) => { s.append(value); },
+                    None => { s.append_empty_data(); },
+                }
+            },
+            Node::Hash(ref hash) => { s.append(hash); },
+        }
+        s.out().to_vec()
+    }
+
+    /// Append the reference to this node from its parent: its
+    /// encoding when shorter than a hash, its hash otherwise
+    fn append_reference(&self, s: &mut RlpStream) {
+        match *self {
+            Node::Empty => { s.append_empty_data(); },
+            Node::Hash(ref hash) => { s.append(hash); },
+            _ => {
+                let encoded = self.encode();
+                if encoded.len() < 32 {
+                    s.append_raw(&encoded, 1);
+                } else {
+                    s.append(&keccak(&encoded));
+                }
+            },
+        }
+    }
+
+    fn insert(self, path: &[u8], value: Vec<u8>) -> Result<Node, io::Error> {
+        Ok(match self {
+            Node::Empty => Node::Leaf(path.to_vec(), value),
+            Node::Leaf(leaf_path, leaf_value) => {
+                if leaf_path == path {
+                    return Err(invalid("duplicate trie leaf"));
+                }
+                let common = common_prefix(&leaf_path, path);
+                let branch = Node::Branch(vec![Node::Empty; 16], None)
+                    .insert(&leaf_path[common..], leaf_value)?
+                    .insert(&path[common..], value)?;
+                Node::extend(&path[..common], branch)
+            },
+            Node::Extension(extension_path, child) => {
+                let common = common_prefix(&extension_path, path);
+                if common == extension_path.len() {
+                    return Ok(Node::Extension(extension_path, Box::new(child.insert(&path[common..], value)?)));
+                }
+                let mut children = vec![Node::Empty; 16];
+                children[extension_path[common] as usize] = Node::extend(&extension_path[common + 1..], *child);
+                let branch = Node::Branch(children, None).insert(&path[common..], value)?;
+                Node::extend(&path[..common], branch)
+            },
+            Node::Branch(mut children, branch_value) => {
+                match path.split_first() {
+                    Some((&nibble, rest)) => {
+                        let child = ::std::mem::replace(&mut children[nibble as usize], Node::Empty);
+                        children[nibble as usize] = child.insert(rest, value)?;
+                    },
+                    None => return Err(invalid("trie leaf in a branch")),
+                }
+                Node::Branch(children, branch_value)
+            },
+            Node::Hash(_) => return Err(invalid("leaf outside the proven range")),
+        })
+    }
+
+    /// Node under an extension with the given path, if any
+    fn extend(path: &[u8], node: Node) -> Node {
+        if path.is_empty() {
+            node
+        } else {
+            Node::Extension(path.to_vec(), Box::new(node))
+        }
+    }
+
+    /// Whether the trie has a leaf after the given key
+    fn has_right(&self, path: &[u8], key: &[u8]) -> bool {
+        match *self {
+            Node::Empty => false,
+            Node::Hash(_) => true,
+            Node::Leaf(ref leaf_path, _) => [path, leaf_path].concat().as_slice() > key,
+            Node::Extension(ref extension_path, ref child) => {
+                let full = [path, extension_path].concat();
+                if key.starts_with(&full) {
+                    child.has_right(&full, key)
+                } else {
+                    position(&full, key, key) == Position::Right
+                }
+            },
+            Node::Branch(ref children, _) => {
+                let nibble = key[path.len()] as usize;
+                children[nibble + 1..].iter().any(|child| *child != Node::Empty) ||
+                    children[nibble].has_right(&[path, &[nibble as u8]].concat(), key)
+            },
+        }
+    }
+}
+
+/// Trie nodes of a proof by hash, along the paths of the edges of a
+/// range
+struct PartialTrie<'a> {
+    proof: HashMap<H256, &'a [u8]>,
+    left: Vec<u8>,
+    right: Vec<u8>,
+}
+
+impl<'a> PartialTrie<'a> {
+    /// Node from its encoding, at the given path. Subtries on the
+    /// edges are resolved from the proof, subtries strictly between
+    /// the edges dropped, and the others kept as they are.
+    fn build(&self, encoded: &[u8], path: &[u8]) -> Result<Node, io::Error> {
+        let rlp = UntrustedRlp::new(encoded);
+        match rlp.item_count().map_err(|_| invalid("invalid trie node"))? {
+            2 => {
+                let (segment, leaf) = decode_path(rlp.at(0).and_then(|item| item.data())
+                                                  .map_err(|_| invalid("invalid trie node"))?)?;
+                let full = [path, &segment].concat();
+                let position = position(&full, &self.left, &self.right);
+                if leaf {
+                    let value = rlp.val_at(1).map_err(|_| invalid("invalid trie node"))?;
+                    let in_range = full.len() == KEY_NIBBLES &&
+                        full >= self.left && full <= self.right;
+                    return Ok(if in_range { Node::Empty } else { Node::Leaf(segment, value) });
+                }
+                // Extensions lead to branches, which are above the keys
+                if full.len() >= KEY_NIBBLES {
+                    return Err(invalid("trie path too long"));
+                }
+                if position == Position::Inside {
+                    return Ok(Node::Empty);
+                }
+                let child = rlp.at(1).map_err(|_| invalid("invalid trie node"))?;
+                Ok(Node::Extension(segment, Box::new(self.child(&child, &full)?)))
+            },
+            17 if path.len() < KEY_NIBBLES => {
+                let mut children = Vec::with_capacity(16);
+                for nibble in 0..16u8 {
+                    let child = rlp.at(nibble as usize).map_err(|_| invalid("invalid trie node"))?;
+                    children.push(self.child(&child, &[path, &[nibble]].concat())?);
+                }
+                let value = rlp.at(16).and_then(|item| item.data()).map_err(|_| invalid("invalid trie node"))?;
+                Ok(Node::Branch(children, if value.is_empty() { None } else { Some(value.to_vec()) }))
+            },
+            _ => Err(invalid("invalid trie node")),
+        }
+    }
+
+    /// Child of a node at the given path from its reference
+    fn child(&self, reference: &UntrustedRlp, path: &[u8]) -> Result<Node, io::Error> {
+        if reference.is_empty() {
+            return Ok(Node::Empty);
+        }
+        if reference.is_list() {
+            return self.build(reference.as_raw(), path);
+        }
+        let hash: H256 = reference.as_val().map_err(|_| invalid("invalid trie node reference"))?;
+        match position(path, &self.left, &self.right) {
+            Position::Inside => Ok(Node::Empty),
+            Position::Left | Position::Right => Ok(Node::Hash(hash)),
+            Position::Edge => match self.proof.get(&hash) {
+                Some(encoded) => self.build(encoded, path),
+                None => Err(invalid("range proof misses a trie node")),
+            },
+        }
+    }
+}
+
+/// Verify a range of leaves with a proof of its first and last key
+/// against the root of the trie they belong to, as Geth's
+/// `VerifyRangeProof`. The proof is of the request origin on the left
+/// and of the last leaf on the right, or of the origin on both sides
+/// when the range is empty. The leaves must be ordered, start at or
+/// after `origin` and leave out none of the leaves between their
+/// edges. Returns whether the trie has leaves after the range.
+pub fn verify_range_proof(
+    root: H256, origin: H256, leaves: &[(H256, Vec<u8>)], proof: &[Vec<u8>]
+) -> Result<bool, io::Error> {
+    let left = nibbles(&origin);
+    let right = match leaves.last() {
+        Some(&(last, _)) => nibbles(&last),
+        None => left.clone(),
+    };
+    let trie = PartialTrie {
+        proof: proof.iter().map(|node| (keccak(node), &node[..])).collect(),
+        left, right,
+    };
+
+    let encoded = trie.proof.get(&root).ok_or_else(|| invalid("range proof misses the root"))?;
+    let mut node = trie.build(encoded, &[])?;
+    for &(key, ref value) in leaves {
+        if value.is_empty() {
+            return Err(invalid("empty trie leaf"));
+        }
+        node = node.insert(&nibbles(&key), value.clone())?;
+    }
+    if keccak(&node.encode()) != root {
+        return Err(invalid("range does not match the root"));
+    }
+
+    let more = node.has_right(&[], &trie.right);
+    if leaves.is_empty() && more {
+        return Err(invalid("empty range with leaves after its origin"));
+    }
+    Ok(more)
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::{Node, verify_range_proof, nibbles, keccak, encode_path, decode_path};
+    use bigint::{H256, U256};
+    use rlp::UntrustedRlp;
+    use trie::MemoryTrie;
+    use std::collections::HashMap;
+
+    /// Trie holding the given leaves
+    fn build(leaves: &[(H256, Vec<u8>)]) -> Node {
+        leaves.iter().fold(Node::Empty, |node, &(key, ref value)| {
+            node.insert(&nibbles(&key), value.clone()).unwrap()
+        })
+    }
+
+    /// Nodes on the path of a key stored by hash, as proofs hold them
+    fn prove(node: &Node, key: &H256, proof: &mut Vec<Vec<u8>>) {
+        let path = nibbles(key);
+        let mut node = node;
+        let mut depth = 0;
+        loop {
+            let encoded = node.encode();
+            if (depth == 0 || encoded.len() >= 32) && !proof.contains(&encoded) {
+                proof.push(encoded);
+            }
+            node = match *node {
+                Node::Extension(ref segment, ref child) if path[depth..].starts_with(segment) => {
+                    depth += segment.len();
+                    child
+                },
+                Node::Branch(ref children, _) => {
+                    depth += 1;
+                    &children[path[depth - 1] as usize]
+                },
+                _ => return,
+            };
+        }
+    }
+
+    /// Proof of the range of the given leaves from `origin` to the
+    /// last one
+    pub fn range_proof(leaves: &[(H256, Vec<u8>)], origin: H256, last: H256) -> Vec<Vec<u8>> {
+        let trie = build(leaves);
+        let mut proof = Vec::new();
+        prove(&trie, &origin, &mut proof);
+        prove(&trie, &last, &mut proof);
+        proof
+    }
+
+    fn leaves(count: u8) -> Vec<(H256, Vec<u8>)> {
+        let mut leaves: Vec<(H256, Vec<u8>)> = (0..count)
+            .map(|i| (keccak(&[i]), vec![0x80 + i; 1 + i as usize % 40]))
+            .collect();
+        leaves.sort();
+        leaves
+    }
+
+    #[test]
+    fn paths_encoded() {
+        for path in &[vec![], vec![1], vec![1, 2], vec![0, 15, 3]] {
+            for &leaf in &[false, true] {
+                assert_eq!(decode_path(&encode_path(path, leaf)).unwrap(), (path.clone(), leaf));
+            }
+        }
+        assert!(decode_path(&[0x41]).is_err());
+    }
+
+    #[test]
+    fn root_matches_trie() {
+        let leaves = leaves(50);
+        let mut trie = MemoryTrie::empty(HashMap::new());
+        for &(key, ref value) in &leaves {
+            trie.insert_raw(key.to_vec(), value.clone());
+        }
+        assert_eq!(keccak(&build(&leaves).encode()), trie.root());
+        let encoded = build(&leaves).encode();
+        assert_eq!(UntrustedRlp::new(&encoded).item_count().unwrap(), 17);
+    }
+
+    #[test]
+    fn ranges_verified() {
+        let all = leaves(100);
+        let root = keccak(&build(&all).encode());
+
+        // Middle, start and end of the trie
+        for &(start, end) in &[(10, 40), (0, 5), (90, 100), (0, 100), (42, 43)] {
+            let origin = all[start].0;
+            let proof = range_proof(&all, origin, all[end - 1].0);
+            assert_eq!(verify_range_proof(root, origin, &all[start..end], &proof).unwrap(), end < 100);
+        }
+
+        // An origin before the first leaf, which does not exist
+        let origin = H256::from(U256::from(all[20].0) - 1.into());
+        let proof = range_proof(&all, origin, all[29].0);
+        assert!(verify_range_proof(root, origin, &all[20..30], &proof).unwrap());
+        // ... with the leaf before the origin left out
+        let proof = range_proof(&all, all[19].0, all[29].0);
+        assert!(verify_range_proof(root, all[19].0, &all[20..30], &proof).is_err());
+
+        // No leaves after the origin
+        let origin = H256::from(U256::max_value());
+        let proof = range_proof(&all, origin, origin);
+        assert!(!verify_range_proof(root, origin, &[], &proof).unwrap());
+        let proof = range_proof(&all, all[50].0, all[50].0);
+        assert!(verify_range_proof(root, all[50].0, &[], &proof).is_err());
+    }
+
+    #[test]
+    fn tampered_ranges_rejected() {
+        let all = leaves(100);
+        let root = keccak(&build(&all).encode());
+        let proof = range_proof(&all, all[10].0, all[39].0);
+        assert!(verify_range_proof(root, all[10].0, &all[10..40], &proof).is_ok());
+
+        // A leaf removed from the middle of the range
+        let mut gapped = all[10..40].to_vec();
+        gapped.remove(15);
+        assert!(verify_range_proof(root, all[10].0, &gapped, &proof).is_err());
+
+        // A leaf with another value
+        let mut changed = all[10..40].to_vec();
+        changed[15].1 = vec![0x42];
+        assert!(verify_range_proof(root, all[10].0, &changed, &proof).is_err());
+
+        // An extra leaf, and a proof missing a node
+        let mut extra = all[10..40].to_vec();
+        extra.insert(15, (H256::from(U256::from(extra[14].0) + 1.into()), vec![0x42]));
+        assert!(verify_range_proof(root, all[10].0, &extra, &proof).is_err());
+        assert!(verify_range_proof(root, all[10].0, &all[10..40], &proof[..proof.len() - 1]).is_err());
+        assert!(verify_range_proof(root, all[10].0, &all[10..40], &proof[1..]).is_err());
+    }
+}
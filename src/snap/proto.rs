@@ -0,0 +1,299 @@
+use rlp::{Encodable, RlpStream, DecoderError, UntrustedRlp};
+use bigint::H256;
+
+/// SNAP message version 1. Every message starts with the id of the
+/// request it is or answers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SNAPMessage {
+    GetAccountRange {
+        request_id: u64,
+        root_hash: H256,
+        starting_hash: H256,
+        limit_hash: H256,
+        response_bytes: u64,
+    },
+    /// Accounts are (hash, slim account RLP) pairs
+    AccountRange {
+        request_id: u64,
+        accounts: Vec<(H256, Vec<u8>)>,
+        proof: Vec<Vec<u8>>,
+    },
+    GetStorageRanges {
+        request_id: u64,
+        root_hash: H256,
+        account_hashes: Vec<H256>,
+        starting_hash: Vec<u8>,
+        limit_hash: Vec<u8>,
+        response_bytes: u64,
+    },
+    /// Storage slots of each requested account as (hash, value) pairs
+    StorageRanges {
+        request_id: u64,
+        slots: Vec<Vec<(H256, Vec<u8>)>>,
+        proof: Vec<Vec<u8>>,
+    },
+    GetByteCodes {
+        request_id: u64,
+        hashes: Vec<H256>,
+        response_bytes: u64,
+    },
+    ByteCodes {
+        request_id: u64,
+        codes: Vec<Vec<u8>>,
+    },
+    /// Each path is an account trie path optionally followed by
+    /// storage trie paths of that account
+    GetTrieNodes {
+        request_id: u64,
+        root_hash: H256,
+        paths: Vec<Vec<Vec<u8>>>,
+        response_bytes: u64,
+    },
+    TrieNodes {
+        request_id: u64,
+        nodes: Vec<Vec<u8>>,
+    },
+}
+
+impl SNAPMessage {
+    /// Get the message id of the SNAP message
+    pub fn id(&self) -> usize {
+        match *self {
+            SNAPMessage::GetAccountRange { .. } => 0x00,
+            SNAPMessage::AccountRange { .. } => 0x01,
+            SNAPMessage::GetStorageRanges { .. } => 0x02,
+            SNAPMessage::StorageRanges { .. } => 0x03,
+            SNAPMessage::GetByteCodes { .. } => 0x04,
+            SNAPMessage::ByteCodes { .. } => 0x05,
+            SNAPMessage::GetTrieNodes { .. } => 0x06,
+            SNAPMessage::TrieNodes { .. } => 0x07,
+        }
+    }
+
+    /// Id of the request the message is or answers
+    pub fn request_id(&self) -> u64 {
+        match self {
+            &SNAPMessage::GetAccountRange { request_id, .. } |
+            &SNAPMessage::AccountRange { request_id, .. } |
+            &SNAPMessage::GetStorageRanges { request_id, .. } |
+            &SNAPMessage::StorageRanges { request_id, .. } |
+            &SNAPMessage::GetByteCodes { request_id, .. } |
+            &SNAPMessage::ByteCodes { request_id, .. } |
+            &SNAPMessage::GetTrieNodes { request_id, .. } |
+            &SNAPMessage::TrieNodes { request_id, .. } => request_id,
+        }
+    }
+
+    /// Decode a RLP into SNAP message using the given message id
+    pub fn decode(rlp: &UntrustedRlp, id: usize) -> Result<Self, DecoderError> {
+        Ok(match id {
+            0x00 => {
+                SNAPMessage::GetAccountRange {
+                    request_id: rlp.val_at(0)?,
+                    root_hash: rlp.val_at(1)?,
+                    starting_hash: rlp.val_at(2)?,
+                    limit_hash: rlp.val_at(3)?,
+                    response_bytes: rlp.val_at(4)?,
+                }
+            },
+            0x01 => {
+                let mut accounts = Vec::new();
+                for account in rlp.at(1)?.iter() {
+                    accounts.push((account.val_at(0)?, account.at(1)?.as_raw().to_vec()));
+                }
+                SNAPMessage::AccountRange {
+                    request_id: rlp.val_at(0)?,
+                    accounts,
+                    proof: rlp.list_at(2)?,
+                }
+            },
+            0x02 => {
+                SNAPMessage::GetStorageRanges {
+                    request_id: rlp.val_at(0)?,
+                    root_hash: rlp.val_at(1)?,
+                    account_hashes: rlp.list_at(2)?,
+                    starting_hash: rlp.val_at(3)?,
+                    limit_hash: rlp.val_at(4)?,
+                    response_bytes: rlp.val_at(5)?,
+                }
+            },
+            0x03 => {
+                let mut slots = Vec::new();
+                for account in rlp.at(1)?.iter() {
+                    let mut r = Vec::new();
+                    for slot in account.iter() {
+                        r.push((slot.val_at(0)?, slot.val_at(1)?));
+                    }
+                    slots.push(r);
+                }
+                SNAPMessage::StorageRanges {
+                    request_id: rlp.val_at(0)?,
+                    slots,
+                    proof: rlp.list_at(2)?,
+                }
+            },
+            0x04 => {
+                SNAPMessage::GetByteCodes {
+                    request_id: rlp.val_at(0)?,
+                    hashes: rlp.list_at(1)?,
+                    response_bytes: rlp.val_at(2)?,
+                }
+            },
+            0x05 => {
+                SNAPMessage::ByteCodes {
+                    request_id: rlp.val_at(0)?,
+                    codes: rlp.list_at(1)?,
+                }
+            },
+            0x06 => {
+                let mut paths = Vec::new();
+                for path in rlp.at(2)?.iter() {
+                    paths.push(path.as_list()?);
+                }
+                SNAPMessage::GetTrieNodes {
+                    request_id: rlp.val_at(0)?,
+                    root_hash: rlp.val_at(1)?,
+                    paths,
+                    response_bytes: rlp.val_at(3)?,
+                }
+            },
+            0x07 => {
+                SNAPMessage::TrieNodes {
+                    request_id: rlp.val_at(0)?,
+                    nodes: rlp.list_at(1)?,
+                }
+            },
+            _ => return Err(DecoderError::Custom("unknown snap message id")),
+        })
+    }
+}
+
+impl Encodable for SNAPMessage {
+    fn rlp_append(&self, s: &mut RlpStream) {
+        match *self {
+            SNAPMessage::GetAccountRange {
+                request_id, root_hash, starting_hash, limit_hash, response_bytes
+            } => {
+                s.begin_list(5);
+                s.append(&request_id);
+                s.append(&root_hash);
+                s.append(&starting_hash);
+                s.append(&limit_hash);
+                s.append(&response_bytes);
+            },
+            SNAPMessage::AccountRange { request_id, ref accounts, ref proof } => {
+                s.begin_list(3);
+                s.append(&request_id);
+                s.begin_list(accounts.len());
+                for (hash, body) in accounts {
+                    s.begin_list(2);
+                    s.append(hash);
+                    s.append_raw(body, 1);
+                }
+                s.append_list::<Vec<u8>, _>(proof);
+            },
+            SNAPMessage::GetStorageRanges {
+                request_id, root_hash, ref account_hashes,
+                ref starting_hash, ref limit_hash, response_bytes
+            } => {
+                s.begin_list(6);
+                s.append(&request_id);
+                s.append(&root_hash);
+                s.append_list(account_hashes);
+                s.append(starting_hash);
+                s.append(limit_hash);
+                s.append(&response_bytes);
+            },
+            SNAPMessage::StorageRanges { request_id, ref slots, ref proof } => {
+                s.begin_list(3);
+                s.append(&request_id);
+                s.begin_list(slots.len());
+                for account in slots {
+                    s.begin_list(account.len());
+                    for (hash, value) in account {
+                        s.begin_list(2);
+                        s.append(hash);
+                        s.append(value);
+                    }
+                }
+                s.append_list::<Vec<u8>, _>(proof);
+            },
+            SNAPMessage::GetByteCodes { request_id, ref hashes, response_bytes } => {
+                s.begin_list(3);
+                s.append(&request_id);
+                s.append_list(hashes);
+                s.append(&response_bytes);
+            },
+            SNAPMessage::ByteCodes { request_id, ref codes } => {
+                s.begin_list(2);
+                s.append(&request_id);
+                s.append_list::<Vec<u8>, _>(codes);
+            },
+            SNAPMessage::GetTrieNodes { request_id, root_hash, ref paths, response_bytes } => {
+                s.begin_list(4);
+                s.append(&request_id);
+                s.append(&root_hash);
+                s.begin_list(paths.len());
+                for path in paths {
+                    s.append_list::<Vec<u8>, _>(path);
+                }
+                s.append(&response_bytes);
+            },
+            SNAPMessage::TrieNodes { request_id, ref nodes } => {
+                s.begin_list(2);
+                s.append(&request_id);
+                s.append_list::<Vec<u8>, _>(nodes);
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SNAPMessage;
+    use rlp::{self, UntrustedRlp};
+    use bigint::H256;
+
+    #[test]
+    fn round_trip() {
+        let messages = vec![
+            SNAPMessage::GetAccountRange {
+                request_id: 1, root_hash: H256::from(1), starting_hash: H256::zero(),
+                limit_hash: H256::from(2), response_bytes: 512 * 1024,
+            },
+            SNAPMessage::AccountRange {
+                request_id: 1,
+                // Slim accounts are RLP lists embedded as they are
+                accounts: vec![(H256::from(3), vec![0xc4, 0x01, 0x02, 0x80, 0x80])],
+                proof: vec![vec![0x42; 40]],
+            },
+            SNAPMessage::GetStorageRanges {
+                request_id: 2, root_hash: H256::from(1), account_hashes: vec![H256::from(3)],
+                starting_hash: Vec::new(), limit_hash: Vec::new(), response_bytes: 1024,
+            },
+            SNAPMessage::StorageRanges {
+                request_id: 2,
+                slots: vec![vec![(H256::from(4), vec![0x01]), (H256::from(5), vec![0x02])],
+                            Vec::new()],
+                proof: Vec::new(),
+            },
+            SNAPMessage::GetByteCodes {
+                request_id: 3, hashes: vec![H256::from(6)], response_bytes: 1024,
+            },
+            SNAPMessage::ByteCodes { request_id: 3, codes: vec![vec![0x60, 0x00]] },
+            SNAPMessage::GetTrieNodes {
+                request_id: 4, root_hash: H256::from(1),
+                paths: vec![vec![vec![0x12]], vec![vec![0x34], vec![0x56, 0x78]]],
+                response_bytes: 1024,
+            },
+            SNAPMessage::TrieNodes { request_id: 4, nodes: vec![vec![0xc0]] },
+        ];
+
+        for (id, message) in messages.into_iter().enumerate() {
+            assert_eq!(message.id(), id);
+            let encoded = rlp::encode(&message);
+            let decoded = SNAPMessage::decode(&UntrustedRlp::new(&encoded), id).unwrap();
+            assert_eq!(decoded, message);
+        }
+    }
+}
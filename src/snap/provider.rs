@@ -0,0 +1,255 @@
+use bigint::H256;
+use super::SNAPMessage;
+
+/// Hard cap on the size of a response, whatever the request asks for
+pub const SOFT_RESPONSE_LIMIT: u64 = 2 * 1024 * 1024;
+
+/// Source of state data used to answer snap requests automatically
+pub trait SnapProvider {
+    /// Accounts of the state with the given root, as (hash, slim
+    /// account RLP) pairs in hash order starting at `start`. `None` if
+    /// the state is not available.
+    fn accounts(&self, root: H256, start: H256) -> Option<Box<dyn Iterator<Item = (H256, Vec<u8>)>>>;
+
+    /// Storage slots of an account of the state with the given root,
+    /// as (hash, value) pairs in hash order starting at `start`
+    fn storage(
+        &self, root: H256, account: H256, start: H256
+    ) -> Option<Box<dyn Iterator<Item = (H256, Vec<u8>)>>>;
+
+    /// Trie nodes proving the range from `start` to `last` of the
+    /// account trie, or of an account's storage trie if `account` is
+    /// given
+    fn range_proof(&self, root: H256, account: Option<H256>, start: H256, last: H256) -> Vec<Vec<u8>>;
+
+    /// Contract code with the given hash
+    fn byte_code(&self, hash: H256) -> Option<Vec<u8>>;
+
+    /// Trie node at `path` of the account trie, or of the storage trie
+    /// of the account at `path` if a `storage_path` is given
+    fn trie_node(&self, root: H256, path: &[u8], storage_path: Option<&[u8]>) -> Option<Vec<u8>>;
+}
+
+/// Build the response to a snap request, or `None` if the message is
+/// not a request. Responses are cut once they reach the requested
+/// size, capped at `SOFT_RESPONSE_LIMIT`, but always hold at least
+/// one item if any is available.
+pub fn serve_snap<P: SnapProvider + ?Sized>(provider: &P, message: &SNAPMessage) -> Option<SNAPMessage> {
+    Some(match *message {
+        SNAPMessage::GetAccountRange {
+            request_id, root_hash, starting_hash, limit_hash, response_bytes
+        } => {
+            let mut accounts = Vec::new();
+            if let Some(iter) = provider.accounts(root_hash, starting_hash) {
+                let mut bytes = 0;
+                for (hash, body) in iter {
+                    bytes += 32 + body.len() as u64;
+                    accounts.push((hash, body));
+                    if hash >= limit_hash || bytes >= budget(response_bytes) {
+                        break;
+                    }
+                }
+            }
+            let proof = match accounts.last() {
+                Some(&(last, _)) => provider.range_proof(root_hash, None, starting_hash, last),
+                None => Vec::new(),
+            };
+            SNAPMessage::AccountRange { request_id, accounts, proof }
+        },
+        SNAPMessage::GetStorageRanges {
+            request_id, root_hash, ref account_hashes,
+            ref starting_hash, ref limit_hash, response_bytes
+        } => {
+            // The origin applies to the first account and the limit to
+            // the last one only
+            let origin = hash_or(starting_hash, H256::zero());
+            let limit = hash_or(limit_hash, H256::from(&[0xffu8; 32][..]));
+            let mut slots = Vec::new();
+            let mut proof = Vec::new();
+            let mut bytes = 0;
+
+            for (index, &account) in account_hashes.iter().enumerate() {
+                if bytes >= budget(response_bytes) {
+                    break;
+                }
+                let start = if index == 0 { origin } else { H256::zero() };
+                let last_account = index + 1 == account_hashes.len();
+                let iter = match provider.storage(root_hash, account, start) {
+                    Some(iter) => iter,
+                    None => break,
+                };
+
+                let mut account_slots = Vec::new();
+                let mut complete = true;
+                for (hash, value) in iter {
+                    if last_account && hash > limit {
+                        break;
+                    }
+                    bytes += 32 + value.len() as u64;
+                    account_slots.push((hash, value));
+                    if bytes >= budget(response_bytes) {
+                        complete = false;
+                        break;
+                    }
+                }
+
+                // A partial range has to be proven
+                if !complete || start != H256::zero() {
+                    if let Some(&(last, _)) = account_slots.last() {
+                        proof = provider.range_proof(root_hash, Some(account), start, last);
+                    }
+                }
+                slots.push(account_slots);
+                if !complete {
+                    break;
+                }
+            }
+            SNAPMessage::StorageRanges { request_id, slots, proof }
+        },
+        SNAPMessage::GetByteCodes { request_id, ref hashes, response_bytes } => {
+            let mut codes = Vec::new();
+            let mut bytes = 0;
+            for &hash in hashes {
+                if let Some(code) = provider.byte_code(hash) {
+                    bytes += code.len() as u64;
+                    codes.push(code);
+                    if bytes >= budget(response_bytes) {
+                        break;
+                    }
+                }
+            }
+            SNAPMessage::ByteCodes { request_id, codes }
+        },
+        SNAPMessage::GetTrieNodes { request_id, root_hash, ref paths, response_bytes } => {
+            let mut nodes = Vec::new();
+            let mut bytes = 0;
+            'paths: for path in paths {
+                let (account_path, storage_paths) = match path.split_first() {
+                    Some(val) => val,
+                    None => continue,
+                };
+                let lookups: Vec<Option<&[u8]>> = if storage_paths.is_empty() {
+                    vec![None]
+                } else {
+                    storage_paths.iter().map(|p| Some(&p[..])).collect()
+                };
+                for storage_path in lookups {
+                    // Nodes are matched to requests by position, so
+                    // stop at the first one we do not have
+                    let node = match provider.trie_node(root_hash, account_path, storage_path) {
+                        Some(node) => node,
+                        None => break 'paths,
+                    };
+                    bytes += node.len() as u64;
+                    nodes.push(node);
+                    if bytes >= budget(response_bytes) {
+                        break 'paths;
+                    }
+                }
+            }
+            SNAPMessage::TrieNodes { request_id, nodes }
+        },
+        _ => return None,
+    })
+}
+
+/// Response size to aim for given the requested one
+fn budget(response_bytes: u64) -> u64 {
+    if response_bytes < SOFT_RESPONSE_LIMIT { response_bytes } else { SOFT_RESPONSE_LIMIT }
+}
+
+/// Decode a possibly empty hash of a storage range request
+fn hash_or(bytes: &[u8], default: H256) -> H256 {
+    if bytes.len() == 32 { H256::from(bytes) } else { default }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SnapProvider, serve_snap, SOFT_RESPONSE_LIMIT};
+    use super::super::SNAPMessage;
+    use bigint::H256;
+    use std::collections::BTreeMap;
+
+    struct MockProvider {
+        root: H256,
+        accounts: BTreeMap<H256, Vec<u8>>,
+        codes: BTreeMap<H256, Vec<u8>>,
+    }
+
+    impl MockProvider {
+        fn new() -> Self {
+            let accounts = (1..101u64).map(|n| (H256::from(n), vec![0xc4; 68])).collect();
+            let codes = (1..11u64).map(|n| (H256::from(n), vec![0x60; 1000])).collect();
+            MockProvider { root: H256::from(42), accounts, codes }
+        }
+    }
+
+    impl SnapProvider for MockProvider {
+        fn accounts(&self, root: H256, start: H256) -> Option<Box<dyn Iterator<Item = (H256, Vec<u8>)>>> {
+            if root != self.root {
+                return None;
+            }
+            let accounts: Vec<_> = self.accounts.range(start..)
+                .map(|(&hash, body)| (hash, body.clone())).collect();
+            Some(Box::new(accounts.into_iter()))
+        }
+
+        fn storage(
+            &self, _root: H256, _account: H256, _start: H256
+        ) -> Option<Box<dyn Iterator<Item = (H256, Vec<u8>)>>> {
+            None
+        }
+
+        fn range_proof(&self, _root: H256, _account: Option<H256>, _start: H256, last: H256) -> Vec<Vec<u8>> {
+            vec![last.to_vec()]
+        }
+
+        fn byte_code(&self, hash: H256) -> Option<Vec<u8>> {
+            self.codes.get(&hash).cloned()
+        }
+
+        fn trie_node(&self, _root: H256, _path: &[u8], _storage_path: Option<&[u8]>) -> Option<Vec<u8>> {
+            None
+        }
+    }
+
+    #[test]
+    fn account_range_budget_and_limit() {
+        let provider = MockProvider::new();
+        let request = |limit: u64, response_bytes: u64| SNAPMessage::GetAccountRange {
+            request_id: 1, root_hash: H256::from(42), starting_hash: H256::from(11),
+            limit_hash: H256::from(limit), response_bytes,
+        };
+
+        // 100 bytes per account
+        match serve_snap(&provider, &request(100, 1000)).unwrap() {
+            SNAPMessage::AccountRange { accounts, proof, .. } => {
+                assert_eq!(accounts.len(), 10);
+                assert_eq!(accounts[0].0, H256::from(11));
+                assert_eq!(proof, vec![H256::from(20).to_vec()]);
+            },
+            _ => panic!(),
+        }
+        match serve_snap(&provider, &request(14, SOFT_RESPONSE_LIMIT)).unwrap() {
+            SNAPMessage::AccountRange { accounts, .. } => assert_eq!(accounts.len(), 4),
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn byte_codes_budget() {
+        let provider = MockProvider::new();
+        let response = serve_snap(&provider, &SNAPMessage::GetByteCodes {
+            request_id: 3,
+            hashes: vec![H256::from(1), H256::from(500), H256::from(2), H256::from(3)],
+            response_bytes: 1500,
+        }).unwrap();
+        match response {
+            SNAPMessage::ByteCodes { request_id, codes } => {
+                assert_eq!(request_id, 3);
+                assert_eq!(codes.len(), 2);
+            },
+            _ => panic!(),
+        }
+    }
+}
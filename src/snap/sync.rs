@@ -0,0 +1,503 @@
+use rlp::{self, RlpStream, UntrustedRlp};
+use bigint::{H256, H512, U256};
+use sha3::{Digest, Keccak256};
+use trie::MemoryTrie;
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use super::SNAPMessage;
+use super::proof::verify_range_proof;
+
+/// Default number of bytes asked for in each snap request
+pub const DEFAULT_RESPONSE_BYTES: u64 = 512 * 1024;
+/// Maximum number of accounts whose storage is requested at once
+const STORAGE_BATCH: usize = 64;
+/// Maximum number of trie nodes requested at once while healing
+const HEAL_BATCH: usize = 128;
+
+/// Root of an empty trie, the storage root of accounts without storage
+const EMPTY_ROOT: [u8; 32] = [
+    0x56, 0xe8, 0x1f, 0x17, 0x1b, 0xcc, 0x55, 0xa6, 0xff, 0x83, 0x45, 0xe6, 0x92, 0xc0, 0xf8, 0x6e,
+    0x5b, 0x48, 0xe0, 0x1b, 0x99, 0x6c, 0xad, 0xc0, 0x01, 0x62, 0x2f, 0xb5, 0xe3, 0x63, 0xb4, 0x21,
+];
+/// Hash of empty code, the code hash of accounts without code
+const EMPTY_CODE_HASH: [u8; 32] = [
+    0xc5, 0xd2, 0x46, 0x01, 0x86, 0xf7, 0x23, 0x3c, 0x92, 0x7e, 0x7d, 0xb2, 0xdc, 0xc7, 0x03, 0xc0,
+    0xe5, 0x00, 0xb6, 0x53, 0xca, 0x82, 0x27, 0x3b, 0x7b, 0xfa, 0xd8, 0x04, 0x5d, 0x85, 0xa4, 0x70,
+];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// Progress of the download of a range of the account trie
+pub struct AccountRange {
+    /// Hash of the next account to download
+    pub next: H256,
+    /// Hash of the last account of the range
+    pub limit: H256,
+    /// Whether every account of the range is downloaded
+    pub done: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// Verified state data downloaded by `SnapSync`
+pub enum SnapSyncEvent {
+    /// Accounts as (hash, slim account RLP) pairs
+    Accounts(Vec<(H256, Vec<u8>)>),
+    /// Storage slots of an account as (hash, value) pairs
+    Storage {
+        account: H256,
+        slots: Vec<(H256, Vec<u8>)>,
+    },
+    /// Healed trie nodes with their paths
+    TrieNodes(Vec<(Vec<Vec<u8>>, Vec<u8>)>),
+}
+
+#[derive(Debug, Clone)]
+/// Storage of an account left to download
+struct StorageTask {
+    account: H256,
+    root: H256,
+    origin: H256,
+}
+
+#[derive(Debug)]
+enum Work {
+    Accounts(usize),
+    Storage(Vec<StorageTask>),
+    Heal(Vec<(Vec<Vec<u8>>, H256)>),
+}
+
+/// Client side of snap sync: downloads the account trie of a state
+/// split into ranges, then the storage of every account with one, and
+/// finally heals the trie nodes reported missing. Requests are built
+/// by `next_request` for an idle peer and answered through
+/// `handle_response`; sending them over a `SNAPStream` is left to the
+/// caller.
+///
+/// Ranges answered without a proof must hash to the expected root as
+/// a whole. Ranges with a proof are verified against the root with the
+/// proofs of their edges, so a peer can leave out no leaf in between.
+pub struct SnapSync {
+    root: H256,
+    ranges: Vec<AccountRange>,
+    storage: VecDeque<StorageTask>,
+    heal: VecDeque<(Vec<Vec<u8>>, H256)>,
+    in_flight: HashMap<u64, (H512, Work)>,
+    next_request_id: u64,
+    response_bytes: u64,
+}
+
+impl SnapSync {
+    /// Sync the state with the given root, splitting the account trie
+    /// into `ranges` ranges downloaded in parallel
+    pub fn new(root: H256, ranges: usize) -> Self {
+        let ranges = ::std::cmp::max(ranges, 1);
+        let step = U256::max_value() / U256::from(ranges);
+        let ranges = (0..ranges).map(|i| {
+            let next = step * U256::from(i);
+            let limit = if i + 1 == ranges { U256::max_value() } else { next + step - U256::one() };
+            AccountRange { next: H256::from(next), limit: H256::from(limit), done: false }
+        }).collect();
+
+        SnapSync {
+            root,
+            ranges,
+            storage: VecDeque::new(),
+            heal: VecDeque::new(),
+            in_flight: HashMap::new(),
+            next_request_id: 0,
+            response_bytes: DEFAULT_RESPONSE_BYTES,
+        }
+    }
+
+    /// Set the number of bytes asked for in each request
+    pub fn set_response_bytes(&mut self, response_bytes: u64) {
+        self.response_bytes = response_bytes;
+    }
+
+    /// Progress of each account range
+    pub fn progress(&self) -> &[AccountRange] {
+        &self.ranges
+    }
+
+    /// Queue trie nodes to heal, as (path, expected hash) pairs, once
+    /// accounts and storage are downloaded
+    pub fn add_missing_nodes(&mut self, nodes: Vec<(Vec<Vec<u8>>, H256)>) {
+        self.heal.extend(nodes);
+    }
+
+    /// Whether the whole state is downloaded and healed
+    pub fn is_done(&self) -> bool {
+        self.ranges.iter().all(|range| range.done) && self.storage.is_empty() &&
+            self.heal.is_empty() && self.in_flight.is_empty()
+    }
+
+    /// Next request to send to an idle peer, or `None` if there is
+    /// nothing to ask for right now
+    pub fn next_request(&mut self, peer: H512) -> Option<SNAPMessage> {
+        let request_id = self.next_request_id;
+        let busy: Vec<usize> = self.in_flight.values().filter_map(|(_, work)| match work {
+            &Work::Accounts(index) => Some(index),
+            _ => None,
+        }).collect();
+
+        let (message, work) = if let Some(index) = (0..self.ranges.len())
+            .find(|index| !self.ranges[*index].done && !busy.contains(index))
+        {
+            let range = &self.ranges[index];
+            (SNAPMessage::GetAccountRange {
+                request_id, root_hash: self.root,
+                starting_hash: range.next, limit_hash: range.limit,
+                response_bytes: self.response_bytes,
+            }, Work::Accounts(index))
+        } else if !self.storage.is_empty() {
+            // The origin only applies to the first account, so a partly
+            // downloaded storage is requested on its own
+            let count = if self.storage[0].origin.is_zero() {
+                self.storage.iter().take(STORAGE_BATCH).take_while(|task| task.origin.is_zero()).count()
+            } else {
+                1
+            };
+            let tasks: Vec<StorageTask> = self.storage.drain(..count).collect();
+            let starting_hash = if tasks[0].origin.is_zero() { Vec::new() } else { tasks[0].origin.to_vec() };
+            (SNAPMessage::GetStorageRanges {
+                request_id, root_hash: self.root,
+                account_hashes: tasks.iter().map(|task| task.account).collect(),
+                starting_hash, limit_hash: Vec::new(),
+                response_bytes: self.response_bytes,
+            }, Work::Storage(tasks))
+        } else if !self.heal.is_empty() && self.ranges.iter().all(|range| range.done) && self.in_flight.is_empty() {
+            let count = ::std::cmp::min(HEAL_BATCH, self.heal.len());
+            let nodes: Vec<(Vec<Vec<u8>>, H256)> = self.heal.drain(..count).collect();
+            (SNAPMessage::GetTrieNodes {
+                request_id, root_hash: self.root,
+                paths: nodes.iter().map(|(path, _)| path.clone()).collect(),
+                response_bytes: self.response_bytes,
+            }, Work::Heal(nodes))
+        } else {
+            return None;
+        };
+
+        self.next_request_id += 1;
+        self.in_flight.insert(request_id, (peer, work));
+        Some(message)
+    }
+
+    /// Give back the work of every request in flight to a peer, which
+    /// disconnected or stopped answering
+    pub fn requests_failed(&mut self, peer: H512) {
+        let request_ids: Vec<u64> = self.in_flight.iter()
+            .filter(|&(_, &(node, _))| node == peer)
+            .map(|(&request_id, _)| request_id)
+            .collect();
+        for request_id in request_ids {
+            let (_, work) = self.in_flight.remove(&request_id).unwrap();
+            self.give_back(work);
+        }
+    }
+
+    /// Verify a response from a peer to one of our requests, returning
+    /// the state data it holds. Invalid responses fail, and their work
+    /// is requested again later.
+    pub fn handle_response(&mut self, peer: H512, message: SNAPMessage) -> Result<Vec<SnapSyncEvent>, io::Error> {
+        let work = match self.in_flight.get(&message.request_id()) {
+            Some(&(node, _)) if node == peer => self.in_flight.remove(&message.request_id()).unwrap().1,
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "response to no pending request")),
+        };
+
+        let result = match (&work, message) {
+            (&Work::Accounts(index), SNAPMessage::AccountRange { accounts, proof, .. }) =>
+                self.accounts_received(index, accounts, proof),
+            (Work::Storage(tasks), SNAPMessage::StorageRanges { slots, proof, .. }) =>
+                self.storage_received(tasks, slots, proof),
+            (Work::Heal(nodes), SNAPMessage::TrieNodes { nodes: received, .. }) =>
+                self.nodes_received(nodes, received),
+            _ => Err(io::Error::new(io::ErrorKind::InvalidData, "unexpected response")),
+        };
+        if result.is_err() {
+            self.give_back(work);
+        }
+        result
+    }
+
+    fn accounts_received(
+        &mut self, index: usize, accounts: Vec<(H256, Vec<u8>)>, proof: Vec<Vec<u8>>
+    ) -> Result<Vec<SnapSyncEvent>, io::Error> {
+        if accounts.is_empty() && proof.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::Other, "peer does not serve the state"));
+        }
+        let mut leaves = Vec::new();
+        let mut storage = Vec::new();
+        for &(hash, ref body) in &accounts {
+            let (full, storage_root) = full_account(body)
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid account"))?;
+            if storage_root != H256::from(&EMPTY_ROOT[..]) {
+                storage.push(StorageTask { account: hash, root: storage_root, origin: H256::zero() });
+            }
+            leaves.push((hash, full));
+        }
+        let more = check_range(self.root, self.ranges[index].next, &leaves, &proof)?;
+
+        let range = &mut self.ranges[index];
+        match accounts.last() {
+            Some(&(last, _)) if last < range.limit && more =>
+                range.next = H256::from(U256::from(last) + U256::one()),
+            _ => range.done = true,
+        }
+        self.storage.extend(storage);
+        Ok(if accounts.is_empty() { Vec::new() } else { vec![SnapSyncEvent::Accounts(accounts)] })
+    }
+
+    fn storage_received(
+        &mut self, tasks: &[StorageTask], slots: Vec<Vec<(H256, Vec<u8>)>>, proof: Vec<Vec<u8>>
+    ) -> Result<Vec<SnapSyncEvent>, io::Error> {
+        if slots.len() > tasks.len() || (slots.is_empty() && proof.is_empty()) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid storage ranges"));
+        }
+        // Only the last storage returned may be partial, with a proof
+        let count = slots.len();
+        let mut more = false;
+        for (i, (task, slots)) in tasks.iter().zip(slots.iter()).enumerate() {
+            let proof: &[Vec<u8>] = if i + 1 == count { &proof } else { &[] };
+            more = check_range(task.root, task.origin, slots, proof)?;
+        }
+
+        let mut events = Vec::new();
+        let mut left: Vec<StorageTask> = tasks[count..].to_vec();
+        for (i, (task, slots)) in tasks.iter().zip(slots).enumerate() {
+            if i + 1 == count && more {
+                let last = slots.last().unwrap().0;
+                left.insert(0, StorageTask { origin: H256::from(U256::from(last) + U256::one()), ..task.clone() });
+            }
+            if !slots.is_empty() {
+                events.push(SnapSyncEvent::Storage { account: task.account, slots });
+            }
+        }
+        for task in left.into_iter().rev() {
+            self.storage.push_front(task);
+        }
+        Ok(events)
+    }
+
+    fn nodes_received(
+        &mut self, nodes: &[(Vec<Vec<u8>>, H256)], received: Vec<Vec<u8>>
+    ) -> Result<Vec<SnapSyncEvent>, io::Error> {
+        if received.is_empty() || received.len() > nodes.len() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid trie nodes"));
+        }
+        for (&(_, hash), node) in nodes.iter().zip(received.iter()) {
+            if keccak(node) != hash {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "trie node does not match its hash"));
+            }
+        }
+        for node in nodes[received.len()..].iter().rev() {
+            self.heal.push_front(node.clone());
+        }
+        let healed = nodes.iter().zip(received)
+            .map(|((path, _), node)| (path.clone(), node))
+            .collect();
+        Ok(vec![SnapSyncEvent::TrieNodes(healed)])
+    }
+
+    fn give_back(&mut self, work: Work) {
+        match work {
+            Work::Accounts(_) => (),
+            Work::Storage(tasks) => for task in tasks.into_iter().rev() {
+                self.storage.push_front(task);
+            },
+            Work::Heal(nodes) => for node in nodes.into_iter().rev() {
+                self.heal.push_front(node);
+            },
+        }
+    }
+}
+
+fn keccak(data: &[u8]) -> H256 {
+    H256::from(Keccak256::digest(data).as_slice())
+}
+
+/// Account RLP as stored in the trie from its slim RLP, in which an
+/// empty storage root and code hash are left out, with its storage root
+fn full_account(slim: &[u8]) -> Result<(Vec<u8>, H256), rlp::DecoderError> {
+    let rlp = UntrustedRlp::new(slim);
+    let hash_or = |index: usize, empty: &[u8; 32]| -> Result<H256, rlp::DecoderError> {
+        let item = rlp.at(index)?;
+        Ok(if item.is_empty() { H256::from(&empty[..]) } else { item.as_val()? })
+    };
+    let storage_root = hash_or(2, &EMPTY_ROOT)?;
+    let code_hash = hash_or(3, &EMPTY_CODE_HASH)?;
+
+    let mut s = RlpStream::new_list(4);
+    s.append_raw(rlp.at(0)?.as_raw(), 1);
+    s.append_raw(rlp.at(1)?.as_raw(), 1);
+    s.append(&storage_root);
+    s.append(&code_hash);
+    Ok((s.out().to_vec(), storage_root))
+}
+
+/// Root of the trie holding the given (key hash, value) leaves
+fn trie_root(leaves: &[(H256, Vec<u8>)]) -> H256 {
+    let mut trie = MemoryTrie::empty(HashMap::new());
+    for &(key, ref value) in leaves {
+        trie.insert_raw(key.to_vec(), value.clone());
+    }
+    trie.root()
+}
+
+/// Check a range of trie leaves answering a request starting at
+/// `origin`, returning whether the trie has leaves after it. Without a
+/// proof the leaves must be the whole trie.
+fn check_range(root: H256, origin: H256, leaves: &[(H256, Vec<u8>)], proof: &[Vec<u8>]) -> Result<bool, io::Error> {
+    let ordered = leaves.first().map(|&(first, _)| first >= origin).unwrap_or(true) &&
+        leaves.windows(2).all(|pair| pair[0].0 < pair[1].0);
+    if !ordered {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "range out of order"));
+    }
+    if !proof.is_empty() {
+        return verify_range_proof(root, origin, leaves, proof);
+    }
+    if !origin.is_zero() || trie_root(leaves) != root {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "range does not match the root"));
+    }
+    Ok(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SnapSync, SnapSyncEvent, trie_root, full_account, keccak, EMPTY_ROOT};
+    use super::super::SNAPMessage;
+    use super::super::proof::tests::range_proof;
+    use bigint::{H256, H512, U256};
+    use rlp::RlpStream;
+
+    fn slim_account(storage_root: Option<H256>) -> Vec<u8> {
+        let mut s = RlpStream::new_list(4);
+        s.append(&1u64);
+        s.append(&U256::from(1000));
+        match storage_root {
+            Some(root) => s.append(&root),
+            None => s.append_empty_data(),
+        };
+        s.append_empty_data();
+        s.out().to_vec()
+    }
+
+    #[test]
+    fn ranges_cover_the_hash_space() {
+        let mut sync = SnapSync::new(H256::random(), 4);
+        assert_eq!(sync.progress()[0].next, H256::zero());
+        assert_eq!(sync.progress()[3].limit, H256::from(U256::max_value()));
+        for pair in sync.progress().windows(2) {
+            assert_eq!(H256::from(U256::from(pair[0].limit) + U256::one()), pair[1].next);
+        }
+
+        // One request per range, then nothing to ask for
+        let peer = H512::random();
+        for _ in 0..4 {
+            match sync.next_request(peer) {
+                Some(SNAPMessage::GetAccountRange { .. }) => (),
+                request => panic!("unexpected request {:?}", request),
+            }
+        }
+        assert_eq!(sync.next_request(peer), None);
+        sync.requests_failed(peer);
+        assert!(sync.next_request(peer).is_some());
+    }
+
+    #[test]
+    fn downloads_and_heals_state() {
+        let slots = vec![(H256::from(1), vec![0x2a]), (H256::from(2), vec![0x2b])];
+        let storage_root = trie_root(&slots);
+        let accounts = vec![
+            (H256::from(10), slim_account(None)),
+            (H256::from(20), slim_account(Some(storage_root))),
+        ];
+        let leaves: Vec<_> = accounts.iter()
+            .map(|&(hash, ref body)| (hash, full_account(body).unwrap().0))
+            .collect();
+        assert_eq!(full_account(&accounts[0].1).unwrap().1, H256::from(&EMPTY_ROOT[..]));
+        let root = trie_root(&leaves);
+        let peer = H512::random();
+        let mut sync = SnapSync::new(root, 1);
+
+        // A range that does not hash to the root is requested again
+        let request = sync.next_request(peer).unwrap();
+        let response = SNAPMessage::AccountRange {
+            request_id: request.request_id(), accounts: accounts[..1].to_vec(), proof: Vec::new(),
+        };
+        assert!(sync.handle_response(peer, response).is_err());
+        assert!(!sync.progress()[0].done);
+
+        let request = sync.next_request(peer).unwrap();
+        let response = SNAPMessage::AccountRange {
+            request_id: request.request_id(), accounts: accounts.clone(), proof: Vec::new(),
+        };
+        assert_eq!(sync.handle_response(peer, response).unwrap(), vec![SnapSyncEvent::Accounts(accounts)]);
+        assert!(sync.progress()[0].done);
+
+        // Storage of the account with one
+        let request = sync.next_request(peer).unwrap();
+        match request {
+            SNAPMessage::GetStorageRanges { ref account_hashes, .. } => assert_eq!(account_hashes, &vec![H256::from(20)]),
+            _ => panic!(),
+        }
+        let response = SNAPMessage::StorageRanges {
+            request_id: request.request_id(), slots: vec![slots.clone()], proof: Vec::new(),
+        };
+        assert_eq!(sync.handle_response(peer, response).unwrap(),
+                   vec![SnapSyncEvent::Storage { account: H256::from(20), slots }]);
+        assert!(sync.is_done());
+
+        // Healing a missing node
+        let node = vec![0xc0];
+        sync.add_missing_nodes(vec![(vec![vec![0x01]], keccak(&node))]);
+        let request = sync.next_request(peer).unwrap();
+        let response = SNAPMessage::TrieNodes { request_id: request.request_id(), nodes: vec![vec![0xc1]] };
+        assert!(sync.handle_response(peer, response).is_err());
+        let request = sync.next_request(peer).unwrap();
+        let response = SNAPMessage::TrieNodes { request_id: request.request_id(), nodes: vec![node.clone()] };
+        assert_eq!(sync.handle_response(peer, response).unwrap(),
+                   vec![SnapSyncEvent::TrieNodes(vec![(vec![vec![0x01]], node)])]);
+        assert!(sync.is_done());
+    }
+
+    #[test]
+    fn ranges_with_proofs() {
+        let mut accounts: Vec<(H256, Vec<u8>)> = (1..9u8)
+            .map(|i| (keccak(&[i]), slim_account(None)))
+            .collect();
+        accounts.sort();
+        let leaves: Vec<_> = accounts.iter()
+            .map(|&(hash, ref body)| (hash, full_account(body).unwrap().0))
+            .collect();
+        let root = trie_root(&leaves);
+        let peer = H512::random();
+        let mut sync = SnapSync::new(root, 1);
+
+        // The first half, with an account left out of the middle
+        let proof = range_proof(&leaves, H256::zero(), accounts[3].0);
+        let mut gapped = accounts[..4].to_vec();
+        gapped.remove(1);
+        let request = sync.next_request(peer).unwrap();
+        let response = SNAPMessage::AccountRange {
+            request_id: request.request_id(), accounts: gapped, proof: proof.clone(),
+        };
+        assert!(sync.handle_response(peer, response).is_err());
+
+        let request = sync.next_request(peer).unwrap();
+        let response = SNAPMessage::AccountRange {
+            request_id: request.request_id(), accounts: accounts[..4].to_vec(), proof,
+        };
+        sync.handle_response(peer, response).unwrap();
+        assert!(!sync.progress()[0].done);
+        assert_eq!(sync.progress()[0].next, H256::from(U256::from(accounts[3].0) + U256::one()));
+
+        // The rest, after which the trie has no more accounts
+        let origin = sync.progress()[0].next;
+        let request = sync.next_request(peer).unwrap();
+        let response = SNAPMessage::AccountRange {
+            request_id: request.request_id(), accounts: accounts[4..].to_vec(),
+            proof: range_proof(&leaves, origin, accounts[7].0),
+        };
+        sync.handle_response(peer, response).unwrap();
+        assert!(sync.is_done());
+    }
+}
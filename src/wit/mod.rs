@@ -0,0 +1,228 @@
+mod proto;
+
+use futures::{StartSend, Async, Poll, Stream, Sink, AsyncSink};
+use rlp::{self, UntrustedRlp};
+use bigint::H512;
+use rlpx::{RLPxSendMessage, RLPxReceiveMessage, RLPxNode, CapabilityInfo, DisconnectReason, PeerInfo,
+           PeerTable, NodeSigner};
+use tokio_core::reactor::Handle;
+use std::io;
+use std::sync::Arc;
+use std::net::{IpAddr, SocketAddr};
+
+use super::{DevP2PStream, DevP2PBuilder, Error};
+
+pub use self::proto::WITMessage;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// Receiving message of WIT
+pub enum WITReceiveMessage {
+    Connected {
+        node: H512,
+        version: usize,
+    },
+    /// A peer went away, see `RLPxReceiveMessage::Disconnected`
+    Disconnected {
+        node: H512,
+        reason: Option<DisconnectReason>,
+        clean: bool,
+    },
+    Normal {
+        node: H512,
+        version: usize,
+        data: WITMessage,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// Sending message of WIT
+pub struct WITSendMessage {
+    pub node: RLPxNode,
+    pub data: WITMessage,
+}
+
+/// Represent a WIT stream over DevP2P protocol, advertising the wit/0
+/// capability used to fetch block witnesses for stateless clients
+pub struct WITStream {
+    stream: DevP2PStream,
+}
+
+impl WITStream {
+    /// Create a new WIT stream on the network set up by `network`
+    pub fn new(addr: &SocketAddr, public_addr: &IpAddr,
+               handle: &Handle, signer: Arc<dyn NodeSigner>,
+               network: DevP2PBuilder,
+    ) -> Result<Self, io::Error> {
+        Ok(WITStream {
+            stream: network.capability(CapabilityInfo { name: "wit", version: 0, length: 3 })
+                .build(addr, public_addr, handle, signer)?,
+        })
+    }
+
+    /// Force disconnecting a peer if it is already connected or about
+    /// to be connected, telling a connected peer the reason in a
+    /// Disconnect message
+    pub fn disconnect_peer(&mut self, remote_id: H512, reason: DisconnectReason) {
+        self.stream.disconnect_peer(remote_id, reason);
+    }
+
+    /// Active peers
+    pub fn active_peers(&self) -> &[H512] {
+        self.stream.active_peers()
+    }
+
+    /// Table of the connected peers, see `DevP2PStream::peer_table`
+    pub fn peer_table(&self) -> PeerTable {
+        self.stream.peer_table()
+    }
+
+    /// Summary of a connected peer, see `DevP2PStream::peer_info`
+    pub fn peer_info(&self, remote_id: H512) -> Option<PeerInfo> {
+        self.stream.peer_info(remote_id)
+    }
+
+    /// Summaries of all connected peers
+    pub fn peers(&self) -> Vec<PeerInfo> {
+        self.stream.peers()
+    }
+}
+
+impl Stream for WITStream {
+    type Item = WITReceiveMessage;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        let result = match try_ready!(self.stream.poll()) {
+            Some(result) => result,
+            None => return Ok(Async::Ready(None)),
+        };
+
+        match result {
+            RLPxReceiveMessage::Connected { node, capabilities } => {
+                match capabilities.iter().find(|cap| cap.name == "wit") {
+                    Some(cap) => Ok(Async::Ready(Some(WITReceiveMessage::Connected {
+                        node, version: cap.version,
+                    }))),
+                    None => {
+                        debug!("connected a node without matching capability, ignoring.");
+                        self.poll()
+                    },
+                }
+            },
+            RLPxReceiveMessage::Disconnected { node, reason, clean } => {
+                Ok(Async::Ready(Some(WITReceiveMessage::Disconnected {
+                    node, reason, clean
+                })))
+            },
+            RLPxReceiveMessage::Normal {
+                node, capability, id, data,
+            } => {
+                debug!("got wit message with id {}", id);
+                match WITMessage::decode(&UntrustedRlp::new(&data), id) {
+                    Ok(message) => Ok(Async::Ready(Some(WITReceiveMessage::Normal {
+                        node, version: capability.version,
+                        data: message,
+                    }))),
+                    Err(_) => {
+                        debug!("got an ununderstandable message with id {}, data {:?}, ignoring.", id, data);
+                        self.poll()
+                    },
+                }
+            },
+        }
+    }
+}
+
+impl Sink for WITStream {
+    type SinkItem = WITSendMessage;
+    type SinkError = Error;
+
+    fn start_send(&mut self, val: WITSendMessage) -> StartSend<Self::SinkItem, Self::SinkError> {
+        match self.stream.start_send(RLPxSendMessage {
+            node: val.node,
+            capability_name: "wit",
+            id: val.data.id(),
+            data: rlp::encode(&val.data).to_vec(),
+        })? {
+            AsyncSink::Ready => Ok(AsyncSink::Ready),
+            AsyncSink::NotReady(_) => Ok(AsyncSink::NotReady(val)),
+        }
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
+        self.stream.poll_complete()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{WITStream, WITMessage, WITReceiveMessage};
+    use super::super::{DevP2PConfig, DevP2PBuilder};
+    use bigint::{H256, H512};
+    use rlp;
+    use rlpx::{PeerStream, CapabilityInfo};
+    use secp256k1::SECP256K1;
+    use secp256k1::key::{PublicKey, SecretKey};
+    use tokio_core::reactor::Core;
+    use futures::{future, Async, Poll, Future, Stream, Sink};
+    use rand::os::OsRng;
+    use std::io;
+    use std::net::TcpListener;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    fn pk2id(key: &PublicKey) -> H512 {
+        H512::from(&key.serialize_vec(&SECP256K1, false)[1..65])
+    }
+
+    #[test]
+    fn receives_witness_request() {
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let config = DevP2PConfig {
+     ping_interval: Duration::new(600, 0),
+     ping_timeout_interval: Duration::new(700, 0),
+     optimal_peers_interval: Duration::new(600, 0),
+     max_peers_per_ip: None,
+     ..Default::default()
+        };
+
+        let addr = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+        let secret_key = SecretKey::new(&SECP256K1, &mut OsRng::new().unwrap());
+        let id = pk2id(&PublicKey::from_secret_key(&SECP256K1, &secret_key).unwrap());
+        let mut wit = WITStream::new(&addr, &addr.ip(), &handle, Arc::new(secret_key),
+                                     DevP2PBuilder::from(config).client_version("wit".to_string())).unwrap();
+
+        let peer_key = SecretKey::new(&SECP256K1, &mut OsRng::new().unwrap());
+        let peer = PeerStream::connect(
+            &addr, &handle, Arc::new(peer_key), id,
+            5, "peer".to_string(), vec![CapabilityInfo { name: "wit", version: 0, length: 3 }],
+            0, Default::default());
+        let request = WITMessage::GetBlockWitnessHashes {
+            request_id: 9, hash: H256::random(),
+        };
+        let payload = rlp::encode(&request).to_vec();
+        let peer = peer.and_then(|peer| peer.send(("wit", 0x01, payload)));
+
+        let mut received = Vec::new();
+        let messages = future::poll_fn(|| -> Poll<(), io::Error> {
+            while received.len() < 2 {
+                match try_ready!(wit.poll()) {
+                    Some(message) => received.push(message),
+                    None => panic!(),
+                }
+            }
+            Ok(Async::Ready(()))
+        });
+        core.run(peer.join(messages)).unwrap();
+
+        match received[0] {
+            WITReceiveMessage::Connected { version, .. } => assert_eq!(version, 0),
+            _ => panic!(),
+        }
+        match received[1] {
+            WITReceiveMessage::Normal { ref data, .. } => assert_eq!(data, &request),
+            _ => panic!(),
+        }
+    }
+}
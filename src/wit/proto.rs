@@ -0,0 +1,92 @@
+use rlp::{Encodable, RlpStream, DecoderError, UntrustedRlp};
+use bigint::H256;
+
+/// WIT message version 0. Message id 0x00 is reserved and there is
+/// no handshake.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WITMessage {
+    GetBlockWitnessHashes {
+        request_id: u64,
+        hash: H256,
+    },
+    /// Hashes of the trie nodes making up the witness of the block
+    BlockWitnessHashes {
+        request_id: u64,
+        hashes: Vec<H256>,
+    },
+}
+
+impl WITMessage {
+    /// Get the message id of the WIT message
+    pub fn id(&self) -> usize {
+        match *self {
+            WITMessage::GetBlockWitnessHashes { .. } => 0x01,
+            WITMessage::BlockWitnessHashes { .. } => 0x02,
+        }
+    }
+
+    /// Id of the request the message is or answers
+    pub fn request_id(&self) -> u64 {
+        match self {
+            &WITMessage::GetBlockWitnessHashes { request_id, .. } |
+            &WITMessage::BlockWitnessHashes { request_id, .. } => request_id,
+        }
+    }
+
+    /// Decode a RLP into WIT message using the given message id
+    pub fn decode(rlp: &UntrustedRlp, id: usize) -> Result<Self, DecoderError> {
+        Ok(match id {
+            0x01 => {
+                WITMessage::GetBlockWitnessHashes {
+                    request_id: rlp.val_at(0)?,
+                    hash: rlp.val_at(1)?,
+                }
+            },
+            0x02 => {
+                WITMessage::BlockWitnessHashes {
+                    request_id: rlp.val_at(0)?,
+                    hashes: rlp.list_at(1)?,
+                }
+            },
+            _ => return Err(DecoderError::Custom("unknown wit message id")),
+        })
+    }
+}
+
+impl Encodable for WITMessage {
+    fn rlp_append(&self, s: &mut RlpStream) {
+        match *self {
+            WITMessage::GetBlockWitnessHashes { request_id, hash } => {
+                s.begin_list(2);
+                s.append(&request_id);
+                s.append(&hash);
+            },
+            WITMessage::BlockWitnessHashes { request_id, ref hashes } => {
+                s.begin_list(2);
+                s.append(&request_id);
+                s.append_list(hashes);
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::WITMessage;
+    use rlp::{self, UntrustedRlp};
+    use bigint::H256;
+
+    #[test]
+    fn round_trip() {
+        let messages = vec![
+            WITMessage::GetBlockWitnessHashes { request_id: 1, hash: H256::from(1) },
+            WITMessage::BlockWitnessHashes { request_id: 1, hashes: vec![H256::from(2), H256::from(3)] },
+        ];
+
+        for message in messages {
+            let encoded = rlp::encode(&message);
+            let decoded = WITMessage::decode(&UntrustedRlp::new(&encoded), message.id()).unwrap();
+            assert_eq!(decoded, message);
+        }
+    }
+}